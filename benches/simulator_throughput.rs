@@ -0,0 +1,62 @@
+//! Measures simulated instructions per second on the logging and fast execution paths.
+//!
+//! Run with `cargo bench --bench simulator_throughput`.
+use std::time::{Duration, Instant};
+use y86_seq::assembler::parse_and_gen;
+use y86_seq::simulator::observer::Observer;
+use y86_seq::simulator::simulator_guts::{LogPolicy, Simulator, Status};
+
+const PROGRAM: &str = include_str!("../examples/bubble_sort.ys");
+const MIN_DURATION: Duration = Duration::from_secs(2);
+
+/// Forces the logging path without keeping a log.
+struct NoopObserver;
+impl Observer for NoopObserver {}
+
+/// Repeatedly runs `code` to completion with `run`, which returns the halted simulator.
+fn measure(name: &str, code: &[u8], run: impl Fn(&[u8]) -> Simulator<'_, 1024>) {
+    let start = Instant::now();
+    let mut runs = 0u64;
+    let mut instructions = 0u64;
+    while start.elapsed() < MIN_DURATION {
+        let simulator = run(code);
+        assert_eq!(simulator.state, Status::Halted);
+        instructions += simulator.steps;
+        runs += 1;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{:<28} {:>6} runs {:>12} instructions {:>14.0} instructions/s",
+        name,
+        runs,
+        instructions,
+        instructions as f64 / elapsed
+    );
+}
+
+fn main() {
+    let code = parse_and_gen(PROGRAM).unwrap().1.bytes;
+
+    measure("logging path, full log", &code, |code| {
+        let mut simulator = Simulator::<1024>::new(code);
+        simulator.run();
+        simulator
+    });
+    measure("logging path, log off", &code, |code| {
+        let mut simulator = Simulator::<1024>::new(code).with_log_policy(LogPolicy::Off);
+        simulator.add_observer(NoopObserver);
+        simulator.run();
+        simulator
+    });
+    measure("fast path", &code, |code| {
+        let mut simulator = Simulator::<1024>::new(code).with_log_policy(LogPolicy::Off);
+        simulator.run();
+        simulator
+    });
+    #[cfg(all(feature = "jit", target_arch = "x86_64"))]
+    measure("jit", &code, |code| {
+        let mut jit = y86_seq::simulator::jit::Jit::new(Simulator::<1024>::new(code)).unwrap();
+        jit.run();
+        jit.simulator
+    });
+}
@@ -1,10 +1,13 @@
-mod codegen;
-mod parser;
+pub(crate) mod codegen;
+pub mod formatter;
+pub mod listing;
+pub(crate) mod parser;
 
-use crate::ast::BorrowedInstruction;
+use crate::ast::{BorrowedInstruction, Instruction};
+use crate::object::{DebugInfo, LineEntry, Symbol};
 use chumsky::prelude::*;
 use codegen::{AssembledCode, gen_code};
-use parser::mk_parser;
+use parser::{mk_parser, mk_spanned_parser};
 type ParseResult<'a> = Vec<BorrowedInstruction<'a>>;
 
 /// Remove Comments from the source assembly code.
@@ -50,8 +53,24 @@ fn handle_parse_errors<'a>(src_asm: &str, errors: Vec<chumsky::error::Simple<'a,
     )
 }
 
+/// The 0-based line on which each spanned instruction starts.
+///
+/// Spans of labels include surrounding whitespace, so each is placed at its first character.
+fn start_lines(src: &str, spans: impl IntoIterator<Item = SimpleSpan>) -> Vec<usize> {
+    let line_starts = std::iter::once(0)
+        .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    spans
+        .into_iter()
+        .map(|span| {
+            let start = span.start + src[span.start..].len() - src[span.start..].trim_start().len();
+            line_starts.partition_point(|&line_start| line_start <= start) - 1
+        })
+        .collect()
+}
+
 /// Invoke the parser and generate the assembled code from the provided assembly source code.
-pub fn parse_and_gen(src_asm: &str) -> Result<(ParseResult, AssembledCode), String> {
+pub fn parse_and_gen(src_asm: &str) -> Result<(ParseResult<'_>, AssembledCode), String> {
     let parse_result = mk_parser().parse(src_asm);
 
     if parse_result.has_output() {
@@ -63,3 +82,46 @@ pub fn parse_and_gen(src_asm: &str) -> Result<(ParseResult, AssembledCode), Stri
         Err(handle_parse_errors(src_asm, errors))
     }
 }
+
+/// Like [`parse_and_gen`], but also builds the debug section for the object file.
+///
+/// Line numbers in the debug section refer to `src_asm`, which may contain comments.
+pub fn parse_and_gen_with_debug<'a>(
+    src_asm: &'a str,
+    file_name: &str,
+) -> Result<(ParseResult<'a>, AssembledCode, DebugInfo), String> {
+    let parse_result = mk_spanned_parser().parse(src_asm);
+    if parse_result.has_errors() {
+        return Err(handle_parse_errors(src_asm, parse_result.into_errors()));
+    }
+    let (ast, spans): (Vec<_>, Vec<_>) = parse_result.into_output().unwrap().into_iter().unzip();
+    let assembled_code = gen_code(&ast)?;
+
+    let start_lines = start_lines(src_asm, spans);
+    let source_lines = src_asm.lines().collect::<Vec<_>>();
+
+    let mut symbols = Vec::new();
+    let mut lines = Vec::new();
+    for ((instruction, start_line), &(start, end)) in ast
+        .iter()
+        .zip(start_lines)
+        .zip(assembled_code.line_ranges.iter())
+    {
+        if let Instruction::Label(name) = instruction {
+            symbols.push(Symbol {
+                name: name.to_string(),
+                addr: start as i64,
+            });
+        } else if start < end {
+            lines.push(LineEntry {
+                start: start as i64,
+                end: end as i64,
+                line: start_line + 1,
+                text: source_lines[start_line].trim().to_string(),
+            });
+        }
+    }
+
+    let debug_info = DebugInfo::new(file_name.to_string(), symbols, lines);
+    Ok((ast, assembled_code, debug_info))
+}
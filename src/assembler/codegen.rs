@@ -39,26 +39,15 @@ pub struct AssembledCode {
 }
 
 pub fn gen_code<'a>(ast: &Vec<BorrowedInstruction<'a>>) -> Result<AssembledCode, String> {
+    if ast.is_empty() {
+        return Ok(AssembledCode {
+            bytes: Vec::new(),
+            line_ranges: Vec::new(),
+        });
+    }
     let mut instruction_lengths: Vec<_> = ast
         .iter()
-        .map(|line| {
-            match line {
-                Instruction::Label(_) => 0,        // Labels do not generate code
-                Instruction::Directive(_, _) => 0, // Directives do not generate code
-                Instruction::Halt => 1,
-                Instruction::Nop => 1,
-                Instruction::Irmov(_, _) => 10,
-                Instruction::Rmmov(_, _, _) => 10,
-                Instruction::Mrmov(_, _, _) => 10,
-                Instruction::Binop(_, _, _) => 2,
-                Instruction::Jmp(_, _) => 9,
-                Instruction::Cmov(_, _, _) => 2,
-                Instruction::Call(_) => 9,
-                Instruction::Ret => 1,
-                Instruction::Push(_) => 2,
-                Instruction::Pop(_) => 2,
-            }
-        })
+        .map(|line| line.encoded_len() as i64) // Labels and directives start at 0
         .collect();
 
     let mut instruction_starts = vec![0; ast.len()];
@@ -67,6 +56,9 @@ pub fn gen_code<'a>(ast: &Vec<BorrowedInstruction<'a>>) -> Result<AssembledCode,
             instruction_starts[i] = instruction_starts[i - 1] + instruction_lengths[i - 1];
         }
         match ast[i] {
+            Instruction::Directive(".align", align) if align <= 0 => {
+                return Err(format!("Invalid alignment: {}", align));
+            }
             Instruction::Directive(".align", align) => {
                 let start = instruction_starts[i];
                 let padding = ((-start) % align + align) % align;
@@ -170,6 +162,10 @@ pub fn gen_code<'a>(ast: &Vec<BorrowedInstruction<'a>>) -> Result<AssembledCode,
                 output_bytes[start] = 0x0B << 4; // POP opcode
                 output_bytes[start + 1] = (*reg as u8) << 4 | 0xF;
             }
+            Instruction::Syscall => output_bytes[start] = 0x0C << 4, // SYSCALL opcode
+            Instruction::Iret => output_bytes[start] = 0x0D << 4,    // IRET opcode
+            Instruction::Ei => output_bytes[start] = 0x0E << 4,      // EI opcode
+            Instruction::Di => output_bytes[start] = 0x0E << 4 | 1,  // DI opcode
         }
     }
 
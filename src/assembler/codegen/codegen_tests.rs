@@ -414,3 +414,13 @@ fn test_code_gen_complex_program() {
     let halt_pos = assembled_code.line_ranges[5].0;
     assert_eq!(assembled_code.bytes[halt_pos], 0x00); // HALT opcode
 }
+
+#[test]
+fn test_code_gen_empty_and_bad_alignment() {
+    let assembled_code = gen_code(&vec![]).unwrap();
+    assert!(assembled_code.bytes.is_empty());
+    assert!(assembled_code.line_ranges.is_empty());
+
+    let parsed = mk_parser().parse("nop\n.align 0").into_output().unwrap();
+    assert!(gen_code(&parsed).is_err());
+}
@@ -0,0 +1,198 @@
+use super::parser::mk_spanned_parser;
+use super::{handle_parse_errors, parse_and_gen, start_lines};
+use crate::ast::{BorrowedInstruction, CondOp, ImmType, Instruction, LabOrImm};
+use chumsky::Parser;
+#[cfg(test)]
+mod formatter_tests;
+
+/// Indentation of instructions, directives and indented comments.
+const INDENT: &str = "    ";
+
+/// Width of the widest mnemonic, so that operands line up.
+const MNEMONIC_WIDTH: usize = 6;
+
+/// How immediate operands and displacements are written.
+///
+/// `.quad` data is always written as 16 hex digits and `.align` in decimal.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Radix {
+    #[default]
+    Decimal,
+    Hex,
+}
+
+/// One line of formatted output, before trailing comments are aligned.
+enum OutputLine<'a> {
+    Blank,
+    Code(String, Option<&'a str>),
+    Comment { indented: bool, text: &'a str },
+}
+
+/// Reformats Y86-64 assembly source.
+///
+/// Labels go on their own lines, instructions are indented with their operands aligned, and
+/// immediates are written in `radix`. Comments and single blank lines between groups of lines
+/// are kept, and trailing comments within a group are aligned.
+///
+/// The result always assembles to the same bytes as `src`; an error is returned if `src` does
+/// not parse.
+pub fn format_source(src: &str, radix: Radix) -> Result<String, String> {
+    let parse_result = mk_spanned_parser().parse(src);
+    if parse_result.has_errors() {
+        return Err(handle_parse_errors(src, parse_result.into_errors()));
+    }
+    let parsed = parse_result.into_output().unwrap();
+
+    let mut instructions = start_lines(src, parsed.iter().map(|(_, span)| *span))
+        .into_iter()
+        .zip(parsed.iter().map(|(instruction, _)| instruction))
+        .peekable();
+
+    let mut lines = Vec::new();
+    for (line_number, line) in src.lines().enumerate() {
+        let (code, comment) = match line.find('#') {
+            Some(i) => (&line[..i], Some(line[i..].trim_end())),
+            None => (line, None),
+        };
+        while let Some((_, instruction)) =
+            instructions.next_if(|&(start_line, _)| start_line == line_number)
+        {
+            lines.push(OutputLine::Code(
+                format_instruction(instruction, radix),
+                None,
+            ));
+        }
+        match comment {
+            // A comment after code belongs to the last instruction, even one started above
+            Some(text) if !code.trim().is_empty() => {
+                if let Some(OutputLine::Code(_, trailing)) = lines.last_mut() {
+                    *trailing = Some(text);
+                }
+            }
+            Some(text) => lines.push(OutputLine::Comment {
+                indented: !code.is_empty(),
+                text,
+            }),
+            None if code.trim().is_empty() => lines.push(OutputLine::Blank),
+            None => {}
+        }
+    }
+
+    let formatted = render(&lines);
+    if assembled(&formatted) != assembled(src) {
+        return Err("Formatting changed the assembled code".to_string());
+    }
+    Ok(formatted)
+}
+
+/// The bytes `src` assembles to, or the reason it does not.
+fn assembled(src: &str) -> Result<Vec<u8>, String> {
+    parse_and_gen(src).map(|(_, code)| code.bytes)
+}
+
+/// Joins the output lines, collapsing runs of blank lines and aligning trailing comments within
+/// each group of lines between blanks.
+fn render(lines: &[OutputLine]) -> String {
+    let mut out = String::new();
+    let groups = lines
+        .split(|line| matches!(line, OutputLine::Blank))
+        .filter(|group| !group.is_empty());
+    for (i, group) in groups.enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let comment_column = group
+            .iter()
+            .filter_map(|line| match line {
+                OutputLine::Code(code, Some(_)) => Some(code.len()),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+            + 2;
+        for line in group {
+            match line {
+                OutputLine::Code(code, Some(comment)) => {
+                    out.push_str(&format!("{:comment_column$}{}\n", code, comment))
+                }
+                OutputLine::Code(code, None) => out.push_str(&format!("{}\n", code)),
+                OutputLine::Comment { indented, text } => out.push_str(&format!(
+                    "{}{}\n",
+                    if *indented { INDENT } else { "" },
+                    text
+                )),
+                OutputLine::Blank => {}
+            }
+        }
+    }
+    out
+}
+
+/// Writes one instruction in canonical form, without a trailing newline.
+fn format_instruction(instruction: &BorrowedInstruction, radix: Radix) -> String {
+    let imm = |value: ImmType| format_immediate(value, radix);
+    let lab_or_imm = |value: &LabOrImm<&str>| match value {
+        LabOrImm::Labelled(label) => label.to_string(),
+        LabOrImm::Immediate(value) => format!("${}", imm(*value)),
+    };
+    let displaced = |displacement: ImmType, reg| match displacement {
+        0 => format!("(%{})", reg),
+        _ => format!("{}(%{})", imm(displacement), reg),
+    };
+    let (mnemonic, operands) = match instruction {
+        Instruction::Label(label) => return format!("{}:", label),
+        Instruction::Directive(directive, value) if *directive == ".quad" => {
+            (directive.to_string(), format!("0x{:016x}", value))
+        }
+        Instruction::Directive(directive, value) => (
+            directive.to_string(),
+            format_immediate(*value, Radix::Decimal),
+        ),
+        Instruction::Halt => ("halt".to_string(), String::new()),
+        Instruction::Nop => ("nop".to_string(), String::new()),
+        Instruction::Irmov(value, reg) => (
+            "irmovq".to_string(),
+            format!("{}, %{}", lab_or_imm(value), reg),
+        ),
+        Instruction::Rmmov(src, displacement, base) => (
+            "rmmovq".to_string(),
+            format!("%{}, {}", src, displaced(*displacement, base)),
+        ),
+        Instruction::Mrmov(displacement, base, dest) => (
+            "mrmovq".to_string(),
+            format!("{}, %{}", displaced(*displacement, base), dest),
+        ),
+        Instruction::Binop(op, src, dest) => (format!("{}q", op), format!("%{}, %{}", src, dest)),
+        Instruction::Jmp(CondOp::Uncon, target) => ("jmp".to_string(), lab_or_imm(target)),
+        Instruction::Jmp(cond, target) => (format!("j{}", cond), lab_or_imm(target)),
+        Instruction::Cmov(CondOp::Uncon, src, dest) => {
+            ("rrmovq".to_string(), format!("%{}, %{}", src, dest))
+        }
+        Instruction::Cmov(cond, src, dest) => {
+            (format!("cmov{}", cond), format!("%{}, %{}", src, dest))
+        }
+        Instruction::Call(target) => ("call".to_string(), lab_or_imm(target)),
+        Instruction::Ret => ("ret".to_string(), String::new()),
+        Instruction::Push(reg) => ("pushq".to_string(), format!("%{}", reg)),
+        Instruction::Pop(reg) => ("popq".to_string(), format!("%{}", reg)),
+        Instruction::Syscall => ("syscall".to_string(), String::new()),
+        Instruction::Iret => ("iret".to_string(), String::new()),
+        Instruction::Ei => ("ei".to_string(), String::new()),
+        Instruction::Di => ("di".to_string(), String::new()),
+    };
+    if operands.is_empty() {
+        format!("{}{}", INDENT, mnemonic)
+    } else {
+        format!("{}{:MNEMONIC_WIDTH$} {}", INDENT, mnemonic, operands)
+    }
+}
+
+fn format_immediate(value: ImmType, radix: Radix) -> String {
+    match (value, radix) {
+        // Its magnitude does not fit, but all 64 hex bits parse back to the same value
+        (ImmType::MIN, _) => format!("0x{:x}", value),
+        (_, Radix::Decimal) => value.to_string(),
+        (0.., Radix::Hex) => format!("0x{:x}", value),
+        (_, Radix::Hex) => format!("-0x{:x}", -value),
+    }
+}
@@ -0,0 +1,90 @@
+use super::*;
+
+const EXAMPLES: [&str; 5] = [
+    include_str!("../../../examples/add_numbers.ys"),
+    include_str!("../../../examples/bubble_sort.ys"),
+    include_str!("../../../examples/test_instructions.ys"),
+    include_str!("../../../examples/test_jump.ys"),
+    include_str!("../../../examples/test_sp_edge_cases.ys"),
+];
+
+#[test]
+fn test_format_layout() {
+    let src = "
+# Header comment
+
+
+start: irmovq $0x10,%rsp   # stack
+  rmmovq %rax,0(%rsp)
+mrmovq -8( %rsp ) , %rbx # load
+    a: b:
+  cmovle %rax,%rbx
+     # indented comment
+  rrmovq %rax, %rcx
+jne   start
+
+
+.align 8
+.quad -1
+";
+    let expected = "\
+# Header comment
+
+start:
+    irmovq $16, %rsp       # stack
+    rmmovq %rax, (%rsp)
+    mrmovq -8(%rsp), %rbx  # load
+a:
+b:
+    cmovle %rax, %rbx
+    # indented comment
+    rrmovq %rax, %rcx
+    jne    start
+
+    .align 8
+    .quad  0xffffffffffffffff
+";
+    assert_eq!(format_source(src, Radix::Decimal).unwrap(), expected);
+    assert_eq!(format_source(expected, Radix::Decimal).unwrap(), expected);
+}
+
+#[test]
+fn test_format_radix() {
+    let src = "irmovq $255, %rax\nmrmovq -16(%rax), %rbx\nirmovq $0x8000000000000000, %rcx\n";
+    assert_eq!(
+        format_source(src, Radix::Hex).unwrap(),
+        "    irmovq $0xff, %rax\n    mrmovq -0x10(%rax), %rbx\n    irmovq $0x8000000000000000, %rcx\n"
+    );
+    assert_eq!(
+        format_source(src, Radix::Decimal).unwrap(),
+        "    irmovq $255, %rax\n    mrmovq -16(%rax), %rbx\n    irmovq $0x8000000000000000, %rcx\n"
+    );
+}
+
+#[test]
+fn test_examples_are_idempotent_and_assemble_identically() {
+    for src in EXAMPLES {
+        for radix in [Radix::Decimal, Radix::Hex] {
+            let formatted = format_source(src, radix).unwrap();
+            assert_eq!(format_source(&formatted, radix).unwrap(), formatted);
+            assert_eq!(assembled(&formatted), assembled(src));
+            // Nothing but whitespace and number spelling changes, so every comment survives
+            assert_eq!(formatted.matches('#').count(), src.matches('#').count());
+        }
+    }
+}
+
+#[test]
+fn test_format_errors() {
+    let error = format_source("irmovq $1, %rzx\n", Radix::Decimal).unwrap_err();
+    assert!(error.starts_with("Parsing Error"), "{}", error);
+    // Programs with undefined labels still format
+    assert_eq!(
+        format_source("jmp  nowhere", Radix::Decimal).unwrap(),
+        "    jmp    nowhere\n"
+    );
+    assert_eq!(
+        format_source("\n\n# only\n\n", Radix::Decimal).unwrap(),
+        "# only\n"
+    );
+}
@@ -0,0 +1,147 @@
+use super::codegen::gen_code;
+use super::parser::mk_spanned_parser;
+use super::{handle_parse_errors, start_lines};
+use crate::ast::{Instruction, LabOrImm};
+use chumsky::Parser;
+use std::collections::HashMap;
+#[cfg(test)]
+mod listing_tests;
+
+/// Bytes shown on each row; longer runs, such as `.align` padding, continue on following rows.
+const BYTES_PER_ROW: usize = 10;
+
+/// Width of the bytes column: two hex digits and a separating space per byte.
+const BYTES_WIDTH: usize = BYTES_PER_ROW * 3 - 1;
+
+/// A label with where it is defined and the 1-based lines which use it.
+struct SymbolEntry<'a> {
+    name: &'a str,
+    addr: usize,
+    line: usize,
+    references: Vec<usize>,
+}
+
+/// Produces an assembler listing of `src`.
+///
+/// Every source line is shown, comments included, next to its line number and the address
+/// and bytes it assembled to. The listing ends with the symbol table, sorted by address and
+/// then by name, giving the line defining each label and the lines referring to it.
+pub fn make_listing(src: &str) -> Result<String, String> {
+    let parse_result = mk_spanned_parser().parse(src);
+    if parse_result.has_errors() {
+        return Err(handle_parse_errors(src, parse_result.into_errors()));
+    }
+    let (ast, spans): (Vec<_>, Vec<_>) = parse_result.into_output().unwrap().into_iter().unzip();
+    let assembled_code = gen_code(&ast)?;
+
+    let source_lines = src.lines().collect::<Vec<_>>();
+    // The address of the first instruction on each line, and the bytes of all of them
+    let mut rows: Vec<Option<(usize, Vec<u8>)>> = vec![None; source_lines.len()];
+    let mut symbols = Vec::new();
+    let mut references: HashMap<&str, Vec<usize>> = HashMap::new();
+    for ((instruction, line), &(start, end)) in ast
+        .iter()
+        .zip(start_lines(src, spans))
+        .zip(assembled_code.line_ranges.iter())
+    {
+        let (_, bytes) = rows[line].get_or_insert((start, Vec::new()));
+        bytes.extend_from_slice(&assembled_code.bytes[start..end]);
+        match instruction {
+            Instruction::Label(name) => symbols.push(SymbolEntry {
+                name,
+                addr: start,
+                line: line + 1,
+                references: Vec::new(),
+            }),
+            Instruction::Irmov(LabOrImm::Labelled(name), _)
+            | Instruction::Jmp(_, LabOrImm::Labelled(name))
+            | Instruction::Call(LabOrImm::Labelled(name)) => {
+                references.entry(name).or_default().push(line + 1)
+            }
+            _ => {}
+        }
+    }
+    for symbol in &mut symbols {
+        symbol.references = references.remove(symbol.name).unwrap_or_default();
+    }
+
+    let mut out = format!(
+        "{:6}  {:BYTES_WIDTH$}  {:>4}  Source\n",
+        "Addr", "Bytes", "Line"
+    );
+    for (i, (text, row)) in source_lines.iter().zip(&rows).enumerate() {
+        let Some((addr, bytes)) = row else {
+            push_row(
+                &mut out,
+                format!("{:6}  {:BYTES_WIDTH$}  {:4}  {}", "", "", i + 1, text),
+            );
+            continue;
+        };
+        let mut chunks = bytes.chunks(BYTES_PER_ROW);
+        let first = chunks.next().unwrap_or_default();
+        push_row(
+            &mut out,
+            format!(
+                "{:#06x}  {:BYTES_WIDTH$}  {:4}  {}",
+                addr,
+                hex_bytes(first),
+                i + 1,
+                text
+            ),
+        );
+        for (j, chunk) in chunks.enumerate() {
+            let addr = addr + (j + 1) * BYTES_PER_ROW;
+            push_row(&mut out, format!("{:#06x}  {}", addr, hex_bytes(chunk)));
+        }
+    }
+
+    symbols.sort_by_key(|symbol| symbol.addr);
+    out.push_str("\nSymbols by address:\n");
+    out.push_str(&symbol_table(&symbols));
+    symbols.sort_by_key(|symbol| symbol.name);
+    out.push_str("\nSymbols by name:\n");
+    out.push_str(&symbol_table(&symbols));
+    Ok(out)
+}
+
+/// Appends a row without the padding left by empty trailing columns.
+fn push_row(out: &mut String, row: String) {
+    out.push_str(row.trim_end());
+    out.push('\n');
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn symbol_table(symbols: &[SymbolEntry]) -> String {
+    let name_width = symbols
+        .iter()
+        .map(|symbol| symbol.name.len())
+        .max()
+        .unwrap_or(0);
+    symbols
+        .iter()
+        .map(|symbol| {
+            let references = match symbol.references.as_slice() {
+                [] => "unreferenced".to_string(),
+                lines => format!(
+                    "referenced on lines {}",
+                    lines
+                        .iter()
+                        .map(|line| line.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+            format!(
+                "{:#06x}  {:name_width$}  defined on line {:<4}  {}\n",
+                symbol.addr, symbol.name, symbol.line, references
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,67 @@
+use super::*;
+
+const SRC: &str = "# Count down
+main: irmovq $2, %rax   # counter
+    irmovq $1, %rbx
+
+loop:
+    subq %rbx, %rax
+    jne loop
+    call done
+    halt
+    .align 64
+done: ret
+";
+
+#[test]
+fn test_listing_rows() {
+    let listing = make_listing(SRC).unwrap();
+    let rows = listing.lines().collect::<Vec<_>>();
+    assert_eq!(rows[0], format!("Addr    {:29}  Line  Source", "Bytes"));
+    assert_eq!(rows[1], format!("{:>43}  # Count down", 1));
+    assert_eq!(
+        rows[2],
+        "0x0000  30 f0 02 00 00 00 00 00 00 00     2  main: irmovq $2, %rax   # counter"
+    );
+    assert_eq!(rows[4], format!("{:>43}", 4));
+    assert_eq!(rows[5], format!("0x0014  {:29}     5  loop:", ""));
+    // The padding of `.align` continues on a row of its own
+    assert_eq!(
+        rows[10],
+        "0x0029  00 00 00 00 00 00 00 00 00 00    10      .align 64"
+    );
+    assert_eq!(rows[11], "0x0033  00 00 00 00 00 00 00 00 00 00");
+    assert_eq!(rows[12], "0x003d  00 00 00");
+    assert_eq!(rows[13], format!("0x0040  {:29}    11  done: ret", "90"));
+}
+
+#[test]
+fn test_listing_symbol_tables() {
+    let listing = make_listing(SRC).unwrap();
+    let (_, tables) = listing.split_once("\nSymbols by address:\n").unwrap();
+    let (by_address, by_name) = tables.split_once("\nSymbols by name:\n").unwrap();
+    assert_eq!(
+        by_address,
+        "0x0000  main  defined on line 2     unreferenced\n\
+         0x0014  loop  defined on line 5     referenced on lines 7\n\
+         0x0040  done  defined on line 11    referenced on lines 8\n"
+    );
+    assert_eq!(
+        by_name.lines().map(|row| &row[8..12]).collect::<Vec<_>>(),
+        ["done", "loop", "main"]
+    );
+}
+
+#[test]
+fn test_listing_errors() {
+    assert!(
+        make_listing("jmp nowhere\n")
+            .unwrap_err()
+            .contains("nowhere")
+    );
+    assert!(
+        make_listing("irmovq $1, %rzx\n")
+            .unwrap_err()
+            .starts_with("Parsing Error")
+    );
+}
@@ -26,17 +26,34 @@ fn reg_parser<'a>() -> Boxed<'a, 'a, &'a str, Register, extra::Err<Simple<'a, ch
 
 /// Handles parsing positive and negative decimals and hexadecimals
 fn imm_parser<'a>() -> Boxed<'a, 'a, &'a str, ImmType, extra::Err<Simple<'a, char>>> {
-    let pos_imm = (choice((
-        just("0x")
-            .ignore_then(text::digits(16).to_slice())
-            .map(|s: &str| ImmType::from_str_radix(s, 16).unwrap()),
-        text::digits(16)
-            .to_slice()
-            .map(|s: &str| s.parse::<ImmType>().unwrap()),
-    )))
-    .padded();
+    // Hex immediates may use all 64 bits, so 0xFFFFFFFFFFFFFFFF is -1
+    let hex = just("0x")
+        .ignore_then(text::digits(16).to_slice())
+        .try_map(|s: &str, span| {
+            u64::from_str_radix(s, 16)
+                .map(|imm| imm as ImmType)
+                .map_err(|_| Simple::new(None, span))
+        });
+    // Decimal magnitudes go through u64 too, so that -9223372036854775808 can be written
+    let decimal = |max: u64| {
+        text::digits(10).to_slice().try_map(move |s: &str, span| {
+            s.parse::<u64>()
+                .ok()
+                .filter(|&imm| imm <= max)
+                .map(|imm| imm as ImmType)
+                .ok_or_else(|| Simple::new(None, span))
+        })
+    };
+    let pos_imm = choice((hex, decimal(ImmType::MAX as u64))).padded();
+    let neg_magnitude = choice((hex, decimal(ImmType::MIN.unsigned_abs()))).padded();
 
-    choice((just('-').then(pos_imm).map(|(_, imm)| -imm), pos_imm)).boxed()
+    choice((
+        just('-')
+            .ignore_then(neg_magnitude)
+            .map(ImmType::wrapping_neg),
+        pos_imm,
+    ))
+    .boxed()
 }
 
 /// Parses either D(reg) or reg
@@ -59,6 +76,42 @@ fn displaced_reg_parser<'a>()
 /// Constructs a parser for the Y86-64 assembly language
 pub fn mk_parser<'a>()
 -> impl Parser<'a, &'a str, Vec<BorrowedInstruction<'a>>, extra::Err<Simple<'a, char>>> {
+    let comments = comment_parser();
+    comments.clone().ignore_then(
+        line_parser()
+            .then_ignore(comments)
+            .repeated()
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Like [`mk_parser`], but also yields the source span of each parsed line.
+pub fn mk_spanned_parser<'a>()
+-> impl Parser<'a, &'a str, Vec<(BorrowedInstruction<'a>, SimpleSpan)>, extra::Err<Simple<'a, char>>>
+{
+    let comments = comment_parser();
+    comments.clone().ignore_then(
+        line_parser()
+            .map_with(|instr, e| (instr, e.span()))
+            .then_ignore(comments)
+            .repeated()
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Skips whitespace and `#` comments running to the end of a line
+fn comment_parser<'a>() -> Boxed<'a, 'a, &'a str, (), extra::Err<Simple<'a, char>>> {
+    just('#')
+        .then(none_of('\n').repeated())
+        .padded()
+        .repeated()
+        .padded()
+        .boxed()
+}
+
+/// Parses a single label, directive or instruction
+fn line_parser<'a>() -> Boxed<'a, 'a, &'a str, BorrowedInstruction<'a>, extra::Err<Simple<'a, char>>>
+{
     let reg = reg_parser();
 
     let dollar_imm = just('$').ignore_then(imm_parser());
@@ -149,10 +202,17 @@ pub fn mk_parser<'a>()
         .ignore_then(reg.clone())
         .map(Instruction::Pop);
 
+    let syscall = keyword("syscall").to(Instruction::Syscall);
+
+    let interrupts = choice((
+        keyword("iret").to(Instruction::Iret),
+        keyword("ei").to(Instruction::Ei),
+        keyword("di").to(Instruction::Di),
+    ));
+
     choice((
         label, directive, halt, nop, rmmov, irmov, mrmov, binop, jmp, cmov, call, ret, push, pop,
+        syscall, interrupts,
     ))
-    .padded()
-    .repeated()
-    .collect::<Vec<_>>()
+    .boxed()
 }
@@ -2,6 +2,7 @@
 use super::*;
 
 #[test]
+#[allow(clippy::extra_unused_lifetimes)]
 fn test_int_parsing<'a>() {
     let src = "42";
     let parsed = imm_parser()
@@ -201,3 +202,50 @@ ret
 ";
     assert!(mk_parser().parse(src).into_output().is_some());
 }
+
+#[test]
+fn test_parse_skips_comments() {
+    let src = "# header\nstart: # entry\n    halt # stop\n# trailer";
+    let parsed = mk_parser().parse(src).into_output().unwrap();
+    assert_eq!(parsed, vec![Instruction::Label("start"), Instruction::Halt]);
+}
+
+#[test]
+fn test_spanned_parser_spans() {
+    let src = "nop\n  halt # stop\n";
+    let parsed = mk_spanned_parser().parse(src).into_output().unwrap();
+    assert_eq!(parsed.len(), 2);
+    assert_eq!(&src[parsed[1].1.into_range()], "halt");
+}
+
+#[test]
+fn test_bad_immediates_are_errors() {
+    for src in [
+        "irmovq $1a, %rax",
+        "irmovq $99999999999999999999, %rax",
+        "irmovq $9223372036854775808, %rax",
+        "irmovq $-9223372036854775809, %rax",
+        ".quad 0x1ffffffffffffffff",
+    ] {
+        assert!(mk_parser().parse(src).has_errors(), "{}", src);
+    }
+    let parsed = imm_parser().parse("0xFFFFFFFFFFFFFFFF").into_output();
+    assert_eq!(parsed, Some(-1));
+}
+
+#[test]
+fn test_immediate_radix() {
+    // Digits without a prefix are decimal, as before; only the malformed cases above changed,
+    // from panicking to parse errors
+    for (src, imm) in [
+        ("10", 10),
+        ("-10", -10),
+        ("0x10", 16),
+        ("0x8000000000000000", i64::MIN),
+        ("-0x8000000000000000", i64::MIN),
+        ("9223372036854775807", i64::MAX),
+        ("-9223372036854775808", i64::MIN),
+    ] {
+        assert_eq!(imm_parser().parse(src).into_output(), Some(imm), "{}", src);
+    }
+}
@@ -97,7 +97,70 @@ pub enum Instruction<S> {
     Ret,
     Push(Register),
     Pop(Register),
+    /// System call, numbered by `%rax`
+    Syscall,
+    /// Return from an interrupt handler
+    Iret,
+    /// Enable interrupts
+    Ei,
+    /// Disable interrupts
+    Di,
 }
 
 pub type BorrowedInstruction<'a> = Instruction<&'a str>;
 pub type OwnedInstruction = Instruction<String>;
+
+impl<S> Instruction<S> {
+    /// Number of bytes the instruction encodes to.
+    /// Labels and directives report 0, as their size depends on their position.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Instruction::Label(_) => 0,
+            Instruction::Directive(_, _) => 0,
+            Instruction::Halt => 1,
+            Instruction::Nop => 1,
+            Instruction::Irmov(_, _) => 10,
+            Instruction::Rmmov(_, _, _) => 10,
+            Instruction::Mrmov(_, _, _) => 10,
+            Instruction::Binop(_, _, _) => 2,
+            Instruction::Jmp(_, _) => 9,
+            Instruction::Cmov(_, _, _) => 2,
+            Instruction::Call(_) => 9,
+            Instruction::Ret => 1,
+            Instruction::Push(_) => 2,
+            Instruction::Pop(_) => 2,
+            Instruction::Syscall => 1,
+            Instruction::Iret => 1,
+            Instruction::Ei => 1,
+            Instruction::Di => 1,
+        }
+    }
+
+    /// Converts the label and directive names with `f`, e.g. between owned and borrowed strings.
+    pub fn map_labels<'a, T>(&'a self, f: impl Fn(&'a S) -> T) -> Instruction<T> {
+        let map_target = |target: &'a LabOrImm<S>| match target {
+            LabOrImm::Labelled(label) => LabOrImm::Labelled(f(label)),
+            &LabOrImm::Immediate(imm) => LabOrImm::Immediate(imm),
+        };
+        match self {
+            Instruction::Label(label) => Instruction::Label(f(label)),
+            Instruction::Directive(name, imm) => Instruction::Directive(f(name), *imm),
+            Instruction::Halt => Instruction::Halt,
+            Instruction::Nop => Instruction::Nop,
+            Instruction::Irmov(source, reg) => Instruction::Irmov(map_target(source), *reg),
+            &Instruction::Rmmov(src, disp, dst) => Instruction::Rmmov(src, disp, dst),
+            &Instruction::Mrmov(disp, src, dst) => Instruction::Mrmov(disp, src, dst),
+            &Instruction::Binop(op, src, dst) => Instruction::Binop(op, src, dst),
+            Instruction::Jmp(cond, target) => Instruction::Jmp(*cond, map_target(target)),
+            &Instruction::Cmov(cond, src, dst) => Instruction::Cmov(cond, src, dst),
+            Instruction::Call(target) => Instruction::Call(map_target(target)),
+            Instruction::Ret => Instruction::Ret,
+            &Instruction::Push(reg) => Instruction::Push(reg),
+            &Instruction::Pop(reg) => Instruction::Pop(reg),
+            Instruction::Syscall => Instruction::Syscall,
+            Instruction::Iret => Instruction::Iret,
+            Instruction::Ei => Instruction::Ei,
+            Instruction::Di => Instruction::Di,
+        }
+    }
+}
@@ -100,6 +100,10 @@ impl<S: fmt::Display> fmt::Display for Instruction<S> {
             Instruction::Ret => write!(f, "ret"),
             Instruction::Push(reg) => write!(f, "push {}", reg),
             Instruction::Pop(reg) => write!(f, "pop {}", reg),
+            Instruction::Syscall => write!(f, "syscall"),
+            Instruction::Iret => write!(f, "iret"),
+            Instruction::Ei => write!(f, "ei"),
+            Instruction::Di => write!(f, "di"),
         }
     }
 }
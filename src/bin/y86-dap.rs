@@ -0,0 +1,11 @@
+use y86_seq::dap::DebugAdapter;
+use y86_seq::protocol::spawn_reader;
+
+/// Serves the Debug Adapter Protocol on stdin and stdout, for editors such as VS Code.
+fn main() {
+    let requests = spawn_reader(std::io::BufReader::new(std::io::stdin()));
+    if let Err(e) = DebugAdapter::new(std::io::stdout()).serve(requests) {
+        eprintln!("Debug adapter failed: {}", e);
+        std::process::exit(1);
+    }
+}
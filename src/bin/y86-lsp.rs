@@ -0,0 +1,10 @@
+use y86_seq::lsp::LanguageServer;
+
+/// Serves the Language Server Protocol on stdin and stdout, for editors such as VS Code.
+fn main() {
+    let mut input = std::io::stdin().lock();
+    if let Err(e) = LanguageServer::new(std::io::stdout()).serve(&mut input) {
+        eprintln!("Language server failed: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,582 @@
+use colour::{println_bold, red_ln};
+use std::cell::RefCell;
+use std::io::Read;
+use std::rc::Rc;
+use y86_seq::ast::Register;
+use y86_seq::object::load_program;
+use y86_seq::simulator::branch::{self, BranchProfiler};
+use y86_seq::simulator::cache::{self, CacheConfig, CacheHierarchy};
+use y86_seq::simulator::convention::ConventionChecker;
+use y86_seq::simulator::device::framebuffer::{self, ImageFormat};
+use y86_seq::simulator::device::{Console, Framebuffer, console};
+use y86_seq::simulator::memory_map::{self, MemoryMap, Region};
+use y86_seq::simulator::observer::TracePrinter;
+use y86_seq::simulator::sanitizer::MemorySanitizer;
+use y86_seq::simulator::simulator_guts::{LogPolicy, Simulator, Status};
+use y86_seq::simulator::syscall::HostSyscalls;
+use y86_seq::simulator::{interrupt, mmu};
+
+const MEM_SIZE: usize = 1024;
+
+const USAGE: &str = "Usage: y86 run [--trace] [--quiet] [--max-steps N] [--detect-loops] \
+                     [--jit|--jit-check] [--console] [--console-base ADDR] \
+                     [--framebuffer WxH] [--framebuffer-base ADDR] [--frame-dir DIR] [--png] \
+                     [--interrupts] [--interrupts-base ADDR] [--vector-faults] \
+                     [--mmu] [--mmu-base ADDR] [--l1i SPEC] [--l1d SPEC] [--l2 SPEC] \
+                     [--predictor SPEC] [--ras DEPTH] [--estimate-cpi] [--check-conventions] \
+                     [--sanitize] [--regions] [--stack-size N] \
+                     [--region NAME:START-END:PERMS]... [--sparse] \
+                     <file.ys|file.yo|file.yso|->";
+
+struct RunOptions {
+    path: String,
+    trace: bool,
+    quiet: bool,
+    max_steps: Option<u64>,
+    detect_loops: bool,
+    /// Run translated native code
+    jit: bool,
+    /// Run the JIT and the interpreter side by side, comparing state after every block
+    jit_check: bool,
+    /// Map a console on stdin and stdout at this address
+    console: Option<i64>,
+    /// Map a framebuffer with this width and height
+    framebuffer: Option<(usize, usize)>,
+    framebuffer_base: Option<i64>,
+    /// Directory the framebuffer's frames are written to
+    frame_dir: String,
+    frame_format: ImageFormat,
+    /// Map an interrupt controller at this address
+    interrupts: Option<i64>,
+    /// Deliver faults to the program's handlers
+    vector_faults: bool,
+    /// Map an MMU at this address
+    mmu: Option<i64>,
+    /// Simulate caches, each configured as `SIZE:WAYS:BLOCK[:POLICY][:wb|wt]`
+    l1i: Option<CacheConfig>,
+    l1d: Option<CacheConfig>,
+    l2: Option<CacheConfig>,
+    /// Profile branches with this predictor
+    predictor: Option<String>,
+    return_stack: Option<usize>,
+    /// Estimate pipeline cycles from the instruction count and mispredictions
+    estimate_cpi: bool,
+    /// Check that functions preserve the callee-saved registers and `%rsp`
+    check_conventions: bool,
+    /// Flag uninitialized reads, writes into the program and accesses below `%rsp`
+    sanitize: bool,
+    /// Check accesses against the standard memory map, with a stack of this many bytes
+    stack_size: Option<i64>,
+    /// Check accesses against these regions instead
+    regions: Vec<Region>,
+    /// Back addresses outside RAM with memory allocated a page at a time
+    sparse: bool,
+}
+
+impl RunOptions {
+    fn memory_map(&self) -> bool {
+        self.stack_size.is_some() || !self.regions.is_empty()
+    }
+
+    /// Whether any cache is configured, which simulates them all.
+    fn caches(&self) -> bool {
+        self.l1i.is_some() || self.l1d.is_some() || self.l2.is_some()
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<RunOptions, String> {
+    match args.next().as_deref() {
+        Some("run") => {}
+        Some(command) => return Err(format!("Unknown command: {}", command)),
+        None => return Err("No command provided".to_string()),
+    }
+
+    let mut options = RunOptions {
+        path: String::new(),
+        trace: false,
+        quiet: false,
+        max_steps: None,
+        detect_loops: false,
+        jit: false,
+        jit_check: false,
+        console: None,
+        framebuffer: None,
+        framebuffer_base: None,
+        frame_dir: ".".to_string(),
+        frame_format: ImageFormat::Ppm,
+        interrupts: None,
+        vector_faults: false,
+        mmu: None,
+        l1i: None,
+        l1d: None,
+        l2: None,
+        predictor: None,
+        return_stack: None,
+        estimate_cpi: false,
+        check_conventions: false,
+        sanitize: false,
+        stack_size: None,
+        regions: Vec::new(),
+        sparse: false,
+    };
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => options.trace = true,
+            "--quiet" => options.quiet = true,
+            "--detect-loops" => options.detect_loops = true,
+            "--jit" => options.jit = true,
+            "--jit-check" => options.jit_check = true,
+            "--max-steps" => {
+                let steps = args.next().ok_or("--max-steps requires a value")?;
+                let steps = steps
+                    .parse()
+                    .map_err(|_| format!("Invalid step count: {}", steps))?;
+                options.max_steps = Some(steps);
+            }
+            "--console" => options.console = Some(console::DEFAULT_BASE),
+            "--console-base" => {
+                let base = args.next().ok_or("--console-base requires a value")?;
+                options.console = Some(parse_address(&base)?);
+            }
+            "--framebuffer" => {
+                let size = args.next().ok_or("--framebuffer requires a value")?;
+                options.framebuffer = Some(
+                    size.split_once('x')
+                        .and_then(|(width, height)| {
+                            Some((width.parse().ok()?, height.parse().ok()?))
+                        })
+                        .ok_or_else(|| format!("Invalid framebuffer size: {}", size))?,
+                );
+            }
+            "--framebuffer-base" => {
+                let base = args.next().ok_or("--framebuffer-base requires a value")?;
+                options.framebuffer_base = Some(parse_address(&base)?);
+            }
+            "--frame-dir" => {
+                options.frame_dir = args.next().ok_or("--frame-dir requires a value")?
+            }
+            "--png" => options.frame_format = ImageFormat::Png,
+            "--interrupts" => options.interrupts = Some(interrupt::DEFAULT_BASE),
+            "--interrupts-base" => {
+                let base = args.next().ok_or("--interrupts-base requires a value")?;
+                options.interrupts = Some(parse_address(&base)?);
+            }
+            "--vector-faults" => options.vector_faults = true,
+            "--mmu" => options.mmu = Some(mmu::DEFAULT_BASE),
+            "--mmu-base" => {
+                let base = args.next().ok_or("--mmu-base requires a value")?;
+                options.mmu = Some(parse_address(&base)?);
+            }
+            "--l1i" | "--l1d" | "--l2" => {
+                let spec = args
+                    .next()
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                let config = Some(spec.parse()?);
+                match arg.as_str() {
+                    "--l1i" => options.l1i = config,
+                    "--l1d" => options.l1d = config,
+                    _ => options.l2 = config,
+                }
+            }
+            "--predictor" => {
+                let spec = args.next().ok_or("--predictor requires a value")?;
+                branch::parse_predictor(&spec)?;
+                options.predictor = Some(spec);
+            }
+            "--ras" => {
+                let depth = args.next().ok_or("--ras requires a value")?;
+                let depth = depth
+                    .parse()
+                    .map_err(|_| format!("Invalid return stack depth: {}", depth))?;
+                options.return_stack = Some(depth);
+            }
+            "--estimate-cpi" => options.estimate_cpi = true,
+            "--check-conventions" => options.check_conventions = true,
+            "--sanitize" => options.sanitize = true,
+            "--regions" => {
+                options.stack_size = options.stack_size.or(Some(memory_map::DEFAULT_STACK_SIZE))
+            }
+            "--stack-size" => {
+                let size = args.next().ok_or("--stack-size requires a value")?;
+                options.stack_size = Some(parse_address(&size)?);
+            }
+            "--region" => {
+                let region = args.next().ok_or("--region requires a value")?;
+                options.regions.push(region.parse()?);
+            }
+            "--sparse" => options.sparse = true,
+            "-" => path = Some(arg),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    options.path = path.ok_or("No input file provided")?;
+    if (options.jit || options.jit_check) && (options.trace || options.detect_loops) {
+        return Err("--jit cannot be combined with --trace or --detect-loops".to_string());
+    }
+    if (options.jit || options.jit_check) && options.caches() {
+        return Err("--jit cannot be combined with caches".to_string());
+    }
+    if options.predictor.is_none() && (options.return_stack.is_some() || options.estimate_cpi) {
+        return Err("--ras and --estimate-cpi require --predictor".to_string());
+    }
+    if (options.jit || options.jit_check) && options.predictor.is_some() {
+        return Err("--jit cannot be combined with --predictor".to_string());
+    }
+    if (options.jit || options.jit_check) && options.check_conventions {
+        return Err("--jit cannot be combined with --check-conventions".to_string());
+    }
+    if (options.jit || options.jit_check) && options.sanitize {
+        return Err("--jit cannot be combined with --sanitize".to_string());
+    }
+    if (options.jit || options.jit_check) && options.memory_map() {
+        return Err("--jit cannot be combined with a memory map".to_string());
+    }
+    if (options.jit || options.jit_check) && options.sparse {
+        return Err("--jit cannot be combined with --sparse".to_string());
+    }
+    if options.framebuffer.is_none() && options.framebuffer_base.is_some() {
+        return Err("--framebuffer-base requires --framebuffer".to_string());
+    }
+    if options.vector_faults && options.interrupts.is_none() {
+        return Err("--vector-faults requires --interrupts".to_string());
+    }
+    if (options.jit || options.jit_check) && (options.interrupts.is_some() || options.mmu.is_some())
+    {
+        return Err("--jit cannot be combined with --interrupts or --mmu".to_string());
+    }
+    if options.mmu.is_some() && options.caches() {
+        return Err("--l1i, --l1d and --l2 cannot be combined with --mmu".to_string());
+    }
+    if options.path == "-" && options.console.is_some() {
+        return Err("--console cannot read stdin when the program is read from it".to_string());
+    }
+    if options.jit_check && (options.console.is_some() || options.framebuffer.is_some()) {
+        return Err("--jit-check cannot be combined with devices".to_string());
+    }
+    Ok(options)
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal address.
+fn parse_address(address: &str) -> Result<i64, String> {
+    match address.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(|_| format!("Invalid address: {}", address))
+}
+
+/// Maps the requested devices: a console on stdin and stdout, a framebuffer writing numbered
+/// image files, an interrupt controller and an MMU.
+fn map_devices(simulator: &mut Simulator<MEM_SIZE>, options: &RunOptions) -> Result<(), String> {
+    if let Some(base) = options.console {
+        simulator.map_device(base, Console::new(std::io::stdin(), std::io::stdout()))?;
+    }
+    if let Some((width, height)) = options.framebuffer {
+        let dir = std::path::PathBuf::from(&options.frame_dir);
+        let extension = match options.frame_format {
+            ImageFormat::Ppm => "ppm",
+            ImageFormat::Png => "png",
+        };
+        let device =
+            Framebuffer::new(width, height, options.frame_format, move |number, frame| {
+                let path = dir.join(format!("frame_{:04}.{}", number, extension));
+                std::fs::write(&path, frame)
+                    .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+            })?;
+        let base = options
+            .framebuffer_base
+            .unwrap_or(framebuffer::DEFAULT_BASE);
+        simulator.map_device(base, device)?;
+    }
+    if let Some(base) = options.interrupts {
+        let controller = simulator.map_interrupt_controller(base)?;
+        controller
+            .borrow_mut()
+            .set_vector_faults(options.vector_faults);
+    }
+    if let Some(base) = options.mmu {
+        simulator.map_mmu(base)?;
+    }
+    Ok(())
+}
+
+fn read_input(path: &str) -> Result<Vec<u8>, String> {
+    if path == "-" {
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("Failed to read stdin: {}", e))?;
+        Ok(contents)
+    } else {
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))
+    }
+}
+
+/// Input for the `read` system call: stdin, unless the program itself was read from it.
+fn syscall_input(options: &RunOptions) -> Box<dyn Read> {
+    if options.path == "-" {
+        Box::new(ConsumedStdin)
+    } else {
+        Box::new(std::io::stdin())
+    }
+}
+
+/// Fails every read, so that a program read from stdin cannot also take input from it.
+struct ConsumedStdin;
+
+impl Read for ConsumedStdin {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("stdin held the program"))
+    }
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+fn run_jit<'a>(code: &'a [u8], options: &RunOptions) -> Result<Simulator<'a, MEM_SIZE>, String> {
+    use y86_seq::simulator::jit::{Jit, run_differential};
+    if options.jit_check {
+        return run_differential::<MEM_SIZE>(code, options.max_steps);
+    }
+    let mut simulator = Simulator::<MEM_SIZE>::new(code)
+        .with_syscalls(HostSyscalls::new(syscall_input(options), std::io::stdout()));
+    simulator.max_steps = options.max_steps;
+    map_devices(&mut simulator, options)?;
+    let mut jit = Jit::new(simulator)?;
+    jit.run();
+    Ok(jit.simulator)
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64")))]
+fn run_jit<'a>(_code: &'a [u8], _options: &RunOptions) -> Result<Simulator<'a, MEM_SIZE>, String> {
+    Err("y86 was built without the `jit` feature (x86-64 hosts only)".to_string())
+}
+
+fn print_report(simulator: &Simulator<MEM_SIZE>) {
+    println_bold!("Status: {}", simulator.state);
+    if let Some(code) = simulator.exit_code {
+        println!("Exit code: {}", code);
+    }
+    println!("Steps: {}", simulator.steps);
+    if let Some(mmu) = simulator.mmu() {
+        let mmu = mmu.borrow();
+        println!("TLB: {} hits, {} misses", mmu.tlb_hits(), mmu.tlb_misses());
+    }
+    println!("PC: {:#x}", simulator.instruction_pointer);
+    println!("CC: {:04b}", simulator.condition_code);
+
+    println!("Registers:");
+    for (i, value) in simulator.registers.iter().enumerate() {
+        let reg = Register::try_from(i as u8).unwrap();
+        println!("  {:5} = {:#018x} ({})", format!("%{}", reg), value, value);
+    }
+
+    println!("Memory (non-zero):");
+    for (addr, value) in simulator.memory.iter().enumerate() {
+        if *value != 0 {
+            println!("  {:#06x}: {:#018x} ({})", addr, value, value);
+        }
+    }
+    if let Some(sparse_memory) = simulator.sparse_memory() {
+        let pages = sparse_memory.page_count();
+        println!(
+            "Sparse memory ({} page{} touched):",
+            pages,
+            if pages == 1 { "" } else { "s" }
+        );
+        for (base, page) in sparse_memory.pages() {
+            println!("  page {:#x}:", base);
+            for (offset, value) in page.iter().enumerate() {
+                if *value != 0 {
+                    let addr = base.wrapping_add(offset as i64);
+                    println!("    {:#018x}: {:#018x} ({})", addr, value, value);
+                }
+            }
+        }
+    }
+}
+
+/// Assembles (if needed) and simulates a Y86-64 program in one step.
+///
+/// Exits with 1 on an error, 2 at the step limit, 3 on an infinite loop and 4 for convention
+/// violations or sanitizer findings. Only a program that halts without any of these chooses
+/// its own status through the `exit` system call.
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        red_ln!("{}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+
+    let name = if options.path == "-" {
+        "<stdin>"
+    } else {
+        options.path.as_str()
+    };
+    let program = read_input(&options.path)
+        .and_then(|contents| load_program(name, &contents))
+        .unwrap_or_else(|e| {
+            red_ln!("{}", e);
+            std::process::exit(1);
+        });
+
+    // Regions given on the command line override those the object file declares
+    let memory_map = if !options.regions.is_empty() {
+        Some(MemoryMap::new(options.regions.clone()))
+    } else if let Some(stack_size) = options.stack_size {
+        Some(MemoryMap::standard(
+            program.code.len(),
+            MEM_SIZE,
+            stack_size,
+        ))
+    } else if !program.regions.is_empty() {
+        Some(MemoryMap::new(program.regions.clone()))
+    } else {
+        None
+    };
+    let memory_map = memory_map.transpose().unwrap_or_else(|e| {
+        red_ln!("{}", e);
+        std::process::exit(1);
+    });
+    if (options.jit || options.jit_check) && memory_map.is_some() {
+        red_ln!("--jit cannot run a program that declares memory regions");
+        std::process::exit(1);
+    }
+
+    let mut caches = None;
+    let mut branches = None;
+    let mut conventions = None;
+    let mut sanitizer = None;
+    let simulator = if options.jit || options.jit_check {
+        run_jit(&program.code, &options).unwrap_or_else(|e| {
+            red_ln!("{}", e);
+            std::process::exit(1);
+        })
+    } else {
+        // Only the final state is reported, so there is no need to keep the execution log
+        let mut simulator = Simulator::<MEM_SIZE>::new(&program.code)
+            .with_log_policy(LogPolicy::Off)
+            .with_syscalls(HostSyscalls::new(
+                syscall_input(&options),
+                std::io::stdout(),
+            ));
+        simulator.max_steps = options.max_steps;
+        if options.detect_loops {
+            simulator = simulator.with_loop_detection();
+        }
+        if let Some(memory_map) = memory_map {
+            simulator = simulator.with_memory_map(memory_map);
+        }
+        if options.sparse {
+            simulator = simulator.with_sparse_memory();
+        }
+        map_devices(&mut simulator, &options).unwrap_or_else(|e| {
+            red_ln!("{}", e);
+            std::process::exit(1);
+        });
+        if options.trace {
+            simulator.add_observer(TracePrinter::new(
+                std::io::stdout(),
+                program.debug_info.as_ref(),
+            ));
+        }
+        if options.caches() {
+            let l1 = |config: Option<CacheConfig>| config.unwrap_or(cache::DEFAULT_L1);
+            let hierarchy = CacheHierarchy::new(l1(options.l1i), l1(options.l1d), options.l2)
+                .unwrap_or_else(|e| {
+                    red_ln!("{}", e);
+                    std::process::exit(1);
+                })
+                .with_memory_size(MEM_SIZE);
+            let hierarchy = Rc::new(RefCell::new(hierarchy));
+            simulator.add_observer(hierarchy.clone());
+            caches = Some(hierarchy);
+        }
+        if let Some(spec) = &options.predictor {
+            // Already checked while parsing the arguments
+            let mut profiler = BranchProfiler::new(branch::parse_predictor(spec).unwrap())
+                .with_return_stack(
+                    options
+                        .return_stack
+                        .unwrap_or(branch::DEFAULT_RETURN_STACK_DEPTH),
+                );
+            if options.estimate_cpi {
+                profiler = profiler.with_cpi_estimate();
+            }
+            let profiler = Rc::new(RefCell::new(profiler));
+            simulator.add_observer(profiler.clone());
+            branches = Some(profiler);
+        }
+        if options.check_conventions {
+            let checker = ConventionChecker::new(program.debug_info.as_ref())
+                .with_registers(simulator.registers);
+            let checker = Rc::new(RefCell::new(checker));
+            simulator.add_observer(checker.clone());
+            conventions = Some(checker);
+        }
+        if options.sanitize {
+            let checker =
+                MemorySanitizer::new(program.debug_info.as_ref(), program.code.len(), MEM_SIZE);
+            let checker = Rc::new(RefCell::new(checker));
+            simulator.add_observer(checker.clone());
+            sanitizer = Some(checker);
+        }
+        simulator.run();
+        simulator
+    };
+
+    if !options.quiet {
+        print_report(&simulator);
+        if let Some(caches) = &caches {
+            print!("{}", caches.borrow().report());
+        }
+        if let Some(branches) = &branches {
+            print!("{}", branches.borrow().report());
+        }
+    }
+    // Reported even with --quiet, as they decide the exit status
+    let mut violations = conventions.as_ref().map_or(0, |checker| {
+        let checker = checker.borrow();
+        if !checker.violations().is_empty() {
+            print!("{}", checker.report());
+        }
+        checker.violations().len()
+    });
+    violations += sanitizer.as_ref().map_or(0, |sanitizer| {
+        let sanitizer = sanitizer.borrow();
+        if !sanitizer.findings().is_empty() {
+            print!("{}", sanitizer.report());
+        }
+        sanitizer.findings().len()
+    });
+    match &simulator.state {
+        Status::Halted => {
+            if violations > 0 {
+                std::process::exit(4);
+            }
+            // Otherwise a program that exits through the `exit` system call chooses the exit
+            // status, modulo 256 as a shell would see it
+            if let Some(code) = simulator.exit_code {
+                std::process::exit((code & 0xff) as i32);
+            }
+        }
+        Status::StepLimitExceeded => {
+            red_ln!("Step limit of {} exceeded", simulator.steps);
+            std::process::exit(2);
+        }
+        Status::InfiniteLoop { .. } => {
+            red_ln!("{}", simulator.state);
+            std::process::exit(3);
+        }
+        _ => {
+            red_ln!("{}", simulator.state);
+            if let Some(fault) = simulator.memory_fault()
+                && let Some(region) = &fault.region
+            {
+                eprintln!("Region: {}", region);
+            }
+            std::process::exit(1);
+        }
+    }
+}
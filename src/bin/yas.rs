@@ -1,32 +1,103 @@
 use colour::{println_bold, red_ln};
-use y86_seq::assembler::{parse_and_gen, remove_comments};
+use y86_seq::assembler::listing::make_listing;
+use y86_seq::assembler::{parse_and_gen_with_debug, remove_comments};
+use y86_seq::object::ObjectFile;
+use y86_seq::simulator::memory_map::MemoryMap;
 
 /// Assembles An Input Y86-64 Assembly File into a Machine Code Object File
+///
+/// Usage: yas [-g] [-l listing] [-m map] [-r region]... [--] <input> [output]
+/// -g: append a debug section (symbols and line numbers) to the object file
+/// -l: write a listing of addresses, bytes and source lines, with a cross-referenced symbol table
+/// -m: write the symbols and line map as text, for `yis --map` and other tools
+/// -r: add a `NAME:START-END:PERMS` region to the memory map the object is run under
+/// --: treat the remaining arguments as file names, even if they start with `-`
 fn main() {
     println_bold!("Y86-64 Assembler");
-    let src_file = std::env::args().nth(1).expect("No input file provided");
-    let dest_file = match std::env::args().nth(2) {
-        Some(file) => file,
+    let mut emit_debug_info = false;
+    let mut listing_file = None;
+    let mut map_file = None;
+    let mut regions = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-g" => emit_debug_info = true,
+            "--" => positional.extend(args.by_ref()),
+            "-r" => match args.next().map(|region| region.parse()) {
+                Some(Ok(region)) => regions.push(region),
+                Some(Err(e)) => {
+                    red_ln!("{}", e);
+                    std::process::exit(1);
+                }
+                None => {
+                    red_ln!("-r requires a region");
+                    std::process::exit(1);
+                }
+            },
+            "-l" | "-m" => {
+                let Some(file) = args.next() else {
+                    red_ln!("{} requires a file name", arg);
+                    std::process::exit(1);
+                };
+                match arg.as_str() {
+                    "-l" => listing_file = Some(file),
+                    _ => map_file = Some(file),
+                }
+            }
+            _ if arg.starts_with('-') => {
+                red_ln!("Unknown option: {}", arg);
+                std::process::exit(1);
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    if let Err(e) = MemoryMap::new(regions.iter().cloned()) {
+        red_ln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let src_file = positional.first().cloned().expect("No input file provided");
+    let dest_file = match positional.get(1) {
+        Some(file) => file.clone(),
         None => {
             let default_dest = format!("{}o", src_file);
             default_dest
         }
     };
     println!("Input file: {}", src_file);
-    let src_content = std::fs::read_to_string(&src_file).expect("Failed to read input file");
-    let src_content = remove_comments(&src_content);
+    let raw_content = std::fs::read_to_string(&src_file).expect("Failed to read input file");
+    let (parse_result, assembly_result, debug_info) =
+        match parse_and_gen_with_debug(&raw_content, &src_file) {
+            Ok(res) => res,
+            Err(e) => {
+                red_ln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+    if let Some(listing_file) = &listing_file {
+        match make_listing(&raw_content) {
+            Ok(listing) => {
+                println!("Writing listing to: {}", listing_file);
+                std::fs::write(listing_file, listing).expect("Failed to write listing file");
+            }
+            Err(e) => {
+                red_ln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(map_file) = &map_file {
+        println!("Writing symbol map to: {}", map_file);
+        std::fs::write(map_file, debug_info.to_map()).expect("Failed to write map file");
+    }
+    let debug_info = emit_debug_info.then_some(debug_info);
 
     println!("=========================");
     println!("Assembly Code:");
     println!("=========================");
-    println!("{}", src_content);
-
-    let res = parse_and_gen(&src_content);
-    if let Err(e) = res {
-        red_ln!("{}", e);
-        std::process::exit(1);
-    }
-    let (parse_result, assembly_result) = res.unwrap();
+    println!("{}", remove_comments(&raw_content));
 
     println!("=========================");
     println!("Generated Code:");
@@ -52,7 +123,12 @@ fn main() {
     }
     println!();
 
-    let output_bytes = assembly_result.bytes;
+    let output_bytes = ObjectFile {
+        code: &assembly_result.bytes,
+        debug_info,
+        regions,
+    }
+    .to_bytes();
     println!("Writing output to: {}", dest_file);
 
     std::fs::write(&dest_file, &output_bytes).expect("Failed to write output file");
@@ -0,0 +1,66 @@
+use colour::red_ln;
+use std::io::Read;
+use y86_seq::assembler::formatter::{Radix, format_source};
+
+const USAGE: &str = "Usage: yasfmt [--check] [--hex] [file.ys...]";
+
+/// Formats Y86-64 assembly files in place, or stdin to stdout when no files are given
+///
+/// --check: change nothing, but list the files that are not formatted and exit with status 1
+/// --hex: write immediates and displacements in hexadecimal
+fn main() {
+    let mut check = false;
+    let mut radix = Radix::Decimal;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--hex" => radix = Radix::Hex,
+            flag if flag.starts_with('-') && flag != "-" => {
+                red_ln!("Unknown option: {}\n{}", flag, USAGE);
+                std::process::exit(2);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        paths.push("-".to_string());
+    }
+
+    let mut unformatted = false;
+    for path in &paths {
+        let src = if path == "-" {
+            let mut src = String::new();
+            std::io::stdin()
+                .read_to_string(&mut src)
+                .expect("Failed to read stdin");
+            src
+        } else {
+            std::fs::read_to_string(path).unwrap_or_else(|e| {
+                red_ln!("Failed to read {}: {}", path, e);
+                std::process::exit(2);
+            })
+        };
+        let formatted = match format_source(&src, radix) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                red_ln!("{}: {}", path, e);
+                std::process::exit(2);
+            }
+        };
+
+        if check {
+            if formatted != src {
+                println!("Would reformat: {}", path);
+                unformatted = true;
+            }
+        } else if path == "-" {
+            print!("{}", formatted);
+        } else if formatted != src {
+            std::fs::write(path, formatted).expect("Failed to write output file");
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+}
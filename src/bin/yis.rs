@@ -1,24 +1,108 @@
 use itertools::Itertools;
 use memmap2::Mmap;
-use y86_seq::simulator::simulate;
+use std::cell::RefCell;
+use std::rc::Rc;
+use y86_seq::ast::OwnedInstruction;
+use y86_seq::object::{DebugInfo, ObjectFile};
+use y86_seq::simulator::calls::CallTracer;
+use y86_seq::simulator::gdb_stub::GdbStub;
+use y86_seq::simulator::memory_map::MemoryMap;
+use y86_seq::simulator::observer::TracePrinter;
+use y86_seq::simulator::profiler::Profiler;
+use y86_seq::simulator::simulator_guts::{LogPolicy, Simulator};
+use y86_seq::simulator::syscall::HostSyscalls;
+use y86_seq::simulator::trace::{TraceReader, TraceRecord, TraceWriter};
 
-/// Memory-maps an input file and simulates the Y86-64 instructions contained within it.
-fn main() {
-    colour::println_bold!("Y86-64 Instruction Level Simulator");
-    let src_file = std::env::args().nth(1).expect("No input file provided");
+const USAGE: &str = "Usage: yis [--log full|off|last=N] [--trace-file FILE] [--gdb-port N] \
+                     [--map FILE] [--profile] [--folded FILE] [--calls] \
+                     <input.yso>\n       \
+                     yis --replay FILE [--map FILE] [<input.yso>]";
 
-    let file = std::fs::File::open(&src_file)
-        .unwrap_or_else(|_| panic!("Failed to open input file: {}", src_file));
+#[derive(Default)]
+struct Options {
+    input: Option<String>,
+    /// Log policy for the in-memory log: "full", "off" or "last=N"
+    log: Option<String>,
+    /// Stream the execution log to this file instead of keeping it in memory
+    trace_file: Option<String>,
+    /// Print a previously recorded trace instead of simulating
+    replay: Option<String>,
+    /// Wait for a debugger on this local TCP port instead of running straight through
+    gdb_port: Option<u16>,
+    /// Symbols and line map written by `yas -m`, used instead of the object's debug section
+    map: Option<String>,
+    /// Print execution counts after the run
+    profile: bool,
+    /// Write the profile's call stacks here in the folded format of flame graph tools
+    folded: Option<String>,
+    /// Print the tree of calls and returns after the run
+    calls: bool,
+}
 
-    let mmap = unsafe {
-        Mmap::map(&file).unwrap_or_else(|_| panic!("Failed to memory-map the file: {}", src_file))
-    };
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--log" => options.log = Some(value()?),
+            "--trace-file" => options.trace_file = Some(value()?),
+            "--replay" => options.replay = Some(value()?),
+            "--map" => options.map = Some(value()?),
+            "--profile" => options.profile = true,
+            "--calls" => options.calls = true,
+            "--folded" => options.folded = Some(value()?),
+            "--gdb-port" => {
+                let port = value()?;
+                options.gdb_port = Some(
+                    port.parse()
+                        .map_err(|_| format!("Invalid port: {}", port))?,
+                );
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ if options.input.is_none() => options.input = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    if options.input.is_none() && options.replay.is_none() {
+        return Err("No input file provided".to_string());
+    }
+    if options.log.is_some() && options.trace_file.is_some() {
+        return Err("--log and --trace-file cannot be combined".to_string());
+    }
+    if options.replay.is_some() && options.gdb_port.is_some() {
+        return Err("--replay and --gdb-port cannot be combined".to_string());
+    }
+    if options.replay.is_some() && (options.profile || options.folded.is_some() || options.calls) {
+        return Err("--replay cannot be combined with --profile, --folded or --calls".to_string());
+    }
+    Ok(options)
+}
 
-    let final_state = simulate::<1024>(&mmap);
-    let diassembly_width = final_state
-        .disassembly
-        .iter()
-        .map(|(_, line)| format!("{}", line).len())
+fn parse_log_policy(policy: &str) -> Result<LogPolicy<'static>, String> {
+    match policy {
+        "full" => Ok(LogPolicy::Full),
+        "off" => Ok(LogPolicy::Off),
+        _ => policy
+            .strip_prefix("last=")
+            .and_then(|n| n.parse().ok())
+            .map(LogPolicy::LastN)
+            .ok_or_else(|| format!("Invalid log policy: {}", policy)),
+    }
+}
+
+/// Prints each retired instruction with the changes it made.
+///
+/// `records` is called twice, to size the disassembly column and then to print.
+fn print_simulation<I: Iterator<Item = TraceRecord>>(
+    records: impl Fn() -> I,
+    debug_info: Option<&DebugInfo>,
+) {
+    let symbolize = |instruction: &OwnedInstruction| match debug_info {
+        Some(debug_info) => debug_info.symbolize(instruction),
+        None => instruction.clone(),
+    };
+    let diassembly_width = records()
+        .map(|record| format!("{}", symbolize(&record.instruction)).len())
         .max()
         .unwrap_or(0)
         + 2; // Add padding
@@ -26,18 +110,21 @@ fn main() {
     println!("=========================");
     println!("Simulation:");
     println!("=========================");
-    let disassembly = final_state.disassembly.iter();
-    let mut log = final_state.log.iter();
-    for (id, (addr, instruction)) in disassembly.enumerate() {
-        let changes = log
-            .take_while_ref(|(log_id, _)| *log_id == id)
-            .map(|(_, change)| change)
-            .collect::<Vec<_>>();
+    for TraceRecord {
+        ip: addr,
+        instruction,
+        changes,
+        ..
+    } in records()
+    {
+        if let Some(label) = debug_info.and_then(|debug_info| debug_info.symbol_at(addr)) {
+            println!("{}:", label);
+        }
 
         println!(
             "{:04x} {:diassembly_width$} | {}",
             addr,
-            format!("{}", instruction),
+            format!("{}", symbolize(&instruction)),
             changes
                 .first()
                 .map(|change| change.to_string())
@@ -49,3 +136,164 @@ fn main() {
         }
     }
 }
+
+/// The instructions still held in the simulator's in-memory log.
+fn logged_records<'s>(simulator: &'s Simulator<1024>) -> impl Iterator<Item = TraceRecord> + 's {
+    let mut log = simulator.log.iter();
+    simulator
+        .disassembly
+        .iter()
+        .enumerate()
+        .map(move |(i, (addr, instruction))| {
+            let id = simulator.log_base + i;
+            let changes = log
+                .take_while_ref(|(log_id, _)| *log_id == id)
+                .map(|(_, change)| change.clone())
+                .collect();
+            TraceRecord {
+                step: id as u64,
+                ip: *addr,
+                instruction: instruction.clone(),
+                changes,
+            }
+        })
+}
+
+/// The records of a binary trace file, exiting with an error if it cannot be read.
+fn trace_file_records(path: &str) -> impl Iterator<Item = TraceRecord> {
+    let reader = TraceReader::open(path).unwrap_or_else(|e| {
+        colour::red_ln!("Failed to open trace file {}: {}", path, e);
+        std::process::exit(1);
+    });
+    reader.map(move |record| {
+        record.unwrap_or_else(|e| {
+            colour::red_ln!("Failed to read trace file {}: {}", path, e);
+            std::process::exit(1);
+        })
+    })
+}
+
+/// Lets a GDB-compatible debugger drive the simulator over `127.0.0.1:port`.
+fn debug_with_gdb<'a>(simulator: Simulator<'a, 1024>, port: u16) -> Simulator<'a, 1024> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+        .unwrap_or_else(|e| panic!("Failed to listen on port {}: {}", port, e));
+    println!("Waiting for debugger on 127.0.0.1:{}", port);
+    let (stream, peer) = listener
+        .accept()
+        .unwrap_or_else(|e| panic!("Failed to accept debugger: {}", e));
+    println!("Debugger connected from {}", peer);
+
+    let mut stub = GdbStub::new(simulator);
+    if let Err(e) = stub.serve(stream) {
+        colour::red_ln!("Debugger connection failed: {}", e);
+    }
+    stub.into_simulator()
+}
+
+/// Memory-maps an input file and simulates the Y86-64 instructions contained within it.
+///
+/// If the object file has a debug section, or a symbol map is given with `--map`, each executed
+/// instruction is traced back to its source line and jump/call targets are shown by label.
+/// If it declares memory regions, every access is checked against them.
+fn main() {
+    colour::println_bold!("Y86-64 Instruction Level Simulator");
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        colour::red_ln!("{}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+
+    let mmap = options.input.as_ref().map(|src_file| {
+        let file = std::fs::File::open(src_file)
+            .unwrap_or_else(|_| panic!("Failed to open input file: {}", src_file));
+        unsafe {
+            Mmap::map(&file)
+                .unwrap_or_else(|_| panic!("Failed to memory-map the file: {}", src_file))
+        }
+    });
+    let object = mmap.as_ref().map(|mmap| {
+        ObjectFile::parse(mmap).unwrap_or_else(|e| {
+            colour::red_ln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let map = options.map.as_ref().map(|path| {
+        std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read map file {}: {}", path, e))
+            .and_then(|map| DebugInfo::parse_map(&map))
+            .unwrap_or_else(|e| {
+                colour::red_ln!("{}", e);
+                std::process::exit(1);
+            })
+    });
+    let debug_info = map.as_ref().or(object
+        .as_ref()
+        .and_then(|object| object.debug_info.as_ref()));
+
+    if let Some(replay) = &options.replay {
+        print_simulation(|| trace_file_records(replay), debug_info);
+        return;
+    }
+    let object = object.as_ref().unwrap();
+
+    let log_policy = match (&options.trace_file, &options.log) {
+        (Some(path), _) => TraceWriter::create(path)
+            .map(LogPolicy::Stream)
+            .map_err(|e| format!("Failed to create trace file {}: {}", path, e)),
+        (None, Some(policy)) => parse_log_policy(policy),
+        (None, None) => Ok(LogPolicy::Full),
+    };
+    let log_policy = log_policy.unwrap_or_else(|e| {
+        colour::red_ln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let mut final_state = Simulator::<1024>::new(object.code)
+        .with_log_policy(log_policy)
+        .with_syscalls(HostSyscalls::new(std::io::stdin(), std::io::stdout()));
+    if !object.regions.is_empty() {
+        let memory_map = MemoryMap::new(object.regions.iter().cloned()).unwrap_or_else(|e| {
+            colour::red_ln!("{}", e);
+            std::process::exit(1);
+        });
+        final_state = final_state.with_memory_map(memory_map);
+    }
+    final_state.add_observer(TracePrinter::new(std::io::stdout(), debug_info));
+    let profiler = (options.profile || options.folded.is_some()).then(|| {
+        let profiler = Rc::new(RefCell::new(Profiler::new(debug_info)));
+        final_state.add_observer(profiler.clone());
+        profiler
+    });
+    let calls = options.calls.then(|| {
+        let calls = Rc::new(RefCell::new(CallTracer::new(debug_info)));
+        final_state.add_observer(calls.clone());
+        calls
+    });
+    match options.gdb_port {
+        Some(port) => final_state = debug_with_gdb(final_state, port),
+        None => final_state.run(),
+    }
+
+    match &options.trace_file {
+        Some(path) => {
+            drop(final_state); // Flush the trace before reading it back
+            print_simulation(|| trace_file_records(path), debug_info);
+        }
+        None => print_simulation(|| logged_records(&final_state), debug_info),
+    }
+
+    if let Some(calls) = &calls {
+        println!("Calls:");
+        print!("{}", calls.borrow().tree());
+    }
+    if let Some(profiler) = &profiler {
+        let profiler = profiler.borrow();
+        if options.profile {
+            print!("{}", profiler.report());
+        }
+        if let Some(path) = &options.folded {
+            std::fs::write(path, profiler.folded())
+                .unwrap_or_else(|e| panic!("Failed to write folded stacks {}: {}", path, e));
+        }
+    }
+}
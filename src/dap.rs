@@ -0,0 +1,557 @@
+use crate::assembler::parse_and_gen_with_debug;
+use crate::ast::{Instruction, Register};
+use crate::object::DebugInfo;
+use crate::protocol::write_message;
+use crate::simulator::gdb_stub::quad_index;
+use crate::simulator::observer::Breakpoints;
+use crate::simulator::simulator_guts::{LogPolicy, Simulator, Status, decode};
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+#[cfg(test)]
+mod dap_tests;
+
+const MEM_SIZE: usize = 1024;
+/// Instructions executed between checks for incoming requests such as `pause`
+const STEPS_PER_POLL: u64 = 10_000;
+/// Most stack slots shown in the variables view
+const STACK_SLOTS: usize = 64;
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const CONDITION_CODES_REFERENCE: i64 = 2;
+const STACK_REFERENCE: i64 = 3;
+
+/// How execution proceeds until the next stop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Run {
+    Stopped,
+    Continue,
+    /// Stepping over a call: stop on returning to `return_to` with the stack at `sp` or above
+    StepOver {
+        return_to: i64,
+        sp: i64,
+    },
+    /// Stop after a `ret` leaves the stack above `sp`
+    StepOut {
+        sp: i64,
+    },
+}
+
+/// A program launched from a `.ys` file.
+struct Session {
+    path: String,
+    debug_info: DebugInfo,
+    simulator: Simulator<'static, MEM_SIZE>,
+    breakpoints: Rc<RefCell<Breakpoints>>,
+    stop_on_entry: bool,
+}
+
+impl Session {
+    fn launch(path: &str, stop_on_entry: bool) -> Result<Self, String> {
+        let src =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let (_, code, debug_info) = parse_and_gen_with_debug(&src, path)?;
+        // Leaked so that the simulator can borrow it; an adapter debugs one program per session
+        let image: &'static [u8] = Box::leak(code.bytes.into_boxed_slice());
+
+        // Nothing steps backwards, so there is no need to keep the execution log
+        let mut simulator = Simulator::new(image).with_log_policy(LogPolicy::Off);
+        let breakpoints = Rc::new(RefCell::new(Breakpoints::default()));
+        simulator.add_observer(breakpoints.clone());
+        Ok(Self {
+            path: path.to_string(),
+            debug_info,
+            simulator,
+            breakpoints,
+            stop_on_entry,
+        })
+    }
+
+    /// The address of the first instruction at or after `line`, and the line it is on.
+    fn resolve_line(&self, line: i64) -> Option<(i64, usize)> {
+        self.debug_info
+            .lines
+            .iter()
+            .filter(|entry| entry.line as i64 >= line)
+            .min_by_key(|entry| (entry.line, entry.start))
+            .map(|entry| (entry.start, entry.line))
+    }
+
+    fn sp(&self) -> i64 {
+        self.simulator.registers[Register::Rsp as usize]
+    }
+}
+
+/// Serves the Debug Adapter Protocol for Y86 programs.
+///
+/// Launching assembles a `.ys` file in-process; breakpoints on source lines are placed on the
+/// first instruction at or after the line. There is a single thread with a single stack frame,
+/// and the variables view shows the registers, condition codes and stack. Memory holds one quad
+/// per address, so memory reads are laid out as in the GDB stub: `count` bytes from `addr` are
+/// the little-endian quads at `addr`, `addr + 8`, ...
+pub struct DebugAdapter<W: Write> {
+    out: W,
+    seq: i64,
+    session: Option<Session>,
+    /// Breakpoint lines from the last `setBreakpoints`, kept until the program is launched
+    breakpoint_lines: Vec<i64>,
+    configured: bool,
+    run: Run,
+    finished: bool,
+}
+
+impl<W: Write> DebugAdapter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            seq: 0,
+            session: None,
+            breakpoint_lines: Vec::new(),
+            configured: false,
+            run: Run::Stopped,
+            finished: false,
+        }
+    }
+
+    /// Handles requests until the client disconnects, running the program in between.
+    pub fn serve(&mut self, requests: Receiver<Value>) -> io::Result<()> {
+        while !self.finished {
+            let request = if self.run == Run::Stopped {
+                match requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => break,
+                }
+            } else {
+                match requests.try_recv() {
+                    Ok(request) => request,
+                    Err(TryRecvError::Empty) => {
+                        self.advance()?;
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            };
+            self.handle(&request)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.out, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn handle(&mut self, request: &Value) -> io::Result<()> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        match command {
+            "initialize" => self.respond(
+                request,
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsTerminateRequest": true,
+                })),
+            ),
+            "launch" => {
+                let result = self.launch(args);
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                if launched {
+                    self.event("initialized", json!({}))?;
+                    self.start()?;
+                }
+                Ok(())
+            }
+            "setBreakpoints" => {
+                let result = Ok(self.set_breakpoints(args));
+                self.respond(request, result)
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, Ok(json!({})))?;
+                self.start()
+            }
+            "threads" => self.respond(
+                request,
+                Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            ),
+            "stackTrace" => {
+                let result = self.with_session(stack_trace);
+                self.respond(request, result)
+            }
+            "scopes" => self.respond(
+                request,
+                Ok(json!({ "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Condition Codes", "variablesReference": CONDITION_CODES_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                ]})),
+            ),
+            "variables" => {
+                let reference = args["variablesReference"].as_i64().unwrap_or_default();
+                let result = self.with_session(|session| variables(session, reference));
+                self.respond(request, result)
+            }
+            "readMemory" => {
+                let result = self.with_session(|session| read_memory(session, args));
+                self.respond(request, result)
+            }
+            "continue" => {
+                let result = self.resume(Run::Continue);
+                self.respond(request, result.map(|_| json!({ "allThreadsContinued": true })))
+            }
+            "next" | "stepIn" | "stepOut" => self.step(request, command),
+            "pause" => {
+                self.respond(request, Ok(json!({})))?;
+                if self.run != Run::Stopped {
+                    self.stop("pause")?;
+                }
+                Ok(())
+            }
+            "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                self.run = Run::Stopped;
+                self.event("terminated", json!({}))
+            }
+            "disconnect" => {
+                self.finished = true;
+                self.respond(request, Ok(json!({})))
+            }
+            _ => self.respond(request, Err(format!("Unsupported request: {}", command))),
+        }
+    }
+
+    fn with_session(
+        &self,
+        f: impl FnOnce(&Session) -> Result<Value, String>,
+    ) -> Result<Value, String> {
+        self.session
+            .as_ref()
+            .ok_or_else(|| "No program has been launched".to_string())
+            .and_then(f)
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["program"]
+            .as_str()
+            .ok_or("Launch requires a `program` to debug")?;
+        let stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        let session = Session::launch(path, stop_on_entry)?;
+        self.session = Some(session);
+        self.place_breakpoints();
+        Ok(json!({}))
+    }
+
+    /// Sets breakpoints from source lines, returning where each one ended up.
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        self.breakpoint_lines = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_i64())
+            .collect();
+        let breakpoints = self.place_breakpoints();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn place_breakpoints(&mut self) -> Vec<Value> {
+        let Some(session) = &self.session else {
+            return self
+                .breakpoint_lines
+                .iter()
+                .map(|line| json!({ "verified": false, "line": line }))
+                .collect();
+        };
+        let mut addresses = session.breakpoints.borrow_mut();
+        addresses.addresses.clear();
+        self.breakpoint_lines
+            .iter()
+            .map(|&line| match session.resolve_line(line) {
+                Some((addr, line)) => {
+                    addresses.addresses.insert(addr);
+                    json!({ "verified": true, "line": line })
+                }
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No instruction at or after this line",
+                }),
+            })
+            .collect()
+    }
+
+    /// Starts the program once it is both launched and configured.
+    fn start(&mut self) -> io::Result<()> {
+        match &self.session {
+            Some(session) if self.configured && session.simulator.steps == 0 => {
+                if session.stop_on_entry {
+                    self.stop("entry")
+                } else {
+                    self.resume(Run::Continue).map_err(io::Error::other)?;
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn resume(&mut self, run: Run) -> Result<(), String> {
+        let session = self
+            .session
+            .as_mut()
+            .ok_or("No program has been launched")?;
+        // Execute the instruction at the PC even if it has a breakpoint on it
+        session.simulator.paused_at = Some(session.simulator.instruction_pointer);
+        self.run = run;
+        Ok(())
+    }
+
+    fn step(&mut self, request: &Value, command: &str) -> io::Result<()> {
+        let Some(session) = &self.session else {
+            return self.respond(request, Err("No program has been launched".to_string()));
+        };
+        let simulator = &session.simulator;
+        let ip = simulator.instruction_pointer;
+        let run = match (command, decode(simulator.source, ip)) {
+            ("next", Ok(instruction @ Instruction::Call(_))) => Run::StepOver {
+                return_to: ip + instruction.encoded_len() as i64,
+                sp: session.sp(),
+            },
+            ("stepOut", _) => Run::StepOut { sp: session.sp() },
+            _ => Run::Continue,
+        };
+        self.respond(request, Ok(json!({})))?;
+        self.resume(run).map_err(io::Error::other)?;
+        if run == Run::Continue {
+            // A single instruction
+            self.execute(1)?;
+            if self.run != Run::Stopped {
+                self.stop("step")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the program for a while, reporting any stop.
+    fn advance(&mut self) -> io::Result<()> {
+        self.execute(STEPS_PER_POLL)
+    }
+
+    fn execute(&mut self, steps: u64) -> io::Result<()> {
+        let Some(session) = &mut self.session else {
+            self.run = Run::Stopped;
+            return Ok(());
+        };
+        for _ in 0..steps {
+            let simulator = &mut session.simulator;
+            if simulator.state != Status::Running {
+                return self.finish();
+            }
+            let ip = simulator.instruction_pointer;
+            let is_ret = matches!(decode(simulator.source, ip), Ok(Instruction::Ret));
+            simulator.run_single();
+            if simulator.state != Status::Running {
+                return self.finish();
+            }
+            if simulator.paused_at.is_some() {
+                return self.stop("breakpoint");
+            }
+            let ip = simulator.instruction_pointer;
+            let sp = session.sp();
+            let stepped = match self.run {
+                Run::StepOver {
+                    return_to,
+                    sp: start,
+                } => ip == return_to && sp >= start,
+                Run::StepOut { sp: start } => is_ret && sp > start,
+                Run::Stopped | Run::Continue => false,
+            };
+            if stepped {
+                return self.stop("step");
+            }
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, reason: &str) -> io::Result<()> {
+        self.run = Run::Stopped;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    /// Reports that the program halted or failed.
+    fn finish(&mut self) -> io::Result<()> {
+        self.run = Run::Stopped;
+        let state = match &self.session {
+            Some(session) => session.simulator.state.clone(),
+            None => return Ok(()),
+        };
+        match state {
+            Status::Running => Ok(()),
+            Status::Halted => {
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", json!({}))
+            }
+            // Stop rather than exit, so the state that led to the error can be inspected
+            error => {
+                let text = error.to_string();
+                self.event(
+                    "output",
+                    json!({ "category": "stderr", "output": text + "\n" }),
+                )?;
+                self.event(
+                    "stopped",
+                    json!({
+                        "reason": "exception",
+                        "description": "Execution failed",
+                        "text": error.to_string(),
+                        "threadId": THREAD_ID,
+                        "allThreadsStopped": true,
+                    }),
+                )
+            }
+        }
+    }
+}
+
+fn stack_trace(session: &Session) -> Result<Value, String> {
+    let ip = session.simulator.instruction_pointer;
+    let name = session
+        .debug_info
+        .describe(ip)
+        .unwrap_or_else(|| format!("{:#x}", ip));
+    let mut frame = json!({
+        "id": 0,
+        "name": name,
+        "line": 0,
+        "column": 0,
+        "instructionPointerReference": format!("{:#x}", ip),
+    });
+    if let Some(entry) = session.debug_info.line_at(ip) {
+        let file_name = std::path::Path::new(&session.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        frame["line"] = json!(entry.line);
+        frame["column"] = json!(1);
+        frame["source"] = json!({ "name": file_name, "path": session.path });
+    }
+    Ok(json!({ "stackFrames": [frame], "totalFrames": 1 }))
+}
+
+fn variable(name: impl Into<String>, value: i64, memory_reference: bool) -> Value {
+    let mut variable = json!({
+        "name": name.into(),
+        "value": format!("{} ({:#x})", value, value),
+        "variablesReference": 0,
+    });
+    if memory_reference {
+        variable["memoryReference"] = json!(format!("{:#x}", value));
+    }
+    variable
+}
+
+fn variables(session: &Session, reference: i64) -> Result<Value, String> {
+    let simulator = &session.simulator;
+    let variables: Vec<Value> = match reference {
+        REGISTERS_REFERENCE => (0..simulator.registers.len() as u8)
+            .map(|n| Register::try_from(n).unwrap())
+            .map(|reg| variable(reg.to_string(), simulator.registers[reg as usize], true))
+            .chain([variable("pc", simulator.instruction_pointer, false)])
+            .collect(),
+        CONDITION_CODES_REFERENCE => ["CF", "ZF", "SF", "OF"]
+            .iter()
+            .enumerate()
+            .map(|(bit, name)| {
+                json!({
+                    "name": name,
+                    "value": ((simulator.condition_code >> bit) & 1).to_string(),
+                    "variablesReference": 0,
+                })
+            })
+            .collect(),
+        STACK_REFERENCE => (session.sp().max(0)..MEM_SIZE as i64)
+            .step_by(8)
+            .take(STACK_SLOTS)
+            .map(|addr| {
+                let mut slot = variable(
+                    format!("{:#06x}", addr),
+                    simulator.memory[addr as usize],
+                    false,
+                );
+                slot["memoryReference"] = json!(format!("{:#x}", addr));
+                slot
+            })
+            .collect(),
+        _ => return Err(format!("Unknown variables reference: {}", reference)),
+    };
+    Ok(json!({ "variables": variables }))
+}
+
+fn parse_address(reference: &str) -> Option<i64> {
+    match reference.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+fn read_memory(session: &Session, args: &Value) -> Result<Value, String> {
+    let reference = args["memoryReference"].as_str().unwrap_or_default();
+    let addr = parse_address(reference)
+        .ok_or_else(|| format!("Invalid memory reference: {}", reference))?
+        .checked_add(args["offset"].as_i64().unwrap_or(0))
+        .ok_or_else(|| format!("Memory reference out of range: {}", reference))?;
+    let count = args["count"].as_i64().unwrap_or(0).max(0);
+    let bytes: Vec<u8> = (0..count as usize)
+        .map_while(|offset| {
+            let index = quad_index::<MEM_SIZE>(addr, offset)?;
+            Some(session.simulator.memory[index].to_le_bytes()[offset % 8])
+        })
+        .collect();
+    Ok(json!({
+        "address": format!("{:#x}", addr),
+        "data": base64(&bytes),
+        "unreadableBytes": count - bytes.len() as i64,
+    }))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
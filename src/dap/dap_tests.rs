@@ -0,0 +1,292 @@
+use super::*;
+use crate::protocol::read_message;
+use std::sync::mpsc;
+
+const PROGRAM: &str = "# Sum two numbers in a function
+    irmovq $256, %rsp
+    irmovq $1, %rax
+    call add
+
+    irmovq $3, %rcx
+    halt
+add:
+    irmovq $2, %rbx
+    addq %rbx, %rax
+    ret
+";
+
+/// Writes `src` to a fresh file under the temp directory and returns its path.
+fn source_file(name: &str, src: &str) -> String {
+    let path = std::env::temp_dir().join(format!("y86_dap_{}_{}.ys", std::process::id(), name));
+    std::fs::write(&path, src).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+fn request(seq: i64, command: &str, arguments: Value) -> Value {
+    json!({ "seq": seq, "type": "request", "command": command, "arguments": arguments })
+}
+
+/// Sends `requests` to a new adapter one at a time, letting the program run to its next stop
+/// after each, and returns every message the adapter sent back.
+fn session(requests: Vec<Value>) -> Vec<Value> {
+    let mut out = Vec::new();
+    let mut adapter = DebugAdapter::new(&mut out);
+    for request in requests {
+        adapter.handle(&request).unwrap();
+        while adapter.run != Run::Stopped {
+            adapter.advance().unwrap();
+        }
+    }
+    drop(adapter);
+    parse_messages(&out)
+}
+
+fn parse_messages(mut out: &[u8]) -> Vec<Value> {
+    std::iter::from_fn(|| read_message(&mut out).unwrap()).collect()
+}
+
+fn launch_requests(path: &str, stop_on_entry: bool, lines: &[i64]) -> Vec<Value> {
+    let breakpoints: Vec<Value> = lines.iter().map(|line| json!({ "line": line })).collect();
+    vec![
+        request(1, "initialize", json!({ "adapterID": "y86" })),
+        request(
+            2,
+            "launch",
+            json!({ "program": path, "stopOnEntry": stop_on_entry }),
+        ),
+        request(
+            3,
+            "setBreakpoints",
+            json!({ "source": { "path": path }, "breakpoints": breakpoints }),
+        ),
+        request(4, "configurationDone", json!({})),
+    ]
+}
+
+fn response(messages: &[Value], request_seq: i64) -> &Value {
+    messages
+        .iter()
+        .find(|message| message["type"] == "response" && message["request_seq"] == request_seq)
+        .unwrap_or_else(|| panic!("No response to {}: {:#?}", request_seq, messages))
+}
+
+fn events<'a>(messages: &'a [Value], event: &'a str) -> impl Iterator<Item = &'a Value> {
+    messages
+        .iter()
+        .filter(move |message| message["event"] == event)
+}
+
+fn stop_reasons(messages: &[Value]) -> Vec<&str> {
+    events(messages, "stopped")
+        .map(|event| event["body"]["reason"].as_str().unwrap())
+        .collect()
+}
+
+fn variable_value<'a>(variables: &'a Value, name: &str) -> &'a str {
+    variables["body"]["variables"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|variable| variable["name"] == name)
+        .unwrap()["value"]
+        .as_str()
+        .unwrap()
+}
+
+#[test]
+fn test_runs_to_exit_without_breakpoints() {
+    let path = source_file("exit", PROGRAM);
+    let messages = session(launch_requests(&path, false, &[]));
+    assert_eq!(response(&messages, 2)["success"], true);
+    assert_eq!(events(&messages, "initialized").count(), 1);
+    assert_eq!(
+        events(&messages, "exited").next().unwrap()["body"]["exitCode"],
+        0
+    );
+    assert_eq!(events(&messages, "terminated").count(), 1);
+    // Sequence numbers count up from 1
+    let seqs: Vec<i64> = messages
+        .iter()
+        .map(|m| m["seq"].as_i64().unwrap())
+        .collect();
+    assert_eq!(seqs, (1..=seqs.len() as i64).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_breakpoints_map_source_lines() {
+    let path = source_file("breakpoints", PROGRAM);
+    let mut requests = launch_requests(&path, false, &[4, 5, 50]);
+    requests.push(request(5, "stackTrace", json!({ "threadId": 1 })));
+    requests.push(request(
+        6,
+        "variables",
+        json!({ "variablesReference": REGISTERS_REFERENCE }),
+    ));
+    requests.push(request(7, "continue", json!({ "threadId": 1 })));
+    let messages = session(requests);
+
+    // Line 5 is blank, so its breakpoint moves to the next instruction
+    let breakpoints = &response(&messages, 3)["body"]["breakpoints"];
+    assert_eq!(breakpoints[0], json!({ "verified": true, "line": 4 }));
+    assert_eq!(breakpoints[1], json!({ "verified": true, "line": 6 }));
+    assert_eq!(breakpoints[2]["verified"], false);
+
+    assert_eq!(stop_reasons(&messages), ["breakpoint", "breakpoint"]);
+    let frame = &response(&messages, 5)["body"]["stackFrames"][0];
+    assert_eq!(frame["line"], 4);
+    assert_eq!(frame["source"]["path"], path.as_str());
+    assert_eq!(variable_value(response(&messages, 6), "rax"), "1 (0x1)");
+}
+
+#[test]
+fn test_stepping() {
+    let path = source_file("stepping", PROGRAM);
+    let mut requests = launch_requests(&path, true, &[]);
+    requests.extend([
+        request(5, "next", json!({ "threadId": 1 })),
+        request(6, "next", json!({ "threadId": 1 })),
+        // Steps over the call
+        request(7, "next", json!({ "threadId": 1 })),
+        request(8, "stackTrace", json!({ "threadId": 1 })),
+    ]);
+    let messages = session(requests);
+    assert_eq!(stop_reasons(&messages), ["entry", "step", "step", "step"]);
+    assert_eq!(response(&messages, 8)["body"]["stackFrames"][0]["line"], 6);
+
+    let mut requests = launch_requests(&path, false, &[4]);
+    requests.extend([
+        request(5, "stepIn", json!({ "threadId": 1 })),
+        request(6, "stackTrace", json!({ "threadId": 1 })),
+        request(7, "stepOut", json!({ "threadId": 1 })),
+        request(8, "stackTrace", json!({ "threadId": 1 })),
+    ]);
+    let messages = session(requests);
+    assert_eq!(stop_reasons(&messages), ["breakpoint", "step", "step"]);
+    assert_eq!(
+        response(&messages, 6)["body"]["stackFrames"][0]["name"],
+        "add"
+    );
+    assert_eq!(response(&messages, 8)["body"]["stackFrames"][0]["line"], 6);
+}
+
+#[test]
+fn test_condition_codes_stack_and_memory() {
+    let path = source_file("variables", PROGRAM);
+    let mut requests = launch_requests(&path, false, &[11]);
+    requests.extend([
+        request(5, "scopes", json!({ "frameId": 0 })),
+        request(
+            6,
+            "variables",
+            json!({ "variablesReference": CONDITION_CODES_REFERENCE }),
+        ),
+        request(
+            7,
+            "variables",
+            json!({ "variablesReference": STACK_REFERENCE }),
+        ),
+        request(
+            8,
+            "readMemory",
+            json!({ "memoryReference": "0xf8", "count": 16 }),
+        ),
+    ]);
+    let messages = session(requests);
+    assert_eq!(
+        response(&messages, 5)["body"]["scopes"]
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(variable_value(response(&messages, 6), "ZF"), "0");
+
+    // The return address of the call, 0x1d, is on top of the stack
+    let stack = response(&messages, 7);
+    assert_eq!(variable_value(stack, "0x00f8"), "29 (0x1d)");
+    let memory = &response(&messages, 8)["body"];
+    assert_eq!(memory["address"], "0xf8");
+    assert_eq!(memory["data"], "HQAAAAAAAAAAAAAAAAAAAA==");
+    assert_eq!(memory["unreadableBytes"], 0);
+}
+
+#[test]
+fn test_read_memory_bounds() {
+    let path = source_file("memory_bounds", PROGRAM);
+    let mut requests = launch_requests(&path, false, &[11]);
+    requests.extend([
+        request(
+            5,
+            "readMemory",
+            json!({ "memoryReference": "0x7fffffffffffffff", "offset": 8, "count": 8 }),
+        ),
+        request(
+            6,
+            "readMemory",
+            json!({ "memoryReference": "0x3f8", "count": i64::MAX }),
+        ),
+        // Unaligned addresses read the quad stored there, as in the GDB stub
+        request(
+            7,
+            "readMemory",
+            json!({ "memoryReference": "0xf4", "count": 8 }),
+        ),
+        request(
+            8,
+            "readMemory",
+            json!({ "memoryReference": "0xf0", "offset": 8, "count": 1 }),
+        ),
+    ]);
+    let messages = session(requests);
+    assert_eq!(response(&messages, 5)["success"], false);
+    let memory = &response(&messages, 6)["body"];
+    assert_eq!(memory["data"], "AAAAAAAAAAA=");
+    assert_eq!(memory["unreadableBytes"], i64::MAX - 8);
+    assert_eq!(response(&messages, 7)["body"]["data"], "AAAAAAAAAAA=");
+    assert_eq!(response(&messages, 8)["body"]["data"], "HQ==");
+}
+
+#[test]
+fn test_pause_stops_a_running_program() {
+    let path = source_file("pause", "loop: jmp loop\n");
+    let (sender, receiver) = mpsc::channel();
+    for request in launch_requests(&path, false, &[]) {
+        sender.send(request).unwrap();
+    }
+    sender
+        .send(request(5, "pause", json!({ "threadId": 1 })))
+        .unwrap();
+    drop(sender);
+
+    let mut out = Vec::new();
+    DebugAdapter::new(&mut out).serve(receiver).unwrap();
+    let messages = parse_messages(&out);
+    assert_eq!(stop_reasons(&messages), ["pause"]);
+    assert_eq!(response(&messages, 5)["success"], true);
+}
+
+#[test]
+fn test_errors_are_reported() {
+    let path = source_file("fault", "irmovq $1, %rax\nret\n");
+    let messages = session(launch_requests(&path, false, &[]));
+    let stopped = events(&messages, "stopped").next().unwrap();
+    assert_eq!(stopped["body"]["reason"], "exception");
+    assert_eq!(events(&messages, "output").count(), 1);
+
+    let path = source_file("bad", "irmovq $1, %rzz\n");
+    let messages = session(launch_requests(&path, false, &[]));
+    assert_eq!(response(&messages, 2)["success"], false);
+    assert_eq!(response(&messages, 3)["body"]["breakpoints"], json!([]));
+    assert_eq!(events(&messages, "stopped").count(), 0);
+
+    let messages = session(vec![request(1, "evaluate", json!({ "expression": "rax" }))]);
+    assert_eq!(response(&messages, 1)["success"], false);
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+}
@@ -1,3 +1,7 @@
 pub mod ast; // common AST definitions 
 pub mod assembler;
+pub mod dap;
+pub mod lsp;
+pub mod object;
+pub mod protocol;
 pub mod simulator;
\ No newline at end of file
@@ -0,0 +1,658 @@
+use crate::assembler::codegen::gen_code;
+use crate::assembler::parser::mk_parser;
+use crate::ast::{BorrowedInstruction, Instruction, LabOrImm, OwnedInstruction};
+use crate::protocol::{read_message, write_message};
+use chumsky::Parser;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+#[cfg(test)]
+mod lsp_tests;
+
+/// Mnemonics and directives offered by completion, with their operands.
+const MNEMONICS: &[(&str, &str)] = &[
+    ("halt", "halt"),
+    ("nop", "nop"),
+    ("rrmovq", "rrmovq rA, rB"),
+    ("cmovle", "cmovle rA, rB"),
+    ("cmovl", "cmovl rA, rB"),
+    ("cmove", "cmove rA, rB"),
+    ("cmovne", "cmovne rA, rB"),
+    ("cmovge", "cmovge rA, rB"),
+    ("cmovg", "cmovg rA, rB"),
+    ("irmovq", "irmovq V, rB"),
+    ("rmmovq", "rmmovq rA, D(rB)"),
+    ("mrmovq", "mrmovq D(rB), rA"),
+    ("addq", "addq rA, rB"),
+    ("subq", "subq rA, rB"),
+    ("andq", "andq rA, rB"),
+    ("xorq", "xorq rA, rB"),
+    ("jmp", "jmp Dest"),
+    ("jle", "jle Dest"),
+    ("jl", "jl Dest"),
+    ("je", "je Dest"),
+    ("jne", "jne Dest"),
+    ("jge", "jge Dest"),
+    ("jg", "jg Dest"),
+    ("call", "call Dest"),
+    ("ret", "ret"),
+    ("pushq", "pushq rA"),
+    ("popq", "popq rA"),
+    ("syscall", "syscall"),
+    ("iret", "iret"),
+    ("ei", "ei"),
+    ("di", "di"),
+    (".align", ".align N"),
+    (".quad", ".quad V"),
+];
+
+const REGISTERS: &[&str] = &[
+    "rax", "rbx", "rcx", "rdx", "rdi", "rsi", "rsp", "rbp", "r8", "r9", "r10", "r11", "r12",
+];
+
+// LSP enumerations
+const SEVERITY_ERROR: i64 = 1;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_REFERENCE: i64 = 18;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYNC_INCREMENTAL: i64 = 2;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// A range of bytes within one line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Span {
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Diagnostic {
+    span: Span,
+    message: String,
+}
+
+/// A syntax error, as a byte range within its line.
+#[derive(Debug, Clone, PartialEq)]
+struct SyntaxError {
+    start: usize,
+    end: usize,
+    message: String,
+}
+
+/// A source line and its parse, kept between edits so that only changed lines are reparsed.
+///
+/// Lines are parsed on their own, as no Y86 instruction spans lines.
+#[derive(Debug)]
+struct Line {
+    text: String,
+    parsed: Result<Vec<OwnedInstruction>, SyntaxError>,
+}
+
+impl Line {
+    fn parse(text: &str) -> Self {
+        let (output, errors) = mk_parser().parse(text).into_output_errors();
+        let parsed = match errors.first() {
+            Some(error) => {
+                let range = error.span().into_range();
+                let start = range.start.min(text.len());
+                let end = range.end.clamp(start, text.len());
+                Err(SyntaxError {
+                    start,
+                    end,
+                    message: match error.found() {
+                        _ if start < end => {
+                            format!("Syntax error: unexpected '{}'", &text[start..end])
+                        }
+                        Some(found) => format!("Syntax error: unexpected '{}'", found),
+                        None => "Syntax error: unexpected end of line".to_string(),
+                    },
+                })
+            }
+            None => Ok(output
+                .unwrap_or_default()
+                .iter()
+                .map(|instruction| instruction.map_labels(|label| label.to_string()))
+                .collect()),
+        };
+        Self {
+            text: text.to_string(),
+            parsed,
+        }
+    }
+
+    fn instructions(&self) -> &[OwnedInstruction] {
+        self.parsed.as_deref().unwrap_or_default()
+    }
+
+    /// Spans of the identifiers outside comments, skipping registers, directives and numbers.
+    fn identifiers(&self, line: usize) -> Vec<Span> {
+        let code = self.text.split('#').next().unwrap_or_default().as_bytes();
+        let mut identifiers = Vec::new();
+        let mut i = 0;
+        while i < code.len() {
+            let start = i;
+            if code[i].is_ascii_alphanumeric() || code[i] == b'_' {
+                while i < code.len() && (code[i].is_ascii_alphanumeric() || code[i] == b'_') {
+                    i += 1;
+                }
+                let prefixed = start > 0 && matches!(code[start - 1], b'%' | b'.' | b'$');
+                if !code[start].is_ascii_digit() && !prefixed {
+                    identifiers.push(Span {
+                        line,
+                        start,
+                        end: i,
+                    });
+                }
+            } else {
+                i += 1;
+            }
+        }
+        identifiers
+    }
+
+    fn word(&self, span: Span) -> &str {
+        &self.text[span.start..span.end]
+    }
+
+    /// Whether the identifier at `span` is followed by a colon, making it a label definition.
+    fn is_definition(&self, span: Span) -> bool {
+        self.text[span.end..].trim_start().starts_with(':')
+    }
+}
+
+/// Results of assembling the whole document, rebuilt after every edit.
+#[derive(Debug, Default)]
+struct Analysis {
+    /// Address and encoding of each line that produces code or data, by line
+    code: Vec<Option<(i64, Vec<u8>)>>,
+    /// Where each label is defined, and its address
+    definitions: HashMap<String, (Span, i64)>,
+    /// Where each label is used as an operand
+    references: HashMap<String, Vec<Span>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// An open Y86 assembly file.
+#[derive(Debug)]
+struct Document {
+    lines: Vec<Line>,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn new(text: &str) -> Self {
+        let lines = text.split('\n').map(Line::parse).collect();
+        let mut document = Self {
+            lines,
+            analysis: Analysis::default(),
+        };
+        document.analyse();
+        document
+    }
+
+    /// The byte offset in line `line` of the LSP position, clamped to the document.
+    fn byte_position(&self, position: &Value) -> (usize, usize) {
+        let line = (position["line"].as_u64().unwrap_or(0) as usize).min(self.lines.len() - 1);
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+        (line, byte_offset(&self.lines[line].text, character))
+    }
+
+    /// Applies an LSP content change, reparsing only the lines it touches.
+    ///
+    /// Call [`Document::analyse`] once the last change of an edit has been applied.
+    fn apply_change(&mut self, change: &Value) {
+        let text = change["text"].as_str().unwrap_or_default();
+        let range = &change["range"];
+        if range.is_null() {
+            *self = Self::new(text);
+            return;
+        }
+        let start = self.byte_position(&range["start"]);
+        let end = self.byte_position(&range["end"]);
+        let ((start_line, start), (end_line, end)) = (start.min(end), start.max(end));
+
+        let replaced = format!(
+            "{}{}{}",
+            &self.lines[start_line].text[..start],
+            text,
+            &self.lines[end_line].text[end..]
+        );
+        let new_lines = replaced.split('\n').map(Line::parse);
+        self.lines.splice(start_line..=end_line, new_lines);
+    }
+
+    /// Resolves labels and assembles the document from the parsed lines.
+    fn analyse(&mut self) {
+        let mut analysis = Analysis {
+            code: vec![None; self.lines.len()],
+            ..Analysis::default()
+        };
+        let mut definitions = HashMap::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Err(error) = &line.parsed {
+                analysis.diagnostics.push(Diagnostic {
+                    span: Span {
+                        line: i,
+                        start: error.start,
+                        end: error.end,
+                    },
+                    message: error.message.clone(),
+                });
+            }
+            let referenced: Vec<&str> = line
+                .instructions()
+                .iter()
+                .filter_map(label_operand)
+                .collect();
+            for span in line.identifiers(i) {
+                let word = line.word(span);
+                if line.is_definition(span) {
+                    if definitions.contains_key(word) {
+                        analysis.diagnostics.push(Diagnostic {
+                            span,
+                            message: format!("Label '{}' is already defined", word),
+                        });
+                    } else {
+                        definitions.insert(word.to_string(), span);
+                    }
+                } else if referenced.contains(&word) {
+                    analysis
+                        .references
+                        .entry(word.to_string())
+                        .or_default()
+                        .push(span);
+                }
+            }
+        }
+        for (label, spans) in &analysis.references {
+            if !definitions.contains_key(label) {
+                analysis
+                    .diagnostics
+                    .extend(spans.iter().map(|&span| Diagnostic {
+                        span,
+                        message: format!("Undefined label '{}'", label),
+                    }));
+            }
+        }
+
+        // Assemble what parsed, with undefined labels and bad alignments left out
+        let mut items: Vec<(usize, BorrowedInstruction)> = Vec::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            for instruction in line.instructions() {
+                if let &Instruction::Directive(ref name, align) = instruction
+                    && name == ".align"
+                    && align <= 0
+                {
+                    analysis.diagnostics.push(Diagnostic {
+                        span: Span {
+                            line: i,
+                            start: 0,
+                            end: line.text.len(),
+                        },
+                        message: format!("Invalid alignment: {}", align),
+                    });
+                    continue;
+                }
+                let borrowed = instruction.map_labels(|label| label.as_str());
+                items.push((i, resolve_undefined(borrowed, &definitions)));
+            }
+        }
+        let ast: Vec<_> = items
+            .iter()
+            .map(|(_, instruction)| instruction.clone())
+            .collect();
+        match gen_code(&ast) {
+            Ok(code) => {
+                for ((i, instruction), &(start, end)) in items.iter().zip(&code.line_ranges) {
+                    if let Instruction::Label(label) = instruction
+                        && let Some(&span) = definitions.get(*label)
+                        && span.line == *i
+                    {
+                        analysis
+                            .definitions
+                            .insert(label.to_string(), (span, start as i64));
+                    }
+                    if start < end {
+                        let entry = analysis.code[*i].get_or_insert((start as i64, Vec::new()));
+                        entry.1.extend_from_slice(&code.bytes[start..end]);
+                    }
+                }
+            }
+            Err(message) => analysis.diagnostics.push(Diagnostic {
+                span: Span {
+                    line: 0,
+                    start: 0,
+                    end: 0,
+                },
+                message,
+            }),
+        }
+        analysis
+            .diagnostics
+            .sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.start));
+        self.analysis = analysis;
+    }
+
+    /// The label at an LSP position, if there is one.
+    fn label_at(&self, position: &Value) -> Option<&str> {
+        let (line, offset) = self.byte_position(position);
+        let line_ref = &self.lines[line];
+        let span = line_ref
+            .identifiers(line)
+            .into_iter()
+            .find(|span| span.start <= offset && offset <= span.end)?;
+        let word = line_ref.word(span);
+        (self.analysis.definitions.contains_key(word)
+            || self.analysis.references.contains_key(word))
+        .then_some(word)
+    }
+
+    fn range(&self, span: Span) -> Value {
+        let text = &self.lines[span.line].text;
+        json!({
+            "start": { "line": span.line, "character": utf16_offset(text, span.start) },
+            "end": { "line": span.line, "character": utf16_offset(text, span.end) },
+        })
+    }
+
+    fn diagnostics(&self) -> Vec<Value> {
+        self.analysis
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                json!({
+                    "range": self.range(diagnostic.span),
+                    "severity": SEVERITY_ERROR,
+                    "source": "y86",
+                    "message": diagnostic.message,
+                })
+            })
+            .collect()
+    }
+
+    fn definition(&self, position: &Value) -> Option<Span> {
+        let label = self.label_at(position)?;
+        self.analysis.definitions.get(label).map(|&(span, _)| span)
+    }
+
+    fn references(&self, position: &Value, include_declaration: bool) -> Vec<Span> {
+        let Some(label) = self.label_at(position) else {
+            return Vec::new();
+        };
+        let definition = self
+            .analysis
+            .definitions
+            .get(label)
+            .filter(|_| include_declaration)
+            .map(|&(span, _)| span);
+        definition
+            .into_iter()
+            .chain(
+                self.analysis
+                    .references
+                    .get(label)
+                    .into_iter()
+                    .flatten()
+                    .copied(),
+            )
+            .collect()
+    }
+
+    fn hover(&self, position: &Value) -> Option<String> {
+        let (line, _) = self.byte_position(position);
+        let mut hover = Vec::new();
+        if let Some(label) = self.label_at(position)
+            && let Some((_, addr)) = self.analysis.definitions.get(label)
+        {
+            hover.push(format!("`{}` = `{:#06x}`", label, addr));
+        }
+        if let Some(Some((addr, bytes))) = self.analysis.code.get(line) {
+            let encoding = bytes
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>();
+            hover.push(format!("`{:#06x}`: `{}`", addr, encoding.join(" ")));
+        }
+        (!hover.is_empty()).then(|| hover.join("\n\n"))
+    }
+
+    fn completions(&self, position: &Value) -> Vec<Value> {
+        let (line, offset) = self.byte_position(position);
+        let before = &self.lines[line].text[..offset];
+        // After a `%` only registers make sense, and the `%` is already typed
+        let after_percent = before.ends_with('%');
+        let registers = REGISTERS.iter().map(|reg| {
+            let insert_text = if after_percent { reg.to_string() } else { format!("%{}", reg) };
+            json!({ "label": format!("%{}", reg), "insertText": insert_text, "kind": COMPLETION_VARIABLE })
+        });
+        if after_percent {
+            return registers.collect();
+        }
+        let mnemonics = MNEMONICS.iter().map(|(mnemonic, detail)| {
+            json!({ "label": mnemonic, "detail": detail, "kind": COMPLETION_KEYWORD })
+        });
+        let mut labels: Vec<_> = self.analysis.definitions.iter().collect();
+        labels.sort_by_key(|(_, (span, _))| span.line);
+        let labels = labels.into_iter().map(|(label, (_, addr))| {
+            json!({ "label": label, "detail": format!("{:#06x}", addr), "kind": COMPLETION_REFERENCE })
+        });
+        mnemonics.chain(registers).chain(labels).collect()
+    }
+
+    fn symbols(&self) -> Vec<Value> {
+        let mut labels: Vec<_> = self.analysis.definitions.iter().collect();
+        labels.sort_by_key(|(_, (span, _))| (span.line, span.start));
+        labels
+            .into_iter()
+            .map(|(label, &(span, addr))| {
+                json!({
+                    "name": label,
+                    "detail": format!("{:#06x}", addr),
+                    "kind": SYMBOL_FUNCTION,
+                    "range": self.range(Span { start: 0, end: self.lines[span.line].text.len(), ..span }),
+                    "selectionRange": self.range(span),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The label operand of an instruction, if it has one.
+fn label_operand(instruction: &OwnedInstruction) -> Option<&str> {
+    match instruction {
+        Instruction::Irmov(LabOrImm::Labelled(label), _)
+        | Instruction::Jmp(_, LabOrImm::Labelled(label))
+        | Instruction::Call(LabOrImm::Labelled(label)) => Some(label),
+        _ => None,
+    }
+}
+
+/// Replaces references to undefined labels with 0, so the rest of the program still assembles.
+fn resolve_undefined<'a>(
+    instruction: BorrowedInstruction<'a>,
+    definitions: &HashMap<String, Span>,
+) -> BorrowedInstruction<'a> {
+    let resolve = |target: LabOrImm<&'a str>| match target {
+        LabOrImm::Labelled(label) if !definitions.contains_key(label) => LabOrImm::Immediate(0),
+        target => target,
+    };
+    match instruction {
+        Instruction::Irmov(source, reg) => Instruction::Irmov(resolve(source), reg),
+        Instruction::Jmp(cond, target) => Instruction::Jmp(cond, resolve(target)),
+        Instruction::Call(target) => Instruction::Call(resolve(target)),
+        instruction => instruction,
+    }
+}
+
+/// The number of UTF-16 code units before byte `offset` of `text`, as LSP positions count.
+fn utf16_offset(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    text[..offset].encode_utf16().count()
+}
+
+/// The byte offset of the LSP character position `character` within `text`.
+fn byte_offset(text: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in text.char_indices() {
+        if units >= character {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+/// Serves the Language Server Protocol for Y86 assembly files.
+///
+/// Documents are synchronised incrementally: each edit reparses only the lines it touches,
+/// then the document is reassembled to report diagnostics and resolve labels.
+pub struct LanguageServer<W: Write> {
+    out: W,
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> LanguageServer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Handles messages until the client sends `exit` or closes the input.
+    pub fn serve(&mut self, input: &mut impl BufRead) -> io::Result<()> {
+        loop {
+            match read_message(input) {
+                Ok(Some(message)) => {
+                    if !self.handle(&message)? {
+                        return Ok(());
+                    }
+                }
+                Ok(None) => return Ok(()),
+                // Skip malformed messages
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send(&mut self, message: Value) -> io::Result<()> {
+        write_message(&mut self.out, &message)
+    }
+
+    /// Handles one request or notification, returning false on `exit`.
+    fn handle(&mut self, message: &Value) -> io::Result<bool> {
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let position = &params["position"];
+        let method = message["method"].as_str().unwrap_or_default();
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": SYNC_INCREMENTAL },
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["%"] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "y86-lsp" },
+            }),
+            "shutdown" => Value::Null,
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), Document::new(text));
+                self.publish_diagnostics(uri)?;
+                return Ok(true);
+            }
+            "textDocument/didChange" => {
+                if let Some(document) = self.documents.get_mut(uri) {
+                    for change in params["contentChanges"].as_array().into_iter().flatten() {
+                        document.apply_change(change);
+                    }
+                    document.analyse();
+                    self.publish_diagnostics(uri)?;
+                }
+                return Ok(true);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                self.publish_diagnostics(uri)?;
+                return Ok(true);
+            }
+            "textDocument/definition" => self
+                .documents
+                .get(uri)
+                .and_then(|document| {
+                    let span = document.definition(position)?;
+                    Some(json!({ "uri": uri, "range": document.range(span) }))
+                })
+                .unwrap_or(Value::Null),
+            "textDocument/references" => {
+                let include_declaration = params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(false);
+                let locations: Vec<Value> = self
+                    .documents
+                    .get(uri)
+                    .map(|document| {
+                        document
+                            .references(position, include_declaration)
+                            .into_iter()
+                            .map(|span| json!({ "uri": uri, "range": document.range(span) }))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                json!(locations)
+            }
+            "textDocument/hover" => self
+                .documents
+                .get(uri)
+                .and_then(|document| document.hover(position))
+                .map(|value| json!({ "contents": { "kind": "markdown", "value": value } }))
+                .unwrap_or(Value::Null),
+            "textDocument/completion" => json!(
+                self.documents
+                    .get(uri)
+                    .map(|document| document.completions(position))
+                    .unwrap_or_default()
+            ),
+            "textDocument/documentSymbol" => json!(
+                self.documents
+                    .get(uri)
+                    .map(Document::symbols)
+                    .unwrap_or_default()
+            ),
+            _ if message["id"].is_null() => return Ok(true), // Unhandled notification
+            _ => {
+                self.send(json!({
+                    "jsonrpc": "2.0",
+                    "id": message["id"],
+                    "error": { "code": METHOD_NOT_FOUND, "message": format!("Unsupported method: {}", method) },
+                }))?;
+                return Ok(true);
+            }
+        };
+        self.send(json!({ "jsonrpc": "2.0", "id": message["id"], "result": result }))?;
+        Ok(true)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = self
+            .documents
+            .get(uri)
+            .map(Document::diagnostics)
+            .unwrap_or_default();
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }))
+    }
+}
@@ -0,0 +1,266 @@
+use super::*;
+
+const PROGRAM: &str = "# Count down from three
+main:
+    irmovq $3, %rax
+    irmovq $1, %rbx
+loop:
+    subq %rbx, %rax
+    jne loop
+    call done
+    halt
+done: ret
+";
+
+fn position(line: usize, character: usize) -> Value {
+    json!({ "line": line, "character": character })
+}
+
+fn change(start: (usize, usize), end: (usize, usize), text: &str) -> Value {
+    json!({
+        "range": { "start": position(start.0, start.1), "end": position(end.0, end.1) },
+        "text": text,
+    })
+}
+
+fn text(document: &Document) -> String {
+    document
+        .lines
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn messages(document: &Document) -> Vec<(usize, &str)> {
+    document
+        .analysis
+        .diagnostics
+        .iter()
+        .map(|diagnostic| (diagnostic.span.line, diagnostic.message.as_str()))
+        .collect()
+}
+
+/// Applies `changes` incrementally and checks the result matches parsing the new text afresh.
+fn assert_edit(src: &str, changes: &[Value], expected: &str) -> Document {
+    let mut document = Document::new(src);
+    for change in changes {
+        document.apply_change(change);
+    }
+    document.analyse();
+    assert_eq!(text(&document), expected);
+
+    let fresh = Document::new(expected);
+    assert_eq!(document.analysis.code, fresh.analysis.code);
+    assert_eq!(document.analysis.diagnostics, fresh.analysis.diagnostics);
+    assert_eq!(document.analysis.definitions, fresh.analysis.definitions);
+    document
+}
+
+#[test]
+fn test_program_assembles_cleanly() {
+    let document = Document::new(PROGRAM);
+    assert!(document.analysis.diagnostics.is_empty());
+    assert_eq!(
+        document.analysis.code[2],
+        Some((0, vec![0x30, 0xF0, 3, 0, 0, 0, 0, 0, 0, 0]))
+    );
+    assert_eq!(document.analysis.definitions["loop"].1, 0x14);
+    assert_eq!(document.analysis.definitions["done"].1, 0x29);
+    // `done: ret` defines a label and encodes an instruction on one line
+    assert_eq!(document.analysis.code[9], Some((0x29, vec![0x90])));
+}
+
+#[test]
+fn test_diagnostics() {
+    let document = Document::new(
+        "start:\n  irmovq $1, %rzx\n  jmp nowhere\nstart:\n  .align 0\n  call nowhere # again\n",
+    );
+    assert_eq!(
+        messages(&document),
+        [
+            (1, "Syntax error: unexpected 'rzx'"),
+            (2, "Undefined label 'nowhere'"),
+            (3, "Label 'start' is already defined"),
+            (4, "Invalid alignment: 0"),
+            (5, "Undefined label 'nowhere'"),
+        ]
+    );
+    // The rest of the program still assembles
+    assert_eq!(document.analysis.code[2].as_ref().unwrap().0, 0);
+    assert_eq!(document.analysis.code[5].as_ref().unwrap().0, 9);
+}
+
+#[test]
+fn test_incremental_edits() {
+    // Typing within a line
+    let document = assert_edit(
+        PROGRAM,
+        &[change((6, 12), (6, 12), "_top")],
+        &PROGRAM.replace("jne loop", "jne loop_top"),
+    );
+    assert_eq!(messages(&document), [(6, "Undefined label 'loop_top'")]);
+
+    // Inserting and deleting lines shifts the lines after them
+    let inserted = PROGRAM.replace("loop:\n", "loop:\n    nop\n    nop\n");
+    let document = assert_edit(
+        PROGRAM,
+        &[change((4, 5), (4, 5), "\n    nop\n    nop")],
+        &inserted,
+    );
+    assert_eq!(document.analysis.definitions["done"].1, 0x2b);
+    assert_edit(&inserted, &[change((4, 5), (6, 7), "")], PROGRAM);
+
+    // Several changes in one notification, and a replacement of the whole text
+    assert_edit(
+        PROGRAM,
+        &[
+            change((1, 0), (1, 4), "start"),
+            change((10, 0), (10, 0), "jmp start"),
+        ],
+        &PROGRAM
+            .replace("main:", "start:")
+            .replace("done: ret\n", "done: ret\njmp start"),
+    );
+    assert_edit(PROGRAM, &[json!({ "text": "halt" })], "halt");
+}
+
+#[test]
+fn test_never_panics_on_partial_input() {
+    for src in [
+        PROGRAM,
+        include_str!("../../examples/add_numbers.ys"),
+        include_str!("../../examples/bubble_sort.ys"),
+        include_str!("../../examples/test_instructions.ys"),
+    ] {
+        // Every prefix, as if typed from the start
+        let mut document = Document::new("");
+        for (i, c) in src.char_indices() {
+            let line = src[..i].matches('\n').count();
+            let character = i - src[..i].rfind('\n').map_or(0, |newline| newline + 1);
+            document.apply_change(&change(
+                (line, character),
+                (line, character),
+                &c.to_string(),
+            ));
+            document.analyse();
+            let _ = document.hover(&position(line, character));
+            let _ = document.completions(&position(line, character));
+        }
+        assert_eq!(text(&document), src);
+    }
+    // Positions past the end are clamped, and backwards ranges reversed
+    let mut document = Document::new("héllo\n");
+    document.apply_change(&change((9, 9), (0, 2), "x"));
+    document.apply_change(&change((0, 100), (0, 100), "$0x"));
+    document.analyse();
+    assert_eq!(text(&document), "héx$0x");
+    assert_eq!(messages(&document).len(), 1);
+}
+
+#[test]
+fn test_navigation() {
+    let document = Document::new(PROGRAM);
+    // From the reference in `jne loop` to the definition on line 4
+    assert_eq!(
+        document.definition(&position(6, 9)),
+        Some(Span {
+            line: 4,
+            start: 0,
+            end: 4
+        })
+    );
+    assert_eq!(document.definition(&position(6, 2)), None);
+    assert_eq!(
+        document.references(&position(4, 1), false),
+        [Span {
+            line: 6,
+            start: 8,
+            end: 12
+        }]
+    );
+    assert_eq!(document.references(&position(4, 1), true).len(), 2);
+
+    let symbols = document.symbols();
+    let names: Vec<_> = symbols
+        .iter()
+        .map(|symbol| symbol["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["main", "loop", "done"]);
+    assert_eq!(symbols[2]["detail"], "0x0029");
+}
+
+#[test]
+fn test_hover_and_completion() {
+    let document = Document::new(PROGRAM);
+    assert_eq!(
+        document.hover(&position(3, 4)).unwrap(),
+        "`0x000a`: `30 f1 01 00 00 00 00 00 00 00`"
+    );
+    assert_eq!(
+        document.hover(&position(6, 10)).unwrap(),
+        "`loop` = `0x0014`\n\n`0x0016`: `74 14 00 00 00 00 00 00 00`"
+    );
+    assert_eq!(document.hover(&position(0, 3)), None);
+
+    let labels = |items: Vec<Value>| -> Vec<String> {
+        items
+            .iter()
+            .map(|item| item["label"].as_str().unwrap().to_string())
+            .collect()
+    };
+    let all = labels(document.completions(&position(6, 4)));
+    assert!(all.contains(&"irmovq".to_string()));
+    assert!(all.contains(&"%rsp".to_string()));
+    assert!(all.contains(&"done".to_string()));
+    // After `%`, only registers
+    let registers = document.completions(&position(5, 10));
+    assert_eq!(registers.len(), REGISTERS.len());
+    assert_eq!(registers[0]["insertText"], "rax");
+}
+
+#[test]
+fn test_server_session() {
+    let requests = [
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+        json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": { "uri": "file:///a.ys", "languageId": "y86", "version": 1, "text": "jmp end\n" }
+        }}),
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": { "uri": "file:///a.ys", "version": 2 },
+            "contentChanges": [{ "range": { "start": position(1, 0), "end": position(1, 0) }, "text": "end: halt" }],
+        }}),
+        json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/definition", "params": {
+            "textDocument": { "uri": "file:///a.ys" }, "position": position(0, 5)
+        }}),
+        json!({ "jsonrpc": "2.0", "id": 3, "method": "textDocument/formatting", "params": {} }),
+        json!({ "jsonrpc": "2.0", "id": 4, "method": "shutdown" }),
+        json!({ "jsonrpc": "2.0", "method": "exit" }),
+        json!({ "jsonrpc": "2.0", "id": 5, "method": "shutdown" }),
+    ];
+    let mut input = Vec::new();
+    for request in &requests {
+        write_message(&mut input, request).unwrap();
+    }
+    let mut out = Vec::new();
+    LanguageServer::new(&mut out)
+        .serve(&mut &input[..])
+        .unwrap();
+
+    let mut out = &out[..];
+    let replies: Vec<Value> = std::iter::from_fn(|| read_message(&mut out).unwrap()).collect();
+    assert_eq!(replies.len(), 6);
+    assert_eq!(
+        replies[0]["result"]["capabilities"]["textDocumentSync"]["change"],
+        SYNC_INCREMENTAL
+    );
+    assert_eq!(
+        replies[1]["params"]["diagnostics"][0]["message"],
+        "Undefined label 'end'"
+    );
+    assert_eq!(replies[2]["params"]["diagnostics"], json!([]));
+    assert_eq!(replies[3]["result"]["range"]["start"], position(1, 0));
+    assert_eq!(replies[4]["error"]["code"], METHOD_NOT_FOUND);
+    assert_eq!(replies[5]["id"], 4);
+}
@@ -0,0 +1,110 @@
+mod debug_info;
+mod loader;
+#[cfg(test)]
+mod object_tests;
+
+pub use debug_info::{DebugInfo, LineEntry, Symbol};
+pub use loader::{Program, SourceFormat, load_program};
+
+use crate::simulator::memory_map::Region;
+
+/// Trailer marking an object file that carries sections after its code.
+///
+/// Layout: `code | (tag, len, payload)* | code_len: u64 | MAGIC`.
+/// Objects without extra sections are plain machine code, exactly as before.
+const MAGIC: &[u8; 8] = b"Y86OBJ\x00\x01";
+const TRAILER_LEN: usize = 16;
+const SECTION_HEADER_LEN: usize = 12;
+
+const DEBUG_TAG: &[u8; 4] = b"DBUG";
+/// The memory map, one `NAME:START-END:PERMS` region per line.
+const REGIONS_TAG: &[u8; 4] = b"REGN";
+
+/// A Y86-64 object file: machine code loaded at address 0, plus optional sections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectFile<'a> {
+    /// The machine code.
+    pub code: &'a [u8],
+    /// Symbols and line information, present when assembled with `-g`.
+    pub debug_info: Option<DebugInfo>,
+    /// The memory map to run the code under; empty for none.
+    pub regions: Vec<Region>,
+}
+
+impl<'a> ObjectFile<'a> {
+    /// Splits the contents of an object file into code and sections.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, String> {
+        let Some(trailer_start) = bytes.len().checked_sub(TRAILER_LEN) else {
+            return Ok(Self::plain(bytes));
+        };
+        if &bytes[trailer_start + 8..] != MAGIC {
+            return Ok(Self::plain(bytes));
+        }
+
+        let code_len =
+            u64::from_le_bytes(bytes[trailer_start..trailer_start + 8].try_into().unwrap());
+        let code_len = usize::try_from(code_len)
+            .ok()
+            .filter(|&len| len <= trailer_start)
+            .ok_or_else(|| format!("Invalid object file: code length {} out of range", code_len))?;
+
+        let mut object = Self::plain(&bytes[..code_len]);
+        let mut rest = &bytes[code_len..trailer_start];
+        while !rest.is_empty() {
+            if rest.len() < SECTION_HEADER_LEN {
+                return Err("Invalid object file: truncated section header".to_string());
+            }
+            let tag = &rest[..4];
+            let len = u64::from_le_bytes(rest[4..12].try_into().unwrap()) as usize;
+            let payload = rest
+                .get(SECTION_HEADER_LEN..SECTION_HEADER_LEN.saturating_add(len))
+                .ok_or_else(|| "Invalid object file: truncated section".to_string())?;
+
+            if tag == DEBUG_TAG {
+                object.debug_info = Some(DebugInfo::decode(payload)?);
+            } else if tag == REGIONS_TAG {
+                object.regions = std::str::from_utf8(payload)
+                    .map_err(|_| "Invalid regions section: not UTF-8".to_string())?
+                    .lines()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?;
+            } // Unknown sections are skipped so older tools can read newer objects
+
+            rest = &rest[SECTION_HEADER_LEN + len..];
+        }
+        Ok(object)
+    }
+
+    /// Serialises the object file. Without sections this is just the code.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.code.to_vec();
+        if self.debug_info.is_none() && self.regions.is_empty() {
+            return bytes;
+        }
+
+        let mut section = |tag: &[u8; 4], payload: Vec<u8>| {
+            bytes.extend_from_slice(tag);
+            bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(&payload);
+        };
+        if let Some(debug_info) = &self.debug_info {
+            section(DEBUG_TAG, debug_info.encode());
+        }
+        if !self.regions.is_empty() {
+            let lines: Vec<_> = self.regions.iter().map(Region::to_string).collect();
+            section(REGIONS_TAG, lines.join("\n").into_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.code.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(MAGIC);
+        bytes
+    }
+
+    fn plain(code: &'a [u8]) -> Self {
+        Self {
+            code,
+            debug_info: None,
+            regions: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::ast::{Instruction, LabOrImm, OwnedInstruction};
+
+/// First line of a symbol map written by [`DebugInfo::to_map`].
+const MAP_HEADER: &str = "# Y86-64 symbol map";
+
+/// A label and the address it refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: i64,
+}
+
+/// Maps the bytes [start, end) back to a line of the original source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineEntry {
+    pub start: i64,
+    pub end: i64,
+    /// 1-based line number
+    pub line: usize,
+    /// The source line, including any comment
+    pub text: String,
+}
+
+/// Debug section of an object file: the symbol table and the address-to-line map.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfo {
+    /// Name of the assembly source file
+    pub file: String,
+    /// Symbols sorted by address
+    pub symbols: Vec<Symbol>,
+    /// Line entries sorted by start address
+    pub lines: Vec<LineEntry>,
+}
+
+impl DebugInfo {
+    pub fn new(file: String, mut symbols: Vec<Symbol>, mut lines: Vec<LineEntry>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.addr);
+        lines.sort_by_key(|entry| entry.start);
+        Self {
+            file,
+            symbols,
+            lines,
+        }
+    }
+
+    /// The name of a symbol defined exactly at `addr`.
+    pub fn symbol_at(&self, addr: i64) -> Option<&str> {
+        let idx = self.symbols.partition_point(|symbol| symbol.addr < addr);
+        self.symbols
+            .get(idx)
+            .filter(|symbol| symbol.addr == addr)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    /// The closest symbol at or before `addr`, and the offset of `addr` from it.
+    pub fn locate(&self, addr: i64) -> Option<(&str, i64)> {
+        let idx = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let symbol = self.symbols.get(idx.checked_sub(1)?)?;
+        Some((symbol.name.as_str(), addr - symbol.addr))
+    }
+
+    /// Formats `addr` as `label+0xoff`, or as a bare label when the offset is 0.
+    pub fn describe(&self, addr: i64) -> Option<String> {
+        self.locate(addr).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+{:#x}", name, offset),
+        })
+    }
+
+    /// The source line which generated the byte at `addr`.
+    pub fn line_at(&self, addr: i64) -> Option<&LineEntry> {
+        let idx = self.lines.partition_point(|entry| entry.start <= addr);
+        self.lines[..idx]
+            .iter()
+            .rev()
+            .find(|entry| addr < entry.end)
+    }
+
+    /// Replaces immediate jump and call targets with the labels defined at them.
+    pub fn symbolize(&self, instruction: &OwnedInstruction) -> OwnedInstruction {
+        let symbolize_target = |target: &LabOrImm<String>| match target {
+            &LabOrImm::Immediate(addr) => match self.symbol_at(addr) {
+                Some(name) => LabOrImm::Labelled(name.to_string()),
+                None => target.clone(),
+            },
+            LabOrImm::Labelled(_) => target.clone(),
+        };
+
+        match instruction {
+            Instruction::Jmp(cond, target) => Instruction::Jmp(*cond, symbolize_target(target)),
+            Instruction::Call(target) => Instruction::Call(symbolize_target(target)),
+            _ => instruction.clone(),
+        }
+    }
+
+    /// Writes the symbol table and line map as text, one tab-separated record per line:
+    ///
+    /// ```text
+    /// file    <source file>
+    /// symbol  <addr>  <name>
+    /// line    <start> <end>   <line number>   <source text>
+    /// ```
+    ///
+    /// Addresses are hexadecimal with a `0x` prefix. Blank lines and `#` comments are ignored
+    /// by [`DebugInfo::parse_map`].
+    pub fn to_map(&self) -> String {
+        let mut out = format!("{}\nfile\t{}\n", MAP_HEADER, self.file);
+        for symbol in &self.symbols {
+            out.push_str(&format!("symbol\t{:#x}\t{}\n", symbol.addr, symbol.name));
+        }
+        for entry in &self.lines {
+            out.push_str(&format!(
+                "line\t{:#x}\t{:#x}\t{}\t{}\n",
+                entry.start, entry.end, entry.line, entry.text
+            ));
+        }
+        out
+    }
+
+    /// Reads a symbol map written by [`DebugInfo::to_map`].
+    pub fn parse_map(map: &str) -> Result<Self, String> {
+        let mut file = String::new();
+        let mut symbols = Vec::new();
+        let mut lines = Vec::new();
+        for (i, record) in map.lines().enumerate() {
+            if record.trim().is_empty() || record.starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid symbol map: line {}: {}", i + 1, record);
+            let fields = record.split('\t').collect::<Vec<_>>();
+            match fields.as_slice() {
+                ["file", _, ..] => file = record["file\t".len()..].to_string(),
+                ["symbol", addr, name] => symbols.push(Symbol {
+                    name: name.to_string(),
+                    addr: parse_map_addr(addr).ok_or_else(error)?,
+                }),
+                ["line", start, end, line, _, ..] => lines.push(LineEntry {
+                    start: parse_map_addr(start).ok_or_else(error)?,
+                    end: parse_map_addr(end).ok_or_else(error)?,
+                    line: line.parse().map_err(|_| error())?,
+                    // The source text is the rest of the record, tabs and all
+                    text: record.splitn(5, '\t').last().unwrap().to_string(),
+                }),
+                _ => return Err(error()),
+            }
+        }
+        Ok(Self::new(file, symbols, lines))
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_str(&mut out, &self.file);
+
+        put_u64(&mut out, self.symbols.len() as u64);
+        for symbol in &self.symbols {
+            put_u64(&mut out, symbol.addr as u64);
+            put_str(&mut out, &symbol.name);
+        }
+
+        put_u64(&mut out, self.lines.len() as u64);
+        for entry in &self.lines {
+            put_u64(&mut out, entry.start as u64);
+            put_u64(&mut out, entry.end as u64);
+            put_u64(&mut out, entry.line as u64);
+            put_str(&mut out, &entry.text);
+        }
+        out
+    }
+
+    pub(super) fn decode(mut bytes: &[u8]) -> Result<Self, String> {
+        let input = &mut bytes;
+        let file = get_str(input)?;
+
+        let symbols = (0..get_u64(input)?)
+            .map(|_| {
+                let addr = get_u64(input)? as i64;
+                let name = get_str(input)?;
+                Ok(Symbol { name, addr })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let lines = (0..get_u64(input)?)
+            .map(|_| {
+                let start = get_u64(input)? as i64;
+                let end = get_u64(input)? as i64;
+                let line = get_u64(input)? as usize;
+                let text = get_str(input)?;
+                Ok(LineEntry {
+                    start,
+                    end,
+                    line,
+                    text,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self::new(file, symbols, lines))
+    }
+}
+
+fn parse_map_addr(addr: &str) -> Option<i64> {
+    i64::from_str_radix(addr.strip_prefix("0x")?, 16).ok()
+}
+
+fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, value: &str) {
+    put_u64(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn get_bytes<'b>(input: &mut &'b [u8], len: usize) -> Result<&'b [u8], String> {
+    if input.len() < len {
+        return Err("Invalid debug section: unexpected end of data".to_string());
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+fn get_u64(input: &mut &[u8]) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(get_bytes(input, 8)?.try_into().unwrap()))
+}
+
+fn get_str(input: &mut &[u8]) -> Result<String, String> {
+    let len = get_u64(input)? as usize;
+    String::from_utf8(get_bytes(input, len)?.to_vec())
+        .map_err(|_| "Invalid debug section: string is not UTF-8".to_string())
+}
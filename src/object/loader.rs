@@ -0,0 +1,158 @@
+use super::{DebugInfo, LineEntry, ObjectFile, Region, Symbol};
+use crate::assembler::parse_and_gen_with_debug;
+
+/// The largest image a `.yo` file may describe, so that a stray address cannot make the
+/// loader allocate the whole address space.
+const MAX_IMAGE_SIZE: usize = 1 << 24;
+
+/// The on-disk formats a program can be loaded from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceFormat {
+    /// Assembly source
+    Assembly,
+    /// CS:APP textual object format, `0x000: 30f4... | source`
+    TextObject,
+    /// Binary object file, as written by `yas`
+    BinaryObject,
+}
+
+impl SourceFormat {
+    /// Guesses the format from the file extension, falling back to the contents.
+    pub fn detect(name: &str, contents: &[u8]) -> Self {
+        match name.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("ys") => SourceFormat::Assembly,
+            Some("yo") => SourceFormat::TextObject,
+            Some("yso") => SourceFormat::BinaryObject,
+            _ => Self::detect_contents(contents),
+        }
+    }
+
+    fn detect_contents(contents: &[u8]) -> Self {
+        let Ok(text) = std::str::from_utf8(contents) else {
+            return SourceFormat::BinaryObject;
+        };
+        // Machine code almost always contains a 0x00 (halt) or other control bytes
+        if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+            return SourceFormat::BinaryObject;
+        }
+        if text.lines().any(|line| parse_yo_line(line).is_some()) {
+            SourceFormat::TextObject
+        } else {
+            SourceFormat::Assembly
+        }
+    }
+}
+
+/// A program ready to be simulated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub code: Vec<u8>,
+    pub debug_info: Option<DebugInfo>,
+    /// The memory map from a binary object's regions section; empty for none.
+    pub regions: Vec<Region>,
+}
+
+/// Loads a program in any supported format, assembling it in memory if needed.
+pub fn load_program(name: &str, contents: &[u8]) -> Result<Program, String> {
+    match SourceFormat::detect(name, contents) {
+        SourceFormat::Assembly => {
+            let src = std::str::from_utf8(contents)
+                .map_err(|_| format!("{}: assembly source is not valid UTF-8", name))?;
+            let (_, assembled_code, debug_info) = parse_and_gen_with_debug(src, name)?;
+            Ok(Program {
+                code: assembled_code.bytes,
+                debug_info: Some(debug_info),
+                regions: Vec::new(),
+            })
+        }
+        SourceFormat::TextObject => {
+            let text = std::str::from_utf8(contents)
+                .map_err(|_| format!("{}: text object is not valid UTF-8", name))?;
+            parse_yo(name, text)
+        }
+        SourceFormat::BinaryObject => {
+            let object = ObjectFile::parse(contents)?;
+            Ok(Program {
+                code: object.code.to_vec(),
+                debug_info: object.debug_info,
+                regions: object.regions,
+            })
+        }
+    }
+}
+
+/// Splits a `.yo` line into its address, code bytes and source text.
+fn parse_yo_line(line: &str) -> Option<(usize, Vec<u8>, &str)> {
+    let (code_part, source) = line.split_once('|').unwrap_or((line, ""));
+    let (addr, hex) = code_part.trim().split_once(':')?;
+    let addr = usize::from_str_radix(addr.trim().strip_prefix("0x")?, 16).ok()?;
+
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some((addr, bytes, source.trim()))
+}
+
+/// Parses the CS:APP `.yo` format, keeping the source column as debug information.
+fn parse_yo(name: &str, text: &str) -> Result<Program, String> {
+    let mut code = Vec::new();
+    let mut symbols = Vec::new();
+    let mut lines = Vec::new();
+
+    for (line_number, line) in (1..).zip(text.lines()) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((addr, bytes, source)) = parse_yo_line(line) else {
+            // Lines without an address are comments, e.g. `                            | # data`
+            if line.trim_start().starts_with('|') {
+                continue;
+            }
+            return Err(format!(
+                "{}:{}: malformed line: {}",
+                name, line_number, line
+            ));
+        };
+
+        let end = addr
+            .checked_add(bytes.len())
+            .filter(|&end| end <= MAX_IMAGE_SIZE)
+            .ok_or_else(|| {
+                format!(
+                    "{}:{}: address {:#x} is beyond the {} byte image limit",
+                    name, line_number, addr, MAX_IMAGE_SIZE
+                )
+            })?;
+        if code.len() < end {
+            code.resize(end, 0);
+        }
+        code[addr..end].copy_from_slice(&bytes);
+
+        let statement = source.split('#').next().unwrap_or(source);
+        if let Some((label, _)) = statement.split_once(':') {
+            symbols.push(Symbol {
+                name: label.trim().to_string(),
+                addr: addr as i64,
+            });
+        }
+        if !bytes.is_empty() {
+            lines.push(LineEntry {
+                start: addr as i64,
+                end: end as i64,
+                line: line_number,
+                text: source.to_string(),
+            });
+        }
+    }
+
+    Ok(Program {
+        code,
+        debug_info: Some(DebugInfo::new(name.to_string(), symbols, lines)),
+        regions: Vec::new(),
+    })
+}
@@ -0,0 +1,207 @@
+use super::*;
+use crate::assembler::parse_and_gen_with_debug;
+use crate::ast::{CondOp, Instruction, LabOrImm};
+
+const SRC: &str = r#"# Sum two numbers
+    irmovq $1, %rax   # first
+    jmp skip
+    nop
+skip:
+    addq %rax, %rax
+    halt
+"#;
+
+fn assemble_with_debug(src: &str) -> (Vec<u8>, DebugInfo) {
+    let (_, code, debug_info) = parse_and_gen_with_debug(src, "sum.ys").unwrap();
+    (code.bytes, debug_info)
+}
+
+#[test]
+fn test_plain_object_has_no_sections() {
+    let code = [0x10, 0x00];
+    let object = ObjectFile::parse(&code).unwrap();
+    assert_eq!(object.code, &code);
+    assert!(object.debug_info.is_none());
+
+    let object = ObjectFile {
+        code: &code,
+        debug_info: None,
+        regions: Vec::new(),
+    };
+    assert_eq!(object.to_bytes(), code);
+}
+
+#[test]
+fn test_debug_section_round_trip() {
+    let (code, debug_info) = assemble_with_debug(SRC);
+    let object = ObjectFile {
+        code: &code,
+        debug_info: Some(debug_info.clone()),
+        regions: Vec::new(),
+    };
+
+    let bytes = object.to_bytes();
+    assert!(bytes.len() > code.len());
+
+    let parsed = ObjectFile::parse(&bytes).unwrap();
+    assert_eq!(parsed.code, code.as_slice());
+    assert_eq!(parsed.debug_info, Some(debug_info));
+}
+
+#[test]
+fn test_regions_round_trip() {
+    let code = [0x10, 0x00];
+    let regions = vec![
+        "text:0x0-0x2:r-x".parse().unwrap(),
+        "stack:0x300-0x400:rw-".parse().unwrap(),
+    ];
+    let object = ObjectFile {
+        code: &code,
+        debug_info: None,
+        regions: regions.clone(),
+    };
+
+    let bytes = object.to_bytes();
+    let parsed = ObjectFile::parse(&bytes).unwrap();
+    assert_eq!(parsed.code, &code);
+    assert!(parsed.debug_info.is_none());
+    assert_eq!(parsed.regions, regions);
+}
+
+#[test]
+fn test_truncated_debug_section_is_rejected() {
+    let (code, debug_info) = assemble_with_debug(SRC);
+    let bytes = ObjectFile {
+        code: &code,
+        debug_info: Some(debug_info),
+        regions: Vec::new(),
+    }
+    .to_bytes();
+
+    // Claim the code is shorter than it is, so the section header is misaligned
+    let mut corrupted = bytes.clone();
+    let trailer = corrupted.len() - TRAILER_LEN;
+    corrupted[trailer..trailer + 8].copy_from_slice(&(code.len() as u64 - 1).to_le_bytes());
+    assert!(ObjectFile::parse(&corrupted).is_err());
+}
+
+#[test]
+fn test_line_map_uses_original_line_numbers() {
+    let (_, debug_info) = assemble_with_debug(SRC);
+
+    let irmov = debug_info.line_at(0).unwrap();
+    assert_eq!(irmov.line, 2);
+    assert_eq!(irmov.text, "irmovq $1, %rax   # first");
+    assert_eq!(debug_info.line_at(9).unwrap().line, 2);
+
+    assert_eq!(debug_info.line_at(10).unwrap().line, 3); // jmp
+    assert_eq!(debug_info.line_at(20).unwrap().line, 6); // addq
+    assert!(debug_info.line_at(100).is_none());
+}
+
+#[test]
+fn test_symbol_lookup() {
+    let (_, debug_info) = assemble_with_debug(SRC);
+
+    assert_eq!(debug_info.symbol_at(20), Some("skip"));
+    assert_eq!(debug_info.symbol_at(21), None);
+    assert_eq!(debug_info.locate(22), Some(("skip", 2)));
+    assert_eq!(debug_info.locate(0), None);
+    assert_eq!(debug_info.describe(20).as_deref(), Some("skip"));
+    assert_eq!(debug_info.describe(22).as_deref(), Some("skip+0x2"));
+}
+
+#[test]
+fn test_symbolize_jump_target() {
+    let (_, debug_info) = assemble_with_debug(SRC);
+
+    let jump = Instruction::Jmp(CondOp::Uncon, LabOrImm::Immediate(20));
+    assert_eq!(
+        debug_info.symbolize(&jump),
+        Instruction::Jmp(CondOp::Uncon, LabOrImm::Labelled("skip".to_string()))
+    );
+
+    let unknown = Instruction::Call(LabOrImm::Immediate(3));
+    assert_eq!(debug_info.symbolize(&unknown), unknown);
+}
+
+#[test]
+fn test_detect_format_by_extension() {
+    assert_eq!(SourceFormat::detect("a.ys", b""), SourceFormat::Assembly);
+    assert_eq!(SourceFormat::detect("a.yo", b""), SourceFormat::TextObject);
+    assert_eq!(
+        SourceFormat::detect("a.yso", b"halt"),
+        SourceFormat::BinaryObject
+    );
+}
+
+#[test]
+fn test_detect_format_by_contents() {
+    assert_eq!(
+        SourceFormat::detect("-", SRC.as_bytes()),
+        SourceFormat::Assembly
+    );
+    assert_eq!(
+        SourceFormat::detect("-", b"0x000: 10 |   nop\n0x001: 00 |   halt\n"),
+        SourceFormat::TextObject
+    );
+    assert_eq!(
+        SourceFormat::detect("-", &[0x10, 0x00]),
+        SourceFormat::BinaryObject
+    );
+}
+
+#[test]
+fn test_load_assembly_matches_assembler() {
+    let (code, debug_info) = assemble_with_debug(SRC);
+    let program = load_program("sum.ys", SRC.as_bytes()).unwrap();
+    assert_eq!(program.code, code);
+    assert_eq!(program.debug_info, Some(debug_info));
+}
+
+#[test]
+fn test_load_text_object() {
+    let yo = "\
+                            | # Comment line
+0x000: 30f00500000000000000 |   irmovq $5, %rax
+0x00a:                      | loop:
+0x00a: 6000                 |   addq %rax, %rax
+0x010: 00                   |   halt
+";
+    let program = load_program("prog.yo", yo.as_bytes()).unwrap();
+    let (code, _) = assemble_with_debug("irmovq $5, %rax\naddq %rax, %rax");
+    assert_eq!(program.code[..12], code[..]);
+    assert_eq!(program.code[12..], [0, 0, 0, 0, 0]); // gap up to 0x010 is zero-filled
+
+    let debug_info = program.debug_info.unwrap();
+    assert_eq!(debug_info.symbol_at(10), Some("loop"));
+    assert_eq!(debug_info.line_at(10).unwrap().line, 4);
+    assert_eq!(debug_info.line_at(16).unwrap().text, "halt");
+}
+
+#[test]
+fn test_load_text_object_rejects_garbage() {
+    assert!(load_program("prog.yo", b"0x000: 3 | bad").is_err());
+}
+
+#[test]
+fn test_load_text_object_rejects_huge_addresses() {
+    let err = load_program("prog.yo", b"0xffffffffffffffff: 00 | halt").unwrap_err();
+    assert!(err.contains("image limit"), "{}", err);
+    assert!(load_program("prog.yo", b"0x7fffffff: 00 | halt").is_err());
+}
+
+#[test]
+fn test_symbol_map_round_trip() {
+    let (_, mut debug_info) = assemble_with_debug(SRC);
+    debug_info.lines[0].text = "irmovq $1, %rax\t# tab in a comment".to_string();
+    let map = debug_info.to_map();
+    assert!(map.contains("\nsymbol\t0x14\tskip\n"), "{}", map);
+    assert_eq!(DebugInfo::parse_map(&map), Ok(debug_info));
+
+    assert_eq!(
+        DebugInfo::parse_map("symbol\t14\tskip\n"),
+        Err("Invalid symbol map: line 1: symbol\t14\tskip".to_string())
+    );
+    assert!(DebugInfo::parse_map("\n# comment\nfile\ta.ys\n").is_ok());
+}
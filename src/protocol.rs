@@ -0,0 +1,58 @@
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver};
+
+/// Reads one `Content-Length` framed JSON message, as used by the Debug Adapter Protocol and
+/// the Language Server Protocol.
+///
+/// Returns `None` at end of input.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            content_length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(content_length) = content_length else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing Content-Length header",
+        ));
+    };
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes `message` with a `Content-Length` header.
+pub fn write_message(out: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+/// Reads messages from `input` on a background thread until it ends or a message is malformed.
+pub fn spawn_reader(input: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = input;
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
@@ -1,25 +1,50 @@
+#[cfg(all(feature = "jit", target_arch = "x86_64"))]
+pub mod jit;
+pub mod branch;
+pub mod cache;
+pub mod calls;
+pub mod convention;
+pub mod device;
+pub mod gdb_stub;
+pub mod interrupt;
+pub mod memory_map;
+pub mod mmu;
+pub mod observer;
+pub mod profiler;
+pub mod sanitizer;
 pub mod simulator_guts;
+pub mod sparse_memory;
+pub mod syscall;
+pub mod trace;
 use simulator_guts::Simulator;
 
 type SimulationResult<'a, const MEM_SIZE: usize> = Simulator<'a, MEM_SIZE>;
+
+/// Knobs for [`simulate_with_options`].
+#[derive(Default)]
+pub struct SimulationOptions {
+    /// Stop with `Status::StepLimitExceeded` after this many instructions
+    pub max_steps: Option<u64>,
+    /// Stop with `Status::InfiniteLoop` when the machine state repeats exactly
+    pub detect_loops: bool,
+}
+
 /// Run Simulator Until Halt or Error
 pub fn simulate<'a, const MEM_SIZE: usize>(src: &'a [u8]) -> SimulationResult<'a, MEM_SIZE> {
-    let mut state = Simulator::<'a, MEM_SIZE>::new(src);
+    simulate_with_options(src, &SimulationOptions::default())
+}
 
-
-    loop {
-        state.run_single();
-        let (ip, asm_line) = state.disassembly.last().unwrap();
-        println!("Last Line: {}: {}", ip, asm_line);
-        
-        if state.state == simulator_guts::Status::Halted {
-            return state;
-        }
-        
-        if let simulator_guts::Status::Error(e) = &state.state {
-            println!("Error: {}", e);
-            return state;
-        }
-        
+/// Run Simulator Until Halt, Error, or one of the limits in `options` is reached
+pub fn simulate_with_options<'a, const MEM_SIZE: usize>(
+    src: &'a [u8],
+    options: &SimulationOptions,
+) -> SimulationResult<'a, MEM_SIZE> {
+    let mut state = Simulator::<'a, MEM_SIZE>::new(src);
+    state.max_steps = options.max_steps;
+    if options.detect_loops {
+        state = state.with_loop_detection();
     }
-}
\ No newline at end of file
+
+    state.run();
+    state
+}
@@ -0,0 +1,486 @@
+use super::observer::Observer;
+use crate::ast::{CondOp, Instruction, LabOrImm, OwnedInstruction};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+#[cfg(test)]
+mod branch_tests;
+
+/// Entries in the 1-bit and 2-bit tables unless told otherwise.
+pub const DEFAULT_TABLE_SIZE: usize = 64;
+/// Bits of global history gshare keeps unless told otherwise.
+pub const DEFAULT_HISTORY_BITS: u32 = 6;
+/// Return addresses the return-address stack holds unless told otherwise.
+pub const DEFAULT_RETURN_STACK_DEPTH: usize = 16;
+/// Bubbles after a mispredicted jump, which is resolved in the execute stage.
+pub const JUMP_PENALTY: u64 = 2;
+/// Bubbles after a mispredicted `ret`, whose address is only known after the memory stage.
+pub const RETURN_PENALTY: u64 = 3;
+/// Cycles the pipeline takes to fill before the first instruction completes.
+pub const PIPELINE_DEPTH: u64 = 5;
+
+/// Predicts the direction of conditional jumps, learning from their outcomes.
+pub trait BranchPredictor {
+    /// A short description for reports.
+    fn name(&self) -> String;
+    /// Whether the jump at `pc` to `target` will be taken.
+    fn predict(&mut self, pc: i64, target: i64) -> bool;
+    /// Learns that the jump at `pc` to `target` was, or was not, taken.
+    fn update(&mut self, _pc: i64, _target: i64, _taken: bool) {}
+}
+
+/// Predicts every jump taken.
+#[derive(Debug, Default)]
+pub struct AlwaysTaken;
+
+impl BranchPredictor for AlwaysTaken {
+    fn name(&self) -> String {
+        "always taken".to_string()
+    }
+
+    fn predict(&mut self, _pc: i64, _target: i64) -> bool {
+        true
+    }
+}
+
+/// Backward taken, forward not taken: predicts loops to repeat.
+#[derive(Debug, Default)]
+pub struct Btfnt;
+
+impl BranchPredictor for Btfnt {
+    fn name(&self) -> String {
+        "BTFNT".to_string()
+    }
+
+    fn predict(&mut self, pc: i64, target: i64) -> bool {
+        target <= pc
+    }
+}
+
+/// Predicts that each jump goes the way it went last time, with one bit per table entry.
+#[derive(Debug)]
+pub struct OneBit {
+    table: Vec<bool>,
+}
+
+impl OneBit {
+    /// Entries are shared by jumps whose PCs are equal modulo `size`.
+    pub fn new(size: usize) -> Self {
+        Self {
+            table: vec![false; size.max(1)],
+        }
+    }
+}
+
+impl BranchPredictor for OneBit {
+    fn name(&self) -> String {
+        format!("1-bit, {} entries", self.table.len())
+    }
+
+    fn predict(&mut self, pc: i64, _target: i64) -> bool {
+        self.table[pc.rem_euclid(self.table.len() as i64) as usize]
+    }
+
+    fn update(&mut self, pc: i64, _target: i64, taken: bool) {
+        let index = pc.rem_euclid(self.table.len() as i64) as usize;
+        self.table[index] = taken;
+    }
+}
+
+/// Moves a 2-bit saturating counter towards `taken`.
+fn train(counter: &mut u8, taken: bool) {
+    *counter = if taken {
+        (*counter + 1).min(3)
+    } else {
+        counter.saturating_sub(1)
+    };
+}
+
+/// Predicts with 2-bit saturating counters, so one surprise does not flip a prediction.
+/// Counters start weakly not taken.
+#[derive(Debug)]
+pub struct TwoBit {
+    table: Vec<u8>,
+}
+
+impl TwoBit {
+    /// Entries are shared by jumps whose PCs are equal modulo `size`.
+    pub fn new(size: usize) -> Self {
+        Self {
+            table: vec![1; size.max(1)],
+        }
+    }
+}
+
+impl BranchPredictor for TwoBit {
+    fn name(&self) -> String {
+        format!("2-bit, {} entries", self.table.len())
+    }
+
+    fn predict(&mut self, pc: i64, _target: i64) -> bool {
+        self.table[pc.rem_euclid(self.table.len() as i64) as usize] >= 2
+    }
+
+    fn update(&mut self, pc: i64, _target: i64, taken: bool) {
+        let index = pc.rem_euclid(self.table.len() as i64) as usize;
+        train(&mut self.table[index], taken);
+    }
+}
+
+/// 2-bit counters indexed by the PC XORed with the outcomes of the most recent jumps.
+#[derive(Debug)]
+pub struct Gshare {
+    history: u64,
+    history_bits: u32,
+    table: Vec<u8>,
+}
+
+impl Gshare {
+    /// Keeps `history_bits` outcomes, with a table of `2^history_bits` counters.
+    pub fn new(history_bits: u32) -> Self {
+        let history_bits = history_bits.clamp(1, 20);
+        Self {
+            history: 0,
+            history_bits,
+            table: vec![1; 1 << history_bits],
+        }
+    }
+
+    fn index(&self, pc: i64) -> usize {
+        ((pc as u64 ^ self.history) & ((1 << self.history_bits) - 1)) as usize
+    }
+}
+
+impl BranchPredictor for Gshare {
+    fn name(&self) -> String {
+        format!("gshare, {} bits of history", self.history_bits)
+    }
+
+    fn predict(&mut self, pc: i64, _target: i64) -> bool {
+        self.table[self.index(pc)] >= 2
+    }
+
+    fn update(&mut self, pc: i64, _target: i64, taken: bool) {
+        let index = self.index(pc);
+        train(&mut self.table[index], taken);
+        self.history = ((self.history << 1) | taken as u64) & ((1 << self.history_bits) - 1);
+    }
+}
+
+/// Parses `taken`, `btfnt`, `1bit[:SIZE]`, `2bit[:SIZE]` or `gshare[:HISTORY_BITS]`.
+pub fn parse_predictor(spec: &str) -> Result<Box<dyn BranchPredictor>, String> {
+    let (name, size) = match spec.split_once(':') {
+        Some((name, size)) => (
+            name,
+            Some(
+                size.parse::<usize>()
+                    .map_err(|_| format!("Invalid predictor size in {}", spec))?,
+            ),
+        ),
+        None => (spec, None),
+    };
+    Ok(match (name, size) {
+        ("taken", None) => Box::new(AlwaysTaken),
+        ("btfnt", None) => Box::new(Btfnt),
+        ("1bit", size) => Box::new(OneBit::new(size.unwrap_or(DEFAULT_TABLE_SIZE))),
+        ("2bit", size) => Box::new(TwoBit::new(size.unwrap_or(DEFAULT_TABLE_SIZE))),
+        ("gshare", bits) => Box::new(Gshare::new(
+            bits.map_or(DEFAULT_HISTORY_BITS, |bits| bits as u32),
+        )),
+        _ => return Err(format!("Unknown branch predictor: {}", spec)),
+    })
+}
+
+/// Predicts return addresses by pushing the address after each `call`. When full, the
+/// oldest address is dropped.
+#[derive(Debug)]
+pub struct ReturnStack {
+    depth: usize,
+    stack: Vec<i64>,
+}
+
+impl ReturnStack {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            stack: Vec::with_capacity(depth),
+        }
+    }
+
+    fn push(&mut self, addr: i64) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.stack.len() == self.depth {
+            self.stack.remove(0);
+        }
+        self.stack.push(addr);
+    }
+
+    fn pop(&mut self) -> Option<i64> {
+        self.stack.pop()
+    }
+}
+
+/// How often predictions for one branch, or for all of them, were right.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PredictionStats {
+    pub predictions: u64,
+    pub correct: u64,
+}
+
+impl PredictionStats {
+    /// Correct predictions per prediction, or 1 before any prediction.
+    pub fn accuracy(&self) -> f64 {
+        match self.predictions {
+            0 => 1.0,
+            predictions => self.correct as f64 / predictions as f64,
+        }
+    }
+
+    fn record(&mut self, correct: bool) {
+        self.predictions += 1;
+        self.correct += correct as u64;
+    }
+}
+
+/// A prediction waiting for the next instruction to show whether it was right.
+#[derive(Debug)]
+enum Pending {
+    Jump {
+        pc: i64,
+        target: i64,
+        fall_through: i64,
+        predicted: bool,
+    },
+    Return {
+        pc: i64,
+        predicted: Option<i64>,
+    },
+}
+
+/// An analytic estimate of the cycles a five-stage pipeline whose fetch stage follows the
+/// predictions would take.
+///
+/// No pipeline is simulated: each retired instruction counts one cycle, each misprediction a
+/// fixed penalty and the pipeline fills once. Wrong-path instructions are never fetched, so
+/// their effects on caches and predictors, as well as data hazards, are not modelled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CpiEstimate {
+    pub instructions: u64,
+    /// Cycles charged for mispredictions
+    pub bubbles: u64,
+}
+
+impl CpiEstimate {
+    pub fn cycles(&self) -> u64 {
+        match self.instructions {
+            0 => 0,
+            instructions => instructions + self.bubbles + PIPELINE_DEPTH - 1,
+        }
+    }
+
+    /// Cycles per instruction.
+    pub fn cpi(&self) -> f64 {
+        match self.instructions {
+            0 => 0.0,
+            instructions => self.cycles() as f64 / instructions as f64,
+        }
+    }
+}
+
+/// Observes conditional jumps and `ret`s, predicting each before it executes and counting
+/// how often the predictions were right, per branch and overall.
+///
+/// Conditional jumps go to `predictor`; returns go to a return-address stack. A prediction
+/// is checked against the next instruction fetched, and dropped if that is neither way the
+/// branch could go, as when an interrupt intervenes.
+///
+/// Attach it with [`Simulator::add_observer`](
+/// super::simulator_guts::Simulator::add_observer), keeping a handle through an
+/// `Rc<RefCell<_>>` to read the counts after the run.
+pub struct BranchProfiler {
+    predictor: Box<dyn BranchPredictor>,
+    return_stack: ReturnStack,
+    pending: Option<Pending>,
+    jumps: BTreeMap<i64, PredictionStats>,
+    returns: BTreeMap<i64, PredictionStats>,
+    cpi_estimate: Option<CpiEstimate>,
+}
+
+impl BranchProfiler {
+    pub fn new(predictor: Box<dyn BranchPredictor>) -> Self {
+        Self {
+            predictor,
+            return_stack: ReturnStack::new(DEFAULT_RETURN_STACK_DEPTH),
+            pending: None,
+            jumps: BTreeMap::new(),
+            returns: BTreeMap::new(),
+            cpi_estimate: None,
+        }
+    }
+
+    /// Predicts returns with a stack of `depth` addresses; 0 predicts none of them.
+    pub fn with_return_stack(mut self, depth: usize) -> Self {
+        self.return_stack = ReturnStack::new(depth);
+        self
+    }
+
+    /// Also estimates pipeline cycles, charging `JUMP_PENALTY` bubbles for every mispredicted
+    /// jump and `RETURN_PENALTY` for every mispredicted return.
+    pub fn with_cpi_estimate(mut self) -> Self {
+        self.cpi_estimate = Some(CpiEstimate::default());
+        self
+    }
+
+    /// Counts for each conditional jump, by PC.
+    pub fn jumps(&self) -> &BTreeMap<i64, PredictionStats> {
+        &self.jumps
+    }
+
+    /// Counts for each `ret`, by PC.
+    pub fn returns(&self) -> &BTreeMap<i64, PredictionStats> {
+        &self.returns
+    }
+
+    pub fn cpi_estimate(&self) -> Option<CpiEstimate> {
+        self.cpi_estimate
+    }
+
+    fn total(branches: &BTreeMap<i64, PredictionStats>) -> PredictionStats {
+        branches
+            .values()
+            .fold(PredictionStats::default(), |total, stats| PredictionStats {
+                predictions: total.predictions + stats.predictions,
+                correct: total.correct + stats.correct,
+            })
+    }
+
+    /// All conditional jumps together.
+    pub fn jump_total(&self) -> PredictionStats {
+        Self::total(&self.jumps)
+    }
+
+    /// All returns together.
+    pub fn return_total(&self) -> PredictionStats {
+        Self::total(&self.returns)
+    }
+
+    /// Checks the pending prediction against `ip`, the next instruction fetched.
+    fn resolve(&mut self, ip: i64) {
+        let (correct, penalty) = match self.pending.take() {
+            Some(Pending::Jump {
+                pc,
+                target,
+                fall_through,
+                predicted,
+            }) => {
+                let taken = if ip == target {
+                    // A jump to the next instruction goes there either way
+                    target != fall_through || predicted
+                } else if ip == fall_through {
+                    false
+                } else {
+                    return;
+                };
+                self.predictor.update(pc, target, taken);
+                let correct = taken == predicted;
+                self.jumps.entry(pc).or_default().record(correct);
+                (correct, JUMP_PENALTY)
+            }
+            Some(Pending::Return { pc, predicted }) => {
+                let correct = predicted == Some(ip);
+                self.returns.entry(pc).or_default().record(correct);
+                (correct, RETURN_PENALTY)
+            }
+            None => return,
+        };
+        if let Some(estimate) = &mut self.cpi_estimate
+            && !correct
+        {
+            estimate.bubbles += penalty;
+        }
+    }
+
+    /// The accuracy of each branch and overall, and the estimated cycles if asked for.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "Branch predictor: {}", self.predictor.name());
+        let _ = writeln!(
+            report,
+            "  {:<8}{:<6}{:>12}{:>10}{:>10}",
+            "PC", "Kind", "Predictions", "Correct", "Accuracy"
+        );
+        let kinds = [("jump", &self.jumps), ("ret", &self.returns)];
+        let mut rows: Vec<_> = kinds
+            .iter()
+            .flat_map(|(kind, branches)| {
+                branches.iter().map(move |(pc, stats)| (*pc, *kind, *stats))
+            })
+            .collect();
+        rows.sort_by_key(|(pc, ..)| *pc);
+        let totals = [("jumps", self.jump_total()), ("rets", self.return_total())];
+        for (pc, kind, stats) in rows {
+            let _ = writeln!(
+                report,
+                "  {:<#8x}{:<6}{:>12}{:>10}{:>9.1}%",
+                pc,
+                kind,
+                stats.predictions,
+                stats.correct,
+                stats.accuracy() * 100.0
+            );
+        }
+        for (kind, stats) in totals {
+            let _ = writeln!(
+                report,
+                "  {:<8}{:<6}{:>12}{:>10}{:>9.1}%",
+                "all",
+                kind,
+                stats.predictions,
+                stats.correct,
+                stats.accuracy() * 100.0
+            );
+        }
+        if let Some(estimate) = self.cpi_estimate {
+            let _ = writeln!(
+                report,
+                "Estimated pipeline: {} cycles, {} bubbles, CPI {:.2}",
+                estimate.cycles(),
+                estimate.bubbles,
+                estimate.cpi()
+            );
+        }
+        report
+    }
+}
+
+impl Observer for BranchProfiler {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        self.resolve(ip);
+        if let Some(estimate) = &mut self.cpi_estimate {
+            estimate.instructions += 1;
+        }
+        match instruction {
+            &Instruction::Jmp(cond, LabOrImm::Immediate(target)) if cond != CondOp::Uncon => {
+                let predicted = self.predictor.predict(ip, target);
+                self.pending = Some(Pending::Jump {
+                    pc: ip,
+                    target,
+                    fall_through: ip + instruction.encoded_len() as i64,
+                    predicted,
+                });
+            }
+            Instruction::Call(_) => {
+                self.return_stack
+                    .push(ip + instruction.encoded_len() as i64);
+            }
+            Instruction::Ret => {
+                self.pending = Some(Pending::Return {
+                    pc: ip,
+                    predicted: self.return_stack.pop(),
+                });
+            }
+            _ => {}
+        }
+    }
+}
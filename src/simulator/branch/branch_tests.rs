@@ -0,0 +1,116 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::observer::run_observed;
+
+/// Feeds `outcomes` of one jump at 0x40 to `predictor`, returning how many it got right.
+fn correct(predictor: &mut dyn BranchPredictor, target: i64, outcomes: &[bool]) -> usize {
+    outcomes
+        .iter()
+        .filter(|&&taken| {
+            let predicted = predictor.predict(0x40, target);
+            predictor.update(0x40, target, taken);
+            predicted == taken
+        })
+        .count()
+}
+
+#[test]
+fn test_predictors() {
+    // A loop taken three times, then left, twice over
+    let looping = [true, true, true, false, true, true, true, false];
+    assert_eq!(correct(&mut AlwaysTaken, 0x80, &looping), 6);
+    assert_eq!(correct(&mut Btfnt, 0x80, &looping), 2);
+    assert_eq!(correct(&mut Btfnt, 0x20, &looping), 6);
+    // One bit mispredicts on entering and on leaving each time round
+    assert_eq!(correct(&mut OneBit::new(16), 0x20, &looping), 4);
+    assert_eq!(correct(&mut TwoBit::new(16), 0x20, &looping), 5);
+
+    // Only global history captures an alternating pattern
+    let alternating: Vec<_> = (0..64).map(|i| i % 2 == 0).collect();
+    assert!(correct(&mut TwoBit::new(16), 0x20, &alternating) < 8);
+    assert!(correct(&mut Gshare::new(4), 0x20, &alternating) > 56);
+}
+
+#[test]
+fn test_parse_predictor() {
+    assert_eq!(parse_predictor("taken").unwrap().name(), "always taken");
+    assert_eq!(parse_predictor("btfnt").unwrap().name(), "BTFNT");
+    assert_eq!(
+        parse_predictor("2bit:8").unwrap().name(),
+        "2-bit, 8 entries"
+    );
+    assert_eq!(
+        parse_predictor("gshare").unwrap().name(),
+        "gshare, 6 bits of history"
+    );
+    for bad in ["perceptron", "taken:4", "1bit:x"] {
+        assert!(parse_predictor(bad).is_err(), "{}", bad);
+    }
+}
+
+const PROGRAM: &str = "
+    irmovq $4, %rcx
+    irmovq $1, %rdx
+loop:
+    subq %rdx, %rcx
+    jne loop
+    call f
+    halt
+f:
+    call g
+    ret
+g:
+    ret";
+
+fn profile(profiler: BranchProfiler) -> BranchProfiler {
+    let code = parse_and_gen(PROGRAM).unwrap().1.bytes;
+    run_observed(&code, profiler)
+}
+
+#[test]
+fn test_profiler() {
+    let profiler = profile(BranchProfiler::new(Box::new(Btfnt)).with_cpi_estimate());
+    assert_eq!(
+        profiler.jumps()[&22],
+        PredictionStats {
+            predictions: 4,
+            correct: 3
+        }
+    );
+    assert_eq!(profiler.return_total().correct, 2);
+    // 15 instructions, one mispredicted jump and a pipeline to fill
+    let estimate = profiler.cpi_estimate().unwrap();
+    assert_eq!(estimate.instructions, 15);
+    assert_eq!(estimate.bubbles, JUMP_PENALTY);
+    assert_eq!(estimate.cycles(), 15 + JUMP_PENALTY + PIPELINE_DEPTH - 1);
+    assert!(
+        profiler
+            .report()
+            .contains("all     jumps            4         3     75.0%")
+    );
+}
+
+#[test]
+fn test_return_stack() {
+    // Only the innermost return address fits
+    let profiler = profile(
+        BranchProfiler::new(Box::new(AlwaysTaken))
+            .with_return_stack(1)
+            .with_cpi_estimate(),
+    );
+    assert_eq!(
+        profiler.return_total(),
+        PredictionStats {
+            predictions: 2,
+            correct: 1
+        }
+    );
+    assert_eq!(
+        profiler.cpi_estimate().unwrap().bubbles,
+        JUMP_PENALTY + RETURN_PENALTY
+    );
+
+    let profiler = profile(BranchProfiler::new(Box::new(AlwaysTaken)).with_return_stack(0));
+    assert_eq!(profiler.return_total().correct, 0);
+    assert_eq!(profiler.cpi_estimate(), None);
+}
@@ -0,0 +1,495 @@
+use super::observer::Observer;
+use crate::ast::OwnedInstruction;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as _};
+use std::str::FromStr;
+#[cfg(test)]
+mod cache_tests;
+
+/// Index of each level in [`CacheHierarchy::per_pc`] entries.
+pub const L1I: usize = 0;
+pub const L1D: usize = 1;
+pub const L2: usize = 2;
+
+/// What `y86 run` uses for an L1 cache that is not configured: 256 bytes, two ways of
+/// 16-byte blocks.
+pub const DEFAULT_L1: CacheConfig = CacheConfig {
+    size: 256,
+    associativity: 2,
+    block_size: 16,
+    replacement: Replacement::Lru,
+    write_policy: WritePolicy::WriteBack,
+};
+
+/// Bytes touched by a data access: one quad.
+const QUAD: i64 = 8;
+
+/// Which line of a full set makes way for a new block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    /// The least recently used
+    Lru,
+    /// The one filled first
+    Fifo,
+    /// One picked by a generator seeded with this value, so runs repeat exactly
+    Random { seed: u64 },
+}
+
+/// How a cache handles writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    /// Writes allocate the block on a miss and mark it dirty; dirty blocks reach the next
+    /// level when they are evicted
+    WriteBack,
+    /// Writes go straight to the next level, updating the block only if it is present
+    WriteThrough,
+}
+
+/// The geometry and policies of one cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheConfig {
+    /// Capacity in bytes
+    pub size: usize,
+    /// Lines per set
+    pub associativity: usize,
+    /// Bytes per line, a power of two
+    pub block_size: usize,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+}
+
+impl CacheConfig {
+    /// An LRU, write-back cache.
+    pub fn new(size: usize, associativity: usize, block_size: usize) -> Self {
+        Self {
+            size,
+            associativity,
+            block_size,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+        }
+    }
+
+    pub fn with_replacement(mut self, replacement: Replacement) -> Self {
+        self.replacement = replacement;
+        self
+    }
+
+    pub fn with_write_policy(mut self, write_policy: WritePolicy) -> Self {
+        self.write_policy = write_policy;
+        self
+    }
+
+    fn sets(&self) -> usize {
+        self.size / (self.associativity * self.block_size)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !self.block_size.is_power_of_two() {
+            return Err(format!(
+                "Cache block size must be a power of two: {}",
+                self.block_size
+            ));
+        }
+        if self.associativity == 0 {
+            return Err("Cache associativity must be at least 1".to_string());
+        }
+        let set_size = self
+            .associativity
+            .checked_mul(self.block_size)
+            .filter(|_| self.size <= i64::MAX as usize)
+            .ok_or_else(|| format!("Cache {} is too large", self))?;
+        if self.size == 0 || !self.size.is_multiple_of(set_size) {
+            return Err(format!(
+                "Cache size {} is not a multiple of {} ways of {} bytes",
+                self.size, self.associativity, self.block_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parses `SIZE:WAYS:BLOCK`, optionally followed by `:lru`, `:fifo` or `:random[=SEED]` and
+/// by `:wb` or `:wt`.
+impl FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut fields = spec.split(':');
+        let mut number = |name: &str| {
+            let field = fields.next().unwrap_or_default();
+            field
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid cache {} in {}: {:?}", name, spec, field))
+        };
+        let mut config = CacheConfig::new(number("size")?, number("ways")?, number("block")?);
+        for field in fields {
+            match field {
+                "lru" => config.replacement = Replacement::Lru,
+                "fifo" => config.replacement = Replacement::Fifo,
+                "random" => config.replacement = Replacement::Random { seed: 1 },
+                "wb" => config.write_policy = WritePolicy::WriteBack,
+                "wt" => config.write_policy = WritePolicy::WriteThrough,
+                _ => match field.strip_prefix("random=").map(str::parse) {
+                    Some(Ok(seed)) => config.replacement = Replacement::Random { seed },
+                    _ => return Err(format!("Invalid cache option in {}: {:?}", spec, field)),
+                },
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Display for CacheConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.size, self.associativity, self.block_size
+        )?;
+        match self.replacement {
+            Replacement::Lru => write!(f, ":lru")?,
+            Replacement::Fifo => write!(f, ":fifo")?,
+            Replacement::Random { seed } => write!(f, ":random={}", seed)?,
+        }
+        match self.write_policy {
+            WritePolicy::WriteBack => write!(f, ":wb"),
+            WritePolicy::WriteThrough => write!(f, ":wt"),
+        }
+    }
+}
+
+/// Counts for one cache, or for one cache and PC.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Lines replaced to make room for another block
+    pub evictions: u64,
+    /// Evicted lines that were dirty and written to the next level
+    pub writebacks: u64,
+}
+
+impl CacheStats {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// Misses per access, or 0 before any access.
+    pub fn miss_rate(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            accesses => self.misses as f64 / accesses as f64,
+        }
+    }
+
+    fn since(&self, before: &CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits - before.hits,
+            misses: self.misses - before.misses,
+            evictions: self.evictions - before.evictions,
+            writebacks: self.writebacks - before.writebacks,
+        }
+    }
+
+    fn add(&mut self, other: &CacheStats) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.writebacks += other.writebacks;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    block: i64,
+    dirty: bool,
+    last_used: u64,
+    filled: u64,
+}
+
+/// One set-associative cache, tracking which blocks it holds but not their contents.
+#[derive(Debug)]
+pub struct Cache {
+    config: CacheConfig,
+    /// The lines of each set by index, once the set has been used
+    sets: BTreeMap<i64, Vec<Line>>,
+    /// Counts accesses, to order lines for LRU and FIFO
+    clock: u64,
+    /// State of the xorshift generator for random replacement
+    random: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Result<Self, String> {
+        config.validate()?;
+        let random = match config.replacement {
+            // Xorshift never leaves 0
+            Replacement::Random { seed } => seed.max(1),
+            _ => 1,
+        };
+        Ok(Self {
+            config,
+            sets: BTreeMap::new(),
+            clock: 0,
+            random,
+            stats: CacheStats::default(),
+        })
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Looks up the block holding `addr`, filling it on a miss unless this is a write to a
+    /// write-through cache. Returns whether it hit, and the address of any dirty block
+    /// evicted to make room.
+    fn access(&mut self, addr: i64, write: bool) -> (bool, Option<i64>) {
+        self.clock += 1;
+        let block_size = self.config.block_size as i64;
+        let block = addr.div_euclid(block_size);
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+        let set_count = self.config.sets() as i64;
+        let set = self.sets.entry(block.rem_euclid(set_count)).or_default();
+        if let Some(line) = set.iter_mut().find(|line| line.block == block) {
+            self.stats.hits += 1;
+            line.last_used = self.clock;
+            line.dirty |= write && write_back;
+            return (true, None);
+        }
+        self.stats.misses += 1;
+        if write && !write_back {
+            return (false, None);
+        }
+        let line = Line {
+            block,
+            dirty: write,
+            last_used: self.clock,
+            filled: self.clock,
+        };
+        if set.len() < self.config.associativity {
+            set.push(line);
+            return (false, None);
+        }
+        let victim = match self.config.replacement {
+            Replacement::Lru => (0..set.len()).min_by_key(|&i| set[i].last_used),
+            Replacement::Fifo => (0..set.len()).min_by_key(|&i| set[i].filled),
+            Replacement::Random { .. } => {
+                self.random ^= self.random << 13;
+                self.random ^= self.random >> 7;
+                self.random ^= self.random << 17;
+                Some(self.random as usize % set.len())
+            }
+        }
+        .unwrap();
+        let evicted = std::mem::replace(&mut set[victim], line);
+        self.stats.evictions += 1;
+        if evicted.dirty {
+            self.stats.writebacks += 1;
+            return (false, Some(evicted.block * block_size));
+        }
+        (false, None)
+    }
+}
+
+/// Split L1 instruction and data caches, optionally backed by a unified L2, fed with every
+/// fetch and data access of the program it observes.
+///
+/// Instructions are fetched by PC and data accessed a quad at a time, so an access may span
+/// two blocks. Counts are kept per cache and per PC of the instruction responsible.
+///
+/// Only what the program itself fetches and accesses is counted: device accesses, buffers
+/// read and written by system calls and page-table walks never reach the caches. Addresses are
+/// taken as observed, which are virtual for fetches but physical for data once an MMU is
+/// mapped, so the hierarchy is meant for programs without one.
+///
+/// Attach it with [`Simulator::add_observer`](
+/// super::simulator_guts::Simulator::add_observer), keeping a handle through an
+/// `Rc<RefCell<_>>` to read the counts after the run.
+#[derive(Debug)]
+pub struct CacheHierarchy {
+    l1i: Cache,
+    l1d: Cache,
+    l2: Option<Cache>,
+    /// Accesses at or above this address reach devices, bypassing the caches
+    memory_size: i64,
+    /// The instruction being executed
+    pc: i64,
+    per_pc: BTreeMap<i64, [CacheStats; 3]>,
+}
+
+impl CacheHierarchy {
+    pub fn new(
+        l1i: CacheConfig,
+        l1d: CacheConfig,
+        l2: Option<CacheConfig>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            l1i: Cache::new(l1i)?,
+            l1d: Cache::new(l1d)?,
+            l2: l2.map(Cache::new).transpose()?,
+            memory_size: i64::MAX,
+            pc: 0,
+            per_pc: BTreeMap::new(),
+        })
+    }
+
+    /// Leaves accesses at or above `memory_size`, where devices are mapped, uncached.
+    pub fn with_memory_size(mut self, memory_size: usize) -> Self {
+        self.memory_size = memory_size as i64;
+        self
+    }
+
+    pub fn l1i(&self) -> &Cache {
+        &self.l1i
+    }
+
+    pub fn l1d(&self) -> &Cache {
+        &self.l1d
+    }
+
+    pub fn l2(&self) -> Option<&Cache> {
+        self.l2.as_ref()
+    }
+
+    /// Counts for each PC, indexed by [`L1I`], [`L1D`] and [`L2`].
+    pub fn per_pc(&self) -> &BTreeMap<i64, [CacheStats; 3]> {
+        &self.per_pc
+    }
+
+    /// Fetches the `len` bytes of an instruction at `addr`.
+    pub fn fetch(&mut self, addr: i64, len: i64) {
+        self.attributed(|caches| caches.access(L1I, addr, len, false));
+    }
+
+    /// Reads the quad at `addr`.
+    pub fn read(&mut self, addr: i64) {
+        self.attributed(|caches| caches.access(L1D, addr, QUAD, false));
+    }
+
+    /// Writes the quad at `addr`.
+    pub fn write(&mut self, addr: i64) {
+        self.attributed(|caches| caches.access(L1D, addr, QUAD, true));
+    }
+
+    /// Runs `f`, adding the counts it changes to the current PC's.
+    fn attributed(&mut self, f: impl FnOnce(&mut Self)) {
+        let before = self.totals();
+        f(self);
+        let after = self.totals();
+        let counts = self.per_pc.entry(self.pc).or_default();
+        for level in 0..3 {
+            counts[level].add(&after[level].since(&before[level]));
+        }
+    }
+
+    fn totals(&self) -> [CacheStats; 3] {
+        [
+            self.l1i.stats,
+            self.l1d.stats,
+            self.l2.as_ref().map(Cache::stats).unwrap_or_default(),
+        ]
+    }
+
+    fn access(&mut self, level: usize, addr: i64, len: i64, write: bool) {
+        let l1 = if level == L1I {
+            &mut self.l1i
+        } else {
+            &mut self.l1d
+        };
+        let block_size = l1.config.block_size as i64;
+        let write_through = l1.config.write_policy == WritePolicy::WriteThrough;
+        let first = addr.div_euclid(block_size);
+        let last = (addr + len - 1).div_euclid(block_size);
+        let mut next_level = Vec::new();
+        for block in first..=last {
+            let block_addr = block * block_size;
+            let (hit, evicted) = l1.access(block_addr, write);
+            if let Some(evicted) = evicted {
+                next_level.push((evicted, true));
+            }
+            if write && write_through {
+                next_level.push((block_addr, true));
+            } else if !hit {
+                next_level.push((block_addr, false));
+            }
+        }
+        // Nothing lies beyond the L2, so its own evictions go nowhere
+        if let Some(l2) = &mut self.l2 {
+            for (addr, write) in next_level {
+                l2.access(addr, write);
+            }
+        }
+    }
+
+    /// The counts for each cache and for each PC, as a table.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let levels = [("L1I", Some(&self.l1i)), ("L1D", Some(&self.l1d))]
+            .into_iter()
+            .chain([("L2", self.l2.as_ref())])
+            .filter_map(|(name, cache)| Some((name, cache?)));
+        let _ = writeln!(
+            report,
+            "{:<5}{:<24}{:>10}{:>10}{:>10}{:>10}{:>11}{:>12}",
+            "Cache", "Config", "Accesses", "Hits", "Misses", "Miss rate", "Evictions", "Writebacks"
+        );
+        for (name, cache) in levels {
+            let stats = cache.stats;
+            let _ = writeln!(
+                report,
+                "{:<5}{:<24}{:>10}{:>10}{:>10}{:>9.1}%{:>11}{:>12}",
+                name,
+                cache.config.to_string(),
+                stats.accesses(),
+                stats.hits,
+                stats.misses,
+                stats.miss_rate() * 100.0,
+                stats.evictions,
+                stats.writebacks
+            );
+        }
+
+        let _ = writeln!(report, "Per PC (hits/misses/evictions):");
+        let columns = if self.l2.is_some() { 3 } else { 2 };
+        let _ = write!(report, "  {:<8}", "PC");
+        for name in ["L1I", "L1D", "L2"].iter().take(columns) {
+            let _ = write!(report, "{:>16}", name);
+        }
+        let _ = writeln!(report);
+        for (pc, counts) in &self.per_pc {
+            let _ = write!(report, "  {:<#8x}", pc);
+            for stats in counts.iter().take(columns) {
+                let cell = format!("{}/{}/{}", stats.hits, stats.misses, stats.evictions);
+                let _ = write!(report, "{:>16}", cell);
+            }
+            let _ = writeln!(report);
+        }
+        report
+    }
+}
+
+impl Observer for CacheHierarchy {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        self.pc = ip;
+        self.fetch(ip, instruction.encoded_len() as i64);
+    }
+
+    fn on_memory_read(&mut self, addr: i64, _value: i64) {
+        if addr < self.memory_size {
+            self.read(addr);
+        }
+    }
+
+    fn on_memory_write(&mut self, addr: i64, _old: i64, _new: i64) {
+        if addr < self.memory_size {
+            self.write(addr);
+        }
+    }
+}
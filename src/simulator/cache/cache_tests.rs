@@ -0,0 +1,143 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::observer::run_observed;
+
+fn stats(hits: u64, misses: u64, evictions: u64, writebacks: u64) -> CacheStats {
+    CacheStats {
+        hits,
+        misses,
+        evictions,
+        writebacks,
+    }
+}
+
+#[test]
+fn test_config_parsing() {
+    let config: CacheConfig = "256:4:32:fifo:wt".parse().unwrap();
+    assert_eq!(
+        config,
+        CacheConfig::new(256, 4, 32)
+            .with_replacement(Replacement::Fifo)
+            .with_write_policy(WritePolicy::WriteThrough)
+    );
+    assert_eq!(config.to_string(), "256:4:32:fifo:wt");
+    assert_eq!(
+        "64:1:8:random=7"
+            .parse::<CacheConfig>()
+            .unwrap()
+            .replacement,
+        Replacement::Random { seed: 7 }
+    );
+    assert_eq!(
+        "64:2:8".parse::<CacheConfig>().unwrap().to_string(),
+        "64:2:8:lru:wb"
+    );
+
+    for bad in [
+        "64:2",
+        "64:2:12",
+        "96:2:32",
+        "64:0:8",
+        "64:2:8:plru",
+        "x:2:8",
+        "64:9223372036854775808:4",
+        "18446744073709551615:1:1",
+    ] {
+        assert!(bad.parse::<CacheConfig>().is_err(), "{}", bad);
+    }
+    // Sets are only allocated once used
+    let config = "1099511627776:1099511627776:1".parse::<CacheConfig>().unwrap();
+    assert!(Cache::new(config).is_ok());
+}
+
+#[test]
+fn test_replacement_policies() {
+    // One set of two ways: A, B, A, C, then A again
+    let run = |replacement| {
+        let mut cache =
+            Cache::new(CacheConfig::new(16, 2, 8).with_replacement(replacement)).unwrap();
+        for addr in [0, 8, 0, 16] {
+            cache.access(addr, false);
+        }
+        cache.access(0, false).0
+    };
+    // LRU evicts B, which was used less recently than A; FIFO evicts A, which came first
+    assert!(run(Replacement::Lru));
+    assert!(!run(Replacement::Fifo));
+
+    // Random replacement repeats for the same seed
+    let hits = |seed| {
+        let mut cache =
+            Cache::new(CacheConfig::new(32, 4, 8).with_replacement(Replacement::Random { seed }))
+                .unwrap();
+        (0..64)
+            .map(|i| cache.access(i * 8 % 96, false).0)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(hits(3), hits(3));
+}
+
+#[test]
+fn test_write_policies() {
+    let l2 = CacheConfig::new(64, 2, 8);
+    // A dirty line is written back to the L2 when a read evicts it
+    let mut caches = CacheHierarchy::new(l2, CacheConfig::new(8, 1, 8), Some(l2)).unwrap();
+    caches.write(0);
+    caches.read(8);
+    assert_eq!(caches.l1d().stats(), stats(0, 2, 1, 1));
+    assert_eq!(caches.l2().unwrap().stats(), stats(1, 2, 0, 0));
+
+    // Write-through sends the write on without allocating
+    let l1d = CacheConfig::new(8, 1, 8).with_write_policy(WritePolicy::WriteThrough);
+    let mut caches = CacheHierarchy::new(l2, l1d, Some(l2)).unwrap();
+    caches.write(0);
+    caches.read(8);
+    caches.write(8);
+    assert_eq!(caches.l1d().stats(), stats(1, 2, 0, 0));
+    assert_eq!(caches.l2().unwrap().stats(), stats(1, 2, 0, 0));
+}
+
+#[test]
+fn test_program_accesses() {
+    let code = parse_and_gen(
+        "irmovq $512, %rbx
+        mrmovq (%rbx), %rax
+        mrmovq 8(%rbx), %rax
+        mrmovq 64(%rbx), %rax   # same set, another block
+        halt",
+    )
+    .unwrap()
+    .1
+    .bytes;
+    let caches = CacheHierarchy::new(
+        CacheConfig::new(64, 2, 16),
+        CacheConfig::new(64, 1, 32),
+        Some(CacheConfig::new(256, 4, 32)),
+    )
+    .unwrap()
+    .with_memory_size(1024);
+    let caches = run_observed(&code, caches);
+
+    // Instructions straddling blocks 0-1 and 1-2 take two accesses each
+    assert_eq!(caches.l1i().stats(), stats(4, 3, 0, 0));
+    assert_eq!(caches.l1d().stats(), stats(1, 2, 1, 0));
+    // Fills for code blocks 0 and 16 share an L2 block
+    assert_eq!(caches.l2().unwrap().stats(), stats(1, 4, 0, 0));
+    assert_eq!(caches.per_pc()[&10][L1D], stats(0, 1, 0, 0));
+    assert_eq!(caches.per_pc()[&20][L1D], stats(1, 0, 0, 0));
+    assert_eq!(caches.per_pc()[&30][L1D], stats(0, 1, 1, 0));
+    assert_eq!(caches.per_pc()[&30][L1I], stats(1, 1, 0, 0));
+    assert!(caches.report().contains("L1D  64:1:32:lru:wb"));
+}
+
+#[test]
+fn test_devices_are_uncached() {
+    let config = CacheConfig::new(64, 1, 8);
+    let mut caches = CacheHierarchy::new(config, config, None)
+        .unwrap()
+        .with_memory_size(1024);
+    caches.on_memory_read(0x10000, 0);
+    caches.on_memory_write(0x10000, 0, 1);
+    caches.on_memory_read(8, 0);
+    assert_eq!(caches.l1d().stats().accesses(), 1);
+}
@@ -0,0 +1,195 @@
+use super::observer::Observer;
+use crate::ast::{Instruction, LabOrImm, OwnedInstruction, Register};
+use crate::object::DebugInfo;
+use std::fmt::Write as _;
+#[cfg(test)]
+mod calls_tests;
+
+/// Spaces each level of the call tree is indented by.
+const INDENT: usize = 2;
+
+/// A call whose return address is still on the stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub callee: i64,
+    /// Where the return address was pushed
+    pub slot: i64,
+    pub return_address: i64,
+}
+
+/// A `ret` that did not return through the most recent call's return address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mismatch {
+    pub pc: i64,
+    /// The stack slot the return address was popped from
+    pub slot: i64,
+    pub target: i64,
+    /// The frame that should have been returned from, if any
+    pub expected: Option<Frame>,
+}
+
+/// A `call` or `ret` waiting for the stack access that completes it.
+#[derive(Debug)]
+enum Pending {
+    Call { callee: i64, return_address: i64 },
+    Ret { pc: i64 },
+}
+
+/// Observes `call` and `ret`, building an indented call tree with the argument registers
+/// `%rdi`, `%rsi` and `%rdx` at each entry and `%rax` at each return.
+///
+/// Each call is matched with the slot its return address was pushed to. A `ret` that pops
+/// anything else is recorded as a [`Mismatch`], and still ends every call whose return
+/// address was at or below the slot it popped.
+///
+/// Registers are followed through their writes, so ones set before the run need
+/// [`with_registers`](Self::with_registers).
+pub struct CallTracer<'a> {
+    debug_info: Option<&'a DebugInfo>,
+    registers: [i64; 13],
+    pending: Option<Pending>,
+    frames: Vec<Frame>,
+    mismatches: Vec<Mismatch>,
+    tree: String,
+}
+
+impl<'a> CallTracer<'a> {
+    pub fn new(debug_info: Option<&'a DebugInfo>) -> Self {
+        Self {
+            debug_info,
+            registers: [0; 13],
+            pending: None,
+            frames: Vec::new(),
+            mismatches: Vec::new(),
+            tree: String::new(),
+        }
+    }
+
+    /// Starts from these register values rather than zeros.
+    pub fn with_registers(mut self, registers: [i64; 13]) -> Self {
+        self.registers = registers;
+        self
+    }
+
+    /// Calls that have not returned yet, outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn mismatches(&self) -> &[Mismatch] {
+        &self.mismatches
+    }
+
+    /// One line per call and per return, indented by call depth, with a warning for each
+    /// mismatched return.
+    pub fn tree(&self) -> &str {
+        &self.tree
+    }
+
+    fn name(&self, addr: i64) -> String {
+        self.debug_info
+            .and_then(|debug_info| debug_info.describe(addr))
+            .unwrap_or_else(|| format!("{:#x}", addr))
+    }
+
+    fn call(&mut self, callee: i64, slot: i64, return_address: i64) {
+        let [rdi, rsi, rdx] =
+            [Register::Rdi, Register::Rsi, Register::Rdx].map(|reg| self.registers[reg as usize]);
+        let _ = writeln!(
+            self.tree,
+            "{:indent$}{}(%rdi={}, %rsi={}, %rdx={}) returning to {}",
+            "",
+            self.name(callee),
+            rdi,
+            rsi,
+            rdx,
+            self.name(return_address),
+            indent = self.frames.len() * INDENT,
+        );
+        self.frames.push(Frame {
+            callee,
+            slot,
+            return_address,
+        });
+    }
+
+    fn ret(&mut self, pc: i64, slot: i64, target: i64) {
+        // The stack grows down, so popping `slot` discards every frame pushed at or below it
+        let closed = self.frames.partition_point(|frame| frame.slot > slot);
+        let expected = self.frames.last().copied();
+        let matched = expected.is_some_and(|frame| {
+            frame.slot == slot && frame.return_address == target && closed == self.frames.len() - 1
+        });
+        if !matched {
+            let _ = write!(
+                self.tree,
+                "{:indent$}warning: ret at {} popped {} from stack slot {:#x}",
+                "",
+                self.name(pc),
+                self.name(target),
+                slot,
+                indent = self.frames.len() * INDENT,
+            );
+            let _ = match expected {
+                Some(frame) => writeln!(
+                    self.tree,
+                    ", expected {} from {:#x}",
+                    self.name(frame.return_address),
+                    frame.slot
+                ),
+                None => writeln!(self.tree, ", with no call to return from"),
+            };
+            self.mismatches.push(Mismatch {
+                pc,
+                slot,
+                target,
+                expected,
+            });
+        }
+        if let Some(frame) = self.frames.get(closed).copied() {
+            self.frames.truncate(closed);
+            let _ = writeln!(
+                self.tree,
+                "{:indent$}{} returned %rax={}",
+                "",
+                self.name(frame.callee),
+                self.registers[Register::Rax as usize],
+                indent = closed * INDENT,
+            );
+        }
+    }
+}
+
+impl Observer for CallTracer<'_> {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        self.pending = match instruction {
+            &Instruction::Call(LabOrImm::Immediate(callee)) => Some(Pending::Call {
+                callee,
+                return_address: ip + instruction.encoded_len() as i64,
+            }),
+            Instruction::Ret => Some(Pending::Ret { pc: ip }),
+            _ => None,
+        };
+    }
+
+    fn on_register_write(&mut self, reg: Register, _old: i64, new: i64) {
+        self.registers[reg as usize] = new;
+    }
+
+    fn on_memory_read(&mut self, addr: i64, value: i64) {
+        if let Some(Pending::Ret { pc }) = self.pending.take() {
+            self.ret(pc, addr, value);
+        }
+    }
+
+    fn on_memory_write(&mut self, addr: i64, _old: i64, new: i64) {
+        if let Some(Pending::Call {
+            callee,
+            return_address,
+        }) = self.pending.take()
+            && new == return_address
+        {
+            self.call(callee, addr, return_address);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::*;
+use crate::assembler::{parse_and_gen, parse_and_gen_with_debug};
+use crate::simulator::observer::run_observed;
+
+fn trace<'a>(code: &[u8], debug_info: Option<&'a DebugInfo>) -> CallTracer<'a> {
+    run_observed(code, CallTracer::new(debug_info))
+}
+
+#[test]
+fn test_call_tree() {
+    let src = "
+main:
+    irmovq $3, %rdi
+    irmovq $4, %rsi
+    call add
+    rrmovq %rax, %rdi
+    call twice
+    halt
+twice:
+    rrmovq %rdi, %rsi
+    call add
+    ret
+add:
+    rrmovq %rdi, %rax
+    addq %rsi, %rax
+    ret";
+    let (_, code, debug_info) = parse_and_gen_with_debug(src, "calls.ys").unwrap();
+    let tracer = trace(&code.bytes, Some(&debug_info));
+    assert_eq!(
+        tracer.tree(),
+        "add(%rdi=3, %rsi=4, %rdx=0) returning to main+0x1d\n\
+         add returned %rax=7\n\
+         twice(%rdi=7, %rsi=4, %rdx=0) returning to main+0x28\n\
+         \x20 add(%rdi=7, %rsi=7, %rdx=0) returning to twice+0xb\n\
+         \x20 add returned %rax=14\n\
+         twice returned %rax=14\n"
+    );
+    assert!(tracer.frames().is_empty());
+    assert!(tracer.mismatches().is_empty());
+
+    // Without symbols, addresses stand in for labels
+    let code = parse_and_gen(src).unwrap().1.bytes;
+    assert!(trace(&code, None).tree().starts_with("0x35(%rdi=3"));
+}
+
+#[test]
+fn test_mismatched_returns() {
+    // `f` replaces its return address, and `g` drops its own to return from `f` directly
+    let code = parse_and_gen(
+        "call f
+        halt
+        f:
+        popq %rbx
+        irmovq done, %rbx
+        pushq %rbx
+        ret
+        done:
+        call g2
+        halt
+        g2:
+        call g
+        irmovq $1, %rax
+        ret
+        g:
+        popq %rbx
+        ret",
+    )
+    .unwrap()
+    .1
+    .bytes;
+    let tracer = trace(&code, None);
+    let slot = 1008;
+    assert_eq!(
+        tracer.mismatches(),
+        [
+            Mismatch {
+                pc: 0x18,
+                slot,
+                target: 0x19,
+                expected: Some(Frame {
+                    callee: 0xa,
+                    slot,
+                    return_address: 9
+                })
+            },
+            Mismatch {
+                pc: 0x39,
+                slot,
+                target: 0x22,
+                expected: Some(Frame {
+                    callee: 0x37,
+                    slot: slot - 8,
+                    return_address: 0x2c
+                })
+            }
+        ]
+    );
+    assert!(tracer.frames().is_empty());
+    let lines: Vec<_> = tracer.tree().lines().collect();
+    assert_eq!(
+        lines[1],
+        "  warning: ret at 0x18 popped 0x19 from stack slot 0x3f0, expected 0x9 from 0x3f0"
+    );
+    assert_eq!(lines[2], "0xa returned %rax=0");
+    // Returning past `g` ends it along with its caller
+    assert_eq!(lines[6], "0x23 returned %rax=0");
+}
@@ -0,0 +1,184 @@
+use super::observer::Observer;
+use crate::ast::{Instruction, LabOrImm, OwnedInstruction, Register};
+use crate::object::DebugInfo;
+use std::fmt::Write as _;
+#[cfg(test)]
+mod convention_tests;
+
+/// Registers a function must leave as it found them. `%r13` and `%r14` are callee-saved
+/// too, but this machine stops at `%r12`.
+pub const CALLEE_SAVED: [Register; 3] = [Register::Rbx, Register::Rbp, Register::R12];
+
+/// A register a function returned with a different value from the one it was called with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// Entry address of the function
+    pub function: i64,
+    /// The `ret` that ended the call
+    pub ret: i64,
+    pub register: Register,
+    /// The value at the call
+    pub expected: i64,
+    /// The value after the return
+    pub actual: i64,
+    /// The instruction that last wrote the register, and its address
+    pub last_writer: Option<(i64, OwnedInstruction)>,
+}
+
+/// The callee-saved registers and `%rsp` as they were at a call.
+#[derive(Debug)]
+struct Frame {
+    function: i64,
+    saved: [i64; CALLEE_SAVED.len()],
+    rsp: i64,
+}
+
+/// Observes `call` and `ret`, checking that each function returns with the callee-saved
+/// registers it was called with and with `%rsp` back where it was before the call.
+///
+/// Each `ret` is matched with the innermost call still open, so a function that returns
+/// with its stack unbalanced is still checked against its own call. Registers are followed
+/// through their writes, so callee-saved ones set before the run need
+/// [`with_registers`](Self::with_registers).
+pub struct ConventionChecker<'a> {
+    debug_info: Option<&'a DebugInfo>,
+    registers: [i64; 13],
+    /// The instruction executing, blamed for the registers it writes
+    current: Option<(i64, OwnedInstruction)>,
+    last_writers: [Option<(i64, OwnedInstruction)>; 13],
+    frames: Vec<Frame>,
+    calling: bool,
+    returning: bool,
+    violations: Vec<Violation>,
+}
+
+impl<'a> ConventionChecker<'a> {
+    pub fn new(debug_info: Option<&'a DebugInfo>) -> Self {
+        Self {
+            debug_info,
+            registers: [0; 13],
+            current: None,
+            last_writers: Default::default(),
+            frames: Vec::new(),
+            calling: false,
+            returning: false,
+            violations: Vec::new(),
+        }
+    }
+
+    /// Starts from these register values rather than zeros.
+    pub fn with_registers(mut self, registers: [i64; 13]) -> Self {
+        self.registers = registers;
+        self
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    fn name(&self, addr: i64) -> String {
+        self.debug_info
+            .and_then(|debug_info| debug_info.describe(addr))
+            .unwrap_or_else(|| format!("{:#x}", addr))
+    }
+
+    /// Compares the registers after a `ret` with those at the matching call.
+    fn check(&mut self, ret: i64) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        let expected = frame.saved.iter().copied().chain([frame.rsp]);
+        let registers = CALLEE_SAVED.iter().copied().chain([Register::Rsp]);
+        for (register, expected) in registers.zip(expected) {
+            let actual = self.registers[register as usize];
+            if actual != expected {
+                self.violations.push(Violation {
+                    function: frame.function,
+                    ret,
+                    register,
+                    expected,
+                    actual,
+                    last_writer: self.last_writers[register as usize].clone(),
+                });
+            }
+        }
+    }
+
+    /// One line for each violation, naming the function and the instruction to blame.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "Calling convention: {} violation{}",
+            self.violations.len(),
+            if self.violations.len() == 1 { "" } else { "s" }
+        );
+        for violation in &self.violations {
+            let _ = write!(
+                report,
+                "  {} returned at {} with %{} = {:#x}, not {:#x} as at the call",
+                self.name(violation.function),
+                self.name(violation.ret),
+                violation.register,
+                violation.actual,
+                violation.expected
+            );
+            let _ = match &violation.last_writer {
+                Some((pc, instruction)) => {
+                    let instruction = match self.debug_info {
+                        Some(debug_info) => debug_info.symbolize(instruction),
+                        None => instruction.clone(),
+                    };
+                    writeln!(
+                        report,
+                        "; last written by {}: {}",
+                        self.name(*pc),
+                        instruction.to_string().trim()
+                    )
+                }
+                None => writeln!(report),
+            };
+        }
+        report
+    }
+}
+
+impl Observer for ConventionChecker<'_> {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        self.calling = false;
+        self.returning = false;
+        match instruction {
+            &Instruction::Call(LabOrImm::Immediate(function)) => {
+                self.calling = true;
+                self.frames.push(Frame {
+                    function,
+                    saved: CALLEE_SAVED.map(|register| self.registers[register as usize]),
+                    rsp: self.registers[Register::Rsp as usize],
+                })
+            }
+            Instruction::Ret => self.returning = true,
+            _ => {}
+        }
+        self.current = Some((ip, instruction.clone()));
+    }
+
+    fn on_register_write(&mut self, reg: Register, old: i64, new: i64) {
+        self.registers[reg as usize] = new;
+        // `%rsp` starts at the top of memory rather than zero, so take it from the call's push
+        if self.calling
+            && reg == Register::Rsp
+            && let Some(frame) = self.frames.last_mut()
+        {
+            self.calling = false;
+            frame.rsp = old;
+        }
+        // Popping the return address is the last thing a `ret` does, and is not to blame
+        if self.returning && reg == Register::Rsp {
+            self.returning = false;
+            let ret = self.current.as_ref().map_or(0, |(pc, _)| *pc);
+            self.check(ret);
+            return;
+        }
+        self.last_writers[reg as usize] = self.current.clone();
+    }
+}
@@ -0,0 +1,96 @@
+use super::*;
+use crate::assembler::{parse_and_gen, parse_and_gen_with_debug};
+use crate::simulator::observer::run_observed;
+
+fn check<'a>(code: &[u8], debug_info: Option<&'a DebugInfo>) -> ConventionChecker<'a> {
+    run_observed(code, ConventionChecker::new(debug_info))
+}
+
+#[test]
+fn test_saved_registers_pass() {
+    // Caller-saved registers may change; callee-saved ones are restored before returning
+    let code = parse_and_gen(
+        "irmovq $5, %rbx
+        call f
+        halt
+        f:
+        pushq %rbx
+        pushq %r12
+        irmovq $1, %rbx
+        irmovq $2, %r12
+        irmovq $3, %rax
+        irmovq $4, %rdi
+        popq %r12
+        popq %rbx
+        ret",
+    )
+    .unwrap()
+    .1
+    .bytes;
+    let checker = check(&code, None);
+    assert!(checker.violations().is_empty());
+    assert_eq!(checker.report(), "Calling convention: 0 violations\n");
+}
+
+#[test]
+fn test_clobbered_register() {
+    let src = "
+main:
+    call outer
+    halt
+outer:
+    call clobber
+    ret
+clobber:
+    irmovq $7, %rbp
+    ret";
+    let (_, code, debug_info) = parse_and_gen_with_debug(src, "clobber.ys").unwrap();
+    let checker = check(&code.bytes, Some(&debug_info));
+    // `outer` inherits the clobbered register from its callee
+    let violations: Vec<_> = checker
+        .violations()
+        .iter()
+        .map(|violation| (violation.function, violation.register, violation.actual))
+        .collect();
+    assert_eq!(
+        violations,
+        [(0x14, Register::Rbp, 7), (0xa, Register::Rbp, 7)]
+    );
+    assert_eq!(
+        checker.violations()[0].last_writer,
+        Some((
+            0x14,
+            Instruction::Irmov(LabOrImm::Immediate(7), Register::Rbp)
+        ))
+    );
+    assert_eq!(
+        checker.report().lines().nth(1).unwrap(),
+        "  clobber returned at clobber+0xa with %rbp = 0x7, not 0x0 as at the call; \
+         last written by clobber: irmov 7, rbp"
+    );
+}
+
+#[test]
+fn test_unbalanced_stack() {
+    // Pushes an address and returns to it, leaving %rsp 8 short of where the call found it
+    let code = parse_and_gen(
+        "irmovq $512, %rsp
+        call f
+        halt
+        f:
+        irmovq back, %rax
+        pushq %rax
+        ret
+        back:
+        halt",
+    )
+    .unwrap()
+    .1
+    .bytes;
+    let checker = check(&code, None);
+    assert_eq!(checker.violations().len(), 1);
+    let violation = &checker.violations()[0];
+    assert_eq!(violation.register, Register::Rsp);
+    assert_eq!((violation.expected, violation.actual), (512, 504));
+    assert_eq!(violation.last_writer.as_ref().unwrap().0, 0x1e);
+}
@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+pub mod console;
+#[cfg(test)]
+mod device_tests;
+pub mod framebuffer;
+
+pub use console::Console;
+pub use framebuffer::Framebuffer;
+
+/// A memory-mapped device, attached to a [`Simulator`](super::simulator_guts::Simulator) with
+/// `map_device`.
+///
+/// Like memory, a device holds one quad per address; `offset` is relative to the address the
+/// device is mapped at. An `Err` from either access stops the simulator with `Status::Error`.
+pub trait Device {
+    /// Number of addresses the device occupies.
+    fn size(&self) -> i64;
+    fn read(&mut self, offset: i64) -> Result<i64, String>;
+    fn write(&mut self, offset: i64, value: i64) -> Result<(), String>;
+    /// Called when the program halts.
+    fn on_halt(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Lets the caller keep a handle to a device, for example to inspect its output after a run.
+impl<T: Device + ?Sized> Device for Rc<RefCell<T>> {
+    fn size(&self) -> i64 {
+        self.borrow().size()
+    }
+    fn read(&mut self, offset: i64) -> Result<i64, String> {
+        self.borrow_mut().read(offset)
+    }
+    fn write(&mut self, offset: i64, value: i64) -> Result<(), String> {
+        self.borrow_mut().write(offset, value)
+    }
+    fn on_halt(&mut self) -> Result<(), String> {
+        self.borrow_mut().on_halt()
+    }
+}
+
+/// The devices mapped into the address space, routed to by address.
+///
+/// Devices sit above RAM, so ordinary memory accesses never consult the bus.
+#[derive(Default)]
+pub struct DeviceBus<'a> {
+    devices: Vec<(Range<i64>, Box<dyn Device + 'a>)>,
+    /// Set by every access, so that the loop detector knows the state it sees is incomplete
+    accessed: bool,
+}
+
+impl<'a> DeviceBus<'a> {
+    /// Maps `device` at `base`, which must leave it clear of `ram_size` and other devices.
+    pub fn map(
+        &mut self,
+        base: i64,
+        ram_size: usize,
+        device: impl Device + 'a,
+    ) -> Result<(), String> {
+        let range = base
+            .checked_add(device.size())
+            .filter(|_| base >= ram_size as i64 && device.size() > 0)
+            .map(|end| base..end)
+            .ok_or_else(|| format!("Devices must be mapped above RAM, not at {:#x}", base))?;
+        if let Some((other, _)) = self
+            .devices
+            .iter()
+            .find(|(other, _)| other.start < range.end && range.start < other.end)
+        {
+            return Err(format!(
+                "Device at {:#x}..{:#x} overlaps the device at {:#x}..{:#x}",
+                range.start, range.end, other.start, other.end
+            ));
+        }
+        self.devices.push((range, Box::new(device)));
+        Ok(())
+    }
+
+    /// The address ranges the devices occupy.
+    pub fn ranges(&self) -> impl Iterator<Item = &Range<i64>> {
+        self.devices.iter().map(|(range, _)| range)
+    }
+
+    /// Whether a device is mapped at `addr`.
+    pub fn contains(&self, addr: i64) -> bool {
+        self.devices.iter().any(|(range, _)| range.contains(&addr))
+    }
+
+    /// Reads from the device mapped at `addr`, if any.
+    pub fn read(&mut self, addr: i64) -> Option<Result<i64, String>> {
+        let (range, device) = self
+            .devices
+            .iter_mut()
+            .find(|(range, _)| range.contains(&addr))?;
+        self.accessed = true;
+        Some(device.read(addr - range.start))
+    }
+
+    /// Writes to the device mapped at `addr`, if any.
+    pub fn write(&mut self, addr: i64, value: i64) -> Option<Result<(), String>> {
+        let (range, device) = self
+            .devices
+            .iter_mut()
+            .find(|(range, _)| range.contains(&addr))?;
+        self.accessed = true;
+        Some(device.write(addr - range.start, value))
+    }
+
+    /// Tells every device that the program halted, stopping at the first failure.
+    pub fn halt(&mut self) -> Result<(), String> {
+        self.devices
+            .iter_mut()
+            .try_for_each(|(_, device)| device.on_halt())
+    }
+
+    /// Whether any device has been accessed since the last call.
+    pub fn take_accessed(&mut self) -> bool {
+        std::mem::take(&mut self.accessed)
+    }
+}
@@ -0,0 +1,87 @@
+use super::Device;
+use std::io::{ErrorKind, Read, Write};
+
+/// Where `y86 run --console` maps the console unless told otherwise.
+pub const DEFAULT_BASE: i64 = 0x10000;
+pub const DATA: i64 = 0;
+pub const STATUS: i64 = 8;
+/// Set in `STATUS` once the input is exhausted
+pub const STATUS_EOF: i64 = 1;
+
+/// A character console: bytes written to the data port are printed, and reads consume input.
+///
+/// Registers, at offsets from where the console is mapped:
+/// - `DATA` (0): reads return the next input byte, or -1 at the end of input; writes print
+///   the low byte
+/// - `STATUS` (8): bit 0 is set once the input is exhausted; read-only
+pub struct Console<R: Read, W: Write> {
+    input: R,
+    output: W,
+    /// The next input byte, once `STATUS` has looked ahead; `Some(None)` at end of input
+    peeked: Option<Option<u8>>,
+}
+
+impl<R: Read, W: Write> Console<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            peeked: None,
+        }
+    }
+
+    /// Everything written so far, when the output is a buffer.
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, String> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(byte);
+        }
+        let mut byte = [0];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(format!("Console input failed: {}", e)),
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, String> {
+        let byte = self.next_byte()?;
+        self.peeked = Some(byte);
+        Ok(byte)
+    }
+}
+
+impl<R: Read, W: Write> Device for Console<R, W> {
+    fn size(&self) -> i64 {
+        16
+    }
+
+    fn read(&mut self, offset: i64) -> Result<i64, String> {
+        match offset {
+            DATA => Ok(self.next_byte()?.map_or(-1, i64::from)),
+            STATUS => Ok(match self.peek()? {
+                Some(_) => 0,
+                None => STATUS_EOF,
+            }),
+            _ => Err(format!("Console has no register at offset {}", offset)),
+        }
+    }
+
+    fn write(&mut self, offset: i64, value: i64) -> Result<(), String> {
+        match offset {
+            DATA => self
+                .output
+                .write_all(&[value as u8])
+                .and_then(|_| self.output.flush())
+                .map_err(|e| format!("Console output failed: {}", e)),
+            STATUS => Err("Console status register is read-only".to_string()),
+            _ => Err(format!("Console has no register at offset {}", offset)),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use super::framebuffer::ImageFormat;
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::simulator_guts::{LogPolicy, Simulator, Status};
+
+type BufferConsole = Rc<RefCell<Console<&'static [u8], Vec<u8>>>>;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Copies input to output until the console reports the end of input.
+const ECHO: &str = "
+    irmovq $0x10000, %rbx
+loop:
+    mrmovq 8(%rbx), %rax    # STATUS
+    andq %rax, %rax
+    jne done
+    mrmovq (%rbx), %rcx     # DATA
+    rmmovq %rcx, (%rbx)
+    jmp loop
+done:
+    halt
+";
+
+fn buffer_console(input: &'static [u8]) -> BufferConsole {
+    Rc::new(RefCell::new(Console::new(input, Vec::new())))
+}
+
+fn run_with_console<'a>(simulator: &mut Simulator<'a, 1024>, device: &BufferConsole) {
+    simulator
+        .map_device(console::DEFAULT_BASE, device.clone())
+        .unwrap();
+    simulator.run();
+}
+
+#[test]
+fn test_console_echo() {
+    let code = assemble(ECHO);
+    // The logging path, the fast path, and with loop detection, which must not mistake the
+    // repeated input for a repeated state
+    let simulators = [
+        Simulator::<1024>::new(&code),
+        Simulator::<1024>::new(&code).with_log_policy(LogPolicy::Off),
+        Simulator::<1024>::new(&code).with_loop_detection(),
+    ];
+    for mut simulator in simulators {
+        let device = buffer_console(b"aaaa");
+        run_with_console(&mut simulator, &device);
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(device.borrow().output(), b"aaaa");
+    }
+}
+
+#[test]
+fn test_console_writes_are_logged() {
+    let code = assemble(ECHO);
+    let mut simulator = Simulator::<1024>::new(&code);
+    run_with_console(&mut simulator, &buffer_console(b"!"));
+    assert!(simulator.log.iter().any(|(_, change)| {
+        *change
+            == crate::simulator::simulator_guts::AtomicChange::Memory {
+                addr: 0x10000,
+                value: '!' as i64,
+            }
+    }));
+}
+
+#[test]
+fn test_console_registers() {
+    let mut device = Console::new(&b"x"[..], Vec::new());
+    assert_eq!(device.read(console::STATUS), Ok(0));
+    assert_eq!(device.read(console::DATA), Ok('x' as i64));
+    assert_eq!(device.read(console::DATA), Ok(-1));
+    assert_eq!(device.read(console::STATUS), Ok(1));
+    assert!(device.write(console::STATUS, 0).is_err());
+    assert!(device.read(3).is_err());
+    // Only the low byte is printed
+    device.write(console::DATA, 0x141).unwrap();
+    assert_eq!(device.output(), b"A");
+}
+
+#[test]
+fn test_device_faults_stop_the_simulator() {
+    for policy in [LogPolicy::Full, LogPolicy::Off] {
+        let code = assemble("irmovq $0x10000, %rbx\nrmmovq %rbx, 8(%rbx)\nhalt\n");
+        let mut simulator = Simulator::<1024>::new(&code).with_log_policy(policy);
+        run_with_console(&mut simulator, &buffer_console(b""));
+        assert_eq!(
+            simulator.state,
+            Status::Error("Console status register is read-only".to_string())
+        );
+        assert_eq!(simulator.steps, 1);
+    }
+
+    // Addresses above RAM with no device are still out of bounds
+    let code = assemble("irmovq $0x10010, %rbx\nmrmovq (%rbx), %rax\nhalt\n");
+    let mut simulator = Simulator::<1024>::new(&code);
+    run_with_console(&mut simulator, &buffer_console(b""));
+    assert_eq!(
+        simulator.state,
+        Status::Error("Memory address out of bounds: 65552".to_string())
+    );
+}
+
+#[test]
+fn test_devices_are_mapped_above_ram() {
+    let code = assemble("halt");
+    let mut simulator = Simulator::<1024>::new(&code);
+    assert!(simulator.map_device(1016, buffer_console(b"")).is_err());
+    assert!(
+        simulator
+            .map_device(i64::MAX - 4, buffer_console(b""))
+            .is_err()
+    );
+    simulator.map_device(1024, buffer_console(b"")).unwrap();
+    assert_eq!(
+        simulator.map_device(1032, buffer_console(b"")),
+        Err("Device at 0x408..0x418 overlaps the device at 0x400..0x410".to_string())
+    );
+    simulator.map_device(1040, buffer_console(b"")).unwrap();
+}
+
+/// Draws on a 2x2 framebuffer, presenting one frame before the one emitted on halt.
+const DRAW: &str = "
+    irmovq $0x20000, %rbx
+    irmovq $0xff0000, %rax
+    rmmovq %rax, 32(%rbx)   # (0, 0) red
+    irmovq $0x00ff00, %rax
+    rmmovq %rax, 56(%rbx)   # (1, 1) green
+    rmmovq %rax, 16(%rbx)   # PRESENT
+    irmovq $0x0000ff, %rax
+    rmmovq %rax, 40(%rbx)   # (1, 0) blue
+    mrmovq 16(%rbx), %rcx   # frames so far
+    halt
+";
+
+fn frame(pixels: [[u8; 3]; 4]) -> Vec<u8> {
+    let mut frame = b"P6\n2 2\n255\n".to_vec();
+    frame.extend(pixels.concat());
+    frame
+}
+
+#[test]
+fn test_framebuffer_frames() {
+    const BLACK: [u8; 3] = [0, 0, 0];
+    let code = assemble(DRAW);
+    for policy in [LogPolicy::Full, LogPolicy::Off] {
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let sink = frames.clone();
+        let device = Framebuffer::new(2, 2, ImageFormat::Ppm, move |number, frame| {
+            sink.borrow_mut().push((number, frame));
+            Ok(())
+        })
+        .unwrap();
+
+        let mut simulator = Simulator::<1024>::new(&code).with_log_policy(policy);
+        simulator
+            .map_device(framebuffer::DEFAULT_BASE, device)
+            .unwrap();
+        simulator.run();
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(simulator.registers[2], 1);
+        assert_eq!(
+            *frames.borrow(),
+            [
+                (0, frame([[255, 0, 0], BLACK, BLACK, [0, 255, 0]])),
+                (1, frame([[255, 0, 0], [0, 0, 255], BLACK, [0, 255, 0]])),
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_framebuffer_registers() {
+    let mut device = Framebuffer::new(3, 2, ImageFormat::Ppm, |_, _| Ok(())).unwrap();
+    assert_eq!(device.size(), framebuffer::PIXELS + 48);
+    assert_eq!(device.read(framebuffer::WIDTH), Ok(3));
+    assert_eq!(device.read(framebuffer::HEIGHT), Ok(2));
+    assert!(device.write(framebuffer::WIDTH, 4).is_err());
+    // Pixels are quads, and only their low 32 bits are kept
+    assert!(device.write(framebuffer::PIXELS + 4, 0).is_err());
+    assert!(device.write(framebuffer::PIXELS + 48, 0).is_err());
+    device.write(framebuffer::PIXELS + 40, -1).unwrap();
+    assert_eq!(device.read(framebuffer::PIXELS + 40), Ok(0xFFFF_FFFF));
+
+    assert!(Framebuffer::new(0, 2, ImageFormat::Ppm, |_, _| Ok(())).is_err());
+    let mut failing = Framebuffer::new(1, 1, ImageFormat::Ppm, |_, _| Err("full".to_string()));
+    assert_eq!(failing.as_mut().unwrap().on_halt(), Err("full".to_string()));
+}
+
+#[test]
+fn test_framebuffer_png() {
+    assert_eq!(framebuffer::crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(framebuffer::adler32(b"Wikipedia"), 0x11E6_0398);
+
+    let mut device = Framebuffer::new(2, 1, ImageFormat::Png, |_, _| Ok(())).unwrap();
+    device.write(framebuffer::PIXELS, 0x123456).unwrap();
+    let png = device.snapshot();
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..29], [0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+    // A zlib stream of one stored block holding a filter byte and two pixels per row
+    let idat = &png[33..];
+    assert_eq!(&idat[..4], [0, 0, 0, 18]);
+    assert_eq!(&idat[4..8], b"IDAT");
+    assert_eq!(
+        &idat[8..22],
+        [
+            0x78, 0x01, 1, 7, 0, 0xF8, 0xFF, 0, 0x12, 0x34, 0x56, 0, 0, 0
+        ]
+    );
+    assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+}
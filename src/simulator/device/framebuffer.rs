@@ -0,0 +1,182 @@
+use super::Device;
+
+/// Where `y86 run --framebuffer` maps the framebuffer unless told otherwise.
+pub const DEFAULT_BASE: i64 = 0x20000;
+pub const WIDTH: i64 = 0;
+pub const HEIGHT: i64 = 8;
+/// Writing any value emits a frame; reading returns the number of frames emitted
+pub const PRESENT: i64 = 16;
+/// Offset of pixel (0, 0); pixel (x, y) is at `PIXELS + 8 * (y * width + x)`
+pub const PIXELS: i64 = 32;
+
+/// Frames larger than this many pixels are refused, to keep the device's memory bounded.
+const MAX_PIXELS: usize = 1 << 24;
+
+/// How frames are encoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ImageFormat {
+    /// Binary PPM (`P6`)
+    #[default]
+    Ppm,
+    /// PNG with uncompressed image data
+    Png,
+}
+
+/// Receives each encoded frame along with its number, counting from 0.
+pub type FrameSink<'a> = Box<dyn FnMut(u64, Vec<u8>) -> Result<(), String> + 'a>;
+
+/// A framebuffer of `0x00RRGGBB` pixels, one per quad.
+///
+/// Registers, at offsets from where the framebuffer is mapped:
+/// - `WIDTH` (0) and `HEIGHT` (8): the dimensions in pixels; read-only
+/// - `PRESENT` (16): writes emit the current frame; reads return the number emitted so far
+/// - `PIXELS` (32): the pixels, row by row; only the low 32 bits of a write are kept
+///
+/// A frame is also emitted when the program halts.
+pub struct Framebuffer<'a> {
+    width: usize,
+    height: usize,
+    pixels: Vec<u32>,
+    format: ImageFormat,
+    frames: u64,
+    sink: FrameSink<'a>,
+}
+
+impl<'a> Framebuffer<'a> {
+    pub fn new(
+        width: usize,
+        height: usize,
+        format: ImageFormat,
+        sink: impl FnMut(u64, Vec<u8>) -> Result<(), String> + 'a,
+    ) -> Result<Self, String> {
+        if width == 0 || height == 0 || width.saturating_mul(height) > MAX_PIXELS {
+            return Err(format!("Invalid framebuffer size: {}x{}", width, height));
+        }
+        Ok(Self {
+            width,
+            height,
+            pixels: vec![0; width * height],
+            format,
+            frames: 0,
+            sink: Box::new(sink),
+        })
+    }
+
+    /// The current contents, encoded in the framebuffer's format.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let rgb = self
+            .pixels
+            .iter()
+            .flat_map(|pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+        match self.format {
+            ImageFormat::Ppm => {
+                let mut image = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+                image.extend(rgb);
+                image
+            }
+            ImageFormat::Png => png(self.width, self.height, &rgb.collect::<Vec<_>>()),
+        }
+    }
+
+    fn present(&mut self) -> Result<(), String> {
+        let frame = self.snapshot();
+        self.frames += 1;
+        (self.sink)(self.frames - 1, frame)
+    }
+
+    /// Index of the pixel at `offset`, if there is one.
+    fn pixel(&self, offset: i64) -> Option<usize> {
+        let index = usize::try_from(offset.checked_sub(PIXELS)?).ok()?;
+        index
+            .is_multiple_of(8)
+            .then_some(index / 8)
+            .filter(|&index| index < self.pixels.len())
+    }
+}
+
+impl Device for Framebuffer<'_> {
+    fn size(&self) -> i64 {
+        PIXELS + 8 * self.pixels.len() as i64
+    }
+
+    fn read(&mut self, offset: i64) -> Result<i64, String> {
+        match offset {
+            WIDTH => Ok(self.width as i64),
+            HEIGHT => Ok(self.height as i64),
+            PRESENT => Ok(self.frames as i64),
+            _ => self
+                .pixel(offset)
+                .map(|index| self.pixels[index] as i64)
+                .ok_or_else(|| format!("Framebuffer has no register at offset {}", offset)),
+        }
+    }
+
+    fn write(&mut self, offset: i64, value: i64) -> Result<(), String> {
+        match offset {
+            WIDTH | HEIGHT => Err("Framebuffer dimensions are read-only".to_string()),
+            PRESENT => self.present(),
+            _ => {
+                let index = self
+                    .pixel(offset)
+                    .ok_or_else(|| format!("Framebuffer has no register at offset {}", offset))?;
+                self.pixels[index] = value as u32;
+                Ok(())
+            }
+        }
+    }
+
+    fn on_halt(&mut self) -> Result<(), String> {
+        self.present()
+    }
+}
+
+/// Encodes 8-bit RGB data as a PNG, storing the image data without compression.
+fn png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolour, default compression, filtering and no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Each row starts with filter type 0
+    let raw = rgb
+        .chunks(width * 3)
+        .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+        .collect::<Vec<_>>();
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = image.len();
+        image.extend_from_slice(kind);
+        image.extend_from_slice(data);
+        let crc = crc32(&image[start..]);
+        image.extend_from_slice(&crc.to_be_bytes());
+    }
+    image
+}
+
+pub(super) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+pub(super) fn adler32(bytes: &[u8]) -> u32 {
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
@@ -0,0 +1,460 @@
+use super::observer::Breakpoints;
+use super::simulator_guts::{Simulator, Status};
+use crate::ast::Register;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+#[cfg(test)]
+mod gdb_stub_tests;
+
+/// The 15 Y86-64 program registers as numbered for GDB, in the CS:APP order `%rax`, `%rcx`,
+/// `%rdx`, `%rbx`, `%rsp`, ... rather than the order of [`Register`].
+///
+/// This simulator implements 13 of them; `%r13` and `%r14` read as unavailable and ignore writes.
+const GDB_REGISTERS: [Option<Register>; 15] = [
+    Some(Register::Rax),
+    Some(Register::Rcx),
+    Some(Register::Rdx),
+    Some(Register::Rbx),
+    Some(Register::Rsp),
+    Some(Register::Rbp),
+    Some(Register::Rsi),
+    Some(Register::Rdi),
+    Some(Register::R8),
+    Some(Register::R9),
+    Some(Register::R10),
+    Some(Register::R11),
+    Some(Register::R12),
+    None,
+    None,
+];
+const PC_REGNUM: usize = 15;
+const FLAGS_REGNUM: usize = 16;
+const REGISTER_COUNT: usize = 17;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.y86.core">
+    <reg name="rax" bitsize="64" type="int64" regnum="0"/>
+    <reg name="rcx" bitsize="64" type="int64"/>
+    <reg name="rdx" bitsize="64" type="int64"/>
+    <reg name="rbx" bitsize="64" type="int64"/>
+    <reg name="rsp" bitsize="64" type="data_ptr"/>
+    <reg name="rbp" bitsize="64" type="data_ptr"/>
+    <reg name="rsi" bitsize="64" type="int64"/>
+    <reg name="rdi" bitsize="64" type="int64"/>
+    <reg name="r8" bitsize="64" type="int64"/>
+    <reg name="r9" bitsize="64" type="int64"/>
+    <reg name="r10" bitsize="64" type="int64"/>
+    <reg name="r11" bitsize="64" type="int64"/>
+    <reg name="r12" bitsize="64" type="int64"/>
+    <reg name="r13" bitsize="64" type="int64"/>
+    <reg name="r14" bitsize="64" type="int64"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+    <reg name="flags" bitsize="64" type="int64"/>
+  </feature>
+</target>
+"#;
+
+/// How often `continue` checks the connection for an interrupt request.
+const INTERRUPT_CHECK_INTERVAL: u64 = 4096;
+
+/// A packet, or out-of-band byte, received from the debugger.
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Packet(String),
+    /// A packet whose checksum did not match, to be retransmitted
+    Corrupt,
+    /// Ctrl-C from the debugger
+    Interrupt,
+    Disconnected,
+}
+
+/// Serves the GDB remote serial protocol for a simulator.
+///
+/// Registers are numbered as in [`GDB_REGISTERS`], followed by the PC and a flags register
+/// holding the condition code (bit 0 carry, 1 zero, 2 sign, 3 overflow). Each address in
+/// simulated memory holds one little-endian quad, so a read of `n` bytes at `a` returns the
+/// quads at `a`, `a + 8`, ... truncated to `n` bytes; the program image itself is read-only
+/// and is not visible through memory packets.
+pub struct GdbStub<'a, const MEM_SIZE: usize> {
+    pub simulator: Simulator<'a, MEM_SIZE>,
+    breakpoints: Rc<RefCell<Breakpoints>>,
+    /// Set once the debugger kills or detaches from the target
+    finished: bool,
+    /// Set when the debugger detaches, leaving the program to run on
+    detached: bool,
+}
+
+impl<'a, const MEM_SIZE: usize> GdbStub<'a, MEM_SIZE> {
+    pub fn new(mut simulator: Simulator<'a, MEM_SIZE>) -> Self {
+        let breakpoints = Rc::new(RefCell::new(Breakpoints::default()));
+        simulator.add_observer(breakpoints.clone());
+        Self {
+            simulator,
+            breakpoints,
+            finished: false,
+            detached: false,
+        }
+    }
+
+    pub fn into_simulator(self) -> Simulator<'a, MEM_SIZE> {
+        self.simulator
+    }
+
+    /// Talks to one debugger over `stream` until it kills, detaches or disconnects.
+    ///
+    /// After a detach the program runs on to completion, ignoring breakpoints.
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        // Bytes received while polling for an interrupt during `continue`
+        let mut pending = VecDeque::new();
+        while !self.finished {
+            let packet = match read_packet(&mut (&mut pending).chain(&mut stream))? {
+                Incoming::Packet(packet) => packet,
+                Incoming::Corrupt => {
+                    stream.write_all(b"-")?;
+                    continue;
+                }
+                Incoming::Interrupt => continue, // Already stopped
+                Incoming::Disconnected => break,
+            };
+            stream.write_all(b"+")?;
+
+            let poll_stream = stream.try_clone()?;
+            let mut interrupted = || poll_interrupt(&poll_stream, &mut pending);
+            if let Some(reply) = self.handle_packet(&packet, &mut interrupted) {
+                write_packet(&mut stream, &reply)?;
+            }
+        }
+        if self.detached {
+            self.breakpoints.borrow_mut().addresses.clear();
+            self.simulator.run();
+        }
+        Ok(())
+    }
+
+    /// Handles one packet body, returning the reply to send, if any.
+    ///
+    /// `interrupted` is polled during `continue` and stops execution when it returns true.
+    pub fn handle_packet(
+        &mut self,
+        packet: &str,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.stop_reply(false),
+            "g" => (0..REGISTER_COUNT).map(|n| self.read_register(n)).collect(),
+            "G" => self.write_registers(args),
+            "p" => match parse_hex(args) {
+                Some(n) if (n as usize) < REGISTER_COUNT => self.read_register(n as usize),
+                _ => error(),
+            },
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" | "z" => self.set_breakpoint(args, command == "Z"),
+            "s" | "c" => {
+                if let Some(addr) = (!args.is_empty()).then(|| parse_hex(args)).flatten() {
+                    self.set_pc(addr as i64);
+                }
+                self.resume(command == "s", interrupted)
+            }
+            "H" => "OK".to_string(),
+            "k" => {
+                self.finished = true;
+                return None;
+            }
+            "D" => {
+                self.finished = true;
+                self.detached = true;
+                "OK".to_string()
+            }
+            _ => self.query(packet),
+        };
+        Some(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_annex(TARGET_XML, range)
+        } else {
+            // The empty reply marks a packet as unsupported
+            String::new()
+        }
+    }
+
+    fn stop_reply(&self, interrupted: bool) -> String {
+        match self.simulator.state {
+            Status::Running if interrupted => "S02".to_string(),
+            Status::Running => "S05".to_string(),
+            Status::Halted => "W00".to_string(),
+            Status::Error(_) => "S0b".to_string(),
+            Status::StepLimitExceeded | Status::InfiniteLoop { .. } => "S18".to_string(),
+        }
+    }
+
+    /// Executes one instruction, or runs until a breakpoint, interrupt or stop.
+    fn resume(&mut self, single_step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+        if self.simulator.state != Status::Running {
+            return self.stop_reply(false);
+        }
+        // Execute the instruction at the PC even if it has a breakpoint on it
+        self.simulator.paused_at = Some(self.simulator.instruction_pointer);
+        loop {
+            self.simulator.run_single();
+            if self.simulator.state != Status::Running
+                || self.simulator.paused_at.is_some()
+                || single_step
+            {
+                return self.stop_reply(false);
+            }
+            if self
+                .simulator
+                .steps
+                .is_multiple_of(INTERRUPT_CHECK_INTERVAL)
+                && interrupted()
+            {
+                return self.stop_reply(true);
+            }
+        }
+    }
+
+    fn set_pc(&mut self, addr: i64) {
+        self.simulator.instruction_pointer = addr;
+        self.simulator.paused_at = None;
+    }
+
+    fn register_value(&self, n: usize) -> Option<i64> {
+        match n {
+            PC_REGNUM => Some(self.simulator.instruction_pointer),
+            FLAGS_REGNUM => Some(self.simulator.condition_code as i64),
+            _ => GDB_REGISTERS[n].map(|reg| self.simulator.registers[reg as usize]),
+        }
+    }
+
+    fn read_register(&self, n: usize) -> String {
+        match self.register_value(n) {
+            Some(value) => encode_hex(&value.to_le_bytes()),
+            None => "x".repeat(16),
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: i64) {
+        match n {
+            PC_REGNUM => self.set_pc(value),
+            FLAGS_REGNUM => self.simulator.condition_code = (value & 0xF) as u8,
+            _ => {
+                if let Some(reg) = GDB_REGISTERS[n] {
+                    self.simulator.registers[reg as usize] = value;
+                }
+            }
+        }
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let Some(bytes) = decode_hex(hex).filter(|bytes| bytes.len() == REGISTER_COUNT * 8) else {
+            return error();
+        };
+        for (n, chunk) in bytes.chunks_exact(8).enumerate() {
+            self.set_register(n, i64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(n, value)| {
+            let n = parse_hex(n).filter(|&n| (n as usize) < REGISTER_COUNT)? as usize;
+            let bytes: [u8; 8] = decode_hex(value)?.try_into().ok()?;
+            Some((n, i64::from_le_bytes(bytes)))
+        });
+        match parsed {
+            Some((n, value)) => {
+                self.set_register(n, value);
+                "OK".to_string()
+            }
+            None => error(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_range(args) else {
+            return error();
+        };
+        let mut bytes = Vec::new();
+        for offset in 0..len {
+            let Some(index) = quad_index::<MEM_SIZE>(addr, offset) else {
+                break;
+            };
+            bytes.push(self.simulator.memory[index].to_le_bytes()[offset % 8]);
+        }
+        if bytes.is_empty() && len > 0 {
+            return error();
+        }
+        encode_hex(&bytes)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = parse_range(range)?;
+            Some((addr, decode_hex(data).filter(|data| data.len() == len)?))
+        });
+        let Some((addr, data)) = parsed else {
+            return error();
+        };
+        if (0..data.len()).any(|offset| quad_index::<MEM_SIZE>(addr, offset).is_none()) {
+            return error();
+        }
+        for (offset, byte) in data.into_iter().enumerate() {
+            let index = quad_index::<MEM_SIZE>(addr, offset).unwrap();
+            let mut quad = self.simulator.memory[index].to_le_bytes();
+            quad[offset % 8] = byte;
+            self.simulator.memory[index] = i64::from_le_bytes(quad);
+        }
+        "OK".to_string()
+    }
+
+    /// Handles `Z`/`z` packets; only software breakpoints (type 0) are supported.
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        if fields.next() != Some("0") {
+            return String::new();
+        }
+        let Some(addr) = fields.next().and_then(parse_hex) else {
+            return error();
+        };
+        let addresses = &mut self.breakpoints.borrow_mut().addresses;
+        if insert {
+            addresses.insert(addr as i64);
+        } else {
+            addresses.remove(&(addr as i64));
+        }
+        "OK".to_string()
+    }
+}
+
+/// The memory index holding byte `offset` of a debugger transfer starting at `addr`, if it is
+/// in RAM.
+///
+/// Each address holds one little-endian quad, so a transfer covers the quads at `addr`,
+/// `addr + 8`, ... and shows a debugger the value a program would load from `addr`.
+pub(crate) fn quad_index<const MEM_SIZE: usize>(addr: i64, offset: usize) -> Option<usize> {
+    let index = addr.checked_add(i64::try_from(offset / 8 * 8).ok()?)?;
+    (index >= 0 && (index as usize) < MEM_SIZE).then_some(index as usize)
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// Parses `addr,length`.
+fn parse_range(s: &str) -> Option<(i64, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((parse_hex(addr)? as i64, parse_hex(len)? as usize))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Replies to a `qXfer` read of `offset,length` from `annex`.
+fn read_annex(annex: &str, range: &str) -> String {
+    let Some((offset, len)) = parse_range(range) else {
+        return error();
+    };
+    let start = (offset as usize).min(annex.len());
+    let end = start.saturating_add(len).min(annex.len());
+    let marker = if end == annex.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &annex[start..end])
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn write_packet(out: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(out, "${}#{:02x}", data, checksum(data))?;
+    out.flush()
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Reads up to the next packet, skipping acknowledgements.
+fn read_packet(input: &mut impl Read) -> io::Result<Incoming> {
+    loop {
+        match read_byte(input)? {
+            None => return Ok(Incoming::Disconnected),
+            Some(0x03) => return Ok(Incoming::Interrupt),
+            Some(b'$') => break,
+            Some(_) => {} // Acks and line noise
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        match read_byte(input)? {
+            None => return Ok(Incoming::Disconnected),
+            Some(b'#') => break,
+            Some(byte) => data.push(byte),
+        }
+    }
+    let mut sum = [0; 2];
+    input.read_exact(&mut sum)?;
+    let data = String::from_utf8_lossy(&data).into_owned();
+    let expected = std::str::from_utf8(&sum)
+        .ok()
+        .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+    if expected == Some(checksum(&data)) {
+        Ok(Incoming::Packet(data))
+    } else {
+        Ok(Incoming::Corrupt)
+    }
+}
+
+/// Whether the debugger has sent Ctrl-C, without blocking.
+///
+/// Any other bytes received are kept in `pending`, to be read as packets later.
+fn poll_interrupt(stream: &TcpStream, pending: &mut VecDeque<u8>) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut interrupted = false;
+    let mut buf = [0; 256];
+    while let Ok(len @ 1..) = (&*stream).read(&mut buf) {
+        for &byte in &buf[..len] {
+            match byte {
+                0x03 => interrupted = true,
+                _ => pending.push_back(byte),
+            }
+        }
+    }
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
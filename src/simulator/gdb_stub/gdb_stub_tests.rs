@@ -0,0 +1,250 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use std::net::TcpListener;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+fn send<const M: usize>(stub: &mut GdbStub<'_, M>, packet: &str) -> String {
+    stub.handle_packet(packet, &mut || false).unwrap()
+}
+
+fn quad(value: i64) -> String {
+    encode_hex(&value.to_le_bytes())
+}
+
+const PROGRAM: &str = "irmovq $1, %rax
+    irmovq $2, %rbx
+    addq %rax, %rbx
+    rmmovq %rbx, 512(%rax)
+    halt";
+
+#[test]
+fn test_registers_in_y86_order() {
+    let code = assemble(PROGRAM);
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    send(&mut stub, "s");
+    send(&mut stub, "s");
+    let registers = send(&mut stub, "g");
+    assert_eq!(registers.len(), REGISTER_COUNT * 16);
+    // rax is register 0, rbx register 3 and rsp register 4
+    assert_eq!(&registers[0..16], quad(1));
+    assert_eq!(&registers[3 * 16..4 * 16], quad(2));
+    assert_eq!(
+        send(&mut stub, "p4"),
+        quad(stub.simulator.registers[Register::Rsp as usize])
+    );
+    assert_eq!(send(&mut stub, "pf"), quad(20));
+    // r13 and r14 do not exist in this simulator
+    assert_eq!(send(&mut stub, "pd"), "x".repeat(16));
+    assert_eq!(send(&mut stub, "p11"), "E01");
+}
+
+#[test]
+fn test_register_writes() {
+    let code = assemble(PROGRAM);
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    assert_eq!(send(&mut stub, &format!("P1={}", quad(-7))), "OK");
+    assert_eq!(stub.simulator.registers[Register::Rcx as usize], -7);
+    assert_eq!(send(&mut stub, &format!("P10={}", quad(0b0010))), "OK");
+    assert_eq!(stub.simulator.condition_code, 0b0010);
+    assert_eq!(send(&mut stub, &format!("Pf={}", quad(10))), "OK");
+    assert_eq!(stub.simulator.instruction_pointer, 10);
+
+    let all: String = (0..REGISTER_COUNT as i64).map(quad).collect();
+    assert_eq!(send(&mut stub, &format!("G{}", all)), "OK");
+    assert_eq!(stub.simulator.registers[Register::Rbx as usize], 3);
+    assert_eq!(stub.simulator.instruction_pointer, 15);
+    assert_eq!(send(&mut stub, "G00"), "E01");
+}
+
+#[test]
+fn test_memory_reads_and_writes_quads() {
+    let code = assemble(PROGRAM);
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    send(&mut stub, "c");
+    assert_eq!(stub.simulator.state, Status::Halted);
+    assert_eq!(send(&mut stub, "m201,8"), quad(3));
+    assert_eq!(send(&mut stub, "m201,2"), "0300");
+
+    assert_eq!(
+        send(&mut stub, &format!("M300,10:{}{}", quad(5), quad(6))),
+        "OK"
+    );
+    assert_eq!(stub.simulator.memory[0x300], 5);
+    assert_eq!(stub.simulator.memory[0x308], 6);
+    // Reads are cut short at the end of memory, and fail entirely outside it
+    assert_eq!(send(&mut stub, "m3f8,10"), quad(0));
+    assert_eq!(send(&mut stub, "m400,8"), "E01");
+    assert_eq!(
+        send(&mut stub, &format!("M3f8,10:{}{}", quad(1), quad(2))),
+        "E01"
+    );
+    assert_eq!(stub.simulator.memory[0x3f8], 0);
+}
+
+#[test]
+fn test_breakpoints_step_and_continue() {
+    let code = assemble(PROGRAM);
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    assert_eq!(send(&mut stub, "?"), "S05");
+    assert_eq!(send(&mut stub, "Z0,14,1"), "OK");
+    assert_eq!(send(&mut stub, "c"), "S05");
+    assert_eq!(stub.simulator.instruction_pointer, 0x14);
+    // Continuing from a breakpoint executes the instruction under it
+    assert_eq!(send(&mut stub, "Z0,0,1"), "OK");
+    assert_eq!(send(&mut stub, "s"), "S05");
+    assert_eq!(stub.simulator.instruction_pointer, 0x16);
+    assert_eq!(send(&mut stub, "z0,14,1"), "OK");
+    // Hardware breakpoints and watchpoints are not supported
+    assert_eq!(send(&mut stub, "Z2,200,8"), "");
+    assert_eq!(send(&mut stub, "c"), "W00");
+    assert_eq!(send(&mut stub, "s"), "W00");
+}
+
+#[test]
+fn test_errors_and_interrupts_stop() {
+    let code = assemble("ret");
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    assert_eq!(send(&mut stub, "c"), "S0b");
+
+    let code = assemble("loop: jmp loop");
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    assert_eq!(
+        stub.handle_packet("c", &mut || true),
+        Some("S02".to_string())
+    );
+    assert_eq!(stub.simulator.steps, INTERRUPT_CHECK_INTERVAL);
+}
+
+#[test]
+fn test_target_description() {
+    let code = assemble("halt");
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    assert!(send(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+    let mut xml = String::new();
+    loop {
+        let reply = send(
+            &mut stub,
+            &format!("qXfer:features:read:target.xml:{:x},40", xml.len()),
+        );
+        xml += &reply[1..];
+        if reply.starts_with('l') {
+            break;
+        }
+        assert!(reply.starts_with('m'));
+    }
+    assert_eq!(xml, TARGET_XML);
+    assert_eq!(xml.matches("<reg ").count(), REGISTER_COUNT);
+    assert_eq!(send(&mut stub, "vMustReplyEmpty"), "");
+}
+
+#[test]
+fn test_packet_framing() {
+    let mut input = &b"+$g#67$m0,8#00\x03"[..];
+    assert_eq!(
+        read_packet(&mut input).unwrap(),
+        Incoming::Packet("g".to_string())
+    );
+    assert_eq!(read_packet(&mut input).unwrap(), Incoming::Corrupt);
+    assert_eq!(read_packet(&mut input).unwrap(), Incoming::Interrupt);
+    assert_eq!(read_packet(&mut input).unwrap(), Incoming::Disconnected);
+
+    let mut out = Vec::new();
+    write_packet(&mut out, "OK").unwrap();
+    assert_eq!(out, b"$OK#9a");
+}
+
+#[test]
+fn test_serves_over_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut replies = Vec::new();
+        for packet in ["?", "Z0,14,1", "c", "pf", "k"] {
+            write_packet(&mut stream, packet).unwrap();
+            if packet == "k" {
+                break;
+            }
+            assert_eq!(read_byte(&mut stream).unwrap(), Some(b'+'));
+            match read_packet(&mut stream).unwrap() {
+                Incoming::Packet(reply) => replies.push(reply),
+                other => panic!("{:?}", other),
+            }
+            stream.write_all(b"+").unwrap();
+        }
+        replies
+    });
+
+    let code = assemble(PROGRAM);
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    stub.serve(listener.accept().unwrap().0).unwrap();
+    assert_eq!(
+        client.join().unwrap(),
+        ["S05", "OK", "S05", quad(0x14).as_str()]
+    );
+    assert_eq!(stub.simulator.instruction_pointer, 0x14);
+}
+
+#[test]
+fn test_non_ascii_packets_are_unsupported() {
+    let code = assemble("halt");
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    // The body of `$\xff#6b` decodes to a replacement character, which is not a command
+    let mut input = &b"$\xff#6b"[..];
+    let Incoming::Packet(packet) = read_packet(&mut input).unwrap() else {
+        panic!("not a packet");
+    };
+    assert_eq!(send(&mut stub, &packet), "");
+    assert_eq!(send(&mut stub, "\u{e9}1"), "");
+}
+
+#[test]
+fn test_detach_keeps_running() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut replies = Vec::new();
+        for packet in ["Z0,14,1", "D"] {
+            write_packet(&mut stream, packet).unwrap();
+            assert_eq!(read_byte(&mut stream).unwrap(), Some(b'+'));
+            match read_packet(&mut stream).unwrap() {
+                Incoming::Packet(reply) => replies.push(reply),
+                other => panic!("{:?}", other),
+            }
+            stream.write_all(b"+").unwrap();
+        }
+        replies
+    });
+
+    let code = assemble(PROGRAM);
+    let mut stub = GdbStub::new(Simulator::<1024>::new(&code));
+    stub.serve(listener.accept().unwrap().0).unwrap();
+    assert_eq!(client.join().unwrap(), ["OK", "OK"]);
+    // The breakpoint at 0x14 no longer stops the program
+    assert!(stub.simulator.is_halted());
+    assert_eq!(stub.simulator.memory[513], 3);
+}
+
+#[test]
+fn test_poll_interrupt_keeps_other_bytes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let server = listener.accept().unwrap().0;
+    client.write_all(b"+\x03$?#3f").unwrap();
+
+    let mut pending = VecDeque::new();
+    let mut interrupted = false;
+    while pending.len() < 6 {
+        interrupted |= poll_interrupt(&server, &mut pending);
+    }
+    assert!(interrupted);
+    assert_eq!(pending, b"+$?#3f");
+    assert_eq!(
+        read_packet(&mut (&mut pending).chain(&server)).unwrap(),
+        Incoming::Packet("?".to_string())
+    );
+}
@@ -0,0 +1,146 @@
+use crate::simulator::device::Device;
+#[cfg(test)]
+mod interrupt_tests;
+
+/// Where `y86 run --interrupts` maps the controller unless told otherwise.
+pub const DEFAULT_BASE: i64 = 0x30000;
+/// Address of the vector table, whose quad `n` holds the handler address for vector `n`, or 0
+/// if it has none
+pub const VECTORS: i64 = 0;
+/// Reads return the vectors waiting to be delivered, one bit each; writes clear the bits set
+pub const PENDING: i64 = 8;
+/// Instructions between timer interrupts; 0 stops the timer. Writes restart the count
+pub const TIMER_PERIOD: i64 = 16;
+/// Instructions left until the next timer interrupt; read-only
+pub const TIMER_COUNT: i64 = 24;
+/// Non-zero to deliver faults to their handlers instead of stopping the simulator
+pub const FAULTS: i64 = 32;
+
+/// Raised by the timer
+pub const TIMER: u32 = 0;
+/// An access outside memory or the devices, or a device error
+pub const ADDRESS_FAULT: u32 = 1;
+/// Bytes that do not decode, or a PC outside the program
+pub const INSTRUCTION_FAULT: u32 = 2;
+/// An access the MMU could not translate, whose address it holds in `FAULT_ADDRESS`
+pub const PAGE_FAULT: u32 = 3;
+/// Number of vectors, each with a bit in `PENDING`
+pub const VECTOR_COUNT: u32 = 64;
+
+/// Set in the flags quad pushed on entry to a handler if interrupts were enabled; bits 0-3
+/// hold the condition code.
+pub const FLAGS_INTERRUPTS_ENABLED: i64 = 1 << 4;
+
+/// Delivers interrupts and faults to handlers found through a vector table in memory.
+///
+/// Before the next instruction, the lowest pending vector is delivered if interrupts are
+/// enabled (`ei`). Faults are delivered whether or not they are, if enabled with `FAULTS`.
+/// Entering a handler pushes the PC, then the flags, and disables interrupts; `iret` pops
+/// both. The PC saved for a fault is that of the faulting instruction.
+///
+/// Map it with [`Simulator::map_interrupt_controller`](
+/// super::simulator_guts::Simulator::map_interrupt_controller), which also gives the simulator
+/// its handle.
+#[derive(Debug, Default)]
+pub struct InterruptController {
+    vector_table: i64,
+    pending: u64,
+    timer_period: u64,
+    timer_count: u64,
+    vector_faults: bool,
+}
+
+impl InterruptController {
+    /// Marks `vector` as waiting to be delivered.
+    pub fn raise(&mut self, vector: u32) {
+        assert!(vector < VECTOR_COUNT, "No interrupt vector {}", vector);
+        self.pending |= 1 << vector;
+    }
+
+    /// Interrupts the program every `period` instructions, or never if `period` is 0.
+    pub fn set_timer(&mut self, period: u64) {
+        self.timer_period = period;
+        self.timer_count = period;
+    }
+
+    pub fn set_vector_table(&mut self, addr: i64) {
+        self.vector_table = addr;
+    }
+
+    pub fn vector_table(&self) -> i64 {
+        self.vector_table
+    }
+
+    /// Delivers faults to their handlers instead of stopping the simulator.
+    pub fn set_vector_faults(&mut self, vector_faults: bool) {
+        self.vector_faults = vector_faults;
+    }
+
+    pub fn vector_faults(&self) -> bool {
+        self.vector_faults
+    }
+
+    /// Whether an interrupt may yet arrive without the program touching a device.
+    pub fn is_active(&self) -> bool {
+        self.pending != 0 || self.timer_period != 0
+    }
+
+    /// Counts one retired instruction.
+    pub(super) fn tick(&mut self) {
+        if self.timer_period == 0 {
+            return;
+        }
+        self.timer_count -= 1;
+        if self.timer_count == 0 {
+            self.raise(TIMER);
+            self.timer_count = self.timer_period;
+        }
+    }
+
+    /// Removes and returns the lowest pending vector.
+    pub(super) fn take_pending(&mut self) -> Option<u32> {
+        let vector = (self.pending != 0).then(|| self.pending.trailing_zeros())?;
+        self.pending &= !(1 << vector);
+        Some(vector)
+    }
+}
+
+impl Device for InterruptController {
+    fn size(&self) -> i64 {
+        40
+    }
+
+    fn read(&mut self, offset: i64) -> Result<i64, String> {
+        match offset {
+            VECTORS => Ok(self.vector_table),
+            PENDING => Ok(self.pending as i64),
+            TIMER_PERIOD => Ok(self.timer_period as i64),
+            TIMER_COUNT => Ok(self.timer_count as i64),
+            FAULTS => Ok(self.vector_faults as i64),
+            _ => Err(format!(
+                "Interrupt controller has no register at offset {}",
+                offset
+            )),
+        }
+    }
+
+    fn write(&mut self, offset: i64, value: i64) -> Result<(), String> {
+        match offset {
+            VECTORS => self.vector_table = value,
+            PENDING => self.pending &= !(value as u64),
+            TIMER_PERIOD if value < 0 => {
+                return Err(format!("Invalid timer period: {}", value));
+            }
+            TIMER_PERIOD => self.set_timer(value as u64),
+            TIMER_COUNT => return Err("Timer count register is read-only".to_string()),
+            FAULTS => self.vector_faults = value != 0,
+            _ => {
+                return Err(format!(
+                    "Interrupt controller has no register at offset {}",
+                    offset
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::simulator_guts::{LogPolicy, Simulator, Status};
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Runs `src` with an interrupt controller at 0x30000 on the logging path and on the fast
+/// path, checking that they agree, and returns the fast path's simulator.
+fn run<'a>(code: &'a [u8], setup: impl Fn(&mut InterruptController)) -> Simulator<'a, 1024> {
+    let mut simulators = [LogPolicy::Full, LogPolicy::Off].map(|policy| {
+        let mut simulator = Simulator::<1024>::new(code).with_log_policy(policy);
+        let controller = simulator.map_interrupt_controller(DEFAULT_BASE).unwrap();
+        setup(&mut controller.borrow_mut());
+        simulator.run();
+        simulator
+    });
+    let [logged, fast] = &mut simulators;
+    assert_eq!(logged.state, fast.state);
+    assert_eq!(logged.steps, fast.steps);
+    assert_eq!(logged.instruction_pointer, fast.instruction_pointer);
+    assert_eq!(logged.registers, fast.registers);
+    assert_eq!(logged.condition_code, fast.condition_code);
+    assert_eq!(logged.memory, fast.memory);
+    let [_, fast] = simulators;
+    fast
+}
+
+/// Counts timer interrupts in %rbx, spinning until there have been three.
+const TIMER_PROGRAM: &str = "
+    irmovq $0x30000, %r8
+    irmovq $512, %rcx
+    rmmovq %rcx, (%r8)      # VECTORS
+    irmovq tick, %rax
+    rmmovq %rax, (%rcx)     # vector 0
+    irmovq $7, %rax
+    rmmovq %rax, 16(%r8)    # TIMER_PERIOD
+    irmovq $3, %rdx
+    ei
+spin:
+    rrmovq %rbx, %rax
+    subq %rdx, %rax
+    jne spin
+    di
+    halt
+tick:
+    irmovq $1, %rsi
+    addq %rsi, %rbx
+    iret
+";
+
+#[test]
+fn test_timer_interrupts() {
+    let code = assemble(TIMER_PROGRAM);
+    let simulator = run(&code, |_| {});
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.registers[1], 3);
+    assert!(!simulator.interrupts_enabled);
+    // Every frame was popped again
+    assert_eq!(simulator.registers[6], 1016);
+}
+
+#[test]
+fn test_interrupts_wait_while_disabled() {
+    // Raised before the program starts, but only delivered after `ei`
+    let code = assemble(
+        "irmovq $512, %rcx
+        irmovq handler, %rax
+        rmmovq %rax, 24(%rcx)   # vector 3
+        irmovq $1, %rdx
+        ei
+        halt
+        handler:
+        rrmovq %rdx, %rbx
+        iret",
+    );
+    let simulator = run(&code, |controller| {
+        controller.set_vector_table(512);
+        controller.raise(3);
+    });
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.registers[1], 1);
+    assert!(simulator.interrupts_enabled);
+}
+
+#[test]
+fn test_interrupt_frame() {
+    let code = assemble(
+        "irmovq $512, %rcx
+        irmovq handler, %rax
+        rmmovq %rax, (%rcx)
+        xorq %rax, %rax         # Z set
+        ei
+        nop
+        handler:
+        halt",
+    );
+    let simulator = run(&code, |controller| {
+        controller.set_vector_table(512);
+        controller.raise(TIMER);
+    });
+    assert_eq!(simulator.state, Status::Halted);
+    // Entered after `ei`, returning to the `nop`
+    assert_eq!(simulator.memory[1008], 33);
+    assert_eq!(
+        simulator.memory[1000],
+        FLAGS_INTERRUPTS_ENABLED | simulator.condition_code as i64
+    );
+    assert_eq!(simulator.registers[6], 1000);
+    assert!(!simulator.interrupts_enabled);
+}
+
+#[test]
+fn test_faults_are_vectored() {
+    let src = "
+        irmovq $512, %rcx
+        irmovq address, %rax
+        rmmovq %rax, 8(%rcx)
+        irmovq instruction, %rax
+        rmmovq %rax, 16(%rcx)
+        irmovq $4096, %rbx
+        mrmovq (%rbx), %rax     # bad address
+        halt
+        address:
+        irmovq $1, %rdx
+        mrmovq 8(%rsp), %rsi    # the faulting PC
+        jmp bad
+        halt
+        bad:
+        .quad 0xffffffffffffffff
+        instruction:
+        mrmovq 8(%rsp), %rdi
+        halt";
+    let code = assemble(src);
+    let simulator = run(&code, |controller| {
+        controller.set_vector_table(512);
+        controller.set_vector_faults(true);
+    });
+    assert_eq!(simulator.state, Status::Halted);
+    // Each handler sees the PC of the instruction that faulted
+    assert_eq!(simulator.registers[3], 1);
+    assert_eq!(simulator.registers[5], 60);
+    assert_eq!(simulator.registers[4], 101);
+
+    // Without vectoring, faults stop the simulator as before
+    let simulator = run(&code, |controller| controller.set_vector_table(512));
+    assert_eq!(
+        simulator.state,
+        Status::Error("Memory address out of bounds: 4096".to_string())
+    );
+}
+
+#[test]
+fn test_unhandled_faults() {
+    let code = assemble("irmovq $4096, %rbx\nmrmovq (%rbx), %rax\nhalt");
+    let simulator = run(&code, |controller| controller.set_vector_faults(true));
+    assert_eq!(
+        simulator.state,
+        Status::Error(
+            "Memory address out of bounds: 4096 (fault not handled: No handler for vector 1)"
+                .to_string()
+        )
+    );
+    assert_eq!(simulator.steps, 1);
+}
+
+#[test]
+fn test_controller_registers() {
+    let mut controller = InterruptController::default();
+    controller.raise(5);
+    controller.raise(2);
+    assert_eq!(controller.read(PENDING), Ok(0b100100));
+    controller.write(PENDING, 0b100000).unwrap();
+    assert_eq!(controller.take_pending(), Some(2));
+    assert_eq!(controller.take_pending(), None);
+
+    controller.write(TIMER_PERIOD, 2).unwrap();
+    assert!(controller.write(TIMER_COUNT, 1).is_err());
+    assert!(controller.write(TIMER_PERIOD, -1).is_err());
+    controller.tick();
+    assert_eq!(controller.read(TIMER_COUNT), Ok(1));
+    controller.tick();
+    assert_eq!(controller.read(TIMER_COUNT), Ok(2));
+    assert_eq!(controller.take_pending(), Some(TIMER));
+    assert!(controller.is_active());
+    controller.write(TIMER_PERIOD, 0).unwrap();
+    assert!(!controller.is_active());
+}
+
+#[test]
+fn test_interrupt_instructions() {
+    assert_eq!(assemble("iret\nei\ndi"), [0xD0, 0xE0, 0xE1]);
+
+    // A timer that will interrupt keeps the loop detector from giving up on a spinning program
+    let code = assemble(
+        "irmovq $512, %rcx
+        irmovq handler, %rax
+        rmmovq %rax, (%rcx)
+        ei
+        spin:
+        jmp spin
+        handler:
+        halt",
+    );
+    let mut simulator = Simulator::<1024>::new(&code).with_loop_detection();
+    let controller = simulator.map_interrupt_controller(DEFAULT_BASE).unwrap();
+    controller.borrow_mut().set_vector_table(512);
+    controller.borrow_mut().set_timer(50);
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+}
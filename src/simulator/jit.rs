@@ -0,0 +1,537 @@
+//! Translates Y86 basic blocks to native x86-64 code at runtime.
+//!
+//! Generated code works directly on a [`Context`] holding the registers, PC, condition code and
+//! step count, plus a pointer to the simulator's memory. Anything out of the ordinary, such as
+//! a bounds violation, an arithmetic overflow or a halt, leaves the block through a side exit
+//! so that the interpreter executes that instruction and produces the exact same status.
+use super::simulator_guts::{LogPolicy, Simulator, Status, condition_holds, decode};
+use crate::ast::{BinaryOp, CondOp, Instruction, LabOrImm, OwnedInstruction, Register};
+use emitter::{AluOp, CARRY, Cond, Emitter, NO_CARRY, Reg};
+use memmap2::{Mmap, MmapMut};
+use std::mem::offset_of;
+mod emitter;
+#[cfg(test)]
+mod jit_tests;
+
+/// Machine state shared with generated code.
+#[repr(C)]
+struct Context {
+    registers: [i64; 13],
+    instruction_pointer: i64,
+    steps: u64,
+    memory: *mut i64,
+    condition_code: u8,
+}
+
+const REGISTERS: i32 = offset_of!(Context, registers) as i32;
+const IP: i32 = offset_of!(Context, instruction_pointer) as i32;
+const STEPS: i32 = offset_of!(Context, steps) as i32;
+const MEMORY: i32 = offset_of!(Context, memory) as i32;
+const CC: i32 = offset_of!(Context, condition_code) as i32;
+
+/// The block ran to completion and set the PC to its successor
+const EXIT_OK: u32 = 0;
+/// The instruction at the PC must be executed by the interpreter
+const EXIT_INTERPRET: u32 = 1;
+
+type BlockFn = unsafe extern "sysv64" fn(*mut Context) -> u32;
+
+fn register(reg: Register) -> i32 {
+    REGISTERS + reg as i32 * 8
+}
+
+/// Bit `cc` is set if `cond` holds for condition code `cc`, for testing with `bt`.
+fn condition_table(cond: CondOp) -> u32 {
+    (0..16u8)
+        .filter(|&cc| condition_holds(cond, cc))
+        .fold(0, |table, cc| table | 1 << cc)
+}
+
+/// A block of native code and the Y86 instructions it was translated from.
+struct CompiledBlock {
+    code: Mmap,
+    /// Number of Y86 instructions retired by running the whole block
+    instructions: u64,
+}
+
+impl CompiledBlock {
+    /// Runs the block.
+    ///
+    /// # Safety
+    /// `context.memory` must point to `MEM_SIZE` writable `i64`s for the `MEM_SIZE` the
+    /// block was compiled with.
+    unsafe fn run(&self, context: &mut Context) -> u32 {
+        // Safety: the mapping holds a complete function generated by `compile_block`
+        unsafe {
+            let entry: BlockFn = std::mem::transmute(self.code.as_ptr());
+            entry(context)
+        }
+    }
+}
+
+/// A side exit taken from the instruction at `ip`, the `index`th of its block.
+struct SideExit {
+    patch_at: usize,
+    ip: i64,
+    index: usize,
+    code: u32,
+}
+
+struct Compiler<const MEM_SIZE: usize> {
+    emitter: Emitter,
+    exits: Vec<SideExit>,
+    code_len: i64,
+}
+
+impl<const MEM_SIZE: usize> Compiler<MEM_SIZE> {
+    fn side_exit(&mut self, cond: Cond, ip: i64, index: usize, code: u32) {
+        let patch_at = self.emitter.jump_if(cond);
+        self.exits.push(SideExit {
+            patch_at,
+            ip,
+            index,
+            code,
+        });
+    }
+
+    /// Exits unless `Rax` is a valid memory address.
+    fn check_address(&mut self, ip: i64, index: usize) {
+        let e = &mut self.emitter;
+        e.mov_imm(Reg::Rcx, MEM_SIZE as i64);
+        e.alu(AluOp::Cmp, Reg::Rax, Reg::Rcx);
+        self.side_exit(Cond::AboveOrEqual, ip, index, EXIT_INTERPRET);
+    }
+
+    /// Computes `disp + reg` into `Rax`, exiting if it overflows.
+    fn effective_address(&mut self, disp: i64, reg: Register, ip: i64, index: usize) {
+        let e = &mut self.emitter;
+        e.load(Reg::Rax, register(reg));
+        e.mov_imm(Reg::Rcx, disp);
+        e.alu(AluOp::Add, Reg::Rax, Reg::Rcx);
+        self.side_exit(Cond::Overflow, ip, index, EXIT_INTERPRET);
+    }
+
+    /// Computes `%rsp - 8` into `Rax`, exiting if it overflows or is not a valid address.
+    fn decrement_stack(&mut self, ip: i64, index: usize) {
+        let e = &mut self.emitter;
+        e.load(Reg::Rax, register(Register::Rsp));
+        e.mov_imm(Reg::Rcx, 8);
+        e.alu(AluOp::Sub, Reg::Rax, Reg::Rcx);
+        self.side_exit(Cond::Overflow, ip, index, EXIT_INTERPRET);
+        self.check_address(ip, index);
+    }
+
+    /// Sets the carry flag if `cond` holds.
+    fn test_condition(&mut self, cond: CondOp) {
+        let e = &mut self.emitter;
+        e.load_byte(Reg::Rax, CC);
+        e.mov32_imm(Reg::Rdx, condition_table(cond));
+        e.bt32(Reg::Rdx, Reg::Rax);
+    }
+
+    /// Leaves the block with the PC in `Rax` and `retired` more instructions counted.
+    fn finish(&mut self, retired: usize) {
+        let e = &mut self.emitter;
+        e.store(IP, Reg::Rax);
+        e.add_imm(STEPS, retired as i32);
+        e.mov32_imm(Reg::Rax, EXIT_OK);
+        e.ret();
+    }
+
+    /// Translates one instruction, returning false if it must be left to the interpreter.
+    /// Block-ending instructions emit their own exit.
+    fn instruction(
+        &mut self,
+        instruction: &OwnedInstruction,
+        ip: i64,
+        next: i64,
+        index: usize,
+    ) -> bool {
+        match instruction {
+            Instruction::Nop => {}
+            &Instruction::Irmov(LabOrImm::Immediate(imm), reg) => {
+                self.emitter.mov_imm(Reg::Rax, imm);
+                self.emitter.store(register(reg), Reg::Rax);
+            }
+            &Instruction::Cmov(cond, src, dst) => {
+                let skip = (cond != CondOp::Uncon).then(|| {
+                    self.test_condition(cond);
+                    self.emitter.jump_if(NO_CARRY)
+                });
+                self.emitter.load(Reg::Rax, register(src));
+                self.emitter.store(register(dst), Reg::Rax);
+                if let Some(skip) = skip {
+                    let target = self.emitter.position();
+                    self.emitter.patch(skip, target);
+                }
+            }
+            &Instruction::Rmmov(src, disp, base) => {
+                self.effective_address(disp, base, ip, index);
+                self.check_address(ip, index);
+                self.emitter.load(Reg::Rcx, register(src));
+                self.emitter.store_memory(Reg::Rax, Reg::Rcx);
+            }
+            &Instruction::Mrmov(disp, base, dst) => {
+                self.effective_address(disp, base, ip, index);
+                self.check_address(ip, index);
+                self.emitter.load_memory(Reg::Rcx, Reg::Rax);
+                self.emitter.store(register(dst), Reg::Rcx);
+            }
+            &Instruction::Binop(op, src, dst) => self.binop(op, src, dst, ip, index),
+            &Instruction::Push(reg) => {
+                self.decrement_stack(ip, index);
+                self.emitter.load(Reg::Rcx, register(reg));
+                self.emitter.store_memory(Reg::Rax, Reg::Rcx);
+                self.emitter.store(register(Register::Rsp), Reg::Rax);
+            }
+            &Instruction::Pop(reg) => {
+                self.emitter.load(Reg::Rax, register(Register::Rsp));
+                self.check_address(ip, index);
+                let e = &mut self.emitter;
+                e.load_memory(Reg::Rcx, Reg::Rax);
+                e.store(register(reg), Reg::Rcx);
+                // Popping into %rsp leaves the popped value as the stack pointer
+                if reg != Register::Rsp {
+                    e.mov_imm(Reg::Rcx, 8);
+                    e.alu(AluOp::Add, Reg::Rax, Reg::Rcx);
+                    e.store(register(Register::Rsp), Reg::Rax);
+                }
+            }
+            &Instruction::Jmp(cond, LabOrImm::Immediate(target))
+                if target >= 0 && target < self.code_len =>
+            {
+                if cond == CondOp::Uncon {
+                    self.emitter.mov_imm(Reg::Rax, target);
+                } else {
+                    self.test_condition(cond);
+                    let e = &mut self.emitter;
+                    // `mov` leaves the flags alone
+                    e.mov_imm(Reg::Rax, target);
+                    let taken = e.jump_if(CARRY);
+                    e.mov_imm(Reg::Rax, next);
+                    let target = e.position();
+                    e.patch(taken, target);
+                }
+                self.finish(index + 1);
+            }
+            &Instruction::Call(LabOrImm::Immediate(target))
+                if target >= 0 && target < self.code_len =>
+            {
+                self.decrement_stack(ip, index);
+                let e = &mut self.emitter;
+                e.mov_imm(Reg::Rcx, next);
+                e.store_memory(Reg::Rax, Reg::Rcx);
+                e.store(register(Register::Rsp), Reg::Rax);
+                e.mov_imm(Reg::Rax, target);
+                self.finish(index + 1);
+            }
+            Instruction::Ret => {
+                self.emitter.load(Reg::Rax, register(Register::Rsp));
+                self.check_address(ip, index);
+                let e = &mut self.emitter;
+                e.load_memory(Reg::Rdx, Reg::Rax);
+                // The return address must be in the program, then the new stack pointer in memory
+                e.mov_imm(Reg::Rcx, self.code_len);
+                e.alu(AluOp::Cmp, Reg::Rdx, Reg::Rcx);
+                self.side_exit(Cond::AboveOrEqual, ip, index, EXIT_INTERPRET);
+                let e = &mut self.emitter;
+                e.mov_imm(Reg::Rcx, 8);
+                e.alu(AluOp::Add, Reg::Rax, Reg::Rcx);
+                self.check_address(ip, index);
+                let e = &mut self.emitter;
+                e.store(register(Register::Rsp), Reg::Rax);
+                e.alu(AluOp::Mov, Reg::Rax, Reg::Rdx);
+                self.finish(index + 1);
+            }
+            // Halts, faults and out of range targets are left to the interpreter
+            _ => return false,
+        }
+        true
+    }
+
+    /// Emits `dst = dst op src` and the condition code, exactly as the interpreter's ALU.
+    fn binop(&mut self, op: BinaryOp, src: Register, dst: Register, ip: i64, index: usize) {
+        self.emitter.load(Reg::Rax, register(src));
+        if op == BinaryOp::Sub {
+            // The interpreter adds -src, which overflows for i64::MIN
+            self.emitter.neg(Reg::Rax);
+            self.side_exit(Cond::Overflow, ip, index, EXIT_INTERPRET);
+        }
+        let e = &mut self.emitter;
+        e.load(Reg::Rcx, register(dst));
+        let alu_op = match op {
+            BinaryOp::Add | BinaryOp::Sub => AluOp::Add,
+            BinaryOp::And => AluOp::And,
+            BinaryOp::Xor => AluOp::Xor,
+        };
+        e.alu(alu_op, Reg::Rcx, Reg::Rax);
+        e.store(register(dst), Reg::Rcx);
+
+        // Z and S from the result
+        let arithmetic = matches!(op, BinaryOp::Add | BinaryOp::Sub);
+        if arithmetic {
+            e.set(Cond::Overflow, Reg::Rcx);
+        }
+        e.set(Cond::Zero, Reg::Rax);
+        e.set(Cond::Sign, Reg::Rdx);
+        e.movzx(Reg::Rax, Reg::Rax);
+        e.shl32(Reg::Rax, 1);
+        e.movzx(Reg::Rdx, Reg::Rdx);
+        e.shl32(Reg::Rdx, 2);
+        e.or32(Reg::Rax, Reg::Rdx);
+        if arithmetic {
+            // Signed overflow sets both C and V
+            e.movzx(Reg::Rcx, Reg::Rcx);
+            e.or32(Reg::Rax, Reg::Rcx);
+            e.shl32(Reg::Rcx, 3);
+            e.or32(Reg::Rax, Reg::Rcx);
+        } else {
+            // Logical operations keep C and V
+            e.load_byte(Reg::Rcx, CC);
+            e.and32_imm(Reg::Rcx, 0b1001);
+            e.or32(Reg::Rax, Reg::Rcx);
+        }
+        e.store_byte(CC, Reg::Rax);
+    }
+
+    /// Emits the out-of-line code for every side exit.
+    fn emit_exits(&mut self) {
+        for exit in std::mem::take(&mut self.exits) {
+            let target = self.emitter.position();
+            self.emitter.patch(exit.patch_at, target);
+            let e = &mut self.emitter;
+            e.mov_imm(Reg::Rax, exit.ip);
+            e.store(IP, Reg::Rax);
+            e.add_imm(STEPS, exit.index as i32);
+            e.mov32_imm(Reg::Rax, exit.code);
+            e.ret();
+        }
+    }
+}
+
+/// Translates the basic block at `ip`, or returns `None` if its first instruction has to be
+/// interpreted.
+fn compile_block<const MEM_SIZE: usize>(source: &[u8], ip: i64) -> Option<CompiledBlock> {
+    let mut compiler = Compiler::<MEM_SIZE> {
+        emitter: Emitter::default(),
+        exits: Vec::new(),
+        code_len: source.len() as i64,
+    };
+    compiler.emitter.load(Reg::Rsi, MEMORY);
+
+    let mut next = ip;
+    let mut index = 0;
+    let mut branched = false;
+    while !branched {
+        let Ok(instruction) = decode(source, next) else {
+            break;
+        };
+        let after = next + instruction.encoded_len() as i64;
+        if !compiler.instruction(&instruction, next, after, index) {
+            break;
+        }
+        branched = matches!(
+            instruction,
+            Instruction::Jmp(..) | Instruction::Call(_) | Instruction::Ret
+        );
+        next = after;
+        index += 1;
+    }
+    if index == 0 {
+        return None;
+    }
+    if !branched {
+        // Fall through to an instruction the interpreter has to run
+        compiler.emitter.mov_imm(Reg::Rax, next);
+        compiler.finish(index);
+    }
+    compiler.emit_exits();
+
+    let mut code = MmapMut::map_anon(compiler.emitter.code.len()).ok()?;
+    code.copy_from_slice(&compiler.emitter.code);
+    Some(CompiledBlock {
+        code: code.make_exec().ok()?,
+        instructions: index as u64,
+    })
+}
+
+/// Translation state of one entry address.
+enum Slot {
+    Untranslated,
+    /// The first instruction has to be interpreted
+    Interpreted,
+    Compiled(CompiledBlock),
+}
+
+/// Runs a [`Simulator`] by compiling its program to native code a basic block at a time.
+///
+/// Instructions the JIT does not translate, and instructions that would fail, are executed by
+/// the simulator's interpreter, so the final state is the same as `Simulator::run`. Blocks are
+/// translated from the read-only program image, which stores never reach, so they are never
+/// invalidated.
+pub struct Jit<'a, const MEM_SIZE: usize> {
+    pub simulator: Simulator<'a, MEM_SIZE>,
+    /// Indexed by entry address
+    slots: Vec<Slot>,
+}
+
+impl<'a, const MEM_SIZE: usize> Jit<'a, MEM_SIZE> {
+    /// Takes over `simulator`, which must not have observers, loop detection, an interrupt
+    /// controller, an MMU, a memory map or sparse memory attached, since translated code does
+    /// not report individual instructions or translate or check addresses, and addresses only
+    /// RAM.
+    pub fn new(simulator: Simulator<'a, MEM_SIZE>) -> Result<Self, String> {
+        if simulator.has_observers() || simulator.loop_detector.is_some() {
+            return Err("The JIT cannot run with observers or loop detection".to_string());
+        }
+        if simulator.has_interrupt_controller() {
+            return Err("The JIT cannot run with an interrupt controller".to_string());
+        }
+        if simulator.has_mmu() {
+            return Err("The JIT cannot run with an MMU".to_string());
+        }
+        if simulator.memory_map().is_some() {
+            return Err("The JIT cannot run with a memory map".to_string());
+        }
+        if simulator.sparse_memory().is_some() {
+            return Err("The JIT cannot run with sparse memory".to_string());
+        }
+        let slots = (0..simulator.source.len())
+            .map(|_| Slot::Untranslated)
+            .collect();
+        Ok(Self {
+            simulator: simulator.with_log_policy(LogPolicy::Off),
+            slots,
+        })
+    }
+
+    /// Number of translated blocks.
+    pub fn compiled_blocks(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Compiled(_)))
+            .count()
+    }
+
+    /// Runs until the program halts or fails.
+    pub fn run(&mut self) {
+        while self.simulator.state == Status::Running {
+            self.execute(false);
+        }
+    }
+
+    /// Runs one translated block, or a single interpreted instruction.
+    ///
+    /// Returns false once the simulator has stopped.
+    pub fn step_block(&mut self) -> bool {
+        if self.simulator.state != Status::Running {
+            return false;
+        }
+        self.execute(true);
+        true
+    }
+
+    /// The translated block at `ip`, translating it on first use.
+    fn block(&mut self, ip: i64) -> Option<&CompiledBlock> {
+        let slot = self.slots.get_mut(usize::try_from(ip).ok()?)?;
+        if let Slot::Untranslated = slot {
+            *slot = match compile_block::<MEM_SIZE>(self.simulator.source, ip) {
+                Some(block) => Slot::Compiled(block),
+                None => Slot::Interpreted,
+            };
+        }
+        match slot {
+            Slot::Compiled(block) => Some(block),
+            _ => None,
+        }
+    }
+
+    /// Runs translated blocks until one exits to the interpreter, then runs that instruction.
+    fn execute(&mut self, single_block: bool) {
+        let simulator = &mut self.simulator;
+        let max_steps = simulator.max_steps;
+        let mut context = Context {
+            registers: simulator.registers,
+            instruction_pointer: simulator.instruction_pointer,
+            steps: simulator.steps,
+            memory: simulator.memory.as_mut_ptr(),
+            condition_code: simulator.condition_code,
+        };
+
+        let mut ran_block = false;
+        let exit = loop {
+            if single_block && ran_block {
+                break EXIT_OK;
+            }
+            let steps = context.steps;
+            let Some(block) = self
+                .block(context.instruction_pointer)
+                .filter(|block| max_steps.is_none_or(|max| steps + block.instructions <= max))
+            else {
+                break EXIT_INTERPRET;
+            };
+            // Safety: the block was compiled for MEM_SIZE and `memory` is the simulator's
+            let exit = unsafe { block.run(&mut context) };
+            ran_block = true;
+            if exit != EXIT_OK {
+                break exit;
+            }
+        };
+
+        let simulator = &mut self.simulator;
+        simulator.registers = context.registers;
+        simulator.instruction_pointer = context.instruction_pointer;
+        simulator.steps = context.steps;
+        simulator.condition_code = context.condition_code;
+        if exit == EXIT_INTERPRET {
+            simulator.run_single();
+        }
+    }
+}
+
+/// Runs `source` on the JIT and on the interpreter in lockstep, comparing the machine state
+/// after every block. Returns the JIT's final state, or a description of the first difference.
+pub fn run_differential<const MEM_SIZE: usize>(
+    source: &[u8],
+    max_steps: Option<u64>,
+) -> Result<Simulator<'_, MEM_SIZE>, String> {
+    let mut interpreter = Simulator::<MEM_SIZE>::new(source).with_log_policy(LogPolicy::Off);
+    interpreter.max_steps = max_steps;
+    let mut simulator = Simulator::<MEM_SIZE>::new(source);
+    simulator.max_steps = max_steps;
+    let mut jit = Jit::new(simulator)?;
+
+    loop {
+        let block_ip = jit.simulator.instruction_pointer;
+        let running = jit.step_block();
+        while interpreter.state == Status::Running && interpreter.steps < jit.simulator.steps {
+            interpreter.run_single();
+        }
+        // An instruction that fails retires nothing, so catch up on its status too
+        if jit.simulator.state != Status::Running && interpreter.state == Status::Running {
+            interpreter.run_single();
+        }
+
+        let jit_state = &jit.simulator;
+        let differences = [
+            (jit_state.state != interpreter.state).then_some("status"),
+            (jit_state.steps != interpreter.steps).then_some("step count"),
+            (jit_state.instruction_pointer != interpreter.instruction_pointer).then_some("PC"),
+            (jit_state.registers != interpreter.registers).then_some("registers"),
+            (jit_state.condition_code != interpreter.condition_code).then_some("condition code"),
+            (jit_state.memory != interpreter.memory).then_some("memory"),
+        ];
+        let differences = differences.iter().flatten().copied().collect::<Vec<_>>();
+        if !differences.is_empty() {
+            return Err(format!(
+                "JIT and interpreter differ in {} after the block at {:#x} (step {})",
+                differences.join(", "),
+                block_ip,
+                jit_state.steps
+            ));
+        }
+        if !running {
+            return Ok(jit.simulator);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+/// Host registers used by generated code.
+///
+/// `Rdi` holds the context pointer and `Rsi` the base of simulated memory for the whole block;
+/// the rest are scratch. All of them are caller-saved in the System V ABI.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rsi = 6,
+    Rdi = 7,
+}
+
+/// x86 condition codes, as used by `jcc` and `setcc`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    Overflow = 0x0,
+    Below = 0x2,
+    AboveOrEqual = 0x3,
+    Zero = 0x4,
+    Sign = 0x8,
+}
+
+/// Carry set, after `bt`
+pub const CARRY: Cond = Cond::Below;
+/// Carry clear, after `bt`
+pub const NO_CARRY: Cond = Cond::AboveOrEqual;
+
+/// `op r/m64, r64` opcodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluOp {
+    Add = 0x01,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+    Mov = 0x89,
+}
+
+/// Appends x86-64 machine code for the handful of instructions the JIT needs.
+///
+/// Memory operands are always `[rdi + disp32]` (the context) or `[rsi + index*8]`
+/// (simulated memory, one `i64` per address).
+#[derive(Debug, Default)]
+pub struct Emitter {
+    pub code: Vec<u8>,
+}
+
+impl Emitter {
+    pub fn position(&self) -> usize {
+        self.code.len()
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn modrm_rdi(&mut self, reg: u8, disp: i32) {
+        self.code.push(0x80 | (reg << 3) | Reg::Rdi as u8);
+        self.bytes(&disp.to_le_bytes());
+    }
+
+    /// `mov reg, [rdi + disp]`
+    pub fn load(&mut self, reg: Reg, disp: i32) {
+        self.bytes(&[0x48, 0x8B]);
+        self.modrm_rdi(reg as u8, disp);
+    }
+
+    /// `mov [rdi + disp], reg`
+    pub fn store(&mut self, disp: i32, reg: Reg) {
+        self.bytes(&[0x48, 0x89]);
+        self.modrm_rdi(reg as u8, disp);
+    }
+
+    /// `mov reg, [rsi + index*8]`
+    pub fn load_memory(&mut self, reg: Reg, index: Reg) {
+        self.bytes(&[
+            0x48,
+            0x8B,
+            0x04 | (reg as u8) << 3,
+            0xC0 | (index as u8) << 3 | Reg::Rsi as u8,
+        ]);
+    }
+
+    /// `mov [rsi + index*8], reg`
+    pub fn store_memory(&mut self, index: Reg, reg: Reg) {
+        self.bytes(&[
+            0x48,
+            0x89,
+            0x04 | (reg as u8) << 3,
+            0xC0 | (index as u8) << 3 | Reg::Rsi as u8,
+        ]);
+    }
+
+    /// `mov reg, imm64`
+    pub fn mov_imm(&mut self, reg: Reg, imm: i64) {
+        self.bytes(&[0x48, 0xB8 + reg as u8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `op dst, src` on 64 bit registers
+    pub fn alu(&mut self, op: AluOp, dst: Reg, src: Reg) {
+        self.bytes(&[0x48, op as u8, 0xC0 | (src as u8) << 3 | dst as u8]);
+    }
+
+    /// `neg reg`
+    pub fn neg(&mut self, reg: Reg) {
+        self.bytes(&[0x48, 0xF7, 0xD8 | reg as u8]);
+    }
+
+    /// `setcc reg8`, for the low byte of `Rax`, `Rcx` or `Rdx`
+    pub fn set(&mut self, cond: Cond, reg: Reg) {
+        self.bytes(&[0x0F, 0x90 | cond as u8, 0xC0 | reg as u8]);
+    }
+
+    /// `movzx dst32, src8`
+    pub fn movzx(&mut self, dst: Reg, src: Reg) {
+        self.bytes(&[0x0F, 0xB6, 0xC0 | (dst as u8) << 3 | src as u8]);
+    }
+
+    /// `movzx reg32, byte [rdi + disp]`
+    pub fn load_byte(&mut self, reg: Reg, disp: i32) {
+        self.bytes(&[0x0F, 0xB6]);
+        self.modrm_rdi(reg as u8, disp);
+    }
+
+    /// `mov byte [rdi + disp], reg8`
+    pub fn store_byte(&mut self, disp: i32, reg: Reg) {
+        self.code.push(0x88);
+        self.modrm_rdi(reg as u8, disp);
+    }
+
+    /// `shl reg32, count`
+    pub fn shl32(&mut self, reg: Reg, count: u8) {
+        self.bytes(&[0xC1, 0xE0 | reg as u8, count]);
+    }
+
+    /// `or dst32, src32`
+    pub fn or32(&mut self, dst: Reg, src: Reg) {
+        self.bytes(&[0x09, 0xC0 | (src as u8) << 3 | dst as u8]);
+    }
+
+    /// `and reg32, imm32`
+    pub fn and32_imm(&mut self, reg: Reg, imm: u32) {
+        self.bytes(&[0x81, 0xE0 | reg as u8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `mov reg32, imm32`
+    pub fn mov32_imm(&mut self, reg: Reg, imm: u32) {
+        self.code.push(0xB8 + reg as u8);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `bt base32, bit32`, leaving the selected bit in the carry flag
+    pub fn bt32(&mut self, base: Reg, bit: Reg) {
+        self.bytes(&[0x0F, 0xA3, 0xC0 | (bit as u8) << 3 | base as u8]);
+    }
+
+    /// `add qword [rdi + disp], imm32`
+    pub fn add_imm(&mut self, disp: i32, imm: i32) {
+        self.bytes(&[0x48, 0x81]);
+        self.modrm_rdi(0, disp);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `jcc rel32` to a target patched in later; returns the position to patch.
+    pub fn jump_if(&mut self, cond: Cond) -> usize {
+        self.bytes(&[0x0F, 0x80 | cond as u8, 0, 0, 0, 0]);
+        self.position() - 4
+    }
+
+    /// Points the jump whose rel32 is at `at` to `target`.
+    pub fn patch(&mut self, at: usize, target: usize) {
+        let rel = target as i64 - (at as i64 + 4);
+        self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xC3);
+    }
+}
@@ -0,0 +1,202 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+fn run_both(src: &str, max_steps: Option<u64>) -> Status {
+    let code = assemble(src);
+    match run_differential::<1024>(&code, max_steps) {
+        Ok(simulator) => simulator.state,
+        Err(e) => panic!("{}\n{}", e, src),
+    }
+}
+
+#[test]
+fn test_jit_matches_interpreter_on_examples() {
+    for src in [
+        include_str!("../../../examples/add_numbers.ys"),
+        include_str!("../../../examples/bubble_sort.ys"),
+        include_str!("../../../examples/test_instructions.ys"),
+        include_str!("../../../examples/test_jump.ys"),
+        include_str!("../../../examples/test_jump_not_taken.ys"),
+        include_str!("../../../examples/test_sp_edge_cases.ys"),
+        include_str!("../../../examples/test_unconditional_jump.ys"),
+    ] {
+        run_both(src, None);
+    }
+}
+
+#[test]
+fn test_jit_matches_every_instruction() {
+    let state = run_both(
+        "irmovq $-5, %rax
+        irmovq $7, %rbx
+        addq %rax, %rbx
+        cmovg %rbx, %rcx
+        cmovl %rbx, %rdx
+        rrmovq %rcx, %rbp
+        subq %rbx, %rax
+        andq %rax, %rax
+        xorq %rbx, %rbx
+        rmmovq %rax, 512(%rbx)
+        mrmovq 512(%rbx), %rsi
+        pushq %rsp
+        popq %rdi
+        irmovq $800, %rsi
+        pushq %rsi
+        popq %rsp
+        nop
+        call f
+        halt
+        f:
+        ret",
+        None,
+    );
+    assert_eq!(state, Status::Halted);
+}
+
+#[test]
+fn test_jit_condition_codes_match() {
+    // Every condition after results that are zero, negative, positive and overflowing
+    let values = [0, 1, -1, i64::MAX, i64::MIN + 1];
+    let mut src = String::new();
+    for a in values {
+        for b in values {
+            for op in ["addq", "subq", "andq", "xorq"] {
+                src += &format!(
+                    "irmovq ${a}, %rax\nirmovq ${b}, %rbx\n{op} %rax, %rbx\n\
+                     cmovle %rax, %rcx\ncmovl %rax, %rdx\ncmove %rax, %rsi\ncmovne %rax, %rdi\n\
+                     cmovge %rax, %r8\ncmovg %rax, %r9\npushq %rbx\n"
+                );
+            }
+        }
+    }
+    src += "halt";
+    assert_eq!(run_both(&src, None), Status::Halted);
+}
+
+#[test]
+fn test_jit_matches_interpreter_errors() {
+    for src in [
+        "ret",
+        "jmp end\nend:",
+        "irmovq $4096, %rax\nrmmovq %rax, (%rax)",
+        "irmovq $-8, %rax\nmrmovq (%rax), %rbx",
+        "irmovq $-8, %rsp\npopq %rax",
+        "irmovq $0, %rsp\npushq %rax",
+        "irmovq $1016, %rsp\nirmovq $-1, %rax\npushq %rax\nret",
+        "irmovq $512, %rax\npushq %rax\nret",
+        "call end\nend:",
+        "nop",
+    ] {
+        assert!(matches!(run_both(src, None), Status::Error(_)), "{}", src);
+    }
+    // Code above RAM can still be called and returned to
+    let src = "call f\nhalt\n.align 2048\nf:\nret";
+    assert_eq!(run_both(src, None), Status::Halted);
+}
+
+#[test]
+fn test_jit_honours_step_limit() {
+    for limit in [0, 1, 5, 100, 101] {
+        let state = run_both(
+            "loop: irmovq $1, %rax\naddq %rax, %rbx\njmp loop",
+            Some(limit),
+        );
+        assert_eq!(state, Status::StepLimitExceeded);
+    }
+}
+
+#[test]
+fn test_jit_reuses_blocks() {
+    let code = assemble(include_str!("../../../examples/bubble_sort.ys"));
+    let mut jit = Jit::new(Simulator::<1024>::new(&code)).unwrap();
+    jit.run();
+    assert!(jit.simulator.is_halted());
+    assert!(
+        jit.compiled_blocks() <= 8,
+        "{} blocks",
+        jit.compiled_blocks()
+    );
+    assert_eq!(jit.simulator.memory[0x100], 1);
+}
+
+#[test]
+fn test_stores_over_program_do_not_change_code() {
+    // Each store lands on the first instruction's address, but code runs from the program image
+    let code = assemble(
+        "irmovq $0, %rax
+        loop:
+        rmmovq %rbx, (%rax)
+        irmovq $1, %rcx
+        addq %rcx, %rbx
+        irmovq $3, %rcx
+        rrmovq %rbx, %rdx
+        subq %rcx, %rdx
+        jne loop
+        halt",
+    );
+    let simulator = run_differential::<1024>(&code, None).unwrap();
+    assert!(simulator.is_halted());
+    assert_eq!(simulator.memory[0], 2);
+
+    let mut jit = Jit::new(Simulator::<1024>::new(&code)).unwrap();
+    jit.run();
+    assert!(jit.simulator.is_halted());
+    assert!(matches!(jit.slots[0], Slot::Compiled(_)));
+}
+
+#[test]
+fn test_device_accesses_are_interpreted() {
+    use crate::simulator::device::{Console, console};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Counts down from 3, printing a digit each time round
+    let code = assemble(
+        "irmovq $0x10000, %rbx
+        irmovq $3, %rcx
+        irmovq $1, %rdx
+        irmovq $48, %rsi
+        loop:
+        rrmovq %rcx, %rax
+        addq %rsi, %rax
+        rmmovq %rax, (%rbx)
+        subq %rdx, %rcx
+        jne loop
+        halt",
+    );
+    let device = Rc::new(RefCell::new(Console::new(&b""[..], Vec::new())));
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator
+        .map_device(console::DEFAULT_BASE, device.clone())
+        .unwrap();
+    let mut jit = Jit::new(simulator).unwrap();
+    jit.run();
+    assert!(jit.simulator.is_halted());
+    assert_eq!(device.borrow().output(), b"321");
+}
+
+#[test]
+fn test_jit_rejects_observers() {
+    let code = assemble("halt");
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator.add_observer(crate::simulator::observer::Breakpoints::default());
+    assert!(Jit::new(simulator).is_err());
+    assert!(Jit::new(Simulator::<1024>::new(&code).with_loop_detection()).is_err());
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator.map_interrupt_controller(4096).unwrap();
+    assert!(Jit::new(simulator).is_err());
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator.map_mmu(4096).unwrap();
+    assert!(Jit::new(simulator).is_err());
+}
+
+#[test]
+fn test_condition_table() {
+    assert_eq!(condition_table(CondOp::Uncon), 0xFFFF);
+    // Z is bit 1 of the condition code
+    assert_eq!(condition_table(CondOp::Eq), 0b1100_1100_1100_1100);
+}
@@ -0,0 +1,252 @@
+use super::mmu::Access;
+use std::fmt;
+use std::str::FromStr;
+#[cfg(test)]
+mod memory_map_tests;
+
+/// Bytes at the top of memory given to the stack in the standard layout.
+pub const DEFAULT_STACK_SIZE: i64 = 256;
+/// Bytes below the stack in the standard layout that no access may touch, so that a stack
+/// overflowing into the data faults.
+pub const GUARD_SIZE: i64 = 32;
+
+/// What a region allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const NONE: Self = Self::new(false, false, false);
+    pub const READ_WRITE: Self = Self::new(true, true, false);
+    pub const READ_EXECUTE: Self = Self::new(true, false, true);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+/// Parses `rwx`, with `-` for each permission withheld, as in `r-x`.
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid permissions: {} (expected e.g. rw- or r-x)", s);
+        let &[read, write, execute] = s.as_bytes() else {
+            return Err(invalid());
+        };
+        let flag = |byte: u8, letter: u8| match byte {
+            b'-' => Ok(false),
+            _ if byte == letter => Ok(true),
+            _ => Err(invalid()),
+        };
+        Ok(Self::new(
+            flag(read, b'r')?,
+            flag(write, b'w')?,
+            flag(execute, b'x')?,
+        ))
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set: bool, letter: char| if set { letter } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// The addresses [start, end), with what may be done to them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub start: i64,
+    pub end: i64,
+    pub permissions: Permissions,
+}
+
+impl Region {
+    pub fn new(name: &str, start: i64, end: i64, permissions: Permissions) -> Self {
+        Self {
+            name: name.to_string(),
+            start,
+            end,
+            permissions,
+        }
+    }
+
+    pub fn contains(&self, addr: i64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+fn parse_address(address: &str) -> Result<i64, String> {
+    match address.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(|_| format!("Invalid address: {}", address))
+}
+
+/// Parses `NAME:START-END:PERMS`, with addresses in decimal or `0x` hex, as in
+/// `data:0x100-0x300:rw-`.
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid region: {} (expected NAME:START-END:PERMS)", s);
+        let [name, range, permissions] = s.split(':').collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self::new(
+            name,
+            parse_address(start)?,
+            parse_address(end)?,
+            permissions.parse()?,
+        ))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{:#x}-{:#x}:{}",
+            self.name, self.start, self.end, self.permissions
+        )
+    }
+}
+
+/// An access a memory map does not allow, and the region it fell in, if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryFault {
+    pub addr: i64,
+    pub access: Access,
+    pub region: Option<Region>,
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Segmentation fault: {} of {:#x}", self.access, self.addr)?;
+        match &self.region {
+            Some(region) => write!(f, " in {} ({})", region.name, region.permissions),
+            None => write!(f, " outside any region"),
+        }
+    }
+}
+
+/// Non-overlapping regions covering the addresses a program may use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryMap {
+    /// Sorted by start address
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    /// A map of `regions`, which must not be empty or overlap.
+    pub fn new(regions: impl IntoIterator<Item = Region>) -> Result<Self, String> {
+        let mut map = Self::default();
+        for region in regions {
+            map.add(region)?;
+        }
+        Ok(map)
+    }
+
+    /// The program's code as `text`, read-only and executable; `data` above it; a `guard`
+    /// below a `stack` of `stack_size` bytes at the top of RAM; and `mmio` above RAM, for
+    /// devices.
+    pub fn standard(
+        program_size: usize,
+        memory_size: usize,
+        stack_size: i64,
+    ) -> Result<Self, String> {
+        let (text_end, memory_size) = (program_size as i64, memory_size as i64);
+        let stack = memory_size - stack_size;
+        let guard = stack - GUARD_SIZE;
+        if stack_size <= 0 || guard < text_end {
+            return Err(format!(
+                "A stack of {} bytes does not fit above a program of {} bytes",
+                stack_size, program_size
+            ));
+        }
+        let regions = [
+            Region::new("text", 0, text_end, Permissions::READ_EXECUTE),
+            Region::new("data", text_end, guard, Permissions::READ_WRITE),
+            Region::new("guard", guard, stack, Permissions::NONE),
+            Region::new("stack", stack, memory_size, Permissions::READ_WRITE),
+            Region::new("mmio", memory_size, i64::MAX, Permissions::READ_WRITE),
+        ];
+        // A program of no bytes has no text, and one filling memory up to the guard no data
+        Self::new(
+            regions
+                .into_iter()
+                .filter(|region| region.start < region.end),
+        )
+    }
+
+    pub fn add(&mut self, region: Region) -> Result<(), String> {
+        if region.start >= region.end {
+            return Err(format!("Region {} is empty", region));
+        }
+        let index = self
+            .regions
+            .partition_point(|other| other.start < region.start);
+        let overlapping = [index.checked_sub(1), Some(index)]
+            .into_iter()
+            .flatten()
+            .filter_map(|i| self.regions.get(i))
+            .find(|other| other.start < region.end && region.start < other.end);
+        if let Some(other) = overlapping {
+            return Err(format!("Region {} overlaps {}", region, other));
+        }
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn region_at(&self, addr: i64) -> Option<&Region> {
+        let index = self.regions.partition_point(|region| region.start <= addr);
+        self.regions[..index]
+            .last()
+            .filter(|region| region.contains(addr))
+    }
+
+    /// Whether `access` to `addr` is allowed.
+    pub fn check(&self, addr: i64, access: Access) -> Result<(), MemoryFault> {
+        let region = self.region_at(addr);
+        if region.is_some_and(|region| region.permissions.allows(access)) {
+            return Ok(());
+        }
+        Err(MemoryFault {
+            addr,
+            access,
+            region: region.cloned(),
+        })
+    }
+}
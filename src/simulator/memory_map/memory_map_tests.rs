@@ -0,0 +1,222 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::interrupt;
+use crate::simulator::simulator_guts::{Simulator, Status};
+use crate::simulator::syscall::HostSyscalls;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+fn run(code: &[u8], memory_map: MemoryMap) -> Simulator<'_, 1024> {
+    let mut simulator = Simulator::<1024>::new(code).with_memory_map(memory_map);
+    simulator.run();
+    simulator
+}
+
+#[test]
+fn test_region_parse_and_display() {
+    let region: Region = "data:0x100-768:rw-".parse().unwrap();
+    assert_eq!(
+        region,
+        Region::new("data", 0x100, 0x300, Permissions::READ_WRITE)
+    );
+    assert_eq!(region.to_string(), "data:0x100-0x300:rw-");
+    assert!(region.contains(0x100) && region.contains(0x2ff));
+    assert!(!region.contains(0x300));
+
+    for invalid in [
+        "data:0x100-0x300",
+        ":0-8:rw-",
+        "data:0x100:rw-",
+        "data:0-8:wr-",
+        "x:a-8:r--",
+    ] {
+        assert!(invalid.parse::<Region>().is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_overlapping_regions_are_rejected() {
+    let region = |s: &str| s.parse::<Region>().unwrap();
+    let mut map = MemoryMap::new([region("b:0x100-0x200:rw-"), region("a:0-0x100:r-x")]).unwrap();
+    assert_eq!(
+        map.regions()
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect::<Vec<_>>(),
+        ["a", "b"]
+    );
+
+    assert_eq!(
+        map.add(region("c:0x1f8-0x208:rw-")),
+        Err("Region c:0x1f8-0x208:rw- overlaps b:0x100-0x200:rw-".to_string())
+    );
+    assert_eq!(
+        map.add(region("c:0x80-0x80:rw-")),
+        Err("Region c:0x80-0x80:rw- is empty".to_string())
+    );
+    map.add(region("c:0x200-0x208:---")).unwrap();
+    assert_eq!(map.region_at(0x204).unwrap().name, "c");
+    assert!(map.region_at(0x208).is_none());
+}
+
+#[test]
+fn test_standard_layout() {
+    let map = MemoryMap::standard(0x40, 1024, DEFAULT_STACK_SIZE).unwrap();
+    let layout = map
+        .regions()
+        .iter()
+        .map(Region::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        layout,
+        [
+            "text:0x0-0x40:r-x",
+            "data:0x40-0x2e0:rw-",
+            "guard:0x2e0-0x300:---",
+            "stack:0x300-0x400:rw-",
+            "mmio:0x400-0x7fffffffffffffff:rw-",
+        ]
+    );
+    assert_eq!(
+        map.check(0x10, Access::Write),
+        Err(MemoryFault {
+            addr: 0x10,
+            access: Access::Write,
+            region: Some(map.regions()[0].clone()),
+        })
+    );
+    assert!(map.check(0x3f8, Access::Write).is_ok());
+    assert!(map.check(-8, Access::Read).unwrap_err().region.is_none());
+
+    assert!(MemoryMap::standard(0x3e0, 1024, DEFAULT_STACK_SIZE).is_err());
+}
+
+#[test]
+fn test_write_to_text_faults() {
+    let code = assemble(
+        "irmovq $8, %rax
+        rmmovq %rax, 0(%rax)
+        halt",
+    );
+    let simulator = run(&code, MemoryMap::standard(code.len(), 1024, 256).unwrap());
+    let fault = simulator.memory_fault().unwrap();
+    assert_eq!(fault.addr, 8);
+    assert_eq!(fault.access, Access::Write);
+    assert_eq!(fault.region.unwrap().name, "text");
+    assert_eq!(
+        simulator.state,
+        Status::Error("Segmentation fault: write of 0x8 in text (r-x)".to_string())
+    );
+    // The store did not happen
+    assert_eq!(simulator.memory[8], 0);
+}
+
+#[test]
+fn test_jump_into_data_faults() {
+    let code = assemble(
+        "jmp table
+        halt
+        .align 32
+        table:
+        halt",
+    );
+    let map = MemoryMap::new([
+        "text:0-0x10:r-x".parse().unwrap(),
+        "data:0x10-0x40:rw-".parse().unwrap(),
+    ])
+    .unwrap();
+    let simulator = run(&code, map);
+    assert_eq!(
+        simulator.state,
+        Status::Error("Segmentation fault: execute of 0x20 in data (rw-)".to_string())
+    );
+}
+
+#[test]
+fn test_vectored_fault_is_forgotten() {
+    let code = assemble(
+        "irmovq $0x400, %rsp
+        irmovq $0x200, %rcx
+        irmovq handler, %rax
+        rmmovq %rax, 8(%rcx)    # address fault vector
+        irmovq $0x100, %rbx
+        mrmovq (%rbx), %rax
+        halt
+        handler:
+        halt",
+    );
+    let map = MemoryMap::new([
+        "text:0-0x80:r-x".parse().unwrap(),
+        "data:0x200-0x400:rw-".parse().unwrap(),
+    ])
+    .unwrap();
+    let mut simulator = Simulator::<1024>::new(&code).with_memory_map(map);
+    let controller = simulator
+        .map_interrupt_controller(interrupt::DEFAULT_BASE)
+        .unwrap();
+    controller.borrow_mut().set_vector_table(0x200);
+    controller.borrow_mut().set_vector_faults(true);
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.memory_fault(), None);
+}
+
+#[test]
+fn test_stack_overflow_hits_guard() {
+    let code = assemble(
+        "loop:
+        pushq %rax
+        jmp loop",
+    );
+    let simulator = run(&code, MemoryMap::standard(code.len(), 1024, 64).unwrap());
+    let fault = simulator.memory_fault().unwrap();
+    assert_eq!(fault.access, Access::Write);
+    assert_eq!(fault.region.unwrap().name, "guard");
+    // The stack is the top 64 bytes, and `%rsp` starts 8 below the top
+    assert_eq!(fault.addr, 1024 - 64 - 8);
+}
+
+#[test]
+fn test_unmapped_address_faults() {
+    let code = assemble(
+        "irmovq $0x100, %rbx
+        mrmovq (%rbx), %rax
+        halt",
+    );
+    let map = MemoryMap::new(["text:0-0x20:r-x".parse().unwrap()]).unwrap();
+    let simulator = run(&code, map);
+    assert_eq!(
+        simulator.state,
+        Status::Error("Segmentation fault: read of 0x100 outside any region".to_string())
+    );
+}
+
+#[test]
+fn test_syscall_buffer_crossing_regions_faults() {
+    // Both ends of the buffer are writable, but a region in between is not
+    let code = assemble(
+        "irmovq $0x100, %rsi
+        irmovq $0x40, %rdx
+        irmovq $0, %rdi
+        irmovq $2, %rax     # read
+        syscall
+        halt",
+    );
+    let map = MemoryMap::new([
+        "text:0-0x80:r-x".parse().unwrap(),
+        "data:0x80-0x200:rw-".parse().unwrap(),
+        "consts:0x200-0x210:r--".parse().unwrap(),
+        "heap:0x210-0x300:rw-".parse().unwrap(),
+    ])
+    .unwrap();
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_memory_map(map)
+        .with_syscalls(HostSyscalls::new(&b"abc"[..], Vec::new()));
+    simulator.run();
+    assert_eq!(
+        simulator.state,
+        Status::Error("Segmentation fault: write of 0x200 in consts (r--)".to_string())
+    );
+}
@@ -0,0 +1,280 @@
+use crate::simulator::device::Device;
+use std::fmt::Display;
+#[cfg(test)]
+mod mmu_tests;
+
+/// Where `y86 run --mmu` maps the MMU unless told otherwise.
+pub const DEFAULT_BASE: i64 = 0x40000;
+/// Physical address of the root page table; writes flush the TLB
+pub const ROOT: i64 = 0;
+/// Non-zero turns translation on, from the next access
+pub const ENABLE: i64 = 8;
+/// Writes flush the TLB; reads return 0
+pub const FLUSH: i64 = 16;
+/// The virtual address of the last page fault; read-only
+pub const FAULT_ADDRESS: i64 = 24;
+/// TLB hits and misses so far; read-only
+pub const TLB_HITS: i64 = 32;
+pub const TLB_MISSES: i64 = 40;
+
+pub const PAGE_BITS: u32 = 8;
+pub const PAGE_SIZE: i64 = 1 << PAGE_BITS;
+/// Each table holds `1 << INDEX_BITS` entries, one quad each
+pub const INDEX_BITS: u32 = 4;
+pub const LEVELS: u32 = 2;
+/// Virtual addresses run from 0 up to, but not including, this
+pub const VIRTUAL_SIZE: i64 = 1 << (PAGE_BITS + INDEX_BITS * LEVELS);
+
+/// Set in every entry that maps something
+pub const PTE_VALID: i64 = 1;
+/// Permissions, checked in last-level entries only
+pub const PTE_READ: i64 = 2;
+pub const PTE_WRITE: i64 = 4;
+pub const PTE_EXECUTE: i64 = 8;
+/// The low bits of an entry hold flags; the rest is the address of the next table or page
+const PTE_FLAGS: i64 = 0xF;
+
+/// The TLB size of `Mmu::default()`.
+pub const DEFAULT_TLB_ENTRIES: usize = 8;
+
+/// The kind of access being translated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn permission(self) -> i64 {
+        match self {
+            Access::Read => PTE_READ,
+            Access::Write => PTE_WRITE,
+            Access::Execute => PTE_EXECUTE,
+        }
+    }
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+/// A cached translation of one virtual page.
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    page: i64,
+    frame: i64,
+    permissions: i64,
+}
+
+/// Translates virtual addresses through a page table in memory, once enabled.
+///
+/// Virtual address bits, from the top: `INDEX_BITS` indexing the root table, `INDEX_BITS`
+/// indexing the table it points to, then `PAGE_BITS` of offset into the page. Every entry
+/// needs `PTE_VALID`; the last one also carries the page's permissions. Frames may lie above
+/// RAM, which maps devices into the address space.
+///
+/// The TLB keeps the most recently used translations and is not kept in step with the page
+/// table: programs write `FLUSH` after changing a mapping, as they would on real hardware.
+///
+/// Map it with [`Simulator::map_mmu`](super::simulator_guts::Simulator::map_mmu).
+#[derive(Debug)]
+pub struct Mmu {
+    root: i64,
+    enabled: bool,
+    /// Least recently used first
+    tlb: Vec<TlbEntry>,
+    tlb_entries: usize,
+    hits: u64,
+    misses: u64,
+    fault_address: i64,
+    /// Set by a failed translation, until the simulator takes it
+    faulted: bool,
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new(DEFAULT_TLB_ENTRIES)
+    }
+}
+
+impl Mmu {
+    /// An MMU, with translation off, caching up to `tlb_entries` translations.
+    pub fn new(tlb_entries: usize) -> Self {
+        Self {
+            root: 0,
+            enabled: false,
+            tlb: Vec::with_capacity(tlb_entries),
+            tlb_entries,
+            hits: 0,
+            misses: 0,
+            fault_address: 0,
+            faulted: false,
+        }
+    }
+
+    pub fn set_root(&mut self, root: i64) {
+        self.root = root;
+        self.flush();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Drops every cached translation.
+    pub fn flush(&mut self) {
+        self.tlb.clear();
+    }
+
+    pub fn tlb_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn tlb_misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn fault_address(&self) -> i64 {
+        self.fault_address
+    }
+
+    /// Whether a translation has failed since the last call.
+    pub(super) fn take_fault(&mut self) -> bool {
+        std::mem::take(&mut self.faulted)
+    }
+
+    /// Walks the page table for the page holding `addr`, returning its frame and permissions.
+    fn walk(&self, memory: &[i64], addr: i64) -> Result<(i64, i64), String> {
+        let mut table = self.root;
+        let mut entry = 0;
+        for level in (0..LEVELS).rev() {
+            let index = (addr >> (PAGE_BITS + INDEX_BITS * level)) & ((1 << INDEX_BITS) - 1);
+            let entry_addr = table + 8 * index;
+            entry = usize::try_from(entry_addr)
+                .ok()
+                .and_then(|entry_addr| memory.get(entry_addr))
+                .copied()
+                .ok_or_else(|| {
+                    format!(
+                        "Page table entry for {:#x} at {:#x} is outside memory",
+                        addr, entry_addr
+                    )
+                })?;
+            if entry & PTE_VALID == 0 {
+                return Err(format!("Page fault: {:#x} is not mapped", addr));
+            }
+            table = entry & !PTE_FLAGS;
+        }
+        Ok((table, entry & (PTE_READ | PTE_WRITE | PTE_EXECUTE)))
+    }
+
+    /// Translates `addr` without touching the TLB or its statistics.
+    pub fn translate_quietly(
+        &self,
+        memory: &[i64],
+        addr: i64,
+        access: Access,
+    ) -> Result<i64, String> {
+        if !(0..VIRTUAL_SIZE).contains(&addr) {
+            return Err(format!("Page fault: {:#x} is not a virtual address", addr));
+        }
+        let (frame, permissions) = self.walk(memory, addr)?;
+        check_permission(addr, access, permissions)?;
+        Ok(frame + (addr & (PAGE_SIZE - 1)))
+    }
+
+    /// Translates `addr` for `access`, through the TLB.
+    pub fn translate(&mut self, memory: &[i64], addr: i64, access: Access) -> Result<i64, String> {
+        let result = self.lookup(memory, addr, access);
+        if result.is_err() {
+            self.fault_address = addr;
+            self.faulted = true;
+        }
+        result
+    }
+
+    fn lookup(&mut self, memory: &[i64], addr: i64, access: Access) -> Result<i64, String> {
+        if !(0..VIRTUAL_SIZE).contains(&addr) {
+            return Err(format!("Page fault: {:#x} is not a virtual address", addr));
+        }
+        let page = addr >> PAGE_BITS;
+        let entry = match self.tlb.iter().position(|entry| entry.page == page) {
+            Some(index) => {
+                self.hits += 1;
+                let entry = self.tlb.remove(index);
+                self.tlb.push(entry);
+                entry
+            }
+            None => {
+                self.misses += 1;
+                let (frame, permissions) = self.walk(memory, addr)?;
+                let entry = TlbEntry {
+                    page,
+                    frame,
+                    permissions,
+                };
+                if self.tlb_entries > 0 {
+                    if self.tlb.len() == self.tlb_entries {
+                        self.tlb.remove(0);
+                    }
+                    self.tlb.push(entry);
+                }
+                entry
+            }
+        };
+        check_permission(addr, access, entry.permissions)?;
+        Ok(entry.frame + (addr & (PAGE_SIZE - 1)))
+    }
+}
+
+fn check_permission(addr: i64, access: Access, permissions: i64) -> Result<(), String> {
+    if permissions & access.permission() == 0 {
+        return Err(format!(
+            "Page fault: {} of {:#x} is not permitted",
+            access, addr
+        ));
+    }
+    Ok(())
+}
+
+impl Device for Mmu {
+    fn size(&self) -> i64 {
+        48
+    }
+
+    fn read(&mut self, offset: i64) -> Result<i64, String> {
+        match offset {
+            ROOT => Ok(self.root),
+            ENABLE => Ok(self.enabled as i64),
+            FLUSH => Ok(0),
+            FAULT_ADDRESS => Ok(self.fault_address),
+            TLB_HITS => Ok(self.hits as i64),
+            TLB_MISSES => Ok(self.misses as i64),
+            _ => Err(format!("MMU has no register at offset {}", offset)),
+        }
+    }
+
+    fn write(&mut self, offset: i64, value: i64) -> Result<(), String> {
+        match offset {
+            ROOT => self.set_root(value),
+            ENABLE => self.enabled = value != 0,
+            FLUSH => self.flush(),
+            FAULT_ADDRESS | TLB_HITS | TLB_MISSES => {
+                return Err(format!("MMU register at offset {} is read-only", offset));
+            }
+            _ => return Err(format!("MMU has no register at offset {}", offset)),
+        }
+        Ok(())
+    }
+}
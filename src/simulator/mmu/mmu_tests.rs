@@ -0,0 +1,218 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::interrupt;
+use crate::simulator::simulator_guts::{Simulator, Status};
+use crate::simulator::syscall::HostSyscalls;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const ROOT_TABLE: usize = 0x200;
+const LEAF_TABLE: usize = 0x280;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Maps virtual `page` to `frame` through the tables at `ROOT_TABLE` and `LEAF_TABLE`.
+fn map(memory: &mut [i64], page: usize, frame: i64, permissions: i64) {
+    memory[ROOT_TABLE] = LEAF_TABLE as i64 | PTE_VALID;
+    memory[LEAF_TABLE + 8 * page] = frame | permissions | PTE_VALID;
+}
+
+/// A simulator whose first four pages map to themselves (the code, data, the page tables and
+/// the stack), with the MMU's registers at virtual 0x400.
+fn simulator(code: &[u8]) -> (Simulator<'_, 1024>, Rc<RefCell<Mmu>>) {
+    let mut simulator = Simulator::<1024>::new(code);
+    map(&mut simulator.memory, 0, 0, PTE_READ | PTE_EXECUTE);
+    for page in 1..4 {
+        map(
+            &mut simulator.memory,
+            page,
+            page as i64 * PAGE_SIZE,
+            PTE_READ | PTE_WRITE,
+        );
+    }
+    map(&mut simulator.memory, 4, DEFAULT_BASE, PTE_READ | PTE_WRITE);
+    let mmu = simulator.map_mmu(DEFAULT_BASE).unwrap();
+    mmu.borrow_mut().set_root(ROOT_TABLE as i64);
+    (simulator, mmu)
+}
+
+#[test]
+fn test_translation() {
+    let code = assemble(
+        "irmovq $0x40000, %r8
+        irmovq $1, %rax
+        rmmovq %rax, 8(%r8)     # ENABLE
+        irmovq $0x580, %rbx
+        irmovq $42, %rcx
+        rmmovq %rcx, (%rbx)     # page 5 is frame 1
+        pushq %rcx
+        call load
+        irmovq $0x400, %r8
+        mrmovq 40(%r8), %rsi    # TLB_MISSES
+        halt
+        load:
+        mrmovq (%rbx), %rdx
+        ret",
+    );
+    let (mut simulator, mmu) = simulator(&code);
+    map(&mut simulator.memory, 5, 0x100, PTE_READ | PTE_WRITE);
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.memory[0x180], 42);
+    assert_eq!(simulator.memory[0x3f0], 42);
+    assert_eq!(simulator.registers[3], 42);
+    // One miss each for the code, page 5, the stack and the MMU's own page
+    assert_eq!(simulator.registers[5], 4);
+    assert_eq!(mmu.borrow().tlb_misses(), 4);
+    assert_eq!(mmu.borrow().tlb_hits(), 12);
+}
+
+#[test]
+fn test_page_faults() {
+    let cases = [
+        (
+            "irmovq $8, %rbx\nrmmovq %rax, (%rbx)\nhalt",
+            "Page fault: write of 0x8 is not permitted",
+            8,
+        ),
+        (
+            "irmovq $0x100, %rax\npushq %rax\nret",
+            "Page fault: execute of 0x100 is not permitted",
+            0x100,
+        ),
+        (
+            "irmovq $0x600, %rbx\nmrmovq (%rbx), %rax\nhalt",
+            "Page fault: 0x600 is not mapped",
+            0x600,
+        ),
+        (
+            "irmovq $0x40000, %rbx\nmrmovq (%rbx), %rax\nhalt",
+            "Page fault: 0x40000 is not a virtual address",
+            0x40000,
+        ),
+    ];
+    for (src, error, fault_address) in cases {
+        let code = assemble(src);
+        let (mut simulator, mmu) = simulator(&code);
+        mmu.borrow_mut().set_enabled(true);
+        simulator.run();
+        assert_eq!(simulator.state, Status::Error(error.to_string()), "{}", src);
+        assert_eq!(mmu.borrow().fault_address(), fault_address);
+    }
+}
+
+#[test]
+fn test_page_fault_handler() {
+    // The handler maps the missing page, then returns to the store that faulted
+    let code = assemble(
+        "irmovq handler, %rax
+        irmovq $0x100, %rcx
+        rmmovq %rax, 24(%rcx)   # vector 3
+        irmovq $0x580, %rbx
+        irmovq $7, %rcx
+        rmmovq %rcx, (%rbx)
+        halt
+        handler:
+        irmovq $0x400, %r8
+        mrmovq 24(%r8), %rsi    # FAULT_ADDRESS
+        irmovq $0x107, %rax     # frame 1, valid, readable and writable
+        irmovq $0x2a8, %rdx     # the entry for page 5
+        rmmovq %rax, (%rdx)
+        iret",
+    );
+    let (mut simulator, mmu) = simulator(&code);
+    mmu.borrow_mut().set_enabled(true);
+    let controller = simulator
+        .map_interrupt_controller(interrupt::DEFAULT_BASE)
+        .unwrap();
+    controller.borrow_mut().set_vector_table(0x100);
+    controller.borrow_mut().set_vector_faults(true);
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.registers[5], 0x580);
+    assert_eq!(simulator.memory[0x180], 7);
+    assert_eq!(simulator.registers[6], 0x3f8);
+}
+
+#[test]
+fn test_syscall_buffers_are_translated() {
+    let code = assemble(
+        "irmovq $0x580, %rsi
+        irmovq $0x4f, %rax
+        rmmovq %rax, (%rsi)
+        irmovq $0x4b, %rax
+        rmmovq %rax, 8(%rsi)
+        irmovq $2, %rdx
+        irmovq $1, %rdi
+        irmovq $1, %rax     # write
+        syscall
+        halt",
+    );
+    let table = Rc::new(RefCell::new(HostSyscalls::new(&b""[..], Vec::new())));
+    let (simulator, mmu) = simulator(&code);
+    let mut simulator = simulator.with_syscalls(table.clone());
+    map(&mut simulator.memory, 5, 0x100, PTE_READ | PTE_WRITE);
+    mmu.borrow_mut().set_enabled(true);
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.memory[0x180], 0x4f);
+    assert_eq!(table.borrow().output(), b"OK");
+}
+
+#[test]
+fn test_tlb() {
+    let mut memory = [0; 1024];
+    map(&mut memory, 1, 0x300, PTE_READ);
+    map(&mut memory, 2, 0x100, PTE_READ);
+    let mut mmu = Mmu::new(1);
+    mmu.set_root(ROOT_TABLE as i64);
+    assert_eq!(mmu.translate(&memory, 0x108, Access::Read), Ok(0x308));
+    assert_eq!(mmu.translate(&memory, 0x110, Access::Read), Ok(0x310));
+    assert_eq!((mmu.tlb_hits(), mmu.tlb_misses()), (1, 1));
+
+    // A one-entry TLB evicts page 1 to make room for page 2
+    assert_eq!(mmu.translate(&memory, 0x200, Access::Read), Ok(0x100));
+    assert_eq!(mmu.translate(&memory, 0x100, Access::Read), Ok(0x300));
+    assert_eq!((mmu.tlb_hits(), mmu.tlb_misses()), (1, 3));
+
+    // Changing a mapping has no effect until the TLB is flushed
+    map(&mut memory, 1, 0x200, PTE_READ);
+    assert_eq!(mmu.translate(&memory, 0x100, Access::Read), Ok(0x300));
+    mmu.write(FLUSH, 1).unwrap();
+    assert_eq!(mmu.translate(&memory, 0x100, Access::Read), Ok(0x200));
+    assert_eq!(mmu.read(TLB_HITS), Ok(2));
+    assert_eq!(mmu.read(TLB_MISSES), Ok(4));
+
+    // Lookups for the host leave the TLB alone
+    assert_eq!(
+        mmu.translate_quietly(&memory, 0x208, Access::Read),
+        Ok(0x108)
+    );
+    assert!(
+        mmu.translate_quietly(&memory, 0x208, Access::Write)
+            .is_err()
+    );
+    assert_eq!((mmu.tlb_hits(), mmu.tlb_misses()), (2, 4));
+    assert!(!mmu.take_fault());
+}
+
+#[test]
+fn test_mmu_registers() {
+    let mut mmu = Mmu::default();
+    mmu.write(ROOT, 0x200).unwrap();
+    mmu.write(ENABLE, 1).unwrap();
+    assert_eq!(mmu.read(ROOT), Ok(0x200));
+    assert!(mmu.is_enabled());
+    assert!(mmu.write(TLB_HITS, 0).is_err());
+    assert!(mmu.write(FAULT_ADDRESS, 0).is_err());
+    assert!(mmu.read(48).is_err());
+
+    // Only one MMU, and it keeps the simulator on the logging path
+    let code = assemble("halt");
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator.map_mmu(DEFAULT_BASE).unwrap();
+    assert!(simulator.map_mmu(0x50000).is_err());
+    assert!(!simulator.can_run_fast());
+}
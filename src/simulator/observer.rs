@@ -0,0 +1,142 @@
+#[cfg(test)]
+use super::simulator_guts::Simulator;
+use super::simulator_guts::Status;
+use crate::ast::{OwnedInstruction, Register};
+use crate::object::DebugInfo;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::Write;
+use std::rc::Rc;
+#[cfg(test)]
+mod observer_tests;
+
+/// Returned from [`Observer::before_fetch`] to let execution continue or pause.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    Continue,
+    /// Pause before the instruction is fetched, leaving the simulator `Running`
+    Stop,
+}
+
+/// Callbacks invoked by [`Simulator`](super::simulator_guts::Simulator) as it executes.
+///
+/// Every method has an empty default, so observers only implement what they need.
+/// Register, memory and CC callbacks fire as changes are committed, after `after_decode`
+/// for the instruction responsible.
+pub trait Observer {
+    /// Called before fetching the instruction at `ip`. Returning `Control::Stop` pauses
+    /// the simulator; the next `run_single` resumes without calling this again for `ip`.
+    fn before_fetch(&mut self, _ip: i64) -> Control {
+        Control::Continue
+    }
+    fn after_decode(&mut self, _ip: i64, _instruction: &OwnedInstruction) {}
+    fn on_register_write(&mut self, _reg: Register, _old: i64, _new: i64) {}
+    fn on_memory_read(&mut self, _addr: i64, _value: i64) {}
+    fn on_memory_write(&mut self, _addr: i64, _old: i64, _new: i64) {}
+    fn on_cc_change(&mut self, _old: u8, _new: u8) {}
+    fn on_status_change(&mut self, _old: &Status, _new: &Status) {}
+}
+
+/// Lets the caller keep a handle to an observer after giving it to the simulator.
+impl<T: Observer + ?Sized> Observer for Rc<RefCell<T>> {
+    fn before_fetch(&mut self, ip: i64) -> Control {
+        self.borrow_mut().before_fetch(ip)
+    }
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        self.borrow_mut().after_decode(ip, instruction)
+    }
+    fn on_register_write(&mut self, reg: Register, old: i64, new: i64) {
+        self.borrow_mut().on_register_write(reg, old, new)
+    }
+    fn on_memory_read(&mut self, addr: i64, value: i64) {
+        self.borrow_mut().on_memory_read(addr, value)
+    }
+    fn on_memory_write(&mut self, addr: i64, old: i64, new: i64) {
+        self.borrow_mut().on_memory_write(addr, old, new)
+    }
+    fn on_cc_change(&mut self, old: u8, new: u8) {
+        self.borrow_mut().on_cc_change(old, new)
+    }
+    fn on_status_change(&mut self, old: &Status, new: &Status) {
+        self.borrow_mut().on_status_change(old, new)
+    }
+}
+
+/// Formats `file:line  label+off  source text` for the instruction at `ip`.
+pub fn source_trace_line(debug_info: &DebugInfo, ip: i64) -> String {
+    let location = debug_info
+        .describe(ip)
+        .unwrap_or_else(|| format!("{:#x}", ip));
+    match debug_info.line_at(ip) {
+        Some(entry) => format!(
+            "{}:{}  {}  {}",
+            debug_info.file, entry.line, location, entry.text
+        ),
+        None => format!("{}:?  {}", debug_info.file, location),
+    }
+}
+
+/// Prints each decoded instruction, as source lines when debug info is available,
+/// and reports the status if execution ends in anything other than a halt.
+pub struct TracePrinter<'d, W: Write> {
+    out: W,
+    debug_info: Option<&'d DebugInfo>,
+}
+
+impl<'d, W: Write> TracePrinter<'d, W> {
+    pub fn new(out: W, debug_info: Option<&'d DebugInfo>) -> Self {
+        Self { out, debug_info }
+    }
+}
+
+impl<W: Write> Observer for TracePrinter<'_, W> {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        let _ = match self.debug_info {
+            Some(debug_info) => writeln!(self.out, "{}", source_trace_line(debug_info, ip)),
+            None => writeln!(self.out, "Last Line: {}: {}", ip, instruction),
+        };
+    }
+
+    fn on_status_change(&mut self, _old: &Status, new: &Status) {
+        if !matches!(new, Status::Running | Status::Halted) {
+            let _ = writeln!(self.out, "{}", new);
+        }
+    }
+}
+
+/// Stops execution before fetching from any of a set of addresses.
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    pub addresses: HashSet<i64>,
+}
+
+impl Breakpoints {
+    pub fn new(addresses: impl IntoIterator<Item = i64>) -> Self {
+        Self {
+            addresses: addresses.into_iter().collect(),
+        }
+    }
+}
+
+impl Observer for Breakpoints {
+    fn before_fetch(&mut self, ip: i64) -> Control {
+        if self.addresses.contains(&ip) {
+            Control::Stop
+        } else {
+            Control::Continue
+        }
+    }
+}
+
+/// Runs `code` to a halt with `observer` attached, then hands the observer back for
+/// inspection.
+#[cfg(test)]
+pub(crate) fn run_observed<'a, T: Observer + 'a>(code: &'a [u8], observer: T) -> T {
+    let observer = Rc::new(RefCell::new(observer));
+    let mut simulator = Simulator::<1024>::new(code);
+    simulator.add_observer(observer.clone());
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    drop(simulator);
+    Rc::into_inner(observer).unwrap().into_inner()
+}
@@ -0,0 +1,167 @@
+use super::*;
+use crate::assembler::{parse_and_gen, parse_and_gen_with_debug};
+use crate::simulator::simulator_guts::Simulator;
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Fetch(i64),
+    Decode(i64),
+    RegWrite(Register, i64, i64),
+    MemRead(i64, i64),
+    MemWrite(i64, i64, i64),
+    Cc(u8, u8),
+    Status(Status, Status),
+}
+
+#[derive(Default)]
+struct Recorder {
+    events: Vec<Event>,
+}
+
+impl Observer for Recorder {
+    fn before_fetch(&mut self, ip: i64) -> Control {
+        self.events.push(Event::Fetch(ip));
+        Control::Continue
+    }
+    fn after_decode(&mut self, ip: i64, _instruction: &OwnedInstruction) {
+        self.events.push(Event::Decode(ip));
+    }
+    fn on_register_write(&mut self, reg: Register, old: i64, new: i64) {
+        self.events.push(Event::RegWrite(reg, old, new));
+    }
+    fn on_memory_read(&mut self, addr: i64, value: i64) {
+        self.events.push(Event::MemRead(addr, value));
+    }
+    fn on_memory_write(&mut self, addr: i64, old: i64, new: i64) {
+        self.events.push(Event::MemWrite(addr, old, new));
+    }
+    fn on_cc_change(&mut self, old: u8, new: u8) {
+        self.events.push(Event::Cc(old, new));
+    }
+    fn on_status_change(&mut self, old: &Status, new: &Status) {
+        self.events.push(Event::Status(old.clone(), new.clone()));
+    }
+}
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+#[test]
+fn test_observer_sees_every_event_in_order() {
+    let program = assemble(
+        "irmovq $7, %rax
+        pushq %rax
+        popq %rbx
+        addq %rax, %rbx
+        halt",
+    );
+    let recorder = Rc::new(RefCell::new(Recorder::default()));
+    let mut sim = Simulator::<1024>::new(&program);
+    sim.add_observer(recorder.clone());
+    sim.run();
+
+    let sp = 1016;
+    assert_eq!(
+        recorder.borrow().events,
+        vec![
+            Event::Fetch(0),
+            Event::Decode(0),
+            Event::RegWrite(Register::Rax, 0, 7),
+            Event::Fetch(10),
+            Event::Decode(10),
+            Event::MemWrite(sp - 8, 0, 7),
+            Event::RegWrite(Register::Rsp, sp, sp - 8),
+            Event::Fetch(12),
+            Event::Decode(12),
+            Event::MemRead(sp - 8, 7),
+            Event::RegWrite(Register::Rbx, 0, 7),
+            Event::RegWrite(Register::Rsp, sp - 8, sp),
+            Event::Fetch(14),
+            Event::Decode(14),
+            Event::RegWrite(Register::Rbx, 7, 14),
+            Event::Cc(0, 0),
+            Event::Fetch(16),
+            Event::Decode(16),
+            Event::Status(Status::Running, Status::Halted),
+        ]
+    );
+}
+
+#[test]
+fn test_multiple_observers_and_error_status() {
+    let program = assemble("mrmovq 2000(%rax), %rbx");
+    let first = Rc::new(RefCell::new(Recorder::default()));
+    let second = Rc::new(RefCell::new(Recorder::default()));
+    let mut sim = Simulator::<1024>::new(&program);
+    sim.add_observer(first.clone());
+    sim.add_observer(second.clone());
+    sim.run();
+
+    let expected_status = Event::Status(
+        Status::Running,
+        Status::Error("Memory address out of bounds: 2000".to_string()),
+    );
+    for recorder in [first, second] {
+        assert_eq!(recorder.borrow().events.last(), Some(&expected_status));
+    }
+}
+
+#[test]
+fn test_breakpoint_pauses_and_resumes() {
+    let program = assemble(
+        "irmovq $1, %rax
+        irmovq $2, %rbx
+        irmovq $3, %rcx
+        halt",
+    );
+    let mut sim = Simulator::<1024>::new(&program);
+    sim.add_observer(Breakpoints::new([10, 20]));
+
+    sim.run();
+    assert_eq!(sim.state, Status::Running);
+    assert_eq!(sim.paused_at, Some(10));
+    assert_eq!(sim.registers[Register::Rax as usize], 1);
+    assert_eq!(sim.registers[Register::Rbx as usize], 0);
+
+    sim.run();
+    assert_eq!(sim.paused_at, Some(20));
+    assert_eq!(sim.registers[Register::Rbx as usize], 2);
+    assert_eq!(sim.registers[Register::Rcx as usize], 0);
+
+    sim.run();
+    assert!(sim.is_halted());
+    assert_eq!(sim.registers[Register::Rcx as usize], 3);
+}
+
+#[test]
+fn test_trace_printer_uses_debug_info() {
+    let src = "start:\n    nop\n    halt # done\n";
+    let (_, code, debug_info) = parse_and_gen_with_debug(src, "t.ys").unwrap();
+
+    let mut out = Vec::new();
+    let mut sim = Simulator::<1024>::new(&code.bytes);
+    sim.add_observer(TracePrinter::new(&mut out, Some(&debug_info)));
+    sim.run();
+    drop(sim);
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "t.ys:2  start  nop\nt.ys:3  start+0x1  halt # done\n"
+    );
+}
+
+#[test]
+fn test_trace_printer_reports_errors() {
+    let program = [0xf0];
+    let mut out = Vec::new();
+    let mut sim = Simulator::<1024>::new(&program);
+    sim.add_observer(TracePrinter::new(&mut out, None));
+    sim.run();
+    drop(sim);
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "Error: Unknown opcode: 0xf\n"
+    );
+}
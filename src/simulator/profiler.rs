@@ -0,0 +1,324 @@
+use super::observer::Observer;
+use crate::ast::{Instruction, LabOrImm, OwnedInstruction};
+use crate::object::DebugInfo;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+#[cfg(test)]
+mod profiler_tests;
+
+/// Loops the report lists, hottest first.
+pub const REPORTED_LOOPS: usize = 10;
+
+/// The class an instruction is counted under, named after its Y86-64 mnemonic family.
+pub fn instruction_class<S>(instruction: &Instruction<S>) -> &'static str {
+    match instruction {
+        Instruction::Label(_) | Instruction::Directive(_, _) => "directive",
+        Instruction::Halt => "halt",
+        Instruction::Nop => "nop",
+        Instruction::Irmov(_, _) => "irmovq",
+        Instruction::Rmmov(_, _, _) => "rmmovq",
+        Instruction::Mrmov(_, _, _) => "mrmovq",
+        Instruction::Binop(_, _, _) => "OPq",
+        Instruction::Jmp(_, _) => "jXX",
+        Instruction::Cmov(_, _, _) => "cmovXX",
+        Instruction::Call(_) => "call",
+        Instruction::Ret => "ret",
+        Instruction::Push(_) => "pushq",
+        Instruction::Pop(_) => "popq",
+        Instruction::Syscall => "syscall",
+        Instruction::Iret => "iret",
+        Instruction::Ei => "ei",
+        Instruction::Di => "di",
+    }
+}
+
+/// A loop found from a backward jump taken from `tail` to `head`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loop {
+    pub head: i64,
+    pub tail: i64,
+    /// Times the backward jump was taken
+    pub iterations: u64,
+    /// Instructions executed between `head` and `tail` inclusive
+    pub instructions: u64,
+}
+
+/// A control transfer waiting for the next instruction to show where it went.
+#[derive(Debug)]
+enum Pending {
+    BackEdge { tail: i64, head: i64 },
+    Call { target: i64 },
+}
+
+/// Observes every instruction, counting executions per PC, per instruction class and per
+/// call stack, and the iterations of loops closed by backward jumps.
+///
+/// Call stacks follow `call` and `ret`; a frame is named by the label at its entry in
+/// `debug_info`, or by its address. The outermost frame is entered at the first instruction.
+///
+/// Attach it with [`Simulator::add_observer`](
+/// super::simulator_guts::Simulator::add_observer), keeping a handle through an
+/// `Rc<RefCell<_>>` to read the counts after the run.
+pub struct Profiler<'a> {
+    debug_info: Option<&'a DebugInfo>,
+    executions: BTreeMap<i64, (OwnedInstruction, u64)>,
+    classes: BTreeMap<&'static str, u64>,
+    back_edges: BTreeMap<(i64, i64), u64>,
+    pending: Option<Pending>,
+    /// Entry addresses of the active frames, outermost first
+    stack: Vec<i64>,
+    /// Each distinct call stack seen, interned so that only calls and returns look it up
+    stack_ids: HashMap<Vec<i64>, usize>,
+    stacks: Vec<(Vec<i64>, u64)>,
+    current: usize,
+}
+
+impl<'a> Profiler<'a> {
+    pub fn new(debug_info: Option<&'a DebugInfo>) -> Self {
+        Self {
+            debug_info,
+            executions: BTreeMap::new(),
+            classes: BTreeMap::new(),
+            back_edges: BTreeMap::new(),
+            pending: None,
+            stack: Vec::new(),
+            stack_ids: HashMap::new(),
+            stacks: Vec::new(),
+            current: 0,
+        }
+    }
+
+    /// Instructions executed in total.
+    pub fn total(&self) -> u64 {
+        self.classes.values().sum()
+    }
+
+    /// Times the instruction at `pc` was executed.
+    pub fn count(&self, pc: i64) -> u64 {
+        self.executions.get(&pc).map_or(0, |(_, count)| *count)
+    }
+
+    /// Instructions executed of each class, by [`instruction_class`].
+    pub fn classes(&self) -> &BTreeMap<&'static str, u64> {
+        &self.classes
+    }
+
+    /// Every loop seen, hottest first.
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: Vec<_> = self
+            .back_edges
+            .iter()
+            .map(|(&(head, tail), &iterations)| Loop {
+                head,
+                tail,
+                iterations,
+                instructions: self
+                    .executions
+                    .range(head..=tail)
+                    .map(|(_, (_, count))| count)
+                    .sum(),
+            })
+            .collect();
+        loops.sort_by_key(|hot| (std::cmp::Reverse(hot.instructions), hot.head));
+        loops
+    }
+
+    /// Instructions executed in each function itself, excluding its callees, hottest first.
+    pub fn functions(&self) -> Vec<(String, u64)> {
+        let mut functions = BTreeMap::<i64, u64>::new();
+        for (stack, count) in &self.stacks {
+            *functions.entry(*stack.last().unwrap()).or_default() += count;
+        }
+        let mut functions: Vec<_> = functions
+            .into_iter()
+            .map(|(entry, count)| (self.frame_name(entry), count))
+            .collect();
+        functions.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        functions
+    }
+
+    /// Instructions executed under each label, hottest first; empty without symbols.
+    pub fn labels(&self) -> Vec<(&str, u64)> {
+        let Some(debug_info) = self.debug_info else {
+            return Vec::new();
+        };
+        let mut labels = BTreeMap::<&str, u64>::new();
+        for (pc, (_, count)) in &self.executions {
+            if let Some((label, _)) = debug_info.locate(*pc) {
+                *labels.entry(label).or_default() += count;
+            }
+        }
+        let mut labels: Vec<_> = labels.into_iter().collect();
+        labels.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        labels
+    }
+
+    /// One line per call stack, `outer;inner count`, as read by flame graph tools.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<_> = self
+            .stacks
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(stack, count)| {
+                let names: Vec<_> = stack.iter().map(|&entry| self.frame_name(entry)).collect();
+                format!("{} {}\n", names.join(";"), count)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+
+    fn frame_name(&self, entry: i64) -> String {
+        self.debug_info
+            .and_then(|debug_info| debug_info.symbol_at(entry))
+            .map_or_else(|| format!("{:#x}", entry), str::to_string)
+    }
+
+    /// Makes `stack` the current call stack, interning it if it is new.
+    fn enter_stack(&mut self) {
+        self.current = match self.stack_ids.get(&self.stack) {
+            Some(&id) => id,
+            None => {
+                self.stacks.push((self.stack.clone(), 0));
+                self.stack_ids
+                    .insert(self.stack.clone(), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => 100.0 * count as f64 / total as f64,
+        }
+    }
+
+    /// Counts by class, function, label and loop, then the executed instructions annotated
+    /// with their counts.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "Profile: {} instructions", self.total());
+        let mut classes: Vec<_> = self.classes.iter().collect();
+        classes.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        let sections = [
+            (
+                "Class",
+                classes
+                    .into_iter()
+                    .map(|(c, n)| (c.to_string(), *n))
+                    .collect(),
+            ),
+            ("Function", self.functions()),
+            (
+                "Label",
+                self.labels()
+                    .into_iter()
+                    .map(|(label, count)| (label.to_string(), count))
+                    .collect::<Vec<_>>(),
+            ),
+        ];
+        for (title, rows) in sections {
+            if rows.is_empty() {
+                continue;
+            }
+            let _ = writeln!(report, "  {:<20}{:>12}{:>9}", title, "Count", "Share");
+            for (name, count) in rows {
+                let _ = writeln!(
+                    report,
+                    "  {:<20}{:>12}{:>8.1}%",
+                    name,
+                    count,
+                    self.percent(count)
+                );
+            }
+        }
+
+        let loops = self.loops();
+        if !loops.is_empty() {
+            let _ = writeln!(
+                report,
+                "Hot loops:\n  {:<16}{:<16}{:>12}{:>14}{:>9}",
+                "Range", "Head", "Iterations", "Instructions", "Share"
+            );
+        }
+        for hot in loops.iter().take(REPORTED_LOOPS) {
+            let _ = writeln!(
+                report,
+                "  {:<16}{:<16}{:>12}{:>14}{:>8.1}%",
+                format!("{:#x}-{:#x}", hot.head, hot.tail),
+                self.frame_name(hot.head),
+                hot.iterations,
+                hot.instructions,
+                self.percent(hot.instructions)
+            );
+        }
+
+        let _ = writeln!(report, "Annotated disassembly:");
+        for (pc, (instruction, count)) in &self.executions {
+            if let Some(label) = self.debug_info.and_then(|info| info.symbol_at(*pc)) {
+                let _ = writeln!(report, "{}:", label);
+            }
+            let instruction = match self.debug_info {
+                Some(debug_info) => debug_info.symbolize(instruction),
+                None => instruction.clone(),
+            };
+            let _ = writeln!(
+                report,
+                "  {:>6.2}% {:>10}  {:04x}  {}",
+                self.percent(*count),
+                count,
+                pc,
+                instruction.to_string().trim()
+            );
+        }
+        report
+    }
+}
+
+impl Observer for Profiler<'_> {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        if self.stack.is_empty() {
+            self.stack.push(ip);
+            self.enter_stack();
+        }
+        match self.pending.take() {
+            Some(Pending::BackEdge { tail, head }) if ip == head => {
+                *self.back_edges.entry((head, tail)).or_default() += 1;
+            }
+            Some(Pending::Call { target }) if ip == target => {
+                self.stack.push(target);
+                self.enter_stack();
+            }
+            _ => {}
+        }
+
+        self.executions
+            .entry(ip)
+            .or_insert_with(|| (instruction.clone(), 0))
+            .1 += 1;
+        *self
+            .classes
+            .entry(instruction_class(instruction))
+            .or_default() += 1;
+        self.stacks[self.current].1 += 1;
+
+        match instruction {
+            &Instruction::Jmp(_, LabOrImm::Immediate(target)) if target <= ip => {
+                self.pending = Some(Pending::BackEdge {
+                    tail: ip,
+                    head: target,
+                });
+            }
+            &Instruction::Call(LabOrImm::Immediate(target)) => {
+                self.pending = Some(Pending::Call { target });
+            }
+            // The outermost frame has nowhere to return to
+            Instruction::Ret if self.stack.len() > 1 => {
+                self.stack.pop();
+                self.enter_stack();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use super::*;
+use crate::assembler::{parse_and_gen, parse_and_gen_with_debug};
+use crate::simulator::observer::run_observed;
+
+const PROGRAM: &str = "
+main:
+    irmovq $3, %rcx
+    irmovq $1, %rdx
+loop:
+    call f
+    subq %rdx, %rcx
+    jne loop
+    halt
+f:
+    nop
+    ret";
+
+fn profile<'a>(code: &[u8], debug_info: Option<&'a DebugInfo>) -> Profiler<'a> {
+    run_observed(code, Profiler::new(debug_info))
+}
+
+#[test]
+fn test_counts() {
+    let code = parse_and_gen(PROGRAM).unwrap().1.bytes;
+    let profiler = profile(&code, None);
+    assert_eq!(profiler.total(), 18);
+    assert_eq!(profiler.count(0), 1);
+    assert_eq!(profiler.count(20), 3);
+    assert_eq!(profiler.count(42), 3);
+    assert_eq!(profiler.count(43), 0);
+    assert_eq!(profiler.classes()["OPq"], 3);
+    assert_eq!(profiler.classes()["ret"], 3);
+    assert_eq!(profiler.classes().values().sum::<u64>(), 18);
+    // The third time round the jump falls through
+    assert_eq!(
+        profiler.loops(),
+        [Loop {
+            head: 20,
+            tail: 31,
+            iterations: 2,
+            instructions: 9
+        }]
+    );
+    // Without symbols, frames are named by address
+    assert_eq!(profiler.folded(), "0x0 12\n0x0;0x29 6\n");
+}
+
+#[test]
+fn test_symbols() {
+    let (_, code, debug_info) = parse_and_gen_with_debug(PROGRAM, "loop.ys").unwrap();
+    let profiler = profile(&code.bytes, Some(&debug_info));
+    assert_eq!(profiler.folded(), "main 12\nmain;f 6\n");
+    assert_eq!(
+        profiler.functions(),
+        [("main".to_string(), 12), ("f".to_string(), 6)]
+    );
+    assert_eq!(profiler.labels(), [("loop", 10), ("f", 6), ("main", 2)]);
+
+    let report = profiler.report();
+    assert!(report.contains("Profile: 18 instructions"));
+    assert!(report.contains("  0x14-0x1f       loop                       2             9"));
+    assert!(report.contains("loop:\n   16.67%          3  0014  call f"));
+}
+
+#[test]
+fn test_nested_calls_and_recursion() {
+    // Counts %rcx down from 2 by recursing, then returns through every frame
+    let code = parse_and_gen(
+        "irmovq $2, %rcx
+        irmovq $1, %rdx
+        call r
+        halt
+        r:
+        subq %rdx, %rcx
+        jl done
+        call r
+        done:
+        ret",
+    )
+    .unwrap()
+    .1
+    .bytes;
+    let profiler = profile(&code, None);
+    let folded = profiler.folded();
+    let lines: Vec<_> = folded.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "0x0 4",
+            "0x0;0x1e 4",
+            "0x0;0x1e;0x1e 4",
+            "0x0;0x1e;0x1e;0x1e 3"
+        ]
+    );
+    // A forward jump is not a loop
+    assert!(profiler.loops().is_empty());
+}
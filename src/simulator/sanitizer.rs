@@ -0,0 +1,184 @@
+use super::observer::Observer;
+use crate::ast::{Instruction, OwnedInstruction, Register};
+use crate::object::DebugInfo;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write as _};
+#[cfg(test)]
+mod sanitizer_tests;
+
+/// Bytes below `%rsp` in which a load or store counts as touching the stack: the size of
+/// the x86-64 red zone. Accesses further down are taken to be to other data.
+pub const RED_ZONE: i64 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Issue {
+    /// A read of an address nothing has written
+    UninitializedRead,
+    /// A write into the bytes the program was loaded from
+    CodeWrite,
+    /// A load or store within `RED_ZONE` bytes below `%rsp`, in stack that is not allocated
+    BelowStackPointer,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Issue::UninitializedRead => "read of uninitialized memory",
+            Issue::CodeWrite => "write into the program",
+            Issue::BelowStackPointer => "access below %rsp",
+        })
+    }
+}
+
+/// One kind of bad access by one instruction to one address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub issue: Issue,
+    pub pc: i64,
+    pub addr: i64,
+    pub instruction: OwnedInstruction,
+    /// Times the instruction made this access
+    pub count: u64,
+}
+
+/// Observes memory accesses against shadow memory recording which addresses have been
+/// written, flagging reads of addresses never written, writes into the program image and
+/// loads and stores just below `%rsp`.
+///
+/// Memory reads as zero until written, and the program's bytes are fetched from the image
+/// rather than from data memory, so none of these stop the simulator. Pushes, pops, calls
+/// and returns move `%rsp` as they access the stack, so only `rmmovq` and `mrmovq` are
+/// checked against it.
+pub struct MemorySanitizer<'a> {
+    debug_info: Option<&'a DebugInfo>,
+    program_size: i64,
+    memory_size: i64,
+    written: HashSet<i64>,
+    rsp: i64,
+    current: Option<(i64, OwnedInstruction)>,
+    findings: Vec<Finding>,
+    /// Index into `findings` of each (issue, pc, addr) already found
+    seen: HashMap<(Issue, i64, i64), usize>,
+}
+
+impl<'a> MemorySanitizer<'a> {
+    /// Checks a program of `program_size` bytes running in `memory_size` addresses, with
+    /// `%rsp` starting at the top of memory as it does in a new simulator.
+    pub fn new(debug_info: Option<&'a DebugInfo>, program_size: usize, memory_size: usize) -> Self {
+        Self {
+            debug_info,
+            program_size: program_size as i64,
+            memory_size: memory_size as i64,
+            written: HashSet::new(),
+            rsp: memory_size as i64 - 8,
+            current: None,
+            findings: Vec::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Every bad access, in the order each was first made.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    fn flag(&mut self, issue: Issue, addr: i64) {
+        let Some((pc, instruction)) = &self.current else {
+            return;
+        };
+        match self.seen.get(&(issue, *pc, addr)) {
+            Some(&index) => self.findings[index].count += 1,
+            None => {
+                self.seen.insert((issue, *pc, addr), self.findings.len());
+                self.findings.push(Finding {
+                    issue,
+                    pc: *pc,
+                    addr,
+                    instruction: instruction.clone(),
+                    count: 1,
+                });
+            }
+        }
+    }
+
+    /// Flags a load or store just below `%rsp`.
+    fn check_stack(&mut self, addr: i64) {
+        let explicit = matches!(
+            self.current,
+            Some((_, Instruction::Rmmov(..) | Instruction::Mrmov(..)))
+        );
+        if explicit && (self.rsp - RED_ZONE..self.rsp).contains(&addr) {
+            self.flag(Issue::BelowStackPointer, addr);
+        }
+    }
+
+    fn name(&self, addr: i64) -> String {
+        self.debug_info
+            .and_then(|debug_info| debug_info.describe(addr))
+            .unwrap_or_else(|| format!("{:#x}", addr))
+    }
+
+    /// One line per finding, with the instruction that made the access.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "Memory sanitizer: {} finding{}",
+            self.findings.len(),
+            if self.findings.len() == 1 { "" } else { "s" }
+        );
+        for finding in &self.findings {
+            let instruction = match self.debug_info {
+                Some(debug_info) => debug_info.symbolize(&finding.instruction),
+                None => finding.instruction.clone(),
+            };
+            let _ = writeln!(
+                report,
+                "  {} {:#x} at {}: {}{}",
+                finding.issue,
+                finding.addr,
+                self.name(finding.pc),
+                instruction.to_string().trim(),
+                match finding.count {
+                    1 => String::new(),
+                    count => format!(" ({} times)", count),
+                }
+            );
+        }
+        report
+    }
+}
+
+impl Observer for MemorySanitizer<'_> {
+    fn after_decode(&mut self, ip: i64, instruction: &OwnedInstruction) {
+        self.current = Some((ip, instruction.clone()));
+    }
+
+    fn on_register_write(&mut self, reg: Register, _old: i64, new: i64) {
+        if reg == Register::Rsp {
+            self.rsp = new;
+        }
+    }
+
+    fn on_memory_read(&mut self, addr: i64, _value: i64) {
+        // Devices are mapped above memory
+        if addr >= self.memory_size {
+            return;
+        }
+        if !self.written.contains(&addr) {
+            self.flag(Issue::UninitializedRead, addr);
+        }
+        self.check_stack(addr);
+    }
+
+    fn on_memory_write(&mut self, addr: i64, _old: i64, _new: i64) {
+        if addr >= self.memory_size {
+            return;
+        }
+        self.written.insert(addr);
+        if (0..self.program_size).contains(&addr) {
+            self.flag(Issue::CodeWrite, addr);
+        }
+        self.check_stack(addr);
+    }
+}
@@ -0,0 +1,92 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::observer::run_observed;
+
+fn sanitize(src: &str) -> Vec<(Issue, i64, i64, u64)> {
+    let code = parse_and_gen(src).unwrap().1.bytes;
+    let sanitizer = run_observed(&code, MemorySanitizer::new(None, code.len(), 1024));
+    sanitizer
+        .findings()
+        .iter()
+        .map(|finding| (finding.issue, finding.pc, finding.addr, finding.count))
+        .collect()
+}
+
+#[test]
+fn test_clean_program() {
+    let findings = sanitize(
+        "irmovq $512, %rbx
+        irmovq $3, %rax
+        rmmovq %rax, (%rbx)
+        mrmovq (%rbx), %rcx
+        pushq %rax
+        popq %rdx
+        call f
+        halt
+        f:
+        ret",
+    );
+    assert!(findings.is_empty(), "{:?}", findings);
+}
+
+#[test]
+fn test_uninitialized_reads() {
+    // The second read of the slot counts against the same finding
+    let findings = sanitize(
+        "irmovq $512, %rbx
+        irmovq $2, %rcx
+        irmovq $1, %rdx
+        loop:
+        mrmovq 8(%rbx), %rax
+        subq %rdx, %rcx
+        jne loop
+        rmmovq %rax, 8(%rbx)
+        mrmovq 8(%rbx), %rax
+        halt",
+    );
+    assert_eq!(findings, [(Issue::UninitializedRead, 0x1e, 520, 2)]);
+}
+
+#[test]
+fn test_code_writes() {
+    let findings = sanitize(
+        "irmovq $8, %rbx
+        rmmovq %rbx, (%rbx)
+        halt",
+    );
+    assert_eq!(findings, [(Issue::CodeWrite, 0xa, 8, 1)]);
+}
+
+#[test]
+fn test_below_stack_pointer() {
+    // Reading a popped slot, then writing well below the stack where data may live
+    let findings = sanitize(
+        "irmovq $512, %rsp
+        irmovq $1, %rax
+        pushq %rax
+        popq %rbx
+        mrmovq -8(%rsp), %rcx
+        rmmovq %rax, 256(%rax)
+        halt",
+    );
+    assert_eq!(findings, [(Issue::BelowStackPointer, 0x18, 504, 1)]);
+}
+
+#[test]
+fn test_report() {
+    let code = parse_and_gen("irmovq $64, %rbx\nmrmovq (%rbx), %rax\nhalt")
+        .unwrap()
+        .1
+        .bytes;
+    let mut sanitizer = MemorySanitizer::new(None, code.len(), 1024);
+    sanitizer.after_decode(10, &Instruction::Mrmov(0, Register::Rbx, Register::Rax));
+    sanitizer.on_memory_read(64, 0);
+    sanitizer.on_memory_read(64, 0);
+    // Device registers are above memory
+    sanitizer.on_memory_read(0x10000, 0);
+    assert_eq!(
+        sanitizer.report(),
+        "Memory sanitizer: 1 finding\n  \
+         read of uninitialized memory 0x40 at 0xa: mrmov 0(rbx) rax (2 times)\n"
+    );
+}
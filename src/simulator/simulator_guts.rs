@@ -1,16 +1,52 @@
 use crate::ast::{self, CondOp, OwnedInstruction};
 use crate::ast::{Instruction, LabOrImm, Register};
+use crate::simulator::device::{Device, DeviceBus};
+use crate::simulator::interrupt::{self, FLAGS_INTERRUPTS_ENABLED, InterruptController};
+use crate::simulator::memory_map::{MemoryFault, MemoryMap};
+use crate::simulator::mmu::{Access, Mmu, PAGE_SIZE};
+use crate::simulator::observer::{Control, Observer};
+use crate::simulator::sparse_memory::SparseMemory;
+use crate::simulator::syscall::{SyscallContext, SyscallResult, SyscallTable};
+use crate::simulator::trace::{TraceHeader, TraceWriter};
+use std::cell::RefCell;
+use std::rc::Rc;
 mod atomic_change_display;
+mod block_cache;
+mod decoder;
+mod loop_detector;
 #[cfg(test)]
 mod simulator_guts_tests;
 
+pub use block_cache::BlockCache;
+pub use decoder::decode;
+pub use loop_detector::{LoopDetector, MemoryState};
+
 /// Vec(instruction_pointer, instruction)
 pub type Disassembly = Vec<(i64, OwnedInstruction)>;
 
 /// Vec<(instruction_number, changes)>
-/// (id, change) in Log means that Diassembly\[id\] caused change
+/// (id, change) in Log means that Diassembly\[id - log_base\] caused change
 pub type Log = Vec<(usize, AtomicChange)>;
 
+/// How much of the execution log the simulator keeps.
+#[derive(Default)]
+pub enum LogPolicy<'a> {
+    /// Keep nothing once an instruction has retired
+    Off,
+    /// Keep (at least) the last N instructions and their changes
+    LastN(usize),
+    /// Keep everything in memory
+    #[default]
+    Full,
+    /// Write every retired instruction to a binary trace, keeping nothing in memory
+    Stream(TraceWriter<'a>),
+}
+
+/// A change to the architectural state, as logged and traced.
+///
+/// Simulator bookkeeping is set directly and never logged: [`Simulator::interrupts_enabled`],
+/// [`Simulator::exit_code`] and the error status raised when a device fails to halt.
+#[derive(Debug, Clone, PartialEq)]
 pub enum AtomicChange {
     /// A change in the value of a register.
     Register { reg: ast::Register, value: i64 },
@@ -29,6 +65,14 @@ pub enum Status {
     Running,
     Halted,
     Error(String),
+    /// The step budget ran out before the program halted.
+    StepLimitExceeded,
+    /// The machine state repeated exactly, so the program can never halt.
+    /// Holds the inclusive range of PCs executed in the loop.
+    InfiniteLoop {
+        start: i64,
+        end: i64,
+    },
 }
 
 static CARRY_MASK: u8 = 0b0001; // 4 bits for condition codes
@@ -36,6 +80,74 @@ static ZERO_MASK: u8 = 0b0010;
 static SIGN_MASK: u8 = 0b0100;
 static OVERFLOW_MASK: u8 = 0b1000;
 
+/// Whether `cond` holds for the 4 bit condition code `condition_code`.
+pub fn condition_holds(cond: CondOp, condition_code: u8) -> bool {
+    let zero = (condition_code & ZERO_MASK) != 0; // Z flag
+    let _carry = (condition_code & CARRY_MASK) != 0; // C flag  
+    let sign = (condition_code & SIGN_MASK) != 0; // N flag (negative)
+    let overflow = (condition_code & OVERFLOW_MASK) != 0; // V flag
+
+    match cond {
+        CondOp::Uncon => true,                     // Unconditional
+        CondOp::Eq => zero,                        // Equal: Z==1
+        CondOp::Ne => !zero,                       // Not equal: Z==0
+        CondOp::Ge => sign == overflow,            // Greater or equal: N==V
+        CondOp::Lt => sign != overflow,            // Less than: N!=V
+        CondOp::Gt => !zero && (sign == overflow), // Greater than: (Z==0) && (N==V)
+        CondOp::Le => zero || (sign != overflow),  // Less or equal: (Z==1) || (N!=V)
+    }
+}
+
+/// Computes `dest op src` for a binop, returning the result and the new condition code.
+///
+/// `and` and `xor` leave the carry and overflow flags as they were.
+#[allow(clippy::if_same_then_else, clippy::needless_bool)]
+fn alu(op: ast::BinaryOp, r1: i64, r2: i64, condition_code: u8) -> (i64, u8) {
+    let original_carry = condition_code & CARRY_MASK != 0;
+    let original_overflow = condition_code & OVERFLOW_MASK != 0;
+
+    let (result, carry, overflow) = match op {
+        ast::BinaryOp::Add => {
+            let (res, car) = r1.overflowing_add(r2);
+
+            let overflow = if r1 < 0 && r2 < 0 && res >= 0 {
+                true
+            } else if r1 >= 0 && r2 >= 0 && res < 0 {
+                true
+            } else {
+                false
+            };
+            (res, car, overflow)
+        }
+        ast::BinaryOp::Sub => {
+            let nr1 = -r1;
+            let (res, car) = nr1.overflowing_add(r2);
+            let overflow = if nr1 < 0 && r2 < 0 && res >= 0 {
+                true
+            } else if nr1 >= 0 && r2 >= 0 && res < 0 {
+                true
+            } else {
+                false
+            };
+            (res, car, overflow)
+        }
+        ast::BinaryOp::And => {
+            let res = r1 & r2;
+            (res, original_carry, original_overflow)
+        }
+        ast::BinaryOp::Xor => {
+            let res = r1 ^ r2;
+            (res, original_carry, original_overflow)
+        }
+    };
+
+    let cc = (if carry { CARRY_MASK } else { 0 })
+        | (if result == 0 { ZERO_MASK } else { 0 })
+        | (if result < 0 { SIGN_MASK } else { 0 })
+        | (if overflow { OVERFLOW_MASK } else { 0 });
+    (result, cc)
+}
+
 pub struct Simulator<'a, const MEM_SIZE: usize> {
     /// The current values of the registers.
     pub registers: [i64; 13], // Rax, Rbx, Rcx, Rdx, Rdi, Rsi, Rsp, Rbp, R8, R9, R10, R11, R12
@@ -43,6 +155,8 @@ pub struct Simulator<'a, const MEM_SIZE: usize> {
     pub instruction_pointer: i64,
     /// The memory state.
     pub memory: [i64; MEM_SIZE],
+    /// Memory outside RAM that no device claims, once sparse memory is enabled
+    sparse_memory: Option<SparseMemory>,
     /// The source code being simulated.
     pub source: &'a [u8],
     /// The current state of the simulator.
@@ -54,6 +168,38 @@ pub struct Simulator<'a, const MEM_SIZE: usize> {
     pub disassembly: Disassembly,
     /// Log of changes made during execution
     pub log: Log,
+    /// Instruction number of disassembly\[0\], non-zero once old entries are discarded
+    pub log_base: usize,
+    log_policy: LogPolicy<'a>,
+
+    /// Number of instructions retired
+    pub steps: u64,
+    /// Stop with `Status::StepLimitExceeded` once this many instructions have been retired
+    pub max_steps: Option<u64>,
+    /// Stop with `Status::InfiniteLoop` when an exact state repeats
+    pub loop_detector: Option<LoopDetector>,
+
+    /// Set when an observer paused execution before fetching from this address
+    pub paused_at: Option<i64>,
+    observers: Vec<Box<dyn Observer + 'a>>,
+    /// Memory-mapped devices above RAM
+    devices: DeviceBus<'a>,
+    /// Serves `syscall` instructions
+    syscalls: Option<Box<dyn SyscallTable + 'a>>,
+    /// Set when the program halts through the `exit` system call; not logged
+    pub exit_code: Option<i64>,
+    /// Delivers interrupts and faults to handlers, once mapped
+    interrupts: Option<Rc<RefCell<InterruptController>>>,
+    /// Set by `ei`, cleared by `di` and on entry to a handler, and restored by `iret`; not logged
+    pub interrupts_enabled: bool,
+    /// Translates addresses through a page table, once mapped and enabled
+    mmu: Option<Rc<RefCell<Mmu>>>,
+    /// Regions every fetch, load and store is checked against, once set
+    memory_map: Option<MemoryMap>,
+    /// The most recent access the memory map refused
+    memory_fault: RefCell<Option<MemoryFault>>,
+    /// Predecoded code for the fast path
+    block_cache: BlockCache,
 
     /// Index of the next change to next_to_commit
     next_to_commit: usize,
@@ -69,38 +215,434 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
             registers,
             instruction_pointer: 0,
             memory: [0; MEM_SIZE],
+            sparse_memory: None,
             source: src,
             state: Status::Running,
             condition_code: 0,
             disassembly: Disassembly::new(),
             log: Log::new(),
+            log_base: 0,
+            log_policy: LogPolicy::Full,
+            steps: 0,
+            max_steps: None,
+            loop_detector: None,
+            paused_at: None,
+            observers: Vec::new(),
+            devices: DeviceBus::default(),
+            syscalls: None,
+            exit_code: None,
+            interrupts: None,
+            interrupts_enabled: false,
+            mmu: None,
+            memory_map: None,
+            memory_fault: RefCell::new(None),
+            block_cache: BlockCache::default(),
             next_to_commit: 0,
         }
     }
 
+    /// Sets how much of the execution log is retained.
+    pub fn with_log_policy(mut self, log_policy: LogPolicy<'a>) -> Self {
+        self.log_policy = log_policy;
+        self
+    }
+
+    /// Registers an observer to be notified of execution events.
+    pub fn add_observer(&mut self, observer: impl Observer + 'a) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Backs every address outside RAM that no device claims with memory allocated a page at
+    /// a time, so that programs can use high addresses, such as for their stack.
+    ///
+    /// The JIT does not run with sparse memory.
+    pub fn with_sparse_memory(mut self) -> Self {
+        self.sparse_memory = Some(SparseMemory::new());
+        self
+    }
+
+    /// The memory above RAM written so far, once sparse memory is enabled.
+    pub fn sparse_memory(&self) -> Option<&SparseMemory> {
+        self.sparse_memory.as_ref()
+    }
+
+    /// Whether `addr` is memory: in RAM or, with sparse memory, anywhere no device claims,
+    /// including the upper half of the address space.
+    fn in_memory(&self, addr: i64) -> bool {
+        (addr >= 0 && (addr as usize) < MEM_SIZE)
+            || (self.sparse_memory.is_some() && !self.devices.contains(addr))
+    }
+
+    /// Reads memory at `addr`, which must satisfy `in_memory`.
+    fn read_memory(&self, addr: i64) -> i64 {
+        match &self.sparse_memory {
+            Some(sparse_memory) if addr < 0 || (addr as usize) >= MEM_SIZE => {
+                sparse_memory.get(addr)
+            }
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    /// Writes memory at `addr`, which must satisfy `in_memory`, returning the old value.
+    fn write_memory(&mut self, addr: i64, value: i64) -> i64 {
+        Self::write_to(&mut self.memory, &mut self.sparse_memory, addr, value)
+    }
+
+    /// `write_memory`, for callers holding other borrows of the simulator.
+    fn write_to(
+        memory: &mut [i64; MEM_SIZE],
+        sparse_memory: &mut Option<SparseMemory>,
+        addr: i64,
+        value: i64,
+    ) -> i64 {
+        match sparse_memory {
+            Some(sparse_memory) if addr < 0 || (addr as usize) >= MEM_SIZE => {
+                sparse_memory.set(addr, value)
+            }
+            _ => std::mem::replace(&mut memory[addr as usize], value),
+        }
+    }
+
+    /// Maps a device at `base`, above RAM and clear of any other device.
+    ///
+    /// `rmmovq` and `mrmovq` reach devices; the stack always lives in RAM.
+    pub fn map_device(&mut self, base: i64, device: impl Device + 'a) -> Result<(), String> {
+        self.devices.map(base, MEM_SIZE, device)
+    }
+
+    /// Reads the device mapped at `addr`, which lies outside RAM.
+    fn read_device(&mut self, addr: i64) -> Result<i64, String> {
+        self.devices
+            .read(addr)
+            .unwrap_or_else(|| Err(format!("Memory address out of bounds: {}", addr)))
+    }
+
+    /// Writes the device mapped at `addr`, which lies outside RAM.
+    fn write_device(&mut self, addr: i64, value: i64) -> Result<(), String> {
+        self.devices
+            .write(addr, value)
+            .unwrap_or_else(|| Err(format!("Memory address out of bounds: {}", addr)))
+    }
+
+    /// Lets devices react to the program halting, such as by emitting a final frame.
+    ///
+    /// A failure replaces the status after the instruction retired, so it is not logged.
+    fn halt_devices(&mut self) {
+        if let Err(e) = self.devices.halt() {
+            self.state = Status::Error(e);
+        }
+    }
+
+    /// Serves the program's `syscall` instructions from `table`.
+    ///
+    /// Without a table, a `syscall` stops the simulator with an error.
+    pub fn with_syscalls(mut self, table: impl SyscallTable + 'a) -> Self {
+        self.syscalls = Some(Box::new(table));
+        self
+    }
+
+    /// Performs the system call numbered by `%rax`, returning its result and the memory it
+    /// writes.
+    fn syscall(&mut self) -> Result<(SyscallResult, Vec<(i64, i64)>), String> {
+        let number = self.registers[Register::Rax as usize];
+        let table = self
+            .syscalls
+            .as_mut()
+            .ok_or_else(|| format!("No system call table for system call {}", number))?;
+        // Buffers are virtual addresses too
+        let mmu = self
+            .mmu
+            .as_ref()
+            .map(|mmu| mmu.borrow())
+            .filter(|mmu| mmu.is_enabled());
+        let mut context = SyscallContext::new(
+            [Register::Rdi, Register::Rsi, Register::Rdx].map(|reg| self.registers[reg as usize]),
+            self.steps,
+            self.registers[Register::Rsp as usize],
+            self.source.len() as i64,
+            &self.memory,
+            mmu.as_deref(),
+            self.memory_map.as_ref(),
+        );
+        if let Some(sparse_memory) = &self.sparse_memory {
+            context = context.with_sparse_memory(sparse_memory, &self.devices);
+        }
+        let result = table.call(number, &mut context)?;
+        Ok((result, context.writes))
+    }
+
+    /// Maps an interrupt controller at `base`, returning a handle for setting it up or raising
+    /// interrupts from the host.
+    pub fn map_interrupt_controller(
+        &mut self,
+        base: i64,
+    ) -> Result<Rc<RefCell<InterruptController>>, String> {
+        if self.interrupts.is_some() {
+            return Err("An interrupt controller is already mapped".to_string());
+        }
+        let controller = Rc::new(RefCell::new(InterruptController::default()));
+        self.devices.map(base, MEM_SIZE, controller.clone())?;
+        self.interrupts = Some(controller.clone());
+        Ok(controller)
+    }
+
+    /// Whether an interrupt controller is mapped.
+    pub fn has_interrupt_controller(&self) -> bool {
+        self.interrupts.is_some()
+    }
+
+    /// Maps an MMU at `base`, returning a handle for setting it up or reading its TLB
+    /// statistics. Translation stays off until the MMU is enabled.
+    ///
+    /// Every address the program uses, including the PC, is then virtual, so the simulator
+    /// always takes the logging path.
+    pub fn map_mmu(&mut self, base: i64) -> Result<Rc<RefCell<Mmu>>, String> {
+        if self.mmu.is_some() {
+            return Err("An MMU is already mapped".to_string());
+        }
+        let mmu = Rc::new(RefCell::new(Mmu::default()));
+        self.devices.map(base, MEM_SIZE, mmu.clone())?;
+        self.mmu = Some(mmu.clone());
+        Ok(mmu)
+    }
+
+    /// Whether an MMU is mapped.
+    pub fn has_mmu(&self) -> bool {
+        self.mmu.is_some()
+    }
+
+    /// The mapped MMU, for reading its TLB statistics after a run.
+    pub fn mmu(&self) -> Option<&Rc<RefCell<Mmu>>> {
+        self.mmu.as_ref()
+    }
+
+    /// Checks every fetch, load and store against `memory_map`, stopping with a segmentation
+    /// fault on any access it does not allow. With an MMU, physical addresses are checked.
+    ///
+    /// The simulator then always takes the logging path.
+    pub fn with_memory_map(mut self, memory_map: MemoryMap) -> Self {
+        self.memory_map = Some(memory_map);
+        self
+    }
+
+    pub fn memory_map(&self) -> Option<&MemoryMap> {
+        self.memory_map.as_ref()
+    }
+
+    /// The access the memory map refused that stopped the simulator, if one did.
+    pub fn memory_fault(&self) -> Option<MemoryFault> {
+        self.memory_fault.borrow().clone()
+    }
+
+    /// Checks a physical address against the memory map, if there is one.
+    fn check_access(&self, addr: i64, access: Access) -> Result<(), String> {
+        let Some(memory_map) = &self.memory_map else {
+            return Ok(());
+        };
+        memory_map.check(addr, access).map_err(|fault| {
+            let message = fault.to_string();
+            *self.memory_fault.borrow_mut() = Some(fault);
+            message
+        })
+    }
+
+    /// Whether addresses are being translated.
+    fn translating(&self) -> bool {
+        self.mmu.as_ref().is_some_and(|mmu| mmu.borrow().is_enabled())
+    }
+
+    /// The physical address for `addr`, which is `addr` itself unless translation is on,
+    /// once the memory map allows `access` to it.
+    fn translate(&self, addr: i64, access: Access) -> Result<i64, String> {
+        let addr = match &self.mmu {
+            Some(mmu) if mmu.borrow().is_enabled() => {
+                mmu.borrow_mut().translate(&self.memory, addr, access)?
+            }
+            _ => addr,
+        };
+        self.check_access(addr, access)?;
+        Ok(addr)
+    }
+
+    /// Called after every instruction, once an interrupt controller is mapped: ticks the timer
+    /// if the instruction `retired`, and otherwise delivers a fault if faults are vectored.
+    /// Then enters the handler of any interrupt that is due.
+    ///
+    /// Returns the changes that enter the handler, which the caller applies.
+    fn take_interrupt(&mut self, retired: bool, decode_fault: bool) -> Vec<AtomicChange> {
+        let page_fault = self
+            .mmu
+            .as_ref()
+            .is_some_and(|mmu| mmu.borrow_mut().take_fault());
+        let Some(controller) = &self.interrupts else {
+            return Vec::new();
+        };
+        let mut controller = controller.borrow_mut();
+        let vector = match &self.state {
+            Status::Error(_) if !retired && controller.vector_faults() => {
+                if page_fault {
+                    interrupt::PAGE_FAULT
+                } else if decode_fault {
+                    interrupt::INSTRUCTION_FAULT
+                } else {
+                    interrupt::ADDRESS_FAULT
+                }
+            }
+            Status::Running => {
+                if retired {
+                    controller.tick();
+                }
+                if !self.interrupts_enabled {
+                    return Vec::new();
+                }
+                match controller.take_pending() {
+                    Some(vector) => vector,
+                    None => return Vec::new(),
+                }
+            }
+            _ => return Vec::new(),
+        };
+        let table = controller.vector_table();
+        drop(controller);
+
+        match self.handler_entry(table, vector) {
+            Ok(changes) => {
+                self.state = Status::Running;
+                self.interrupts_enabled = false;
+                // A fault delivered to its handler no longer explains why the program stops
+                *self.memory_fault.get_mut() = None;
+                changes
+            }
+            Err(e) => {
+                self.state = Status::Error(match &self.state {
+                    Status::Error(fault) => format!("{} (fault not handled: {})", fault, e),
+                    _ => e,
+                });
+                Vec::new()
+            }
+        }
+    }
+
+    /// The changes that push the PC and flags and jump to the handler for `vector`.
+    fn handler_entry(&self, table: i64, vector: u32) -> Result<Vec<AtomicChange>, String> {
+        let entry = table.wrapping_add(8 * vector as i64);
+        if !self.in_memory(entry) {
+            return Err(format!("Vector {} at {} is out of bounds", vector, entry));
+        }
+        let handler = self.read_memory(entry);
+        // The table starts out zeroed, and a program entered from the top is rarely a handler
+        if handler == 0 {
+            return Err(format!("No handler for vector {}", vector));
+        }
+        // A virtual handler address is checked when it is fetched
+        if !self.translating() && (handler < 0 || handler as usize >= self.source.len()) {
+            return Err(format!("Handler address out of bounds: {}", handler));
+        }
+        let sp = self.registers[Register::Rsp as usize].wrapping_sub(16);
+        let flags_addr = self.translate(sp, Access::Write)?;
+        let pc_addr = self.translate(sp.wrapping_add(8), Access::Write)?;
+        if !self.in_memory(flags_addr) || !self.in_memory(pc_addr) {
+            return Err(format!("Stack pointer out of bounds: {}", sp));
+        }
+        let flags = self.condition_code as i64
+            | if self.interrupts_enabled {
+                FLAGS_INTERRUPTS_ENABLED
+            } else {
+                0
+            };
+        Ok(vec![
+            AtomicChange::Memory {
+                addr: pc_addr,
+                value: self.instruction_pointer,
+            },
+            AtomicChange::Memory {
+                addr: flags_addr,
+                value: flags,
+            },
+            AtomicChange::Register {
+                reg: Register::Rsp,
+                value: sp,
+            },
+            AtomicChange::InstructionPointer { ip: handler },
+        ])
+    }
+
+    /// Whether any observers are registered.
+    pub fn has_observers(&self) -> bool {
+        !self.observers.is_empty()
+    }
+
+    /// Stops the simulation after `max_steps` instructions.
+    pub fn with_step_limit(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Stops the simulation when it is provably stuck in a loop.
+    pub fn with_loop_detection(mut self) -> Self {
+        self.loop_detector = Some(LoopDetector::new());
+        self
+    }
+
     /// Resets the simulator state to its initial values.
     pub fn reset(&mut self) {
         self.registers = [0; 13];
         self.instruction_pointer = 0;
         self.memory = [0; MEM_SIZE];
+        if let Some(sparse_memory) = &mut self.sparse_memory {
+            sparse_memory.clear();
+        }
         self.condition_code = 0;
         self.state = Status::Running;
         self.disassembly.clear();
         self.log.clear();
+        self.log_base = 0;
+        self.steps = 0;
+        self.exit_code = None;
+        *self.memory_fault.get_mut() = None;
+        self.interrupts_enabled = false;
+        if self.loop_detector.is_some() {
+            self.loop_detector = Some(LoopDetector::new());
+        }
+        self.paused_at = None;
         self.next_to_commit = 0;
     }
 
+    /// Runs until the program halts, fails, or an observer pauses execution.
+    ///
+    /// Uses the predecoded fast path when nothing is observing or logging execution.
+    pub fn run(&mut self) {
+        if self.can_run_fast() {
+            self.run_blocks();
+            return;
+        }
+        while self.state == Status::Running {
+            self.run_single();
+            if self.paused_at.is_some() {
+                return;
+            }
+        }
+    }
+
     /// Applies all the uncommitted changes in the log to the simulator state.
     fn apply_changes(&mut self) {
         for (_, change) in self.log[self.next_to_commit..].iter() {
             match change {
                 &AtomicChange::Register { reg, value } => {
-                    self.registers[reg as usize] = value;
+                    let old = std::mem::replace(&mut self.registers[reg as usize], value);
+                    for observer in &mut self.observers {
+                        observer.on_register_write(reg, old, value);
+                    }
                 }
                 &AtomicChange::Memory { addr, value } => {
-                    if addr >= 0 && (addr as usize) < MEM_SIZE {
-                        self.memory[addr as usize] = value;
-                    } else {
+                    if self.in_memory(addr) {
+                        let old =
+                            Self::write_to(&mut self.memory, &mut self.sparse_memory, addr, value);
+                        for observer in &mut self.observers {
+                            observer.on_memory_write(addr, old, value);
+                        }
+                    } else if !self.devices.contains(addr) {
+                        // Device writes already happened as the instruction executed
                         self.state =
                             Status::Error(format!("Memory address out of bounds: {}", addr));
                     }
@@ -109,7 +651,10 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                     self.instruction_pointer = ip;
                 }
                 &AtomicChange::ConditionCode { cc } => {
-                    self.condition_code = cc;
+                    let old = std::mem::replace(&mut self.condition_code, cc);
+                    for observer in &mut self.observers {
+                        observer.on_cc_change(old, cc);
+                    }
                 }
                 AtomicChange::State { status } => {
                     self.state = status.clone();
@@ -120,34 +665,139 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
     }
 
     fn condition_ok(&self, cond: CondOp) -> bool {
-        let zero = (self.condition_code & ZERO_MASK) != 0; // Z flag
-        let _carry = (self.condition_code & CARRY_MASK) != 0; // C flag  
-        let sign = (self.condition_code & SIGN_MASK) != 0; // N flag (negative)
-        let overflow = (self.condition_code & OVERFLOW_MASK) != 0; // V flag
-
-        match cond {
-            CondOp::Uncon => true,                     // Unconditional
-            CondOp::Eq => zero,                        // Equal: Z==1
-            CondOp::Ne => !zero,                       // Not equal: Z==0
-            CondOp::Ge => sign == overflow,            // Greater or equal: N==V
-            CondOp::Lt => sign != overflow,            // Less than: N!=V
-            CondOp::Gt => !zero && (sign == overflow), // Greater than: (Z==0) && (N==V)
-            CondOp::Le => zero || (sign != overflow),  // Less or equal: (Z==1) || (N!=V)
-        }
+        condition_holds(cond, self.condition_code)
     }
     /// Executes the given instruction until it halts
     pub fn run_single(&mut self) {
+        let old_state = self.state.clone();
+        if let LogPolicy::Stream(writer) = &mut self.log_policy
+            && !writer.has_header()
+        {
+            let header = TraceHeader {
+                registers: self.registers,
+                instruction_pointer: self.instruction_pointer,
+                condition_code: self.condition_code,
+            };
+            // Traces hold instructions by PC, which is virtual once an MMU is enabled
+            if self.mmu.is_some() {
+                self.state = Status::Error("Traces cannot be recorded with an MMU".to_string());
+            } else if let Err(e) = writer.write_header(&header) {
+                self.state = Status::Error(format!("Failed to write trace: {}", e));
+            }
+        }
+        if self.state == old_state {
+            let (steps, decoded) = (self.steps, self.log_base + self.disassembly.len());
+            self.execute_single();
+            if matches!(self.state, Status::Error(_)) && self.steps == steps {
+                let decode_fault = self.log_base + self.disassembly.len() == decoded;
+                let changes = self.take_interrupt(false, decode_fault);
+                // Logged against the faulting instruction, or the one before if it did not decode
+                let id = (self.log_base + self.disassembly.len()).saturating_sub(1);
+                self.log.extend(changes.into_iter().map(|change| (id, change)));
+                self.apply_changes();
+            }
+        }
+        if self.state != old_state {
+            for observer in &mut self.observers {
+                observer.on_status_change(&old_state, &self.state);
+            }
+        }
+        self.retire_log(self.state != old_state);
+    }
+
+    /// Discards or streams out log entries according to the log policy.
+    fn retire_log(&mut self, status_changed: bool) {
+        match &mut self.log_policy {
+            LogPolicy::Full => return,
+            LogPolicy::Off => {}
+            &mut LogPolicy::LastN(keep) => {
+                // Trim in batches so that each instruction is moved at most once
+                if self.disassembly.len() < 2 * keep.max(1) {
+                    return;
+                }
+                let discard = self.disassembly.len() - keep;
+                self.disassembly.drain(..discard);
+                self.log_base += discard;
+                let first_kept = self.log.partition_point(|(id, _)| *id < self.log_base);
+                self.log.drain(..first_kept);
+                self.next_to_commit = self.log.len();
+                return;
+            }
+            LogPolicy::Stream(writer) => {
+                let mut result = Ok(());
+                let mut changes = self.log.iter().peekable();
+                for (i, (ip, instruction)) in self.disassembly.iter().enumerate() {
+                    let start = *ip as usize;
+                    let bytes = &self.source[start..start + instruction.encoded_len()];
+                    result = result.and_then(|_| writer.write_instruction(*ip, bytes));
+                    while let Some((_, change)) =
+                        changes.next_if(|(id, _)| *id == self.log_base + i)
+                    {
+                        result = result.and_then(|_| writer.write_change(change));
+                    }
+                }
+                // Left over when no instruction retired, e.g. entering a handler after the
+                // first fetch faulted
+                for (_, change) in changes {
+                    result = result.and_then(|_| writer.write_change(change));
+                }
+                // Halts are already logged as a state change
+                if status_changed && !matches!(self.state, Status::Halted) {
+                    let status = AtomicChange::State {
+                        status: self.state.clone(),
+                    };
+                    result = result.and_then(|_| writer.write_change(&status));
+                }
+                if self.state != Status::Running {
+                    result = result.and_then(|_| writer.flush());
+                }
+                if let Err(e) = result {
+                    self.state = Status::Error(format!("Failed to write trace: {}", e));
+                }
+            }
+        }
+        self.log_base += self.disassembly.len();
+        self.disassembly.clear();
+        self.log.clear();
+        self.next_to_commit = 0;
+    }
+
+    fn execute_single(&mut self) {
+        if let Some(max_steps) = self.max_steps
+            && self.steps >= max_steps
+        {
+            self.state = Status::StepLimitExceeded;
+            return;
+        }
+
+        // When resuming from a pause, observers have already seen this fetch
+        if self.paused_at.take() != Some(self.instruction_pointer) {
+            let mut control = Control::Continue;
+            for observer in &mut self.observers {
+                if observer.before_fetch(self.instruction_pointer) == Control::Stop {
+                    control = Control::Stop;
+                }
+            }
+            if control == Control::Stop {
+                self.paused_at = Some(self.instruction_pointer);
+                return;
+            }
+        }
+
         let fetch_result = self.fetch_decode();
         if let Err(e) = fetch_result {
             self.state = Status::Error(e);
             return;
         }
         let instruction = fetch_result.unwrap();
+        for observer in &mut self.observers {
+            observer.after_decode(self.instruction_pointer, &instruction);
+        }
         self.disassembly
             .push((self.instruction_pointer, instruction));
 
         let instr = &self.disassembly.last().unwrap().1;
-        let id = self.disassembly.len() - 1;
+        let id = self.log_base + self.disassembly.len() - 1;
         match &instr {
             Instruction::Halt => {
                 self.log.push((
@@ -203,12 +853,23 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                 ));
             }
             Instruction::Rmmov(src, disp, dst) => {
-                let value = self.registers[*src as usize];
-                let addr = disp + self.registers[*dst as usize];
+                let (src, disp, dst) = (*src, *disp, *dst);
+                let value = self.registers[src as usize];
+                let addr = disp + self.registers[dst as usize];
+                let addr = match self.translate(addr, Access::Write) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
 
-                if addr < 0 || (addr as usize) >= MEM_SIZE {
-                    self.state = Status::Error(format!("Memory address out of bounds: {}", addr));
-                    return;
+                if !self.in_memory(addr) {
+                    // Devices see the write now, as reads also happen during execution
+                    if let Err(e) = self.write_device(addr, value) {
+                        self.state = Status::Error(e);
+                        return;
+                    }
                 }
 
                 self.log.push((id, AtomicChange::Memory { addr, value }));
@@ -220,14 +881,31 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                 ));
             }
             Instruction::Mrmov(disp, src, dst) => {
-                let addr = disp + self.registers[*src as usize];
-                if addr < 0 || (addr as usize) >= MEM_SIZE {
-                    self.state = Status::Error(format!("Memory address out of bounds: {}", addr));
-                    return;
+                let (disp, src, dst) = (*disp, *src, *dst);
+                let addr = disp + self.registers[src as usize];
+                let addr = match self.translate(addr, Access::Read) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                let value = if self.in_memory(addr) {
+                    self.read_memory(addr)
+                } else {
+                    match self.read_device(addr) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            self.state = Status::Error(e);
+                            return;
+                        }
+                    }
+                };
+                for observer in &mut self.observers {
+                    observer.on_memory_read(addr, value);
                 }
-                let value = self.memory[addr as usize];
                 self.log
-                    .push((id, AtomicChange::Register { reg: *dst, value }));
+                    .push((id, AtomicChange::Register { reg: dst, value }));
                 self.log.push((
                     id,
                     AtomicChange::InstructionPointer {
@@ -239,43 +917,7 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                 let r1 = self.registers[*src as usize];
                 let r2 = self.registers[*dest as usize];
 
-                let original_carry = self.condition_code & CARRY_MASK != 0;
-                let original_overflow = self.condition_code & OVERFLOW_MASK != 0;
-
-                let (result, carry, overflow) = match op {
-                    ast::BinaryOp::Add => {
-                        let (res, car) = r1.overflowing_add(r2);
-
-                        let overflow = if r1 < 0 && r2 < 0 && res >= 0 {
-                            true
-                        } else if r1 >= 0 && r2 >= 0 && res < 0 {
-                            true
-                        } else {
-                            false
-                        };
-                        (res, car, overflow)
-                    }
-                    ast::BinaryOp::Sub => {
-                        let nr1 = -r1;
-                        let (res, car) = nr1.overflowing_add(r2);
-                        let overflow = if nr1 < 0 && r2 < 0 && res >= 0 {
-                            true
-                        } else if nr1 >= 0 && r2 >= 0 && res < 0 {
-                            true
-                        } else {
-                            false
-                        };
-                        (res, car, overflow)
-                    }
-                    ast::BinaryOp::And => {
-                        let res = r1 & r2;
-                        (res, original_carry, original_overflow)
-                    }
-                    ast::BinaryOp::Xor => {
-                        let res = r1 ^ r2;
-                        (res, original_carry, original_overflow)
-                    }
-                };
+                let (result, cc) = alu(*op, r1, r2, self.condition_code);
 
                 self.log.push((
                     id,
@@ -285,15 +927,7 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                     },
                 ));
 
-                self.log.push((
-                    id,
-                    AtomicChange::ConditionCode {
-                        cc: ((if carry { CARRY_MASK } else { 0 })
-                            | (if result == 0 { ZERO_MASK } else { 0 })
-                            | (if result < 0 { SIGN_MASK } else { 0 })
-                            | (if overflow { OVERFLOW_MASK } else { 0 })),
-                    },
-                ));
+                self.log.push((id, AtomicChange::ConditionCode { cc }));
 
                 self.log.push((
                     id,
@@ -309,13 +943,20 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                     return;
                 };
 
-                if *imm < 0 || (*imm) >= MEM_SIZE as i64 {
+                if !self.translating() && (*imm < 0 || (*imm as usize) >= self.source.len()) {
                     self.state = Status::Error(format!("Call address out of bounds: {}", imm));
                     return;
                 }
 
                 let new_sp = self.registers[Register::Rsp as usize] - 8;
-                if new_sp < 0 || (new_sp as usize) >= MEM_SIZE {
+                let addr = match self.translate(new_sp, Access::Write) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                if !self.in_memory(addr) {
                     self.state = Status::Error(format!("Stack pointer out of bounds: {}", new_sp));
                     return;
                 }
@@ -325,7 +966,7 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                 self.log.push((
                     id,
                     AtomicChange::Memory {
-                        addr: new_sp,
+                        addr,
                         value: ret_addr,
                     },
                 ));
@@ -342,24 +983,37 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                     .push((id, AtomicChange::InstructionPointer { ip: *imm }));
             }
             Instruction::Ret => {
-                let ret_addr = self
-                    .memory
-                    .get(self.registers[Register::Rsp as usize] as usize)
-                    .cloned()
-                    .unwrap_or_else(|| {
-                        self.state = Status::Error("Return address not found on stack".to_string());
-                        -1
-                    });
-                if ret_addr < 0 || (ret_addr as usize) >= MEM_SIZE {
+                let sp = match self.translate(self.registers[Register::Rsp as usize], Access::Read)
+                {
+                    Ok(sp) => sp,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                let ret_addr = if self.in_memory(sp) {
+                    self.read_memory(sp)
+                } else {
+                    self.state = Status::Error("Return address not found on stack".to_string());
+                    -1
+                };
+                // With translation on, bad addresses fault when they are used instead
+                let translating = self.translating();
+                let missing = self.state != Status::Running;
+                let code_len = self.source.len();
+                if missing || (!translating && (ret_addr < 0 || ret_addr as usize >= code_len)) {
                     self.state =
                         Status::Error(format!("Return address out of bounds: {}", ret_addr));
                     return;
                 }
                 let new_sp = self.registers[Register::Rsp as usize] + 8;
-                if new_sp < 0 || (new_sp as usize) >= MEM_SIZE {
+                if !translating && !self.in_memory(new_sp) {
                     self.state = Status::Error(format!("Stack pointer out of bounds: {}", new_sp));
                     return;
                 }
+                for observer in &mut self.observers {
+                    observer.on_memory_read(sp, ret_addr);
+                }
 
                 self.log.push((
                     id,
@@ -378,7 +1032,7 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                     return;
                 };
 
-                if *addr < 0 || (*addr as usize) >= self.source.len() {
+                if !self.translating() && (*addr < 0 || (*addr as usize) >= self.source.len()) {
                     self.state = Status::Error(format!("Jump address out of bounds: {}", addr));
                     return;
                 }
@@ -396,20 +1050,21 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
             }
             Instruction::Push(reg) => {
                 let new_sp = self.registers[Register::Rsp as usize] - 8;
-                if new_sp < 0 || (new_sp as usize) >= MEM_SIZE {
+                let addr = match self.translate(new_sp, Access::Write) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                if !self.in_memory(addr) {
                     self.state = Status::Error(format!("Stack pointer out of bounds: {}", new_sp));
                     return;
                 }
 
                 let value = self.registers[*reg as usize];
 
-                self.log.push((
-                    id,
-                    AtomicChange::Memory {
-                        addr: new_sp,
-                        value,
-                    },
-                ));
+                self.log.push((id, AtomicChange::Memory { addr, value }));
 
                 self.log.push((
                     id,
@@ -428,12 +1083,22 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
             }
             Instruction::Pop(reg) => {
                 let sp = self.registers[Register::Rsp as usize];
-                if sp < 0 || (sp as usize) >= MEM_SIZE {
+                let addr = match self.translate(sp, Access::Read) {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                if !self.in_memory(addr) {
                     self.state = Status::Error(format!("Stack pointer out of bounds: {}", sp));
                     return;
                 }
 
-                let value = self.memory[sp as usize];
+                let value = self.read_memory(addr);
+                for observer in &mut self.observers {
+                    observer.on_memory_read(addr, value);
+                }
 
                 self.log
                     .push((id, AtomicChange::Register { reg: *reg, value }));
@@ -458,153 +1123,179 @@ impl<'a, const MEM_SIZE: usize> Simulator<'a, MEM_SIZE> {
                     },
                 ));
             }
+            Instruction::Syscall => {
+                let (result, writes) = match self.syscall() {
+                    Ok(effects) => effects,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                for (addr, value) in writes {
+                    self.log.push((id, AtomicChange::Memory { addr, value }));
+                }
+                match result {
+                    SyscallResult::Return(value) => {
+                        self.log.push((
+                            id,
+                            AtomicChange::Register {
+                                reg: Register::Rax,
+                                value,
+                            },
+                        ));
+                        self.log.push((
+                            id,
+                            AtomicChange::InstructionPointer {
+                                ip: self.instruction_pointer + 1,
+                            },
+                        ));
+                    }
+                    SyscallResult::Exit(code) => {
+                        self.exit_code = Some(code);
+                        self.log.push((
+                            id,
+                            AtomicChange::State {
+                                status: Status::Halted,
+                            },
+                        ));
+                    }
+                }
+            }
+            Instruction::Iret => {
+                let sp = self.registers[Register::Rsp as usize];
+                let frame = self
+                    .translate(sp, Access::Read)
+                    .and_then(|flags_addr| Ok((flags_addr, self.translate(sp + 8, Access::Read)?)));
+                let (flags_addr, pc_addr) = match frame {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                if !self.in_memory(flags_addr) || !self.in_memory(pc_addr) {
+                    self.state = Status::Error(format!("Stack pointer out of bounds: {}", sp));
+                    return;
+                }
+                let flags = self.read_memory(flags_addr);
+                let ret_addr = self.read_memory(pc_addr);
+                if !self.translating() && (ret_addr < 0 || ret_addr as usize >= self.source.len()) {
+                    self.state =
+                        Status::Error(format!("Return address out of bounds: {}", ret_addr));
+                    return;
+                }
+                for observer in &mut self.observers {
+                    observer.on_memory_read(flags_addr, flags);
+                    observer.on_memory_read(pc_addr, ret_addr);
+                }
+                self.interrupts_enabled = flags & FLAGS_INTERRUPTS_ENABLED != 0;
+
+                self.log.push((
+                    id,
+                    AtomicChange::Register {
+                        reg: Register::Rsp,
+                        value: sp + 16,
+                    },
+                ));
+                self.log.push((
+                    id,
+                    AtomicChange::ConditionCode {
+                        cc: flags as u8 & 0xF,
+                    },
+                ));
+                self.log
+                    .push((id, AtomicChange::InstructionPointer { ip: ret_addr }));
+            }
+            Instruction::Ei | Instruction::Di => {
+                self.interrupts_enabled = matches!(instr, Instruction::Ei);
+                self.log.push((
+                    id,
+                    AtomicChange::InstructionPointer {
+                        ip: self.instruction_pointer + 1,
+                    },
+                ));
+            }
             // Handle other instructions...
             _ => todo!(),
         }
+        let executed_ip = self.instruction_pointer;
+        // System calls, like devices, have effects outside the machine state
+        let syscalled = matches!(self.disassembly.last(), Some((_, Instruction::Syscall)));
+        let interruptible = self
+            .interrupts
+            .as_ref()
+            .is_some_and(|controller| controller.borrow().is_active());
+        let first_change = self.next_to_commit;
         self.apply_changes();
-    }
-
-    fn fetch_decode_regs(&self, ptr: i64) -> Result<(Register, Register), String> {
-        let byte = self
-            .source
-            .get(ptr as usize)
-            .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
-
-        let Ok(reg_a) = Register::try_from(*byte >> 4) else {
-            return Err(format!("Invalid register A: {}", byte >> 4));
-        };
-        let Ok(reg_b) = Register::try_from(*byte & 0x0F) else {
-            return Err(format!("Invalid register B: {}", byte & 0x0F));
-        };
-        Ok((reg_a, reg_b))
-    }
-
-    fn fetch_decode_regb(&self, ptr: i64) -> Result<Register, String> {
-        let byte = self
-            .source
-            .get(ptr as usize)
-            .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
-
-        let Ok(reg_b) = Register::try_from(*byte & 0x0F) else {
-            return Err(format!("Invalid register B: {}", byte & 0x0F));
-        };
-
-        Ok(reg_b)
-    }
-
-    fn fetch_decode_rega(&self, ptr: i64) -> Result<Register, String> {
-        let byte = self
-            .source
-            .get(ptr as usize)
-            .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
-
-        let Ok(reg_a) = Register::try_from(*byte >> 4) else {
-            return Err(format!("Invalid register A: {}", byte >> 4));
-        };
-
-        Ok(reg_a)
-    }
-
-    fn fetch_decode_imm(&self, ptr: i64) -> Result<i64, String> {
-        let bytes = self
-            .source
-            .get(ptr as usize..ptr as usize + 8)
-            .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
-
-        if bytes.len() < 8 {
-            return Err(format!("Immediate value too short at IP: {}", ptr));
+        self.steps += 1;
+        if self.state == Status::Halted {
+            self.halt_devices();
+        }
+        if self.interrupts.is_some() {
+            let changes = self.take_interrupt(true, false);
+            self.log.extend(changes.into_iter().map(|change| (id, change)));
+            self.apply_changes();
         }
 
-        let mut imm = 0i64;
-        for (i, &byte) in bytes.iter().enumerate() {
-            // Little-endian order
-            imm |= (byte as i64) << (i * 8);
+        // Device state is not part of the snapshot, so the search starts over after an access
+        if (self.devices.take_accessed() || syscalled || interruptible)
+            && let Some(detector) = &mut self.loop_detector
+        {
+            *detector = LoopDetector::new();
+        }
+        if let Some(detector) = &mut self.loop_detector
+            && self.state == Status::Running
+            && let Some((start, end)) = detector.record(
+                executed_ip,
+                &self.log[first_change..],
+                &self.registers,
+                self.instruction_pointer,
+                self.condition_code,
+                MemoryState {
+                    ram: &self.memory,
+                    sparse_memory: self.sparse_memory.as_ref(),
+                },
+            )
+        {
+            self.state = Status::InfiniteLoop { start, end };
         }
-        Ok(imm)
     }
 
     fn fetch_decode(&self) -> Result<OwnedInstruction, String> {
-        let byte0 = self
-            .source
-            .get(self.instruction_pointer as usize)
-            .ok_or_else(|| format!("IP Out of Range: {}", self.instruction_pointer))?;
-
-        let opcode = byte0 >> 4;
-        let func = byte0 & 0x0F;
-
-        match opcode {
-            0x0 => Ok(Instruction::Halt),
-            0x1 => Ok(Instruction::Nop),
-            0x2 => {
-                let (r_a, r_b) = self.fetch_decode_regs(self.instruction_pointer + 1)?;
-                let cond = match func {
-                    0x0 => CondOp::Uncon,
-                    0x1 => CondOp::Le,
-                    0x2 => CondOp::Lt,
-                    0x3 => CondOp::Eq,
-                    0x4 => CondOp::Ne,
-                    0x5 => CondOp::Ge,
-                    0x6 => CondOp::Gt,
-                    _ => return Err(format!("Invalid condition code function: {}", func)),
-                };
-                Ok(Instruction::Cmov(cond, r_a, r_b))
-            }
-            0x3 => {
-                let r_b = self.fetch_decode_regb(self.instruction_pointer + 1)?;
-                let imm = self.fetch_decode_imm(self.instruction_pointer + 2)?;
-                Ok(Instruction::Irmov(LabOrImm::Immediate(imm), r_b))
-            }
-            0x4 => {
-                let (r_a, r_b) = self.fetch_decode_regs(self.instruction_pointer + 1)?;
-                let imm = self.fetch_decode_imm(self.instruction_pointer + 2)?;
-                Ok(Instruction::Rmmov(r_a, imm, r_b))
-            }
-            0x5 => {
-                let (r_a, r_b) = self.fetch_decode_regs(self.instruction_pointer + 1)?;
-                let imm = self.fetch_decode_imm(self.instruction_pointer + 2)?;
-                Ok(Instruction::Mrmov(imm, r_b, r_a))
-            }
-            0x6 => {
-                let (r_a, r_b) = self.fetch_decode_regs(self.instruction_pointer + 1)?;
-                let op = match func {
-                    0x0 => ast::BinaryOp::Add,
-                    0x1 => ast::BinaryOp::Sub,
-                    0x2 => ast::BinaryOp::And,
-                    0x3 => ast::BinaryOp::Xor,
-                    _ => return Err(format!("Invalid binary operation function: {}", func)),
-                };
-                Ok(Instruction::Binop(op, r_a, r_b))
-            }
-            0x7 => {
-                let cond = match func {
-                    0x0 => CondOp::Uncon,
-                    0x1 => CondOp::Le,
-                    0x2 => CondOp::Lt,
-                    0x3 => CondOp::Eq,
-                    0x4 => CondOp::Ne,
-                    0x5 => CondOp::Ge,
-                    0x6 => CondOp::Gt,
-                    _ => return Err(format!("Invalid jump condition function: {}", func)),
-                };
-                let imm = self.fetch_decode_imm(self.instruction_pointer + 1)?;
-                Ok(Instruction::Jmp(cond, LabOrImm::Immediate(imm)))
-            }
-            0x8 => {
-                let imm = self.fetch_decode_imm(self.instruction_pointer + 1)?;
-                Ok(Instruction::Call(LabOrImm::Immediate(imm)))
-            }
-            0x9 => Ok(Instruction::Ret),
-            0xa => {
-                let reg = self.fetch_decode_rega(self.instruction_pointer + 1)?;
-                Ok(Instruction::Push(reg))
-            }
-            0xb => {
-                let reg = self.fetch_decode_rega(self.instruction_pointer + 1)?;
-                Ok(Instruction::Pop(reg))
+        if !self.translating() {
+            let ip = self.instruction_pointer;
+            self.check_access(ip, Access::Execute)?;
+            let instruction = decode(self.source, ip)?;
+            // The instruction may run on past the end of its region
+            self.check_access(ip + instruction.encoded_len() as i64 - 1, Access::Execute)?;
+            return Ok(instruction);
+        }
+        // Gather the bytes one by one, as an instruction may continue on another page
+        let ip = self.instruction_pointer;
+        let mut bytes = Vec::new();
+        let mut len = 1;
+        let mut frame = None;
+        while bytes.len() < len {
+            let addr = ip + bytes.len() as i64;
+            let page = addr.div_euclid(PAGE_SIZE);
+            let base = match frame {
+                Some((frame_page, base)) if frame_page == page => base,
+                _ => {
+                    let base = self.translate(addr, Access::Execute)? - addr % PAGE_SIZE;
+                    frame = Some((page, base));
+                    base
+                }
+            };
+            let byte = usize::try_from(base + addr % PAGE_SIZE)
+                .ok()
+                .and_then(|index| self.source.get(index))
+                .ok_or_else(|| format!("IP Out of Range: {}", addr))?;
+            if bytes.is_empty() {
+                len = decoder::encoded_len(*byte);
             }
-            // Add more opcodes as needed
-            _ => Err(format!("Unknown opcode: {:#x}", opcode)),
+            bytes.push(*byte);
         }
+        decode(&bytes, 0)
     }
 
     pub fn is_halted(&self) -> bool {
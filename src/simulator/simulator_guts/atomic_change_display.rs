@@ -7,6 +7,10 @@ impl fmt::Display for Status {
             Status::Running => write!(f, "Running"),
             Status::Halted => write!(f, "Halted"),
             Status::Error(msg) => write!(f, "Error: {}", msg),
+            Status::StepLimitExceeded => write!(f, "Step limit exceeded"),
+            Status::InfiniteLoop { start, end } => {
+                write!(f, "Infinite loop between {:#x} and {:#x}", start, end)
+            }
         }
     }
 }
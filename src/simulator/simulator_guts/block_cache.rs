@@ -0,0 +1,417 @@
+use super::{AtomicChange, LogPolicy, Simulator, Status, alu, decode};
+use crate::ast::{BinaryOp, CondOp, Instruction, LabOrImm, OwnedInstruction, Register};
+use crate::simulator::interrupt::FLAGS_INTERRUPTS_ENABLED;
+use crate::simulator::syscall::SyscallResult;
+#[cfg(test)]
+mod block_cache_tests;
+
+/// An instruction predecoded into the form the fast path executes.
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Halt,
+    Nop,
+    Cmov(CondOp, Register, Register),
+    Irmov(i64, Register),
+    Rmmov(Register, i64, Register),
+    Mrmov(i64, Register, Register),
+    Binop(BinaryOp, Register, Register),
+    Jmp(CondOp, i64),
+    Call(i64),
+    Ret,
+    Push(Register),
+    Pop(Register),
+    Syscall,
+    Iret,
+    /// Sets whether interrupts are enabled
+    SetInterrupts(bool),
+    /// Decoded, but fails with this error when executed
+    Invalid(String),
+    /// The bytes at this address do not decode
+    Fault(String),
+}
+
+impl Op {
+    fn predecode(instruction: OwnedInstruction) -> Self {
+        match instruction {
+            Instruction::Halt => Op::Halt,
+            Instruction::Nop => Op::Nop,
+            Instruction::Cmov(cond, r1, r2) => Op::Cmov(cond, r1, r2),
+            Instruction::Irmov(LabOrImm::Immediate(imm), reg) => Op::Irmov(imm, reg),
+            Instruction::Irmov(..) => Op::Invalid("Invalid immediate value".to_string()),
+            Instruction::Rmmov(src, disp, dst) => Op::Rmmov(src, disp, dst),
+            Instruction::Mrmov(disp, src, dst) => Op::Mrmov(disp, src, dst),
+            Instruction::Binop(op, src, dst) => Op::Binop(op, src, dst),
+            Instruction::Jmp(cond, LabOrImm::Immediate(addr)) => Op::Jmp(cond, addr),
+            Instruction::Jmp(..) => Op::Invalid("Invalid jump target".to_string()),
+            Instruction::Call(LabOrImm::Immediate(addr)) => Op::Call(addr),
+            Instruction::Call(_) => Op::Invalid("Invalid immediate value in Call".to_string()),
+            Instruction::Ret => Op::Ret,
+            Instruction::Push(reg) => Op::Push(reg),
+            Instruction::Pop(reg) => Op::Pop(reg),
+            Instruction::Syscall => Op::Syscall,
+            Instruction::Iret => Op::Iret,
+            Instruction::Ei => Op::SetInterrupts(true),
+            Instruction::Di => Op::SetInterrupts(false),
+            Instruction::Label(_) | Instruction::Directive(..) => {
+                Op::Invalid(format!("Cannot execute {}", instruction))
+            }
+        }
+    }
+
+    /// Whether control may leave the straight-line path after this instruction.
+    fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Op::Halt
+                | Op::Jmp(..)
+                | Op::Call(_)
+                | Op::Ret
+                | Op::Iret
+                | Op::Invalid(_)
+                | Op::Fault(_)
+        )
+    }
+}
+
+/// A predecoded instruction and the address of the one that follows it.
+#[derive(Debug)]
+struct Decoded {
+    next: i64,
+    op: Op,
+}
+
+/// A run of instructions entered only at the top and left only at the bottom.
+#[derive(Debug)]
+struct Block {
+    ops: Vec<Decoded>,
+}
+
+/// Basic blocks predecoded from the program image, indexed by entry address.
+///
+/// Blocks are built the first time their entry address is executed. Instructions are fetched
+/// from the read-only program image, which stores to memory never reach, so a block stays
+/// valid for as long as the image does.
+///
+/// Only the fast path uses the cache, which in particular requires `LogPolicy::Off` rather than
+/// the default full log; see [`Simulator::can_run_fast`].
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    /// Block index for each entry address, or `NO_BLOCK`
+    entries: Vec<u32>,
+}
+
+const NO_BLOCK: u32 = u32::MAX;
+
+impl BlockCache {
+    /// Number of blocks currently cached.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the index of the block starting at `ip`, which must lie within `source`.
+    fn block_at(&mut self, source: &[u8], ip: i64) -> usize {
+        if self.entries.len() != source.len() {
+            self.entries = vec![NO_BLOCK; source.len()];
+            self.blocks.clear();
+        }
+        let entry = self.entries[ip as usize];
+        if entry != NO_BLOCK {
+            return entry as usize;
+        }
+
+        let mut ops = Vec::new();
+        let mut next = ip;
+        loop {
+            let decoded = match decode(source, next) {
+                Ok(instruction) => {
+                    next += instruction.encoded_len() as i64;
+                    Decoded {
+                        next,
+                        op: Op::predecode(instruction),
+                    }
+                }
+                Err(e) => Decoded {
+                    next,
+                    op: Op::Fault(e),
+                },
+            };
+            let ends_block = decoded.op.ends_block();
+            ops.push(decoded);
+            if ends_block {
+                break;
+            }
+        }
+
+        self.blocks.push(Block { ops });
+        self.entries[ip as usize] = (self.blocks.len() - 1) as u32;
+        self.blocks.len() - 1
+    }
+}
+
+impl<const MEM_SIZE: usize> Simulator<'_, MEM_SIZE> {
+    /// Whether `run` may use the predecoded fast path, which is the case when nothing
+    /// needs to see individual instructions: no observers, no loop detection, no log, no
+    /// MMU to translate addresses and no memory map to check them against.
+    pub fn can_run_fast(&self) -> bool {
+        self.observers.is_empty()
+            && self.loop_detector.is_none()
+            && self.paused_at.is_none()
+            && matches!(self.log_policy, LogPolicy::Off)
+            && self.mmu.is_none()
+            && self.memory_map.is_none()
+    }
+
+    /// Runs predecoded basic blocks until the program stops.
+    ///
+    /// Produces exactly the same registers, memory, condition code, PC, status and step count
+    /// as `run_single`, without building any log entries.
+    pub(super) fn run_blocks(&mut self) {
+        let mut cache = std::mem::take(&mut self.block_cache);
+        while self.state == Status::Running {
+            let ip = self.instruction_pointer;
+            if ip < 0 || ip as usize >= self.source.len() {
+                if !self.step_limit_reached() {
+                    self.state = Status::Error(decode(self.source, ip).unwrap_err());
+                    let changes = self.take_interrupt(false, true);
+                    if !changes.is_empty() {
+                        self.enter_handler(changes);
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            let index = cache.block_at(self.source, ip);
+            for decoded in &cache.blocks[index].ops {
+                if self.step_limit_reached() {
+                    break;
+                }
+                let steps = self.steps;
+                self.execute_op(decoded);
+                if self.interrupts.is_some() {
+                    let decode_fault = matches!(decoded.op, Op::Invalid(_) | Op::Fault(_));
+                    let changes = self.take_interrupt(self.steps > steps, decode_fault);
+                    if !changes.is_empty() {
+                        self.enter_handler(changes);
+                        break;
+                    }
+                }
+                if self.state != Status::Running {
+                    break;
+                }
+            }
+        }
+        self.block_cache = cache;
+    }
+
+    fn step_limit_reached(&mut self) -> bool {
+        if let Some(max_steps) = self.max_steps
+            && self.steps >= max_steps
+        {
+            self.state = Status::StepLimitExceeded;
+            return true;
+        }
+        false
+    }
+
+    /// Applies the changes from `take_interrupt`.
+    fn enter_handler(&mut self, changes: Vec<AtomicChange>) {
+        for change in changes {
+            match change {
+                AtomicChange::Memory { addr, value } => {
+                    self.write_memory(addr, value);
+                }
+                AtomicChange::Register { reg, value } => self.registers[reg as usize] = value,
+                AtomicChange::InstructionPointer { ip } => self.instruction_pointer = ip,
+                AtomicChange::ConditionCode { cc } => self.condition_code = cc,
+                AtomicChange::State { status } => self.state = status,
+            }
+        }
+    }
+
+    fn stack_error(&mut self, sp: i64) {
+        self.state = Status::Error(format!("Stack pointer out of bounds: {}", sp));
+    }
+
+    /// Executes one predecoded instruction with the same semantics as `execute_single`.
+    fn execute_op(&mut self, decoded: &Decoded) {
+        // Code addresses are checked against the program image, as in `execute_single`
+        let code_len = self.source.len();
+        let in_code = |addr: i64| addr >= 0 && (addr as usize) < code_len;
+        let rsp = Register::Rsp as usize;
+
+        if !matches!(decoded.op, Op::Fault(_)) {
+            // Keep instruction numbering in step with the logging path
+            self.log_base += 1;
+        }
+        match decoded.op {
+            Op::Halt => {
+                self.state = Status::Halted;
+                self.steps += 1;
+                self.halt_devices();
+                return;
+            }
+            Op::Nop => {}
+            Op::Cmov(cond, r1, r2) => {
+                if self.condition_ok(cond) {
+                    self.registers[r2 as usize] = self.registers[r1 as usize];
+                }
+            }
+            Op::Irmov(imm, reg) => self.registers[reg as usize] = imm,
+            Op::Rmmov(src, disp, dst) => {
+                let addr = disp + self.registers[dst as usize];
+                let value = self.registers[src as usize];
+                if self.in_memory(addr) {
+                    self.write_memory(addr, value);
+                } else if let Err(e) = self.write_device(addr, value) {
+                    self.state = Status::Error(e);
+                    return;
+                }
+            }
+            Op::Mrmov(disp, src, dst) => {
+                let addr = disp + self.registers[src as usize];
+                self.registers[dst as usize] = if self.in_memory(addr) {
+                    self.read_memory(addr)
+                } else {
+                    match self.read_device(addr) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            self.state = Status::Error(e);
+                            return;
+                        }
+                    }
+                };
+            }
+            Op::Binop(op, src, dst) => {
+                let (result, cc) = alu(
+                    op,
+                    self.registers[src as usize],
+                    self.registers[dst as usize],
+                    self.condition_code,
+                );
+                self.registers[dst as usize] = result;
+                self.condition_code = cc;
+            }
+            Op::Jmp(cond, addr) => {
+                if !in_code(addr) {
+                    self.state = Status::Error(format!("Jump address out of bounds: {}", addr));
+                    return;
+                }
+                if self.condition_ok(cond) {
+                    self.instruction_pointer = addr;
+                    self.steps += 1;
+                    return;
+                }
+            }
+            Op::Call(addr) => {
+                if !in_code(addr) {
+                    self.state = Status::Error(format!("Call address out of bounds: {}", addr));
+                    return;
+                }
+                let new_sp = self.registers[rsp] - 8;
+                if !self.in_memory(new_sp) {
+                    return self.stack_error(new_sp);
+                }
+                self.write_memory(new_sp, decoded.next);
+                self.registers[rsp] = new_sp;
+                self.instruction_pointer = addr;
+                self.steps += 1;
+                return;
+            }
+            Op::Ret => {
+                let sp = self.registers[rsp];
+                // A missing return address is reported as out of bounds, as in `execute_single`
+                let ret_addr = if self.in_memory(sp) {
+                    self.read_memory(sp)
+                } else {
+                    -1
+                };
+                if !in_code(ret_addr) {
+                    self.state =
+                        Status::Error(format!("Return address out of bounds: {}", ret_addr));
+                    return;
+                }
+                let new_sp = sp + 8;
+                if !self.in_memory(new_sp) {
+                    return self.stack_error(new_sp);
+                }
+                self.registers[rsp] = new_sp;
+                self.instruction_pointer = ret_addr;
+                self.steps += 1;
+                return;
+            }
+            Op::Push(reg) => {
+                let new_sp = self.registers[rsp] - 8;
+                if !self.in_memory(new_sp) {
+                    return self.stack_error(new_sp);
+                }
+                self.write_memory(new_sp, self.registers[reg as usize]);
+                self.registers[rsp] = new_sp;
+            }
+            Op::Pop(reg) => {
+                let sp = self.registers[rsp];
+                if !self.in_memory(sp) {
+                    return self.stack_error(sp);
+                }
+                self.registers[reg as usize] = self.read_memory(sp);
+                // Popping into %rsp leaves the popped value as the stack pointer
+                if reg != Register::Rsp {
+                    self.registers[rsp] = sp + 8;
+                }
+            }
+            Op::Syscall => {
+                let (result, writes) = match self.syscall() {
+                    Ok(effects) => effects,
+                    Err(e) => {
+                        self.state = Status::Error(e);
+                        return;
+                    }
+                };
+                for (addr, value) in writes {
+                    self.write_memory(addr, value);
+                }
+                match result {
+                    SyscallResult::Return(value) => self.registers[Register::Rax as usize] = value,
+                    SyscallResult::Exit(code) => {
+                        self.exit_code = Some(code);
+                        self.state = Status::Halted;
+                        self.steps += 1;
+                        self.halt_devices();
+                        return;
+                    }
+                }
+            }
+            Op::Iret => {
+                let sp = self.registers[rsp];
+                if !self.in_memory(sp) || !self.in_memory(sp + 8) {
+                    return self.stack_error(sp);
+                }
+                let flags = self.read_memory(sp);
+                let ret_addr = self.read_memory(sp + 8);
+                if !in_code(ret_addr) {
+                    self.state =
+                        Status::Error(format!("Return address out of bounds: {}", ret_addr));
+                    return;
+                }
+                self.interrupts_enabled = flags & FLAGS_INTERRUPTS_ENABLED != 0;
+                self.registers[rsp] = sp + 16;
+                self.condition_code = flags as u8 & 0xF;
+                self.instruction_pointer = ret_addr;
+                self.steps += 1;
+                return;
+            }
+            Op::SetInterrupts(enabled) => self.interrupts_enabled = enabled,
+            Op::Invalid(ref e) | Op::Fault(ref e) => {
+                self.state = Status::Error(e.clone());
+                return;
+            }
+        }
+        self.instruction_pointer = decoded.next;
+        self.steps += 1;
+    }
+}
@@ -0,0 +1,149 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Runs `code` on the logging path and on the fast path and checks they end in the same state.
+fn assert_paths_agree(code: &[u8], max_steps: Option<u64>) -> Simulator<'_, 1024> {
+    let mut logged = Simulator::<1024>::new(code);
+    logged.max_steps = max_steps;
+    while logged.state == Status::Running {
+        logged.run_single();
+    }
+
+    let mut fast = Simulator::<1024>::new(code).with_log_policy(LogPolicy::Off);
+    fast.max_steps = max_steps;
+    assert!(fast.can_run_fast());
+    fast.run();
+
+    assert_eq!(fast.state, logged.state);
+    assert_eq!(fast.steps, logged.steps);
+    assert_eq!(fast.registers, logged.registers);
+    assert_eq!(fast.instruction_pointer, logged.instruction_pointer);
+    assert_eq!(fast.condition_code, logged.condition_code);
+    assert_eq!(fast.memory, logged.memory);
+    assert_eq!(fast.log_base, logged.disassembly.len());
+    assert!(fast.log.is_empty());
+    fast
+}
+
+#[test]
+fn test_fast_path_matches_examples() {
+    for src in [
+        include_str!("../../../../examples/add_numbers.ys"),
+        include_str!("../../../../examples/bubble_sort.ys"),
+        include_str!("../../../../examples/test_instructions.ys"),
+        include_str!("../../../../examples/test_jump.ys"),
+        include_str!("../../../../examples/test_jump_not_taken.ys"),
+        include_str!("../../../../examples/test_sp_edge_cases.ys"),
+        include_str!("../../../../examples/test_unconditional_jump.ys"),
+    ] {
+        assert_paths_agree(&assemble(src), None);
+    }
+}
+
+#[test]
+fn test_fast_path_matches_every_instruction() {
+    let code = assemble(
+        "irmovq $-5, %rax
+            irmovq $7, %rbx
+            addq %rax, %rbx
+            cmovg %rbx, %rcx
+            cmovl %rbx, %rdx
+            subq %rbx, %rax
+            andq %rax, %rax
+            xorq %rbx, %rbx
+            rmmovq %rax, 512(%rbx)
+            mrmovq 512(%rbx), %rsi
+            pushq %rsp
+            popq %rdi
+            irmovq $800, %rsi
+            pushq %rsi
+            popq %rsp
+            nop
+            call f
+            halt
+            f:
+            ret",
+    );
+    let sim = assert_paths_agree(&code, None);
+    assert!(sim.is_halted());
+}
+
+#[test]
+fn test_fast_path_matches_errors() {
+    for src in [
+        // Return with nothing on the stack
+        "ret",
+        // Jump to just past the end of the program
+        "jmp end\nend:",
+        // Store outside memory
+        "irmovq $4096, %rax\nrmmovq %rax, (%rax)",
+        // Pop with the stack pointer outside memory
+        "irmovq $-8, %rsp\npopq %rax",
+        // Fall off the end of the program
+        "nop",
+    ] {
+        let code = assemble(src);
+        let sim = assert_paths_agree(&code, None);
+        assert!(matches!(sim.state, Status::Error(_)), "{}", src);
+    }
+}
+
+#[test]
+fn test_fast_path_honours_step_limit() {
+    let code = assemble("loop: nop\njmp loop");
+    let sim = assert_paths_agree(&code, Some(11));
+    assert_eq!(sim.state, Status::StepLimitExceeded);
+    assert_eq!(sim.steps, 11);
+}
+
+#[test]
+fn test_blocks_are_reused() {
+    let code = assemble(include_str!("../../../../examples/bubble_sort.ys"));
+    let mut sim = Simulator::<1024>::new(&code).with_log_policy(LogPolicy::Off);
+    sim.run();
+    assert!(sim.is_halted());
+    // One block per branch target or fall-through, however many times each runs
+    assert!(
+        sim.block_cache.len() <= 8,
+        "{} blocks",
+        sim.block_cache.len()
+    );
+}
+
+#[test]
+fn test_stores_do_not_reach_code() {
+    // Code runs from the program image, so storing over the second irmovq changes
+    // memory but not what executes
+    let code = assemble(
+        "irmovq $21, %rax
+        rmmovq %rax, (%rax)
+        nop
+        irmovq $1, %rbx
+        halt",
+    );
+    let sim = assert_paths_agree(&code, None);
+    assert!(sim.is_halted());
+    assert_eq!(sim.registers[Register::Rbx as usize], 1);
+    assert_eq!(sim.memory[21], 21);
+    assert_eq!(sim.block_cache.len(), 1);
+}
+
+#[test]
+fn test_observers_and_logs_use_logging_path() {
+    let code = assemble("halt");
+    assert!(!Simulator::<1024>::new(&code).can_run_fast());
+    assert!(
+        !Simulator::<1024>::new(&code)
+            .with_log_policy(LogPolicy::Off)
+            .with_loop_detection()
+            .can_run_fast()
+    );
+
+    let mut observed = Simulator::<1024>::new(&code).with_log_policy(LogPolicy::Off);
+    observed.add_observer(crate::simulator::observer::Breakpoints::default());
+    assert!(!observed.can_run_fast());
+}
@@ -0,0 +1,157 @@
+use crate::ast::{self, CondOp, Instruction, LabOrImm, OwnedInstruction, Register};
+
+fn fetch_decode_regs(source: &[u8], ptr: i64) -> Result<(Register, Register), String> {
+    let byte = source
+        .get(ptr as usize)
+        .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
+
+    let Ok(reg_a) = Register::try_from(*byte >> 4) else {
+        return Err(format!("Invalid register A: {}", byte >> 4));
+    };
+    let Ok(reg_b) = Register::try_from(*byte & 0x0F) else {
+        return Err(format!("Invalid register B: {}", byte & 0x0F));
+    };
+    Ok((reg_a, reg_b))
+}
+
+fn fetch_decode_regb(source: &[u8], ptr: i64) -> Result<Register, String> {
+    let byte = source
+        .get(ptr as usize)
+        .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
+
+    let Ok(reg_b) = Register::try_from(*byte & 0x0F) else {
+        return Err(format!("Invalid register B: {}", byte & 0x0F));
+    };
+
+    Ok(reg_b)
+}
+
+fn fetch_decode_rega(source: &[u8], ptr: i64) -> Result<Register, String> {
+    let byte = source
+        .get(ptr as usize)
+        .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
+
+    let Ok(reg_a) = Register::try_from(*byte >> 4) else {
+        return Err(format!("Invalid register A: {}", byte >> 4));
+    };
+
+    Ok(reg_a)
+}
+
+fn fetch_decode_imm(source: &[u8], ptr: i64) -> Result<i64, String> {
+    let bytes = source
+        .get(ptr as usize..ptr as usize + 8)
+        .ok_or_else(|| format!("IP Out of Range: {}", ptr))?;
+
+    if bytes.len() < 8 {
+        return Err(format!("Immediate value too short at IP: {}", ptr));
+    }
+
+    let mut imm = 0i64;
+    for (i, &byte) in bytes.iter().enumerate() {
+        // Little-endian order
+        imm |= (byte as i64) << (i * 8);
+    }
+    Ok(imm)
+}
+
+/// The length of the instruction starting with `byte0`, or 1 if it does not decode.
+pub(super) fn encoded_len(byte0: u8) -> usize {
+    match byte0 >> 4 {
+        0x2 | 0x6 | 0xa | 0xb => 2,
+        0x3..=0x5 => 10,
+        0x7 | 0x8 => 9,
+        _ => 1,
+    }
+}
+
+/// Decodes the instruction at `ip` in `source`.
+pub fn decode(source: &[u8], ip: i64) -> Result<OwnedInstruction, String> {
+    let byte0 = source
+        .get(ip as usize)
+        .ok_or_else(|| format!("IP Out of Range: {}", ip))?;
+
+    let opcode = byte0 >> 4;
+    let func = byte0 & 0x0F;
+
+    match opcode {
+        0x0 => Ok(Instruction::Halt),
+        0x1 => Ok(Instruction::Nop),
+        0x2 => {
+            let (r_a, r_b) = fetch_decode_regs(source, ip + 1)?;
+            let cond = match func {
+                0x0 => CondOp::Uncon,
+                0x1 => CondOp::Le,
+                0x2 => CondOp::Lt,
+                0x3 => CondOp::Eq,
+                0x4 => CondOp::Ne,
+                0x5 => CondOp::Ge,
+                0x6 => CondOp::Gt,
+                _ => return Err(format!("Invalid condition code function: {}", func)),
+            };
+            Ok(Instruction::Cmov(cond, r_a, r_b))
+        }
+        0x3 => {
+            let r_b = fetch_decode_regb(source, ip + 1)?;
+            let imm = fetch_decode_imm(source, ip + 2)?;
+            Ok(Instruction::Irmov(LabOrImm::Immediate(imm), r_b))
+        }
+        0x4 => {
+            let (r_a, r_b) = fetch_decode_regs(source, ip + 1)?;
+            let imm = fetch_decode_imm(source, ip + 2)?;
+            Ok(Instruction::Rmmov(r_a, imm, r_b))
+        }
+        0x5 => {
+            let (r_a, r_b) = fetch_decode_regs(source, ip + 1)?;
+            let imm = fetch_decode_imm(source, ip + 2)?;
+            Ok(Instruction::Mrmov(imm, r_b, r_a))
+        }
+        0x6 => {
+            let (r_a, r_b) = fetch_decode_regs(source, ip + 1)?;
+            let op = match func {
+                0x0 => ast::BinaryOp::Add,
+                0x1 => ast::BinaryOp::Sub,
+                0x2 => ast::BinaryOp::And,
+                0x3 => ast::BinaryOp::Xor,
+                _ => return Err(format!("Invalid binary operation function: {}", func)),
+            };
+            Ok(Instruction::Binop(op, r_a, r_b))
+        }
+        0x7 => {
+            let cond = match func {
+                0x0 => CondOp::Uncon,
+                0x1 => CondOp::Le,
+                0x2 => CondOp::Lt,
+                0x3 => CondOp::Eq,
+                0x4 => CondOp::Ne,
+                0x5 => CondOp::Ge,
+                0x6 => CondOp::Gt,
+                _ => return Err(format!("Invalid jump condition function: {}", func)),
+            };
+            let imm = fetch_decode_imm(source, ip + 1)?;
+            Ok(Instruction::Jmp(cond, LabOrImm::Immediate(imm)))
+        }
+        0x8 => {
+            let imm = fetch_decode_imm(source, ip + 1)?;
+            Ok(Instruction::Call(LabOrImm::Immediate(imm)))
+        }
+        0x9 => Ok(Instruction::Ret),
+        0xa => {
+            let reg = fetch_decode_rega(source, ip + 1)?;
+            Ok(Instruction::Push(reg))
+        }
+        0xb => {
+            let reg = fetch_decode_rega(source, ip + 1)?;
+            Ok(Instruction::Pop(reg))
+        }
+        0xc => Ok(Instruction::Syscall),
+        0xd => Ok(Instruction::Iret),
+        0xe => match func {
+            0x0 => Ok(Instruction::Ei),
+            0x1 => Ok(Instruction::Di),
+            _ => Err(format!("Invalid interrupt flag function: {}", func)),
+        },
+        // Add more opcodes as needed
+        _ => Err(format!("Unknown opcode: {:#x}", opcode)),
+    }
+}
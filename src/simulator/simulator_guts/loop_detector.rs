@@ -0,0 +1,134 @@
+use super::AtomicChange;
+use crate::simulator::sparse_memory::SparseMemory;
+use std::collections::HashSet;
+
+/// Detects guaranteed non-termination by spotting an exact repeat of the machine state.
+///
+/// Uses Brent's algorithm: the full state is snapshotted after 1, 2, 4, 8, ... steps, and every
+/// step in between is compared against the latest snapshot. Registers, CC and PC are compared by
+/// hash first, then exactly; memory is compared only at the addresses written since the snapshot,
+/// since nothing else can differ. The program image is read-only, so a repeated state means the
+/// simulator will repeat forever.
+pub struct LoopDetector {
+    snapshot: Option<Snapshot>,
+    /// Addresses written since the snapshot
+    dirty: HashSet<i64>,
+    steps_since_snapshot: u64,
+    snapshot_interval: u64,
+    /// Lowest and highest PC executed since the snapshot
+    pc_range: (i64, i64),
+}
+
+struct Snapshot {
+    hash: u64,
+    registers: [i64; 13],
+    instruction_pointer: i64,
+    condition_code: u8,
+    memory: Vec<i64>,
+    sparse_memory: Option<SparseMemory>,
+}
+
+/// The memory a state is compared on: RAM, and the sparse memory above it once enabled.
+#[derive(Clone, Copy)]
+pub struct MemoryState<'m> {
+    pub ram: &'m [i64],
+    pub sparse_memory: Option<&'m SparseMemory>,
+}
+
+impl MemoryState<'_> {
+    /// The value at `addr`, if it is memory.
+    fn get(&self, addr: i64) -> Option<i64> {
+        usize::try_from(addr)
+            .ok()
+            .and_then(|index| self.ram.get(index).copied())
+            .or_else(|| {
+                self.sparse_memory
+                    .map(|sparse_memory| sparse_memory.get(addr))
+            })
+    }
+}
+
+/// Cheap fingerprint of the register file, CC and PC.
+fn state_hash(registers: &[i64; 13], instruction_pointer: i64, condition_code: u8) -> u64 {
+    registers
+        .iter()
+        .chain([instruction_pointer, condition_code as i64].iter())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &value| {
+            (hash ^ value as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+impl LoopDetector {
+    pub fn new() -> Self {
+        Self {
+            snapshot: None,
+            dirty: HashSet::new(),
+            steps_since_snapshot: 0,
+            snapshot_interval: 1,
+            pc_range: (i64::MAX, i64::MIN),
+        }
+    }
+
+    /// Records a retired instruction at `executed_ip` along with the changes it made.
+    ///
+    /// Returns the inclusive PC range of the loop if the resulting state has been seen before.
+    pub fn record(
+        &mut self,
+        executed_ip: i64,
+        changes: &[(usize, AtomicChange)],
+        registers: &[i64; 13],
+        instruction_pointer: i64,
+        condition_code: u8,
+        memory: MemoryState,
+    ) -> Option<(i64, i64)> {
+        for (_, change) in changes {
+            if let &AtomicChange::Memory { addr, .. } = change {
+                self.dirty.insert(addr);
+            }
+        }
+        self.pc_range = (
+            self.pc_range.0.min(executed_ip),
+            self.pc_range.1.max(executed_ip),
+        );
+        self.steps_since_snapshot += 1;
+
+        let hash = state_hash(registers, instruction_pointer, condition_code);
+        if let Some(snapshot) = &self.snapshot
+            && snapshot.hash == hash
+            && snapshot.registers == *registers
+            && snapshot.instruction_pointer == instruction_pointer
+            && snapshot.condition_code == condition_code
+            && self.dirty.iter().all(|&addr| {
+                let snapshot = MemoryState {
+                    ram: &snapshot.memory,
+                    sparse_memory: snapshot.sparse_memory.as_ref(),
+                };
+                snapshot.get(addr) == memory.get(addr)
+            })
+        {
+            return Some(self.pc_range);
+        }
+
+        if self.steps_since_snapshot >= self.snapshot_interval {
+            self.snapshot = Some(Snapshot {
+                hash,
+                registers: *registers,
+                instruction_pointer,
+                condition_code,
+                memory: memory.ram.to_vec(),
+                sparse_memory: memory.sparse_memory.cloned(),
+            });
+            self.dirty.clear();
+            self.steps_since_snapshot = 0;
+            self.snapshot_interval *= 2;
+            self.pc_range = (i64::MAX, i64::MIN);
+        }
+        None
+    }
+}
+
+impl Default for LoopDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -514,4 +514,92 @@ mod tests {
             panic!("Expected IP change as second log entry");
         }
     }
+
+    fn assemble(src: &str) -> Vec<u8> {
+        crate::assembler::parse_and_gen(src).unwrap().1.bytes
+    }
+
+    fn run_to_completion(sim: &mut Simulator<1024>) {
+        while sim.state == Status::Running {
+            sim.run_single();
+        }
+    }
+
+    #[test]
+    fn test_step_limit_stops_execution() {
+        let program = assemble("loop: jmp loop");
+        let mut sim = Simulator::<1024>::new(&program).with_step_limit(10);
+
+        run_to_completion(&mut sim);
+
+        assert_eq!(sim.state, Status::StepLimitExceeded);
+        assert_eq!(sim.steps, 10, "Exactly the budgeted number of instructions should retire");
+    }
+
+    #[test]
+    fn test_step_limit_not_hit_by_halting_program() {
+        let program = assemble("nop\nnop\nhalt");
+        let mut sim = Simulator::<1024>::new(&program).with_step_limit(3);
+
+        run_to_completion(&mut sim);
+
+        assert!(sim.is_halted());
+        assert_eq!(sim.steps, 3);
+    }
+
+    #[test]
+    fn test_loop_detection_reports_pc_range() {
+        let program = assemble(
+            "irmovq $1, %rax
+            loop:
+            addq %rax, %rbx
+            xorq %rbx, %rbx
+            jmp loop",
+        );
+        let mut sim = Simulator::<1024>::new(&program).with_loop_detection();
+
+        run_to_completion(&mut sim);
+
+        assert_eq!(sim.state, Status::InfiniteLoop { start: 10, end: 14 });
+    }
+
+    #[test]
+    fn test_loop_detection_ignores_terminating_loop() {
+        let program = assemble(
+            "irmovq $50, %rax
+            irmovq $1, %rcx
+            loop:
+            subq %rcx, %rax
+            jne loop
+            halt",
+        );
+        let mut sim = Simulator::<1024>::new(&program).with_loop_detection();
+
+        run_to_completion(&mut sim);
+
+        assert!(sim.is_halted(), "A counting loop must not be reported: {}", sim.state);
+    }
+
+    #[test]
+    fn test_loop_detection_considers_memory() {
+        // Registers and CC repeat every iteration, but the counter in memory keeps growing
+        let program = assemble(
+            "irmovq $1, %rcx
+            irmovq $512, %rbx
+            loop:
+            mrmovq 0(%rbx), %rax
+            addq %rcx, %rax
+            rmmovq %rax, 0(%rbx)
+            xorq %rax, %rax
+            jmp loop",
+        );
+        let mut sim = Simulator::<1024>::new(&program)
+            .with_loop_detection()
+            .with_step_limit(1000);
+
+        run_to_completion(&mut sim);
+
+        assert_eq!(sim.state, Status::StepLimitExceeded);
+        assert!(sim.memory[512] > 100);
+    }
 }
@@ -0,0 +1,54 @@
+use super::mmu::PAGE_SIZE;
+use std::collections::BTreeMap;
+#[cfg(test)]
+mod sparse_memory_tests;
+
+/// Memory spanning the whole 64-bit address space, allocated a page at a time on the first
+/// write to it. Unwritten addresses read as zero, as in RAM.
+///
+/// Pages are the MMU's size and, like RAM, hold one quad per address.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SparseMemory {
+    /// Keyed by base address, so that pages come out in address order
+    pages: BTreeMap<i64, Box<[i64; PAGE_SIZE as usize]>>,
+}
+
+/// The base address of the page holding `addr`, and the offset of `addr` into it.
+fn split(addr: i64) -> (i64, usize) {
+    (addr & !(PAGE_SIZE - 1), (addr & (PAGE_SIZE - 1)) as usize)
+}
+
+impl SparseMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, addr: i64) -> i64 {
+        let (base, offset) = split(addr);
+        self.pages.get(&base).map_or(0, |page| page[offset])
+    }
+
+    /// Stores `value` at `addr`, allocating its page if need be, and returns the old value.
+    pub fn set(&mut self, addr: i64, value: i64) -> i64 {
+        let (base, offset) = split(addr);
+        let page = self
+            .pages
+            .entry(base)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+        std::mem::replace(&mut page[offset], value)
+    }
+
+    /// The pages written so far, by base address in increasing order.
+    pub fn pages(&self) -> impl Iterator<Item = (i64, &[i64])> {
+        self.pages.iter().map(|(&base, page)| (base, &page[..]))
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Frees every page.
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
@@ -0,0 +1,294 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::ast::Register;
+use crate::simulator::device::{Console, console};
+use crate::simulator::simulator_guts::{LogPolicy, Simulator, Status};
+use crate::simulator::syscall::{HostSyscalls, MAX_TRANSFER};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Sums 1..=3 through a recursive call on a stack far above RAM.
+const HIGH_STACK: &str = "
+    irmovq $0x7fffffff0000, %rsp
+    irmovq $3, %rdi
+    xorq %rax, %rax
+    call sum
+    halt
+sum:
+    andq %rdi, %rdi
+    je done
+    pushq %rdi
+    addq %rdi, %rax
+    irmovq $1, %rcx
+    subq %rcx, %rdi
+    call sum
+    popq %rdi
+done:
+    ret
+";
+
+#[test]
+fn test_pages_are_allocated_on_write() {
+    let mut memory = SparseMemory::new();
+    assert_eq!(memory.get(0x1234_5678), 0);
+    assert_eq!(memory.page_count(), 0);
+
+    assert_eq!(memory.set(0x1234_5678, 42), 0);
+    assert_eq!(memory.set(0x1234_5678, 43), 42);
+    assert_eq!(memory.set(-8, 7), 0);
+    assert_eq!(memory.get(0x1234_5678), 43);
+    assert_eq!(memory.get(-8), 7);
+    assert_eq!(memory.get(0x1234_5680), 0);
+
+    let pages = memory.pages().collect::<Vec<_>>();
+    assert_eq!(pages.len(), 2);
+    // In address order, negative addresses first
+    assert_eq!(pages[0].0, -PAGE_SIZE);
+    assert_eq!(pages[0].1[PAGE_SIZE as usize - 8], 7);
+    assert_eq!(pages[1].0, 0x1234_5600);
+    assert_eq!(pages[1].1[0x78], 43);
+
+    memory.clear();
+    assert_eq!(memory.page_count(), 0);
+    assert_eq!(memory.get(-8), 0);
+}
+
+#[test]
+fn test_high_stack() {
+    let code = assemble(HIGH_STACK);
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator.run();
+    assert_eq!(
+        simulator.state,
+        Status::Error("Stack pointer out of bounds: 140737488289784".to_string())
+    );
+
+    // The logging path, the fast path and with loop detection must agree
+    let simulators = [
+        Simulator::<1024>::new(&code),
+        Simulator::<1024>::new(&code).with_log_policy(LogPolicy::Off),
+        Simulator::<1024>::new(&code).with_loop_detection(),
+    ];
+    for simulator in simulators {
+        let mut simulator = simulator.with_sparse_memory();
+        simulator.run();
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(simulator.registers[Register::Rax as usize], 6);
+        assert_eq!(
+            simulator.registers[Register::Rsp as usize],
+            0x7fff_ffff_0000
+        );
+        // RAM is untouched, and the stack took a single page
+        assert!(simulator.memory.iter().all(|&value| value == 0));
+        let sparse_memory = simulator.sparse_memory().unwrap();
+        assert_eq!(sparse_memory.page_count(), 1);
+        assert_eq!(sparse_memory.get(0x7fff_ffff_0000 - 16), 3);
+    }
+}
+
+#[test]
+fn test_code_above_ram() {
+    // Call and return targets are checked against the program, not RAM
+    let code = assemble(
+        "irmovq $0x100000, %rsp
+        call f
+        halt
+        .align 2048
+        f:
+        irmovq $1, %rax
+        ret",
+    );
+    let simulators = [
+        Simulator::<1024>::new(&code),
+        Simulator::<1024>::new(&code).with_log_policy(LogPolicy::Off),
+    ];
+    for simulator in simulators {
+        let mut simulator = simulator.with_sparse_memory();
+        simulator.run();
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(simulator.registers[Register::Rax as usize], 1);
+        assert_eq!(simulator.instruction_pointer, 19);
+    }
+}
+
+#[test]
+fn test_upper_half_stack() {
+    // The stack sits in the upper half of the address space, and may run down through 0
+    let code = assemble(
+        "irmovq $0xfffffffffffff000, %rsp
+        irmovq $7, %rdi
+        call f
+        irmovq $8, %rsp
+        pushq %rdi
+        pushq %rdi
+        popq %rax
+        halt
+        f:
+        pushq %rdi
+        popq %rbx
+        ret",
+    );
+    for policy in [LogPolicy::Full, LogPolicy::Off] {
+        let mut simulator = Simulator::<1024>::new(&code)
+            .with_log_policy(policy)
+            .with_sparse_memory();
+        simulator.run();
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(simulator.registers[Register::Rbx as usize], 7);
+        assert_eq!(simulator.registers[Register::Rax as usize], 7);
+        let sparse_memory = simulator.sparse_memory().unwrap();
+        assert_eq!(sparse_memory.get(-8), 7);
+        assert_eq!(sparse_memory.get(-0x1000 - 16), 7);
+    }
+}
+
+#[test]
+fn test_devices_take_precedence() {
+    let code = assemble(
+        "irmovq $0x10000, %rbx
+        irmovq $0x41, %rax
+        rmmovq %rax, (%rbx)         # console DATA
+        rmmovq %rax, 0x20000(%rbx)
+        mrmovq 0x20000(%rbx), %rcx
+        halt",
+    );
+    for policy in [LogPolicy::Full, LogPolicy::Off] {
+        let device = Rc::new(RefCell::new(Console::new(&b""[..], Vec::new())));
+        let mut simulator = Simulator::<1024>::new(&code)
+            .with_log_policy(policy)
+            .with_sparse_memory();
+        simulator
+            .map_device(console::DEFAULT_BASE, device.clone())
+            .unwrap();
+        simulator.run();
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(device.borrow().output(), b"A");
+        assert_eq!(simulator.registers[Register::Rcx as usize], 0x41);
+        let sparse_memory = simulator.sparse_memory().unwrap();
+        assert_eq!(sparse_memory.get(0x10000), 0);
+        assert_eq!(sparse_memory.get(0x30000), 0x41);
+    }
+}
+
+#[test]
+fn test_syscall_buffers_in_sparse_memory() {
+    let code = assemble(
+        "irmovq $0x100000000, %rsi
+        irmovq $3, %rdx
+        irmovq $0, %rdi
+        irmovq $2, %rax     # read
+        syscall
+        irmovq $1, %rdi
+        irmovq $1, %rax     # write
+        syscall
+        halt",
+    );
+    for policy in [LogPolicy::Full, LogPolicy::Off] {
+        let table = Rc::new(RefCell::new(HostSyscalls::new(&b"abc"[..], Vec::new())));
+        let mut simulator = Simulator::<1024>::new(&code)
+            .with_log_policy(policy)
+            .with_syscalls(table.clone())
+            .with_sparse_memory();
+        simulator.run();
+        assert_eq!(simulator.state, Status::Halted);
+        assert_eq!(table.borrow().output(), b"abc");
+        assert_eq!(
+            simulator.sparse_memory().unwrap().get(0x100000008),
+            'b' as i64
+        );
+    }
+}
+
+#[test]
+fn test_huge_syscall_buffers() {
+    // Checked a span at a time and transferred in bounded chunks, not a quad at a time
+    let code = assemble(
+        "irmovq $0x100000000, %rsi
+        irmovq $0x100000000000, %rdx
+        irmovq $0, %rdi
+        irmovq $2, %rax     # read
+        syscall
+        rrmovq %rax, %rbx
+        irmovq $1, %rdi
+        irmovq $1, %rax     # write
+        syscall
+        halt",
+    );
+    let table = Rc::new(RefCell::new(HostSyscalls::new(&b"abc"[..], Vec::new())));
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_syscalls(table.clone())
+        .with_sparse_memory();
+    simulator
+        .map_device(
+            console::DEFAULT_BASE,
+            Rc::new(RefCell::new(Console::new(&b""[..], Vec::new()))),
+        )
+        .unwrap();
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.registers[Register::Rbx as usize], 3);
+    assert_eq!(simulator.registers[Register::Rax as usize], MAX_TRANSFER);
+    assert_eq!(&table.borrow().output()[..4], b"abc\0");
+
+    // A buffer running into a device is still refused
+    let code = assemble(
+        "irmovq $0x8000, %rsi
+        irmovq $0x10000, %rdx
+        irmovq $0, %rdi
+        irmovq $2, %rax     # read
+        syscall
+        halt",
+    );
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_syscalls(HostSyscalls::new(&b"abc"[..], Vec::new()))
+        .with_sparse_memory();
+    simulator
+        .map_device(
+            console::DEFAULT_BASE,
+            Rc::new(RefCell::new(Console::new(&b""[..], Vec::new()))),
+        )
+        .unwrap();
+    simulator.run();
+    assert_eq!(
+        simulator.state,
+        Status::Error(format!(
+            "Memory address out of bounds: {}",
+            console::DEFAULT_BASE
+        ))
+    );
+}
+
+#[test]
+fn test_loop_detection_considers_sparse_memory() {
+    // Registers and CC repeat every iteration, but the counter far above RAM keeps growing
+    let code = assemble(
+        "irmovq $1, %rcx
+        irmovq $0x100000000, %rbx
+        loop:
+        mrmovq 0(%rbx), %rax
+        addq %rcx, %rax
+        rmmovq %rax, 0(%rbx)
+        xorq %rax, %rax
+        jmp loop",
+    );
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_loop_detection()
+        .with_step_limit(1000)
+        .with_sparse_memory();
+    simulator.run();
+    assert_eq!(simulator.state, Status::StepLimitExceeded);
+    assert!(simulator.sparse_memory().unwrap().get(0x100000000) > 100);
+}
+
+#[test]
+fn test_reset_frees_pages() {
+    let code = assemble(HIGH_STACK);
+    let mut simulator = Simulator::<1024>::new(&code).with_sparse_memory();
+    simulator.run();
+    simulator.reset();
+    assert_eq!(simulator.sparse_memory().unwrap().page_count(), 0);
+}
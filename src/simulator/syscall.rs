@@ -0,0 +1,299 @@
+use crate::simulator::device::DeviceBus;
+use crate::simulator::memory_map::MemoryMap;
+use crate::simulator::mmu::{Access, Mmu, PAGE_SIZE};
+use crate::simulator::sparse_memory::SparseMemory;
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+#[cfg(test)]
+mod syscall_tests;
+
+/// `exit(code)`: halts the program with exit code `%rdi`.
+pub const EXIT: i64 = 0;
+/// `write(fd, buf, count)`: prints the low byte of each of the first `count` quads at `buf`,
+/// up to [`MAX_TRANSFER`], returning how many were printed.
+pub const WRITE: i64 = 1;
+/// `read(fd, buf, count)`: reads up to `count` bytes, and at most [`MAX_TRANSFER`], into the
+/// quads at `buf`, returning how many were read, 0 at the end of input.
+pub const READ: i64 = 2;
+/// `sbrk(increment)`: moves the program break, returning its old value.
+pub const SBRK: i64 = 3;
+/// `time()`: returns the number of instructions retired before this one.
+pub const TIME: i64 = 4;
+
+/// Returned by a system call that cannot be carried out, such as one on an unknown descriptor.
+pub const FAILED: i64 = -1;
+
+/// Characters one `read` or `write` transfers at most, like a short transfer on a host; the
+/// program calls again for the rest.
+pub const MAX_TRANSFER: i64 = 4096;
+
+/// How a system call finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyscallResult {
+    /// Continue after the `syscall`, with this value in `%rax`
+    Return(i64),
+    /// Halt with this exit code
+    Exit(i64),
+}
+
+/// What a system call sees of the machine.
+///
+/// Memory writes are collected rather than applied, so that the simulator can log them like
+/// any other instruction's.
+pub struct SyscallContext<'m> {
+    /// `%rdi`, `%rsi` and `%rdx`
+    pub args: [i64; 3],
+    /// Number of instructions retired before the `syscall`
+    pub steps: u64,
+    pub stack_pointer: i64,
+    /// Length of the program image, the lowest address free for the heap
+    pub program_len: i64,
+    memory: &'m [i64],
+    /// Translates buffer addresses, when translation is on
+    mmu: Option<&'m Mmu>,
+    /// Checks buffer accesses, when the simulator has a memory map
+    memory_map: Option<&'m MemoryMap>,
+    /// Memory outside RAM, and the devices that take precedence over it
+    sparse_memory: Option<(&'m SparseMemory, &'m DeviceBus<'m>)>,
+    pub(super) writes: Vec<(i64, i64)>,
+}
+
+impl<'m> SyscallContext<'m> {
+    pub(super) fn new(
+        args: [i64; 3],
+        steps: u64,
+        stack_pointer: i64,
+        program_len: i64,
+        memory: &'m [i64],
+        mmu: Option<&'m Mmu>,
+        memory_map: Option<&'m MemoryMap>,
+    ) -> Self {
+        Self {
+            args,
+            steps,
+            stack_pointer,
+            program_len,
+            memory,
+            mmu,
+            memory_map,
+            sparse_memory: None,
+            writes: Vec::new(),
+        }
+    }
+
+    /// Lets buffers lie anywhere in `sparse_memory` that no device claims.
+    pub(super) fn with_sparse_memory(
+        mut self,
+        sparse_memory: &'m SparseMemory,
+        devices: &'m DeviceBus<'m>,
+    ) -> Self {
+        self.sparse_memory = Some((sparse_memory, devices));
+        self
+    }
+
+    fn in_ram(&self, addr: i64) -> bool {
+        usize::try_from(addr).is_ok_and(|index| index < self.memory.len())
+    }
+
+    /// The physical address of `addr`, which must lie in RAM or sparse memory.
+    fn check(&self, addr: i64, access: Access) -> Result<i64, String> {
+        let addr = match self.mmu {
+            Some(mmu) => mmu.translate_quietly(self.memory, addr, access)?,
+            None => addr,
+        };
+        if let Some(memory_map) = self.memory_map {
+            memory_map
+                .check(addr, access)
+                .map_err(|fault| fault.to_string())?;
+        }
+        let in_sparse_memory = self
+            .sparse_memory
+            .is_some_and(|(_, devices)| !devices.contains(addr));
+        if self.in_ram(addr) || in_sparse_memory {
+            Ok(addr)
+        } else {
+            Err(format!("Memory address out of bounds: {}", addr))
+        }
+    }
+
+    /// Reads memory, including the system call's own writes.
+    pub fn read(&self, addr: i64) -> Result<i64, String> {
+        let addr = self.check(addr, Access::Read)?;
+        let written = self
+            .writes
+            .iter()
+            .rev()
+            .find(|(written, _)| *written == addr);
+        Ok(match (written, self.sparse_memory) {
+            (Some(&(_, value)), _) => value,
+            (None, Some((sparse_memory, _))) if !self.in_ram(addr) => sparse_memory.get(addr),
+            (None, _) => self.memory[addr as usize],
+        })
+    }
+
+    pub fn write(&mut self, addr: i64, value: i64) -> Result<(), String> {
+        let addr = self.check(addr, Access::Write)?;
+        self.writes.push((addr, value));
+        Ok(())
+    }
+
+    /// The first physical address above `addr` where memory may stop behaving as it does at
+    /// `addr`: an edge of RAM, a region or a device.
+    fn next_boundary(&self, addr: i64) -> Option<i64> {
+        let ram = [0, self.memory.len() as i64];
+        let regions = self
+            .memory_map
+            .into_iter()
+            .flat_map(|memory_map| memory_map.regions())
+            .flat_map(|region| [region.start, region.end]);
+        let devices = self
+            .sparse_memory
+            .into_iter()
+            .flat_map(|(_, devices)| devices.ranges())
+            .flat_map(|range| [range.start, range.end]);
+        ram.into_iter()
+            .chain(regions)
+            .chain(devices)
+            .filter(|&boundary| boundary > addr)
+            .min()
+    }
+
+    /// Checks that `count` quads starting at `buf` lie in memory and allow `access`.
+    pub fn check_buffer(&self, buf: i64, count: i64, access: Access) -> Result<(), String> {
+        if count <= 0 {
+            return Ok(());
+        }
+        let last = (count - 1)
+            .checked_mul(8)
+            .and_then(|offset| buf.checked_add(offset))
+            .ok_or_else(|| format!("Buffer at {} of {} quads is out of bounds", buf, count))?;
+        self.check(buf, access)?;
+        self.check(last, access)?;
+        if self.mmu.is_none() && self.memory_map.is_none() && self.sparse_memory.is_none() {
+            return Ok(());
+        }
+        // Pages, regions and devices in between may refuse the access, but every quad up to
+        // the next page or boundary fares as the one before it, so only one per span is checked
+        let (buf, last) = (buf as i128, last as i128);
+        let mut addr = buf;
+        while addr <= last {
+            let physical = self.check(addr as i64, access)? as i128;
+            let mut end = match self.next_boundary(physical as i64) {
+                Some(boundary) => addr + (boundary as i128 - physical),
+                None => last + 1,
+            };
+            if self.mmu.is_some() {
+                let page = PAGE_SIZE as i128;
+                end = end.min(addr - addr.rem_euclid(page) + page);
+            }
+            // The first quad of the buffer at or past `end`
+            addr = buf + (end - buf + 7).div_euclid(8) * 8;
+        }
+        Ok(())
+    }
+}
+
+/// The system calls a program can make with `syscall`, dispatched on the number in `%rax`.
+///
+/// An `Err` stops the simulator with `Status::Error`.
+pub trait SyscallTable {
+    fn call(&mut self, number: i64, context: &mut SyscallContext) -> Result<SyscallResult, String>;
+}
+
+/// Lets the caller keep a handle to a table, for example to inspect its output after a run.
+impl<T: SyscallTable + ?Sized> SyscallTable for Rc<RefCell<T>> {
+    fn call(&mut self, number: i64, context: &mut SyscallContext) -> Result<SyscallResult, String> {
+        self.borrow_mut().call(number, context)
+    }
+}
+
+/// The standard system calls, reading from `input` (descriptor 0) and writing to `output`
+/// (descriptor 1).
+///
+/// Characters occupy one quad each, as with the console device. The program break starts at
+/// the end of the program image, rounded up to a quad, and may not pass the stack pointer.
+pub struct HostSyscalls<R: Read, W: Write> {
+    input: R,
+    output: W,
+    /// The program break, once `sbrk` has been called
+    brk: Option<i64>,
+}
+
+impl<R: Read, W: Write> HostSyscalls<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            brk: None,
+        }
+    }
+
+    /// Everything written so far, when the output is a buffer.
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    fn write(&mut self, context: &SyscallContext) -> Result<i64, String> {
+        let [fd, buf, count] = context.args;
+        if fd != 1 || count < 0 {
+            return Ok(FAILED);
+        }
+        context.check_buffer(buf, count, Access::Read)?;
+        let count = count.min(MAX_TRANSFER);
+        let bytes = (0..count)
+            .map(|i| context.read(buf + 8 * i).map(|value| value as u8))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.output
+            .write_all(&bytes)
+            .and_then(|_| self.output.flush())
+            .map_err(|e| format!("System call output failed: {}", e))?;
+        Ok(count)
+    }
+
+    fn read(&mut self, context: &mut SyscallContext) -> Result<i64, String> {
+        let [fd, buf, count] = context.args;
+        if fd != 0 || count < 0 {
+            return Ok(FAILED);
+        }
+        context.check_buffer(buf, count, Access::Write)?;
+        let mut bytes = vec![0; count.min(MAX_TRANSFER) as usize];
+        let read = loop {
+            match self.input.read(&mut bytes) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(format!("System call input failed: {}", e)),
+            }
+        };
+        for (i, &byte) in bytes[..read].iter().enumerate() {
+            context.write(buf + 8 * i as i64, byte as i64)?;
+        }
+        Ok(read as i64)
+    }
+
+    fn sbrk(&mut self, context: &SyscallContext) -> i64 {
+        let start = (context.program_len as u64).next_multiple_of(8) as i64;
+        let old = self.brk.unwrap_or(start);
+        match old.checked_add(context.args[0]) {
+            Some(new) if new >= start && new <= context.stack_pointer => {
+                self.brk = Some(new);
+                old
+            }
+            _ => FAILED,
+        }
+    }
+}
+
+impl<R: Read, W: Write> SyscallTable for HostSyscalls<R, W> {
+    fn call(&mut self, number: i64, context: &mut SyscallContext) -> Result<SyscallResult, String> {
+        let value = match number {
+            EXIT => return Ok(SyscallResult::Exit(context.args[0])),
+            WRITE => self.write(context)?,
+            READ => self.read(context)?,
+            SBRK => self.sbrk(context),
+            TIME => context.steps as i64,
+            _ => return Err(format!("Unknown system call: {}", number)),
+        };
+        Ok(SyscallResult::Return(value))
+    }
+}
@@ -0,0 +1,177 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::ast::{Instruction, Register};
+use crate::simulator::simulator_guts::{LogPolicy, Simulator, Status, decode};
+
+type BufferSyscalls = Rc<RefCell<HostSyscalls<&'static [u8], Vec<u8>>>>;
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Runs `src` on the logging path and on the fast path, which must agree, returning the
+/// final simulator and everything the program wrote.
+fn run(src: &str, input: &'static [u8]) -> (Status, [i64; 13], Option<i64>, Vec<u8>) {
+    let code = assemble(src);
+    let mut results = Vec::new();
+    for policy in [LogPolicy::Full, LogPolicy::Off] {
+        let table: BufferSyscalls = Rc::new(RefCell::new(HostSyscalls::new(input, Vec::new())));
+        let mut simulator = Simulator::<1024>::new(&code)
+            .with_log_policy(policy)
+            .with_syscalls(table.clone());
+        simulator.run();
+        let output = table.borrow().output().clone();
+        results.push((
+            simulator.state,
+            simulator.registers,
+            simulator.exit_code,
+            output,
+        ));
+    }
+    assert_eq!(results[0], results[1], "{}", src);
+    results.pop().unwrap()
+}
+
+#[test]
+fn test_syscall_encoding() {
+    assert_eq!(assemble("syscall"), [0xC0]);
+    assert_eq!(decode(&[0xC0], 0), Ok(Instruction::Syscall));
+}
+
+#[test]
+fn test_write_and_exit() {
+    let (state, _, exit_code, output) = run(
+        "irmovq $512, %rsi
+        irmovq $0x48, %rax
+        rmmovq %rax, (%rsi)
+        irmovq $0x69, %rax
+        rmmovq %rax, 8(%rsi)
+        irmovq $0x0a, %rax
+        rmmovq %rax, 16(%rsi)
+        irmovq $3, %rdx
+        irmovq $1, %rdi
+        irmovq $1, %rax     # write
+        syscall
+        rrmovq %rax, %rdi
+        irmovq $0, %rax     # exit
+        syscall
+        halt",
+        b"",
+    );
+    assert_eq!(state, Status::Halted);
+    assert_eq!(exit_code, Some(3));
+    assert_eq!(output, b"Hi\n");
+}
+
+#[test]
+fn test_read() {
+    let (state, registers, _, output) = run(
+        "irmovq $512, %rsi
+        irmovq $4, %rdx
+        irmovq $0, %rdi
+        irmovq $2, %rax     # read
+        syscall
+        rrmovq %rax, %rbx
+        rrmovq %rax, %rdx
+        irmovq $1, %rdi
+        irmovq $1, %rax     # write
+        syscall
+        irmovq $2, %rax     # read at the end of input
+        irmovq $0, %rdi
+        syscall
+        halt",
+        b"ok",
+    );
+    assert_eq!(state, Status::Halted);
+    assert_eq!(registers[Register::Rbx as usize], 2);
+    assert_eq!(registers[Register::Rax as usize], 0);
+    assert_eq!(output, b"ok");
+}
+
+#[test]
+fn test_sbrk_and_time() {
+    let (state, registers, exit_code, _) = run(
+        "irmovq $16, %rdi
+        irmovq $3, %rax     # sbrk
+        syscall
+        rrmovq %rax, %rbx
+        irmovq $0, %rdi
+        irmovq $3, %rax
+        syscall
+        rrmovq %rax, %rcx
+        irmovq $4096, %rdi  # past the stack
+        irmovq $3, %rax
+        syscall
+        rrmovq %rax, %rdx
+        irmovq $4, %rax     # time
+        syscall
+        halt",
+        b"",
+    );
+    assert_eq!(state, Status::Halted);
+    assert_eq!(exit_code, None);
+    // The program is 81 bytes long, so the heap starts at 88
+    assert_eq!(registers[Register::Rbx as usize], 88);
+    assert_eq!(registers[Register::Rcx as usize], 104);
+    assert_eq!(registers[Register::Rdx as usize], FAILED);
+    assert_eq!(registers[Register::Rax as usize], 13);
+}
+
+#[test]
+fn test_bad_syscalls() {
+    let (state, ..) = run("irmovq $99, %rax\nsyscall\nhalt", b"");
+    assert_eq!(state, Status::Error("Unknown system call: 99".to_string()));
+
+    // Writing a buffer that leaves RAM faults; an unknown descriptor just fails
+    let (state, ..) = run(
+        "irmovq $1, %rax\nirmovq $1, %rdi\nirmovq $1000, %rsi\nirmovq $4, %rdx\nsyscall\nhalt",
+        b"",
+    );
+    assert_eq!(
+        state,
+        Status::Error("Memory address out of bounds: 1024".to_string())
+    );
+    let (state, registers, ..) = run("irmovq $1, %rax\nirmovq $7, %rdi\nsyscall\nhalt", b"");
+    assert_eq!(state, Status::Halted);
+    assert_eq!(registers[Register::Rax as usize], FAILED);
+
+    let code = assemble("syscall");
+    let mut simulator = Simulator::<1024>::new(&code);
+    simulator.run();
+    assert_eq!(
+        simulator.state,
+        Status::Error("No system call table for system call 0".to_string())
+    );
+}
+
+#[test]
+fn test_syscalls_are_logged() {
+    let code = assemble("irmovq $2, %rax\nirmovq $256, %rsi\nirmovq $1, %rdx\nsyscall\nhalt");
+    let table = HostSyscalls::new(&b"A"[..], Vec::new());
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_syscalls(table)
+        .with_loop_detection();
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.memory[256], 'A' as i64);
+    let (_, syscall_changes): (Vec<_>, Vec<_>) = simulator
+        .log
+        .iter()
+        .filter(|(id, _)| *id == 3)
+        .cloned()
+        .unzip();
+    assert_eq!(
+        syscall_changes,
+        [
+            crate::simulator::simulator_guts::AtomicChange::Memory {
+                addr: 256,
+                value: 'A' as i64
+            },
+            crate::simulator::simulator_guts::AtomicChange::Register {
+                reg: Register::Rax,
+                value: 1
+            },
+            crate::simulator::simulator_guts::AtomicChange::InstructionPointer { ip: 31 },
+        ]
+    );
+}
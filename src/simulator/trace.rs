@@ -0,0 +1,455 @@
+use super::simulator_guts::{AtomicChange, Status, decode};
+use crate::ast::{OwnedInstruction, Register};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+#[cfg(test)]
+mod trace_tests;
+
+/// Binary trace file layout:
+///
+/// `MAGIC | header | change* | record*`, where the header holds the 13 registers, PC and CC
+/// before the first instruction, and each record is a tag byte followed by its fields. An
+/// instruction record stores its PC and raw bytes and is followed by the changes it caused.
+/// Changes before the first instruction record were made without retiring an instruction,
+/// such as the error status when the very first fetch faults.
+/// Integers are zigzag LEB128 varints, so typical records take a handful of bytes.
+const MAGIC: &[u8; 8] = b"Y86TRC\x00\x01";
+
+const TAG_INSTRUCTION: u8 = 0x01;
+const TAG_REGISTER: u8 = 0x02;
+const TAG_MEMORY: u8 = 0x03;
+const TAG_IP: u8 = 0x04;
+const TAG_CC: u8 = 0x05;
+const TAG_STATUS: u8 = 0x06;
+
+/// Machine state before the first traced instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceHeader {
+    pub registers: [i64; 13],
+    pub instruction_pointer: i64,
+    pub condition_code: u8,
+}
+
+/// One retired instruction and the changes it made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// Number of instructions retired before this one
+    pub step: u64,
+    pub ip: i64,
+    pub instruction: OwnedInstruction,
+    pub changes: Vec<AtomicChange>,
+}
+
+/// Streams an execution log to disk in the compact binary trace format.
+pub struct TraceWriter<'a> {
+    out: Box<dyn Write + 'a>,
+    header_written: bool,
+}
+
+impl<'a> TraceWriter<'a> {
+    pub fn new(out: impl Write + 'a) -> Self {
+        Self {
+            out: Box::new(out),
+            header_written: false,
+        }
+    }
+
+    /// Creates a buffered trace file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<TraceWriter<'static>> {
+        Ok(TraceWriter::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Whether the header has been written yet.
+    pub fn has_header(&self) -> bool {
+        self.header_written
+    }
+
+    pub fn write_header(&mut self, header: &TraceHeader) -> io::Result<()> {
+        self.out.write_all(MAGIC)?;
+        for &value in &header.registers {
+            write_varint(&mut self.out, value)?;
+        }
+        write_varint(&mut self.out, header.instruction_pointer)?;
+        self.out.write_all(&[header.condition_code])?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Starts a record for the instruction encoded as `bytes` at `ip`.
+    pub fn write_instruction(&mut self, ip: i64, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(&[TAG_INSTRUCTION])?;
+        write_varint(&mut self.out, ip)?;
+        self.out.write_all(&[bytes.len() as u8])?;
+        self.out.write_all(bytes)
+    }
+
+    /// Appends a change to the current record.
+    pub fn write_change(&mut self, change: &AtomicChange) -> io::Result<()> {
+        match change {
+            &AtomicChange::Register { reg, value } => {
+                self.out.write_all(&[TAG_REGISTER, reg as u8])?;
+                write_varint(&mut self.out, value)
+            }
+            &AtomicChange::Memory { addr, value } => {
+                self.out.write_all(&[TAG_MEMORY])?;
+                write_varint(&mut self.out, addr)?;
+                write_varint(&mut self.out, value)
+            }
+            &AtomicChange::InstructionPointer { ip } => {
+                self.out.write_all(&[TAG_IP])?;
+                write_varint(&mut self.out, ip)
+            }
+            &AtomicChange::ConditionCode { cc } => self.out.write_all(&[TAG_CC, cc]),
+            AtomicChange::State { status } => {
+                self.out.write_all(&[TAG_STATUS])?;
+                write_status(&mut self.out, status)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Reads a binary trace back, one retired instruction at a time.
+pub struct TraceReader<R: Read> {
+    input: R,
+    pub header: TraceHeader,
+    /// Changes recorded before the first instruction, applied on top of the header
+    pub initial_changes: Vec<AtomicChange>,
+    /// A tag read ahead while collecting `initial_changes`
+    next_tag: Option<u8>,
+    current: Option<TraceRecord>,
+    steps_read: u64,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a Y86 trace file"));
+        }
+
+        let mut registers = [0; 13];
+        for value in registers.iter_mut() {
+            *value = read_varint(&mut input)?;
+        }
+        let header = TraceHeader {
+            registers,
+            instruction_pointer: read_varint(&mut input)?,
+            condition_code: read_u8(&mut input)?,
+        };
+        let mut reader = Self {
+            input,
+            header,
+            initial_changes: Vec::new(),
+            next_tag: None,
+            current: None,
+            steps_read: 0,
+        };
+        while let Some(tag) = reader.read_tag()? {
+            if tag == TAG_INSTRUCTION {
+                reader.next_tag = Some(tag);
+                break;
+            }
+            let change = reader.read_change(tag)?;
+            reader.initial_changes.push(change);
+        }
+        Ok(reader)
+    }
+
+    /// The next record tag, or `None` at the end of the trace.
+    fn read_tag(&mut self) -> io::Result<Option<u8>> {
+        if let Some(tag) = self.next_tag.take() {
+            return Ok(Some(tag));
+        }
+        let mut tag = [0];
+        Ok((self.input.read(&mut tag)? != 0).then_some(tag[0]))
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        loop {
+            let Some(tag) = self.read_tag()? else {
+                return Ok(self.current.take());
+            };
+
+            if tag == TAG_INSTRUCTION {
+                let ip = read_varint(&mut self.input)?;
+                let mut bytes = vec![0; read_u8(&mut self.input)? as usize];
+                self.input.read_exact(&mut bytes)?;
+                let instruction = decode(&bytes, 0).map_err(invalid_data)?;
+
+                let record = TraceRecord {
+                    step: self.steps_read,
+                    ip,
+                    instruction,
+                    changes: Vec::new(),
+                };
+                self.steps_read += 1;
+                if let Some(finished) = self.current.replace(record) {
+                    return Ok(Some(finished));
+                }
+                continue;
+            }
+
+            let change = self.read_change(tag)?;
+            match &mut self.current {
+                Some(record) => record.changes.push(change),
+                None => return Err(invalid_data("change recorded before any instruction")),
+            }
+        }
+    }
+
+    fn read_change(&mut self, tag: u8) -> io::Result<AtomicChange> {
+        let input = &mut self.input;
+        Ok(match tag {
+            TAG_REGISTER => AtomicChange::Register {
+                reg: Register::try_from(read_u8(input)?).map_err(invalid_data)?,
+                value: read_varint(input)?,
+            },
+            TAG_MEMORY => AtomicChange::Memory {
+                addr: read_varint(input)?,
+                value: read_varint(input)?,
+            },
+            TAG_IP => AtomicChange::InstructionPointer {
+                ip: read_varint(input)?,
+            },
+            TAG_CC => AtomicChange::ConditionCode {
+                cc: read_u8(input)?,
+            },
+            TAG_STATUS => AtomicChange::State {
+                status: read_status(input)?,
+            },
+            _ => return Err(invalid_data(format!("unknown record tag {:#x}", tag))),
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Reconstructs machine state from a trace, stepping forwards and backwards.
+///
+/// Only memory written during the trace is known; everything else reads as 0. State the
+/// simulator does not log, such as whether interrupts are enabled, is not reconstructed.
+///
+/// Every applied record is kept so that it can be undone, so memory grows with the number of
+/// steps replayed unless the history is bounded with [`TraceReplay::with_history_limit`].
+pub struct TraceReplay<R: Read> {
+    reader: TraceReader<R>,
+    registers: [i64; 13],
+    instruction_pointer: i64,
+    condition_code: u8,
+    status: Status,
+    memory: HashMap<i64, i64>,
+    /// Number of instructions replayed so far
+    steps: u64,
+    /// Applied records, with the values each change overwrote, oldest first
+    undo: VecDeque<(TraceRecord, Vec<AtomicChange>)>,
+    /// Keep at most this many records in `undo`
+    history_limit: Option<usize>,
+    /// Records undone by `step_back`, to be re-applied before reading further
+    redo: Vec<TraceRecord>,
+}
+
+impl<R: Read> TraceReplay<R> {
+    pub fn new(reader: TraceReader<R>) -> Self {
+        let header = reader.header.clone();
+        let initial_changes = reader.initial_changes.clone();
+        let mut replay = Self {
+            reader,
+            registers: header.registers,
+            instruction_pointer: header.instruction_pointer,
+            condition_code: header.condition_code,
+            status: Status::Running,
+            memory: HashMap::new(),
+            steps: 0,
+            undo: VecDeque::new(),
+            history_limit: None,
+            redo: Vec::new(),
+        };
+        for change in &initial_changes {
+            replay.apply(change);
+        }
+        replay
+    }
+
+    /// Only allows stepping back over the last `limit` instructions (at least one), bounding
+    /// memory use.
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = Some(limit.max(1));
+        self
+    }
+
+    pub fn registers(&self) -> &[i64; 13] {
+        &self.registers
+    }
+
+    pub fn instruction_pointer(&self) -> i64 {
+        self.instruction_pointer
+    }
+
+    pub fn condition_code(&self) -> u8 {
+        self.condition_code
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub fn memory(&self, addr: i64) -> i64 {
+        self.memory.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Number of instructions replayed so far.
+    pub fn step(&self) -> u64 {
+        self.steps
+    }
+
+    /// Applies the next instruction. Returns `None` at the end of the trace.
+    pub fn step_forward(&mut self) -> io::Result<Option<&TraceRecord>> {
+        let record = match self.redo.pop() {
+            Some(record) => record,
+            None => match self.reader.next().transpose()? {
+                Some(record) => record,
+                None => return Ok(None),
+            },
+        };
+
+        let undo = record
+            .changes
+            .iter()
+            .map(|change| self.apply(change))
+            .collect();
+        self.undo.push_back((record, undo));
+        self.steps += 1;
+        if let Some(limit) = self.history_limit
+            && self.undo.len() > limit
+        {
+            self.undo.pop_front();
+        }
+        Ok(self.undo.back().map(|(record, _)| record))
+    }
+
+    /// Reverts the last applied instruction. Returns false at the start of the trace, or of
+    /// the history kept.
+    pub fn step_back(&mut self) -> bool {
+        let Some((record, undo)) = self.undo.pop_back() else {
+            return false;
+        };
+        for change in undo.iter().rev() {
+            self.apply(change);
+        }
+        self.redo.push(record);
+        self.steps -= 1;
+        true
+    }
+
+    /// Applies a change, returning the change that would revert it.
+    fn apply(&mut self, change: &AtomicChange) -> AtomicChange {
+        match change {
+            &AtomicChange::Register { reg, value } => AtomicChange::Register {
+                reg,
+                value: std::mem::replace(&mut self.registers[reg as usize], value),
+            },
+            &AtomicChange::Memory { addr, value } => AtomicChange::Memory {
+                addr,
+                value: self.memory.insert(addr, value).unwrap_or(0),
+            },
+            &AtomicChange::InstructionPointer { ip } => AtomicChange::InstructionPointer {
+                ip: std::mem::replace(&mut self.instruction_pointer, ip),
+            },
+            &AtomicChange::ConditionCode { cc } => AtomicChange::ConditionCode {
+                cc: std::mem::replace(&mut self.condition_code, cc),
+            },
+            AtomicChange::State { status } => AtomicChange::State {
+                status: std::mem::replace(&mut self.status, status.clone()),
+            },
+        }
+    }
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn write_varint(out: &mut impl Write, value: i64) -> io::Result<()> {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    loop {
+        let byte = (zigzag & 0x7f) as u8;
+        zigzag >>= 7;
+        if zigzag == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_u8(input: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_varint(input: &mut impl Read) -> io::Result<i64> {
+    let mut zigzag = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(input)?;
+        zigzag |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok((zigzag >> 1) as i64 ^ -((zigzag & 1) as i64));
+        }
+    }
+    Err(invalid_data("varint too long"))
+}
+
+fn write_status(out: &mut impl Write, status: &Status) -> io::Result<()> {
+    match status {
+        Status::Running => out.write_all(&[0]),
+        Status::Halted => out.write_all(&[1]),
+        Status::Error(message) => {
+            out.write_all(&[2])?;
+            write_varint(out, message.len() as i64)?;
+            out.write_all(message.as_bytes())
+        }
+        Status::StepLimitExceeded => out.write_all(&[3]),
+        &Status::InfiniteLoop { start, end } => {
+            out.write_all(&[4])?;
+            write_varint(out, start)?;
+            write_varint(out, end)
+        }
+    }
+}
+
+fn read_status(input: &mut impl Read) -> io::Result<Status> {
+    Ok(match read_u8(input)? {
+        0 => Status::Running,
+        1 => Status::Halted,
+        2 => {
+            let mut message = vec![0; read_varint(input)? as usize];
+            input.read_exact(&mut message)?;
+            Status::Error(String::from_utf8(message).map_err(invalid_data)?)
+        }
+        3 => Status::StepLimitExceeded,
+        4 => Status::InfiniteLoop {
+            start: read_varint(input)?,
+            end: read_varint(input)?,
+        },
+        kind => return Err(invalid_data(format!("unknown status kind {}", kind))),
+    })
+}
@@ -0,0 +1,214 @@
+use super::*;
+use crate::assembler::parse_and_gen;
+use crate::simulator::simulator_guts::{LogPolicy, Simulator};
+
+const PROGRAM: &str = "
+    irmovq $256, %rsp
+    irmovq $3, %rcx
+    irmovq $1, %rdx
+loop:
+    pushq %rcx
+    subq %rdx, %rcx
+    jne loop
+    halt
+";
+
+fn assemble(src: &str) -> Vec<u8> {
+    parse_and_gen(src).unwrap().1.bytes
+}
+
+/// Runs `code` with the full in-memory log and returns it as trace records.
+fn full_log(code: &[u8]) -> (Simulator<'_, 1024>, Vec<TraceRecord>) {
+    let mut simulator = Simulator::<1024>::new(code);
+    simulator.run();
+    let records = simulator
+        .disassembly
+        .iter()
+        .enumerate()
+        .map(|(id, (ip, instruction))| TraceRecord {
+            step: id as u64,
+            ip: *ip,
+            instruction: instruction.clone(),
+            changes: simulator
+                .log
+                .iter()
+                .filter(|(log_id, _)| *log_id == id)
+                .map(|(_, change)| change.clone())
+                .collect(),
+        })
+        .collect();
+    (simulator, records)
+}
+
+fn stream(code: &[u8]) -> Vec<u8> {
+    let mut trace = Vec::new();
+    let mut simulator = Simulator::<1024>::new(code)
+        .with_log_policy(LogPolicy::Stream(TraceWriter::new(&mut trace)));
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert!(simulator.log.is_empty());
+    assert!(simulator.disassembly.is_empty());
+    drop(simulator);
+    trace
+}
+
+#[test]
+fn test_streamed_trace_matches_full_log() {
+    let code = assemble(PROGRAM);
+    let (_, expected) = full_log(&code);
+    let trace = stream(&code);
+
+    let reader = TraceReader::new(trace.as_slice()).unwrap();
+    assert_eq!(
+        reader.header.registers,
+        Simulator::<1024>::new(&code).registers
+    );
+    assert_eq!(reader.header.instruction_pointer, 0);
+    let records = reader.collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!(records, expected);
+}
+
+#[test]
+fn test_trace_is_compact() {
+    let code = assemble(PROGRAM);
+    let (simulator, _) = full_log(&code);
+    let trace = stream(&code);
+    // Each record is an opcode, a PC, up to ten instruction bytes and a few small changes
+    assert!(trace.len() < simulator.steps as usize * 24);
+}
+
+#[test]
+fn test_last_n_bounds_log() {
+    let code = assemble(PROGRAM);
+    let (full, expected) = full_log(&code);
+
+    let mut simulator = Simulator::<1024>::new(&code).with_log_policy(LogPolicy::LastN(2));
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert_eq!(simulator.steps, full.steps);
+    assert!(simulator.disassembly.len() >= 2 && simulator.disassembly.len() < 4);
+
+    // The retained tail is identical to the end of the full log, with ids offset by log_base
+    let retained = simulator.disassembly.len();
+    assert_eq!(simulator.log_base + retained, expected.len());
+    assert_eq!(
+        simulator.disassembly[..],
+        full.disassembly[full.disassembly.len() - retained..]
+    );
+    for (id, change) in &simulator.log {
+        assert!(expected[*id].changes.contains(change));
+    }
+}
+
+#[test]
+fn test_log_off_keeps_nothing() {
+    let code = assemble(PROGRAM);
+    let (full, _) = full_log(&code);
+
+    let mut simulator = Simulator::<1024>::new(&code).with_log_policy(LogPolicy::Off);
+    simulator.run();
+    assert_eq!(simulator.state, Status::Halted);
+    assert!(simulator.log.is_empty());
+    assert!(simulator.disassembly.is_empty());
+    assert_eq!(simulator.registers, full.registers);
+    assert_eq!(simulator.memory, full.memory);
+}
+
+#[test]
+fn test_replay_steps_forward_and_back() {
+    let code = assemble(PROGRAM);
+    let (full, _) = full_log(&code);
+    let trace = stream(&code);
+    let mut replay = TraceReplay::new(TraceReader::new(trace.as_slice()).unwrap());
+
+    // Run to the end, remembering the state after each step
+    let mut states = vec![(*replay.registers(), replay.instruction_pointer())];
+    while replay.step_forward().unwrap().is_some() {
+        states.push((*replay.registers(), replay.instruction_pointer()));
+    }
+    assert_eq!(replay.step(), full.steps);
+    assert_eq!(*replay.registers(), full.registers);
+    assert_eq!(replay.instruction_pointer(), full.instruction_pointer);
+    assert_eq!(replay.memory(0xf8), 3);
+    assert_eq!(replay.memory(0xe8), 1);
+
+    // Walk all the way back, then forward again over the same records
+    for state in states.iter().rev().skip(1) {
+        assert!(replay.step_back());
+        assert_eq!((*replay.registers(), replay.instruction_pointer()), *state);
+    }
+    assert!(!replay.step_back());
+    assert_eq!(replay.memory(0xf8), 0);
+
+    for state in states.iter().skip(1) {
+        replay.step_forward().unwrap().unwrap();
+        assert_eq!((*replay.registers(), replay.instruction_pointer()), *state);
+    }
+    assert!(replay.step_forward().unwrap().is_none());
+}
+
+#[test]
+fn test_trace_records_error_status() {
+    // ret with an empty stack pops past the end of memory
+    let code = assemble("ret");
+    let mut trace = Vec::new();
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_log_policy(LogPolicy::Stream(TraceWriter::new(&mut trace)));
+    simulator.run();
+    let expected = simulator.state.clone();
+    assert!(matches!(expected, Status::Error(_)));
+    drop(simulator);
+
+    let mut replay = TraceReplay::new(TraceReader::new(trace.as_slice()).unwrap());
+    while replay.step_forward().unwrap().is_some() {}
+    assert_eq!(*replay.status(), expected);
+}
+
+#[test]
+fn test_reader_rejects_bad_magic() {
+    assert!(TraceReader::new(&b"NOTATRACE"[..]).is_err());
+}
+
+#[test]
+fn test_trace_of_faulting_first_fetch() {
+    // 0xf0 is not an instruction, so nothing retires
+    let code = [0xf0];
+    let mut trace = Vec::new();
+    let mut simulator = Simulator::<1024>::new(&code)
+        .with_log_policy(LogPolicy::Stream(TraceWriter::new(&mut trace)));
+    simulator.run();
+    let expected = simulator.state.clone();
+    assert!(matches!(expected, Status::Error(_)));
+    drop(simulator);
+
+    let reader = TraceReader::new(trace.as_slice()).unwrap();
+    assert_eq!(
+        reader.initial_changes,
+        vec![AtomicChange::State {
+            status: expected.clone()
+        }]
+    );
+    let mut replay = TraceReplay::new(reader);
+    assert_eq!(*replay.status(), expected);
+    assert!(replay.step_forward().unwrap().is_none());
+    assert_eq!(replay.step(), 0);
+}
+
+#[test]
+fn test_replay_history_limit() {
+    let code = assemble(PROGRAM);
+    let (full, _) = full_log(&code);
+    let trace = stream(&code);
+    let mut replay =
+        TraceReplay::new(TraceReader::new(trace.as_slice()).unwrap()).with_history_limit(2);
+    while replay.step_forward().unwrap().is_some() {}
+    assert_eq!(replay.step(), full.steps);
+
+    assert!(replay.step_back());
+    assert!(replay.step_back());
+    assert!(!replay.step_back());
+    assert_eq!(replay.step(), full.steps - 2);
+    replay.step_forward().unwrap().unwrap();
+    replay.step_forward().unwrap().unwrap();
+    assert_eq!(*replay.registers(), full.registers);
+}
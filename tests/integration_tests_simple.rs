@@ -1,6 +1,7 @@
 use y86_seq::assembler::{parse_and_gen, remove_comments};
 use y86_seq::ast::Register;
-use y86_seq::simulator::simulate;
+use y86_seq::simulator::simulator_guts::Status;
+use y86_seq::simulator::{SimulationOptions, simulate, simulate_with_options};
 
 #[test]
 /// Tests nop, rrmovq, and halt instructions
@@ -652,3 +653,37 @@ halt
         "Stack pointer should be back to original position after balanced push/pop"
     );
 }
+
+#[test]
+/// Tests that a runaway program is stopped by the step budget or the loop detector
+fn integration_test_non_terminating_program() {
+    let src_asm = r#"
+irmovq $3, %rax
+spin:
+rrmovq %rax, %rbx
+jmp spin
+        "#;
+    let machine_code = parse_and_gen(src_asm)
+        .unwrap_or_else(|e| panic!("Parsing failed: {:?}", e))
+        .1
+        .bytes;
+
+    let options = SimulationOptions {
+        max_steps: Some(100),
+        ..Default::default()
+    };
+    let simulator = simulate_with_options::<1024>(&machine_code, &options);
+    assert_eq!(simulator.state, Status::StepLimitExceeded);
+    assert_eq!(simulator.steps, 100);
+
+    let options = SimulationOptions {
+        detect_loops: true,
+        ..Default::default()
+    };
+    let simulator = simulate_with_options::<1024>(&machine_code, &options);
+    assert_eq!(
+        simulator.state,
+        Status::InfiniteLoop { start: 10, end: 12 },
+        "Loop should be reported with the PC range of its body"
+    );
+}